@@ -0,0 +1,70 @@
+#![allow(unstable)]
+extern crate rustirc;
+
+use std::old_io as io;
+use std::os;
+
+use rustirc::server::{Server, ServerConfig};
+
+static USAGE : &'static str = "usage: rustircd [--bind host:port] [--name server.name] \
+  [--network name] [--password pass] [--motd file]";
+
+/// `parse_args` builds a server config from the command line
+///
+/// # Returns
+///
+/// The config, or a description of the bad argument
+fn parse_args( args : Vec < String > ) -> Result < ServerConfig, String > {
+  let mut config = ServerConfig::new( "irc.localhost" );
+  let mut args = args.into_iter( ).skip( 1 );
+  loop {
+    let flag = match args.next( ) {
+      Some ( f ) => f,
+      None       => return Ok( config ),
+    };
+    let value = match args.next( ) {
+      Some ( v ) => v,
+      None       => return Err( format! ( "missing value for {}", flag ) ),
+    };
+    match flag.as_slice( ) {
+      "--bind"     => config.bind = value,
+      "--name"     => config.name = value,
+      "--network"  => config.network = value,
+      "--password" => config.password = Some( value ),
+      "--motd"     => {
+        let mut file = match io::File::open( &Path::new( value.as_slice( ) ) ) {
+          Ok ( f )  => f,
+          Err ( e ) => return Err( format! ( "reading {}: {}", value, e.desc ) ),
+        };
+        let text = match file.read_to_string( ) {
+          Ok ( t )  => t,
+          Err ( e ) => return Err( format! ( "reading {}: {}", value, e.desc ) ),
+        };
+        config.motd = text.as_slice( ).lines( ).map( |l| l.to_string( ) ).collect( );
+      },
+      _            => return Err( format! ( "unknown option {}", flag ) ),
+    }
+  }
+}
+
+fn main( ) {
+  let config = match parse_args( os::args( ) ) {
+    Ok ( c )  => c,
+    Err ( e ) => {
+      println! ( "rustircd: {}\n{}", e, USAGE );
+      os::set_exit_status( 2 );
+      return;
+    },
+  };
+  let name = config.name.clone( );
+  match Server::start( config ) {
+    Ok ( server ) => {
+      println! ( "rustircd: {} listening on {}", name, server.local );
+      server.wait( );
+    },
+    Err ( e )     => {
+      println! ( "rustircd: binding server: {}", e.desc );
+      os::set_exit_status( 1 );
+    },
+  }
+}
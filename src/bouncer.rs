@@ -0,0 +1,792 @@
+// import built in modules
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+use std::old_io as io;
+use std::old_io::{Acceptor, Listener};
+use std::old_io::net::ip::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use time;
+
+// import custom modules
+use client;
+use message;
+use reader;
+use utils::debug;
+use writer;
+
+pub type TID                      = u64;
+pub type TTIMEOUT                 = u64;
+
+static ACCEPT_POLL      : TTIMEOUT = 500;     // ms between checks for a stop request
+static BNC_TICK         : TTIMEOUT = 1000;    // ms between registration timeout checks
+static WRITE_TIMEOUT    : TTIMEOUT = 30000;   // ms a write to a downstream client may take
+static SEND_QUEUE       : usize    = 1 << 23; // bytes queued for a client before it's dropped
+static BUFFER_SIZE      : usize    = 200;     // default lines kept per channel
+static MAX_QUERIES      : usize    = 50;      // default query buffers kept
+static REGISTER_TIMEOUT : i64      = 30;      // default seconds a client has to register
+static MAX_PENDING      : usize    = 10;      // default clients registering at once
+
+/// `BouncerConfig` holds the settings of a `Bouncer`
+///
+/// # Members
+///
+/// * `name` - name the bouncer uses as the source of its own replies
+/// * `bind` - address to listen for downstream clients on, as "host:port"
+/// * `password` - password downstream clients must send with PASS
+/// * `buffer_size` - lines kept per channel or query while nobody is attached
+/// * `max_queries` - query buffers kept at once, the oldest is dropped first
+/// * `register_timeout` - seconds a downstream client has to send PASS, NICK
+/// and USER before it's dropped
+/// * `max_pending` - downstream clients that may be registering at once, more
+/// are dropped as they connect
+pub struct BouncerConfig {
+  pub name             : String,
+  pub bind             : String,
+  pub password         : String,
+  pub buffer_size      : usize,
+  pub max_queries      : usize,
+  pub register_timeout : i64,
+  pub max_pending      : usize,
+}
+
+impl BouncerConfig {
+  /// `new` creates a bouncer config with the default name, buffer size and
+  /// registration limits
+  ///
+  /// # Arguments
+  ///
+  /// * `bind` - address to listen on, as "host:port"
+  /// * `password` - password downstream clients must send with PASS
+  ///
+  /// # Returns
+  ///
+  /// A new BouncerConfig
+  pub fn new( bind : &str, password : &str ) -> BouncerConfig {
+    BouncerConfig {
+      name             : String::from_str( "rustirc.bnc" ),
+      bind             : bind.to_string( ),
+      password         : password.to_string( ),
+      buffer_size      : BUFFER_SIZE,
+      max_queries      : MAX_QUERIES,
+      register_timeout : REGISTER_TIMEOUT,
+      max_pending      : MAX_PENDING,
+    }
+  }
+}
+
+/// `BncEvent` is an event handled by the bouncer's core thread
+enum BncEvent {
+  Upstream ( message::Message ),
+  UpstreamClosed,
+  Attach ( TID, io::TcpStream ),
+  Line ( TID, String ),
+  Detach ( TID ),
+  Tick,
+  Stop,
+}
+
+/// `Bouncer` keeps a client connected upstream and lets downstream IRC clients
+/// attach to it
+///
+/// # Members
+///
+/// * `local` - address the bouncer is listening on
+/// * `chan` - channel to the core thread
+/// * `stop` - set once the bouncer has been asked to stop
+///
+/// # Notes
+///
+/// * Downstream clients register with PASS, NICK and USER as they would on a
+/// server. The nick they send is ignored, they get the upstream nick.
+/// * Each downstream client is written to on its own thread, so a slow one
+/// can't hold up upstream or the others.
+/// * Lines sent by one downstream client are passed upstream. PRIVMSG and
+/// NOTICE are also echoed to the other attached clients.
+/// * While nobody is attached, channel and private messages are buffered and
+/// played back to the next client that attaches.
+pub struct Bouncer {
+  pub local : SocketAddr,
+
+  chan      : mpsc::Sender < BncEvent >,
+  stop      : Arc < AtomicBool >,
+}
+
+impl Bouncer {
+  /// `start` starts a bouncer in front of a running client
+  ///
+  /// # Arguments
+  ///
+  /// * `config` - settings of the bouncer
+  /// * `rx` - receiver returned by `Client::start_thread`
+  /// * `client` - client returned by `Client::start_thread`
+  ///
+  /// # Returns
+  ///
+  /// A tuple containing:
+  /// * Receiver the bouncer passes every upstream message on to
+  /// * The running bouncer
+  pub fn start(
+    config : BouncerConfig,
+    rx : mpsc::Receiver < message::Message >,
+    client : client::Client
+  ) -> io::IoResult < ( mpsc::Receiver < message::Message >, Bouncer ) > {
+    let listener = try! ( io::TcpListener::bind( config.bind.as_slice( ) ) );
+    let mut acceptor = try! ( listener.listen( ) );
+    let local = try! ( acceptor.socket_name( ) );
+    let ( tx, port ) = mpsc::channel( );
+    let ( outtx, outrx ) = mpsc::channel( );
+    let stop = Arc::new( AtomicBool::new( false ) );
+
+    // accept downstream clients until we're stopped
+    let ( atx, astop ) = ( tx.clone( ), stop.clone( ) );
+    thread::Thread::spawn( move || Bouncer::accept_loop( acceptor, atx, astop ) );
+
+    // wake the core up regularly so slow registrations can be dropped
+    let ( ttx, tstop ) = ( tx.clone( ), stop.clone( ) );
+    thread::Thread::spawn( move || {
+      while !tstop.load( Ordering::SeqCst ) {
+        io::timer::sleep( Duration::milliseconds( BNC_TICK as i64 ) );
+        if ttx.send( BncEvent::Tick ).is_err( ) {
+          break;
+        }
+      }
+    } );
+
+    // pass upstream messages to the core
+    let utx = tx.clone( );
+    thread::Thread::spawn( move || {
+      for msg in rx.iter( ) {
+        if utx.send( BncEvent::Upstream( msg ) ).is_err( ) {
+          return;
+        }
+      }
+      let _ = utx.send( BncEvent::UpstreamClosed );
+    } );
+
+    // run the core
+    let label = format! ( "bnc {}", local );
+    thread::Thread::spawn( move || {
+      let _span = debug::Span::enter( label.as_slice( ) );
+      let mut core = Core::new( config, client, outtx );
+      core.run( port );
+    } );
+
+    irc_info! ( "bouncer listening on {}", local );
+    Ok( ( outrx, Bouncer {
+      local : local,
+      chan  : tx,
+      stop  : stop,
+    } ) )
+  }
+
+  /// `stop` detaches every downstream client and stops the upstream client
+  pub fn stop( &self ) {
+    self.stop.store( true, Ordering::SeqCst );
+    match self.chan.send( BncEvent::Stop ) {
+      Ok ( _ )  => (),
+      Err ( _ ) => irc_warn! ( "stopping bouncer: bouncer already stopped" ),
+    }
+  }
+
+  /// `accept_loop` accepts downstream clients and starts a reader for each
+  fn accept_loop(
+    mut acceptor : io::TcpAcceptor,
+    chan : mpsc::Sender < BncEvent >,
+    stop : Arc < AtomicBool >
+  ) {
+    let mut next : TID = 0;
+    while !stop.load( Ordering::SeqCst ) {
+      acceptor.set_timeout( Some( ACCEPT_POLL ) );
+      let tcp = match acceptor.accept( ) {
+        Ok ( tcp )  => tcp,
+        Err ( e )   => match e.kind {
+          io::IoErrorKind::TimedOut => continue,
+          _                         => {
+            irc_error! ( "accepting downstream client: {}", e.desc );
+            break;
+          },
+        },
+      };
+      next += 1;
+      if chan.send( BncEvent::Attach( next, tcp.clone( ) ) ).is_err( ) {
+        break;
+      }
+      let ( id, rchan ) = ( next, chan.clone( ) );
+      thread::Thread::spawn( move || {
+        let mut reader = reader::LineFramer::new( tcp, reader::MAX_LINE );
+        loop {
+          match reader.next_line( ) {
+            Ok ( reader::Frame::Line ( line ) )   => {
+              let line = String::from_utf8_lossy( line.as_slice( ) ).into_owned( );
+              if rchan.send( BncEvent::Line( id, line ) ).is_err( ) {
+                return;
+              }
+            },
+            Ok ( reader::Frame::TooLong ( len ) ) => {
+              irc_warn! ( "downstream client {} sent a {} byte line", id, len );
+              break;
+            },
+            Err ( _ )                             => break,
+          }
+        }
+        let _ = rchan.send( BncEvent::Detach( id ) );
+      } );
+    }
+  }
+}
+
+/// `Session` is a downstream client connected to the bouncer
+struct Session {
+  tcp       : io::TcpStream,
+  writer    : writer::LineWriter,
+  connected : i64,
+  pass_ok   : bool,
+  nick_sent : bool,
+  user_sent : bool,
+  attached  : bool,
+}
+
+/// `Core` owns the state of the bouncer and handles every event
+///
+/// # Members
+///
+/// * `config` - settings of the bouncer
+/// * `client` - the upstream client
+/// * `out` - channel every upstream message is passed on to
+/// * `sessions` - downstream clients by id
+/// * `welcome` - upstream 001-005 replies, without the nick parameter
+/// * `prefix` - our nick!user@host as seen upstream
+/// * `topics` - topics of the channels we're in, by lowercased name
+/// * `buffers` - lines missed while nobody was attached, by channel or nick
+struct Core {
+  config    : BouncerConfig,
+  client    : client::Client,
+  out       : mpsc::Sender < message::Message >,
+  sessions  : HashMap < TID, Session >,
+  welcome   : Vec < ( String, String, String ) >,
+  prefix    : Option < String >,
+  topics    : HashMap < String, String >,
+  buffers   : Vec < ( String, Vec < String > ) >,
+}
+
+impl Core {
+  /// `new` creates a bouncer core with nobody attached
+  fn new( config : BouncerConfig, client : client::Client, out : mpsc::Sender < message::Message > ) -> Core {
+    Core {
+      config    : config,
+      client    : client,
+      out       : out,
+      sessions  : HashMap::new( ),
+      welcome   : Vec::new( ),
+      prefix    : None,
+      topics    : HashMap::new( ),
+      buffers   : Vec::new( ),
+    }
+  }
+
+  /// `run` handles events until the bouncer is stopped or upstream closes
+  fn run( &mut self, port : mpsc::Receiver < BncEvent > ) {
+    for event in port.iter( ) {
+      match event {
+        BncEvent::Upstream ( msg )    => self.upstream( msg ),
+        BncEvent::UpstreamClosed      => {
+          irc_warn! ( "upstream connection closed" );
+          break;
+        },
+        BncEvent::Attach ( id, tcp )  => self.connected( id, tcp ),
+        BncEvent::Line ( id, line )   => self.downstream( id, line ),
+        BncEvent::Detach ( id )       => self.detach( id, "Connection closed" ),
+        BncEvent::Tick                => self.tick( ),
+        BncEvent::Stop                => {
+          self.client.stop( );
+          break;
+        },
+      }
+    }
+    let ids : Vec < TID > = self.sessions.keys( ).map( |id| *id ).collect( );
+    for id in ids.into_iter( ) {
+      self.detach( id, "Bouncer shutting down" );
+    }
+    irc_info! ( "bouncer stopped" );
+  }
+
+  /// `connected` adds a downstream client that hasn't registered yet, or
+  /// drops it if too many are registering already
+  fn connected( &mut self, id : TID, tcp : io::TcpStream ) {
+    irc_debug! ( "downstream client {} connected", id );
+    let pending = self.sessions.values( ).filter( |s| !s.attached ).count( );
+    self.sessions.insert( id, Session {
+      tcp       : tcp.clone( ),
+      writer    : writer::LineWriter::start( tcp, SEND_QUEUE, WRITE_TIMEOUT ),
+      connected : time::get_time( ).sec,
+      pass_ok   : false,
+      nick_sent : false,
+      user_sent : false,
+      attached  : false,
+    } );
+    if pending >= self.config.max_pending {
+      self.detach( id, "Too many connections" );
+    }
+  }
+
+  /// `tick` drops the downstream clients that took too long to register
+  fn tick( &mut self ) {
+    let now = time::get_time( ).sec;
+    let limit = self.config.register_timeout;
+    let late : Vec < TID > = self.sessions.iter( )
+      .filter( |&( _, s )| !s.attached && now - s.connected > limit )
+      .map( |( id, _ )| *id ).collect( );
+    for id in late.into_iter( ) {
+      self.detach( id, "Registration timed out" );
+    }
+  }
+
+  /// `nick` returns our current upstream nick
+  fn nick( &self ) -> String {
+    self.client.get_info( ).nick_name.clone( )
+  }
+
+  /// `our_prefix` returns our nick!user@host, guessing the host until we've
+  /// seen ourselves join a channel
+  fn our_prefix( &self ) -> String {
+    match self.prefix {
+      Some ( ref p ) => p.clone( ),
+      None           => {
+        let info = self.client.get_info( );
+        format! ( "{}!{}@{}", info.nick_name, info.user_name, self.config.name )
+      },
+    }
+  }
+
+  /// `send_to` queues a line for a downstream client, detaching it if it
+  /// has fallen too far behind or its connection failed
+  fn send_to( &mut self, id : TID, line : &str ) {
+    let failed = match self.sessions.get( &id ) {
+      Some ( s ) => s.writer.send( line ).err( ),
+      None       => None,
+    };
+    match failed {
+      Some ( reason ) => self.detach( id, reason ),
+      None            => (),
+    }
+  }
+
+  /// `send_attached` writes a line to every attached client except one
+  fn send_attached( &mut self, line : &str, except : Option < TID > ) {
+    let ids : Vec < TID > = self.sessions.iter( )
+      .filter( |&( id, s )| s.attached && Some( *id ) != except )
+      .map( |( id, _ )| *id ).collect( );
+    for id in ids.into_iter( ) {
+      self.send_to( id, line );
+    }
+  }
+
+  /// `reply` sends a reply from the bouncer itself to a downstream client
+  fn reply( &mut self, id : TID, code : &str, params : &str ) {
+    let line = format! ( ":{} {} {}", self.config.name, code, params );
+    self.send_to( id, line.as_slice( ) );
+  }
+
+  /// `detach` disconnects a downstream client. The upstream client stays.
+  fn detach( &mut self, id : TID, reason : &str ) {
+    match self.sessions.remove( &id ) {
+      Some ( mut s ) => {
+        irc_info! ( "downstream client {} detached: {}", id, reason );
+
+        // the writer closes the connection once the error has been written
+        let _ = s.writer.send( format! ( "ERROR :Closing Link: {}", reason ).as_slice( ) );
+        let _ = s.tcp.close_read( );
+      },
+      None           => (),
+    }
+  }
+
+  /// `attached` returns whether any downstream client is attached
+  fn attached( &self ) -> bool {
+    self.sessions.values( ).any( |s| s.attached )
+  }
+
+  /// `upstream` handles a message from the upstream server
+  fn upstream( &mut self, msg : message::Message ) {
+    let _ = self.out.send( msg.clone( ) );
+    let nick = self.nick( );
+    let ours = msg.nick( ).map( |n| n.to_ascii_lowercase( ) == nick.to_ascii_lowercase( ) ).unwrap_or( false );
+
+    // remember what we need to replay to clients that attach later
+    match msg.code.as_slice( ) {
+      "001" | "002" | "003" | "004" | "005" => {
+        let source = match msg.source {
+          message::Source::Sender ( ref s ) => s.as_slice( ).trim_left_matches( ':' ).to_string( ),
+          message::Source::None             => self.config.name.clone( ),
+        };
+        let rest = match msg.params.as_slice( ).find( ' ' ) {
+          Some ( i ) => msg.params.as_slice( ).slice_from( i + 1 ).to_string( ),
+          None       => String::new( ),
+        };
+        if msg.code.as_slice( ) == "001" {
+          self.welcome.clear( );
+        }
+        self.welcome.push( ( source, msg.code.clone( ), rest ) );
+      },
+      "JOIN" if ours => match msg.source {
+        message::Source::Sender ( ref s ) => self.prefix = Some( s.as_slice( ).trim_left_matches( ':' ).to_string( ) ),
+        message::Source::None             => (),
+      },
+      "332"   => match ( msg.param( 2 ), msg.trailing( ) ) {
+        ( Some ( chan ), Some ( topic ) ) => { self.topics.insert( chan.to_ascii_lowercase( ), topic.to_string( ) ); },
+        _                                 => (),
+      },
+      "TOPIC" => match ( msg.param( 1 ), msg.trailing( ) ) {
+        ( Some ( chan ), Some ( topic ) ) => { self.topics.insert( chan.to_ascii_lowercase( ), topic.to_string( ) ); },
+        _                                 => (),
+      },
+      "PART" if ours => match msg.param( 1 ) {
+        Some ( chan ) => { self.topics.remove( &chan.to_ascii_lowercase( ) ); },
+        None          => (),
+      },
+      _       => (),
+    }
+
+    // the client answers pings itself
+    if msg.code.as_slice( ) == "PING" {
+      return;
+    }
+    if self.attached( ) {
+      self.send_attached( msg.raw.as_slice( ), None );
+    } else {
+      self.buffer( &msg, nick.as_slice( ) );
+    }
+  }
+
+  /// `buffer` keeps a message for playback if it belongs to a channel or query
+  fn buffer( &mut self, msg : &message::Message, nick : &str ) {
+    let key = match msg.code.as_slice( ) {
+      "PRIVMSG" | "NOTICE" => match msg.target( ) {
+        Some ( t ) if t.starts_with( "#" )  => t.to_string( ),
+        Some ( t ) if t.to_ascii_lowercase( ) == nick.to_ascii_lowercase( ) => match msg.nick( ) {
+          Some ( n ) => n,
+          None       => return,
+        },
+        _                                   => return,
+      },
+      "JOIN" | "PART" | "KICK" | "TOPIC" | "MODE" => match msg.param( 1 ) {
+        Some ( t ) if t.starts_with( "#" ) => t.to_string( ),
+        _                                  => return,
+      },
+      _                    => return,
+    };
+    let line = stamp( msg );
+    let key = key.to_ascii_lowercase( );
+    let size = self.config.buffer_size;
+    if size == 0 {
+      return;
+    }
+    match self.buffers.iter_mut( ).find( |b| b.0 == key ) {
+      Some ( b ) => {
+        b.1.push( line );
+        if b.1.len( ) > size {
+          b.1.remove( 0 );
+        }
+        return;
+      },
+      None       => (),
+    }
+
+    // a flood of private messages from new nicks can't grow the buffers forever
+    if !key.as_slice( ).starts_with( "#" ) {
+      let queries = self.buffers.iter( ).filter( |b| !b.0.as_slice( ).starts_with( "#" ) ).count( );
+      if queries >= self.config.max_queries {
+        match self.buffers.iter( ).position( |b| !b.0.as_slice( ).starts_with( "#" ) ) {
+          Some ( i ) => { self.buffers.remove( i ); },
+          None       => return,
+        }
+      }
+    }
+    self.buffers.push( ( key, vec![ line ] ) );
+  }
+
+  /// `downstream` handles a line from a downstream client
+  fn downstream( &mut self, id : TID, line : String ) {
+    let text = line.as_slice( ).trim_right_matches( |c : char| c == '\r' || c == '\n' ).to_string( );
+    if text.as_slice( ).trim( ).is_empty( ) {
+      return;
+    }
+    let padded = if text.as_slice( ).contains_char( ' ' ) { text.clone( ) } else { format! ( "{} ", text ) };
+    let msg = match message::Message::parse( padded.as_slice( ) ) {
+      Some ( m ) => m,
+      None       => return,
+    };
+    let code = msg.code.to_ascii_uppercase( );
+    let attached = match self.sessions.get( &id ) {
+      Some ( s ) => s.attached,
+      None       => return,
+    };
+
+    if !attached {
+      return self.register( id, code.as_slice( ), &msg );
+    }
+    match code.as_slice( ) {
+      "PING"      => {
+        let line = format! ( ":{} PONG {} :{}", self.config.name, self.config.name,
+          msg.param( 1 ).unwrap_or( "" ) );
+        self.send_to( id, line.as_slice( ) );
+      },
+      "QUIT"      => self.detach( id, "Detached" ),
+      "PASS" | "USER" | "PONG" | "CAP" => (),
+      "PRIVMSG" | "NOTICE" => match self.client.send_str( text.as_slice( ) ) {
+        Ok ( _ )  => {
+          let echo = format! ( ":{} {}", self.our_prefix( ), text );
+          self.send_attached( echo.as_slice( ), Some( id ) );
+        },
+        Err ( e ) => irc_warn! ( "dropping line from downstream client {}: {}", id, e ),
+      },
+      _           => match self.client.send_str( text.as_slice( ) ) {
+        Ok ( _ )  => (),
+        Err ( e ) => irc_warn! ( "dropping line from downstream client {}: {}", id, e ),
+      },
+    }
+  }
+
+  /// `register` handles PASS, NICK and USER from a client that hasn't
+  /// attached yet
+  fn register( &mut self, id : TID, code : &str, msg : &message::Message ) {
+    let password = self.config.password.clone( );
+    let ready = match self.sessions.get_mut( &id ) {
+      Some ( s ) => {
+        match code {
+          "PASS" => s.pass_ok = msg.param( 1 ).map( |p| same_secret( p, password.as_slice( ) ) ).unwrap_or( false ),
+          "NICK" => s.nick_sent = true,
+          "USER" => s.user_sent = true,
+          _      => (),
+        }
+        s.nick_sent && s.user_sent
+      },
+      None       => return,
+    };
+    match code {
+      "PING" => {
+        let line = format! ( ":{} PONG {} :{}", self.config.name, self.config.name,
+          msg.param( 1 ).unwrap_or( "" ) );
+        return self.send_to( id, line.as_slice( ) );
+      },
+      "QUIT" => return self.detach( id, "Client Quit" ),
+      _      => (),
+    }
+    if !ready {
+      return;
+    }
+    if !self.sessions.get( &id ).map( |s| s.pass_ok ).unwrap_or( false ) {
+      self.reply( id, "464", "* :Password incorrect" );
+      return self.detach( id, "Bad password" );
+    }
+    match self.sessions.get_mut( &id ) {
+      Some ( s ) => s.attached = true,
+      None       => return,
+    }
+    irc_info! ( "downstream client {} attached", id );
+    self.replay( id );
+  }
+
+  /// `replay` sends the registration, channel state and buffered messages to
+  /// a client that just attached
+  fn replay( &mut self, id : TID ) {
+    let info = self.client.get_info( );
+    let nick = info.nick_name.clone( );
+
+    // registration
+    if self.welcome.is_empty( ) {
+      let line = format! ( "{} :Welcome back, {}", nick, self.our_prefix( ) );
+      self.reply( id, "001", line.as_slice( ) );
+    }
+    let welcome = self.welcome.clone( );
+    for &( ref source, ref code, ref rest ) in welcome.iter( ) {
+      let line = format! ( ":{} {} {} {}", source, code, nick, rest );
+      self.send_to( id, line.as_slice( ) );
+    }
+
+    // channels
+    let prefix = self.our_prefix( );
+    for chan in info.channels.iter( ) {
+      let line = format! ( ":{} JOIN {}", prefix, chan );
+      self.send_to( id, line.as_slice( ) );
+      let topic = self.topics.get( &chan.to_ascii_lowercase( ) ).map( |t| t.clone( ) );
+      match topic {
+        Some ( topic ) => {
+          let line = format! ( "{} {} :{}", nick, chan, topic );
+          self.reply( id, "332", line.as_slice( ) );
+        },
+        None           => (),
+      }
+      match info.get_channel_names( chan.clone( ) ) {
+        Some ( names ) => for chunk in names.chunks( 40 ) {
+          let line = format! ( "{} = {} :{}", nick, chan, chunk.connect( " " ) );
+          self.reply( id, "353", line.as_slice( ) );
+        },
+        None           => (),
+      }
+      let line = format! ( "{} {} :End of /NAMES list.", nick, chan );
+      self.reply( id, "366", line.as_slice( ) );
+    }
+
+    // playback
+    let buffers = self.buffers.clone( );
+    self.buffers.clear( );
+    for &( ref key, ref lines ) in buffers.iter( ) {
+      let line = format! ( "NOTICE {} :Playback of {} ({} lines)", nick, key, lines.len( ) );
+      self.send_from_bouncer( id, line.as_slice( ) );
+      for line in lines.iter( ) {
+        self.send_to( id, line.as_slice( ) );
+      }
+    }
+  }
+
+  /// `send_from_bouncer` sends a command with the bouncer as its source
+  fn send_from_bouncer( &mut self, id : TID, line : &str ) {
+    let line = format! ( ":{} {}", self.config.name, line );
+    self.send_to( id, line.as_slice( ) );
+  }
+}
+
+/// `same_secret` compares a password with the one we expect. Every byte is
+/// compared, so the time taken doesn't tell how much of it was right.
+fn same_secret( given : &str, secret : &str ) -> bool {
+  let ( given, secret ) = ( given.as_bytes( ), secret.as_bytes( ) );
+  let mut diff = given.len( ) ^ secret.len( );
+  for ( i, b ) in secret.iter( ).enumerate( ) {
+    let g = if i < given.len( ) { given[i] } else { 0 };
+    diff |= ( g ^ *b ) as usize;
+  }
+  diff == 0
+}
+
+/// `stamp` prefixes the text of a buffered PRIVMSG or NOTICE with the time it
+/// arrived. CTCP messages and other commands are kept as they are.
+fn stamp( msg : &message::Message ) -> String {
+  if !msg.is_message( ) {
+    return msg.raw.clone( );
+  }
+  match ( msg.target( ), msg.trailing( ) ) {
+    ( Some ( target ), Some ( text ) ) if !text.starts_with( "\x01" ) => {
+      let source = match msg.source {
+        message::Source::Sender ( ref s ) => format! ( "{} ", s ),
+        message::Source::None             => String::new( ),
+      };
+      let now = time::now( );
+      format! ( "{}{} {} :[{}] {}", source, msg.code, target,
+        time::strftime( "%H:%M:%S", &now ).unwrap_or( String::new( ) ), text )
+    },
+    _                                                                => msg.raw.clone( ),
+  }
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[allow(unused_imports)]
+  use std::old_io as io;
+  #[allow(unused_imports)]
+  use std::old_io::{Acceptor, Listener};
+  #[allow(unused_imports)]
+  use std::sync::mpsc;
+  #[allow(unused_imports)]
+  use client::Client;
+  #[allow(unused_imports)]
+  use info::IrcInfo;
+  #[allow(unused_imports)]
+  use message::Message;
+  #[allow(unused_imports)]
+  use mock::{self, MockServer};
+  #[allow(unused_imports)]
+  use super::{BouncerConfig, Core};
+
+  /// `core` creates a bouncer core in front of a client of a mock server
+  #[allow(dead_code)]
+  fn core( server : &MockServer ) -> ( mpsc::Receiver < Message >, Core ) {
+    let info = IrcInfo::gen( "MyIrcTest", "MyIrcTest", "Testing rust-irc", vec![] );
+    let preclient = Client::connect( server.host.as_slice( ), server.port, "", Box::new( info ) );
+    let ( rx, client ) = preclient.start_thread( );
+    let ( out, _ ) = mpsc::channel( );
+    ( rx, Core::new( BouncerConfig::new( "127.0.0.1:0", "secret" ), client, out ) )
+  }
+
+  /// `register` connects a downstream client to the core and registers it
+  #[allow(dead_code)]
+  fn register( core : &mut Core, id : u64, pass : &str ) -> io::BufferedReader < io::TcpStream > {
+    let mut acceptor = io::TcpListener::bind( "127.0.0.1:0" ).unwrap( ).listen( ).unwrap( );
+    let addr = acceptor.socket_name( ).unwrap( );
+    let mut near = io::TcpStream::connect( addr ).unwrap( );
+    near.set_read_timeout( Some( 5000 ) );
+    core.connected( id, acceptor.accept( ).unwrap( ) );
+    core.downstream( id, format! ( "PASS {}", pass ) );
+    core.downstream( id, String::from_str( "NICK desktop" ) );
+    core.downstream( id, String::from_str( "USER desktop 0 * :Desktop" ) );
+    io::BufferedReader::new( near )
+  }
+
+  #[test]
+  fn test_stamp () {
+    let msg = Message::parse( ":Lancey!l@h PRIVMSG #rust :hello there" ).unwrap( );
+    let line = super::stamp( &msg );
+    assert! ( line.as_slice( ).starts_with( ":Lancey!l@h PRIVMSG #rust :[" ) );
+    assert! ( line.as_slice( ).ends_with( "] hello there" ) );
+
+    let action = Message::parse( ":Lancey!l@h PRIVMSG #rust :\x01ACTION waves\x01" ).unwrap( );
+    assert! ( super::stamp( &action ) == action.raw );
+    let join = Message::parse( ":Lancey!l@h JOIN #rust" ).unwrap( );
+    assert! ( super::stamp( &join ) == join.raw );
+  }
+
+  #[test]
+  fn test_same_secret () {
+    assert! ( super::same_secret( "secret", "secret" ) );
+    assert! ( !super::same_secret( "secreT", "secret" ) );
+    assert! ( !super::same_secret( "secret!", "secret" ) );
+    assert! ( !super::same_secret( "", "secret" ) );
+  }
+
+  #[test]
+  fn test_attach () {
+    let server = MockServer::start( vec![ mock::expect( "^USER " ) ] ).unwrap( );
+    let ( _rx, mut core ) = core( &server );
+    let mut reader = register( &mut core, 1, "secret" );
+    assert! ( core.attached( ) );
+    assert! ( reader.read_line( ).unwrap( ).as_slice( ) ==
+      ":rustirc.bnc 001 MyIrcTest :Welcome back, MyIrcTest!MyIrcTest@rustirc.bnc\r\n" );
+
+    let mut wrong = register( &mut core, 2, "wrong" );
+    assert! ( core.sessions.len( ) == 1 );
+    assert! ( wrong.read_line( ).unwrap( ).as_slice( ) == ":rustirc.bnc 464 * :Password incorrect\r\n" );
+    assert! ( wrong.read_line( ).unwrap( ).as_slice( ) == "ERROR :Closing Link: Bad password\r\n" );
+    server.finish( ).unwrap( );
+  }
+
+  #[test]
+  fn test_backlog_replay () {
+    let server = MockServer::start( vec![ mock::expect( "^USER " ) ] ).unwrap( );
+    let ( _rx, mut core ) = core( &server );
+    core.upstream( Message::parse( ":Lancey!l@h PRIVMSG MyIrcTest :psst" ).unwrap( ) );
+    core.upstream( Message::parse( ":Lancey!l@h PRIVMSG #rust :you missed this" ).unwrap( ) );
+    core.upstream( Message::parse( ":Lancey!l@h JOIN #other" ).unwrap( ) );
+    assert! ( core.buffers.len( ) == 3 );
+
+    let mut reader = register( &mut core, 1, "secret" );
+    assert! ( core.buffers.is_empty( ) );
+    let lines : Vec < String > = range( 0, 7 ).map( |_| reader.read_line( ).unwrap( ) ).collect( );
+    assert! ( lines[0].as_slice( ).starts_with( ":rustirc.bnc 001 MyIrcTest " ) );
+    assert! ( lines[1].as_slice( ) == ":rustirc.bnc NOTICE MyIrcTest :Playback of lancey (1 lines)\r\n" );
+    assert! ( lines[2].as_slice( ).starts_with( ":Lancey!l@h PRIVMSG MyIrcTest :[" ) );
+    assert! ( lines[2].as_slice( ).ends_with( "] psst\r\n" ) );
+    assert! ( lines[3].as_slice( ) == ":rustirc.bnc NOTICE MyIrcTest :Playback of #rust (1 lines)\r\n" );
+    assert! ( lines[4].as_slice( ).ends_with( "] you missed this\r\n" ) );
+    assert! ( lines[5].as_slice( ) == ":rustirc.bnc NOTICE MyIrcTest :Playback of #other (1 lines)\r\n" );
+    assert! ( lines[6].as_slice( ) == ":Lancey!l@h JOIN #other\r\n" );
+
+    // once someone is attached, nothing more is buffered
+    core.upstream( Message::parse( ":Lancey!l@h PRIVMSG #rust :live" ).unwrap( ) );
+    assert! ( core.buffers.is_empty( ) );
+    assert! ( reader.read_line( ).unwrap( ).as_slice( ) == ":Lancey!l@h PRIVMSG #rust :live\r\n" );
+    server.finish( ).unwrap( );
+  }
+}
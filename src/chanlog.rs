@@ -0,0 +1,763 @@
+// import built in modules
+use std::ascii::AsciiExt;
+use std::collections;
+use std::old_io as io;
+use std::old_io::fs::PathExtensions;
+use rustc_serialize::json;
+use time;
+
+// import custom modules
+use ctcp;
+use info;
+use message;
+
+static STRIP_CODES  : &'static str = "\x02\x0f\x11\x16\x1d\x1e\x1f"; // formatting toggles
+static COLOR_CODE   : char = '\x03';  // mIRC color, followed by fg[,bg]
+static HEX_CODE     : char = '\x04';  // hex color, followed by rrggbb[,rrggbb]
+
+/// `LogFormat` selects the layout of log files
+///
+/// # Options
+///
+/// `Irssi` - "12:00 <nick> message" lines, as written by irssi
+/// `Weechat` - tab separated "date time\tnick\tmessage" lines, as weechat
+/// `JsonLines` - one JSON object per line holding the raw message
+pub enum LogFormat {
+  Irssi,
+  Weechat,
+  JsonLines,
+}
+
+impl Copy for LogFormat {}
+
+impl LogFormat {
+  /// `default_timestamp` gets the timestamp format the client normally uses
+  ///
+  /// # Returns
+  ///
+  /// A strftime format string
+  pub fn default_timestamp( &self ) -> &'static str {
+    match *self {
+      LogFormat::Irssi     => "%H:%M",
+      LogFormat::Weechat   => "%Y-%m-%d %H:%M:%S",
+      LogFormat::JsonLines => "%Y-%m-%dT%H:%M:%S%z",
+    }
+  }
+}
+
+/// `Rotation` decides when a log file is closed and a new one started
+///
+/// # Options
+///
+/// `Never` - always write to "buffer.log"
+/// `Daily` - write to "buffer-YYYY-MM-DD.log", starting a new file each day
+/// `Size( n )` - write to "buffer.log", moving it aside once it passes n bytes
+pub enum Rotation {
+  Never,
+  Daily,
+  Size ( u64 ),
+}
+
+impl Copy for Rotation {}
+
+/// `OpenLog` is a log file currently being written to
+///
+/// # Members
+///
+/// * `file` - the open file
+/// * `path` - path of the file
+/// * `day` - year and day of year the file was opened on
+/// * `size` - number of bytes in the file
+struct OpenLog {
+  file  : io::File,
+  path  : Path,
+  day   : ( i32, i32 ),
+  size  : u64,
+}
+
+/// `ChanLogger` writes channel and query logs from received messages
+///
+/// # Members
+///
+/// * `dir` - directory the log files are written to
+/// * `format` - layout of the log lines
+/// * `rotation` - when log files are rotated
+/// * `timestamp` - strftime format of line timestamps
+/// * `strip` - whether formatting codes are removed from logged text
+/// * `open` - map of buffer names to their open log files
+pub struct ChanLogger {
+  pub dir       : Path,
+  pub format    : LogFormat,
+  pub rotation  : Rotation,
+  pub timestamp : String,
+  pub strip     : bool,
+
+  open          : collections::HashMap < String, OpenLog >,
+}
+
+impl ChanLogger {
+  /// `new` creates a logger writing to the given directory
+  ///
+  /// # Arguments
+  ///
+  /// * `dir` - directory to write log files to, created if it doesn't exist
+  /// * `format` - layout of the log lines
+  /// * `rotation` - when log files are rotated
+  ///
+  /// # Returns
+  ///
+  /// A logger using the format's usual timestamps and keeping formatting
+  pub fn new( dir : Path, format : LogFormat, rotation : Rotation ) -> io::IoResult < ChanLogger > {
+    if !dir.exists( ) {
+      try! ( io::fs::mkdir_recursive( &dir, io::USER_RWX ) );
+    }
+    Ok( ChanLogger {
+      dir       : dir,
+      format    : format,
+      rotation  : rotation,
+      timestamp : format.default_timestamp( ).to_string( ),
+      strip     : false,
+      open      : collections::HashMap::new( ),
+    } )
+  }
+
+  /// `log` writes a received message to the logs it belongs in
+  ///
+  /// # Arguments
+  ///
+  /// * `msg` - message received from the client
+  /// * `i` - the client info, used to find queries and the channels a nick
+  /// shares with us
+  ///
+  /// # Notes
+  ///
+  /// * Messages to a channel go to that channel's log, messages to us go to a
+  /// query log named after the sender. QUIT and NICK go to every channel the
+  /// nick was in. Anything else is ignored.
+  pub fn log( &mut self, msg : &message::Message, i : &info::IrcInfo ) -> io::IoResult < ( ) > {
+    let buffers = buffers_for( msg, i );
+    if buffers.is_empty( ) {
+      return Ok( ( ) );
+    }
+    let now = time::now( );
+    let stamp = match time::strftime( self.timestamp.as_slice( ), &now ) {
+      Ok ( s )  => s,
+      Err ( _ ) => String::new( ),
+    };
+    for buf in buffers.iter( ) {
+      let line = match self.format_line( msg, buf.as_slice( ), stamp.as_slice( ) ) {
+        Some ( l ) => l,
+        None       => continue,
+      };
+      try! ( self.write_line( buf.as_slice( ), line.as_slice( ), &now ) );
+    }
+    Ok( ( ) )
+  }
+
+  /// `log_sent` writes a message we sent to the logs it belongs in
+  ///
+  /// # Arguments
+  ///
+  /// * `msg` - message sent by the client
+  /// * `i` - the client info, used for our own nick
+  ///
+  /// # Notes
+  ///
+  /// * The server doesn't echo our own messages back, so they have to be
+  /// logged as they're sent.
+  pub fn log_sent( &mut self, msg : &message::Message, i : &info::IrcInfo ) -> io::IoResult < ( ) > {
+    let src = format! ( ":{}!{}@localhost", i.nick_name, i.user_name );
+    let ours = message::Message::parse( format! ( "{} {} {}", src, msg.code, msg.params ).as_slice( ) );
+    match ours {
+      Some ( m ) => {
+        // our messages to a nick belong in that nick's query log
+        if m.is_message( ) && !m.is_public( ) {
+          let buf = m.target( ).unwrap_or( "" ).to_string( );
+          let now = time::now( );
+          let stamp = time::strftime( self.timestamp.as_slice( ), &now ).unwrap_or( String::new( ) );
+          match self.format_line( &m, buf.as_slice( ), stamp.as_slice( ) ) {
+            Some ( l ) => self.write_line( buf.as_slice( ), l.as_slice( ), &now ),
+            None       => Ok( ( ) ),
+          }
+        } else {
+          self.log( &m, i )
+        }
+      },
+      None       => Ok( ( ) ),
+    }
+  }
+
+  /// `close` flushes and closes every open log file
+  pub fn close( &mut self ) {
+    for ( _, log ) in self.open.iter_mut( ) {
+      match log.file.flush( ) {
+        Err ( e ) => irc_error! ( "closing log {}: {}", log.path.display( ), e.desc ),
+        _         => (),
+      }
+    }
+    self.open.clear( );
+  }
+
+  /// `format_line` formats a message as a log line for a buffer
+  ///
+  /// # Arguments
+  ///
+  /// * `msg` - message to format
+  /// * `buf` - buffer the line is written to
+  /// * `stamp` - formatted timestamp of the line
+  ///
+  /// # Returns
+  ///
+  /// The log line, or None if the format has no layout for the message
+  pub fn format_line( &self, msg : &message::Message, buf : &str, stamp : &str ) -> Option < String > {
+    match self.format {
+      LogFormat::JsonLines => {
+        let mut obj = collections::BTreeMap::new( );
+        obj.insert( "ts".to_string( ), json::Json::String( stamp.to_string( ) ) );
+        obj.insert( "buffer".to_string( ), json::Json::String( buf.to_string( ) ) );
+        obj.insert( "raw".to_string( ), json::Json::String( self.text( msg.raw.as_slice( ) ) ) );
+        Some( json::Json::Object( obj ).to_string( ) )
+      },
+      LogFormat::Irssi     => self.format_irssi( msg, buf ).map( |l| format! ( "{} {}", stamp, l ) ),
+      LogFormat::Weechat   => self.format_weechat( msg, buf ).map( |l| format! ( "{}\t{}", stamp, l ) ),
+    }
+  }
+
+  /// `format_irssi` formats the body of an irssi log line
+  fn format_irssi( &self, msg : &message::Message, buf : &str ) -> Option < String > {
+    let nick = msg.nick( ).unwrap_or( String::new( ) );
+    let mask = user_host( msg );
+    let reason = self.text( msg.trailing( ).unwrap_or( "" ) );
+    match msg.code.as_slice( ) {
+      "PRIVMSG" => match ctcp::Ctcp::from_message( msg ) {
+        Some ( ctcp::Ctcp::Action ( a ) ) => Some( format! ( " * {} {}", nick, self.text( a.as_slice( ) ) ) ),
+        Some ( _ )                        => None,
+        None                              => Some( format! ( "<{}> {}", nick, reason ) ),
+      },
+      "NOTICE"  => Some( format! ( "-{}:{}- {}", nick, buf, reason ) ),
+      "JOIN"    => Some( format! ( "-!- {} [{}] has joined {}", nick, mask, buf ) ),
+      "PART"    => Some( format! ( "-!- {} [{}] has left {} [{}]", nick, mask, buf, reason ) ),
+      "QUIT"    => Some( format! ( "-!- {} [{}] has quit [{}]", nick, mask, reason ) ),
+      "KICK"    => Some( format! ( "-!- {} was kicked from {} by {} [{}]",
+        msg.param( 2 ).unwrap_or( "" ), buf, nick, reason ) ),
+      "NICK"    => Some( format! ( "-!- {} is now known as {}", nick, msg.param( 1 ).unwrap_or( "" ) ) ),
+      "TOPIC"   => Some( format! ( "-!- {} changed the topic of {} to: {}", nick, buf, reason ) ),
+      "MODE"    => Some( format! ( "-!- mode/{} [{}] by {}", buf, mode_args( msg ), nick ) ),
+      _         => None,
+    }
+  }
+
+  /// `format_weechat` formats the body of a weechat log line
+  fn format_weechat( &self, msg : &message::Message, buf : &str ) -> Option < String > {
+    let nick = msg.nick( ).unwrap_or( String::new( ) );
+    let mask = user_host( msg );
+    let reason = self.text( msg.trailing( ).unwrap_or( "" ) );
+    match msg.code.as_slice( ) {
+      "PRIVMSG" => match ctcp::Ctcp::from_message( msg ) {
+        Some ( ctcp::Ctcp::Action ( a ) ) => Some( format! ( " *\t{} {}", nick, self.text( a.as_slice( ) ) ) ),
+        Some ( _ )                        => None,
+        None                              => Some( format! ( "{}\t{}", nick, reason ) ),
+      },
+      "NOTICE"  => Some( format! ( "--\tNotice({}): {}", nick, reason ) ),
+      "JOIN"    => Some( format! ( "-->\t{} ({}) has joined {}", nick, mask, buf ) ),
+      "PART"    => Some( format! ( "<--\t{} ({}) has left {} ({})", nick, mask, buf, reason ) ),
+      "QUIT"    => Some( format! ( "<--\t{} ({}) has quit ({})", nick, mask, reason ) ),
+      "KICK"    => Some( format! ( "<--\t{} has kicked {} ({})", nick,
+        msg.param( 2 ).unwrap_or( "" ), reason ) ),
+      "NICK"    => Some( format! ( "--\t{} is now known as {}", nick, msg.param( 1 ).unwrap_or( "" ) ) ),
+      "TOPIC"   => Some( format! ( "--\t{} has changed topic for {} to \"{}\"", nick, buf, reason ) ),
+      "MODE"    => Some( format! ( "--\tMode {} [{}] by {}", buf, mode_args( msg ), nick ) ),
+      _         => None,
+    }
+  }
+
+  /// `text` applies the logger's formatting policy to message text
+  fn text( &self, s : &str ) -> String {
+    if self.strip {
+      strip_formatting( s )
+    } else {
+      s.to_string( )
+    }
+  }
+
+  /// `write_line` appends a line to a buffer's log, rotating it if needed
+  fn write_line( &mut self, buf : &str, line : &str, now : &time::Tm ) -> io::IoResult < ( ) > {
+    let key = buf.to_ascii_lowercase( );
+    let day = ( now.tm_year, now.tm_yday );
+    let rotate = match self.open.get( &key ) {
+      Some ( log ) => match self.rotation {
+        Rotation::Never      => false,
+        Rotation::Daily      => log.day != day,
+        Rotation::Size ( n ) => log.size >= n,
+      },
+      None         => true,
+    };
+
+    if rotate {
+      match self.open.remove( &key ) {
+        Some ( mut old ) => {
+          try! ( old.file.flush( ) );
+          match self.rotation {
+            Rotation::Size ( _ ) => {
+              let stamp = time::strftime( "%Y%m%d%H%M%S", now ).unwrap_or( String::new( ) );
+              let stem = format! ( "{}.{}", file_stem( key.as_slice( ) ), stamp );
+
+              // a busy buffer can fill up more than once a second
+              let mut aside = self.dir.join( format! ( "{}.log", stem ) );
+              let mut n = 1u32;
+              while aside.exists( ) {
+                aside = self.dir.join( format! ( "{}-{}.log", stem, n ) );
+                n += 1;
+              }
+              try! ( io::fs::rename( &old.path, &aside ) );
+            },
+            _                    => (),
+          }
+        },
+        None             => (),
+      }
+      let path = match self.rotation {
+        Rotation::Daily => {
+          let date = time::strftime( "%Y-%m-%d", now ).unwrap_or( String::new( ) );
+          self.dir.join( format! ( "{}-{}.log", file_stem( key.as_slice( ) ), date ) )
+        },
+        _               => self.dir.join( format! ( "{}.log", file_stem( key.as_slice( ) ) ) ),
+      };
+      let file = try! ( io::File::open_mode( &path, io::FileMode::Append, io::FileAccess::Write ) );
+      let size = try! ( path.stat( ) ).size;
+      self.open.insert( key.clone( ), OpenLog { file : file, path : path, day : day, size : size } );
+    }
+
+    let log = self.open.get_mut( &key ).unwrap( );
+    try! ( log.file.write_line( line ) );
+    log.size += line.len( ) as u64 + 1;
+    Ok( ( ) )
+  }
+}
+
+impl Drop for ChanLogger {
+  fn drop ( &mut self ) {
+    self.close( );
+  }
+}
+
+/// `read_log` reads a log file back into messages
+///
+/// # Arguments
+///
+/// * `path` - log file to read
+/// * `format` - layout the file was written in
+/// * `timestamp` - strftime format the file's timestamps were written with
+/// * `buf` - name of the buffer the file belongs to
+///
+/// # Returns
+///
+/// The messages in the log. Lines that can't be understood are skipped.
+///
+/// # Notes
+///
+/// * JSON lines logs hold the raw message and read back exactly. Irssi and
+/// weechat logs lose the parts of a message they don't show, such as the
+/// host of a PRIVMSG sender.
+pub fn read_log(
+  path : &Path,
+  format : LogFormat,
+  timestamp : &str,
+  buf : &str
+) -> io::IoResult < Vec < message::Message > > {
+  let mut read = io::BufferedReader::new( try! ( io::File::open( path ) ) );
+  let mut msgs = Vec::new( );
+  for line in read.lines( ) {
+    let line = try! ( line );
+    let line = line.as_slice( ).trim_right_matches( '\n' );
+    let parsed = match format {
+      LogFormat::JsonLines => parse_json( line ),
+      LogFormat::Irssi     => parse_irssi( skip_words( line, timestamp ), buf ),
+      LogFormat::Weechat   => match line.find( '\t' ) {
+        Some ( i ) => parse_weechat( line.slice_from( i + 1 ), buf ),
+        None       => None,
+      },
+    };
+    match parsed {
+      Some ( m ) => msgs.push( m ),
+      None       => irc_debug! ( "skipping log line: {}", line ),
+    }
+  }
+  Ok( msgs )
+}
+
+/// `strip_formatting` removes bold, color and other formatting codes
+///
+/// # Arguments
+///
+/// * `s` - text to strip
+///
+/// # Returns
+///
+/// The text with every formatting code and color argument removed
+pub fn strip_formatting( s : &str ) -> String {
+  let mut out = String::new( );
+  let chars : Vec < char > = s.chars( ).collect( );
+  let mut i = 0;
+  while i < chars.len( ) {
+    let ch = chars[i];
+    i += 1;
+    if STRIP_CODES.contains_char( ch ) {
+      continue;
+    }
+    if ch == COLOR_CODE || ch == HEX_CODE {
+      // skip the foreground color, then a background color after a comma
+      let ( width, is_digit ) : ( usize, fn( char ) -> bool ) = if ch == COLOR_CODE {
+        ( 2, is_dec as fn( char ) -> bool )
+      } else {
+        ( 6, is_hex as fn( char ) -> bool )
+      };
+      let mut n = 0;
+      while n < width && i < chars.len( ) && is_digit( chars[i] ) { i += 1; n += 1; }
+      if n > 0 && i + 1 < chars.len( ) && chars[i] == ',' && is_digit( chars[i + 1] ) {
+        i += 1;
+        n = 0;
+        while n < width && i < chars.len( ) && is_digit( chars[i] ) { i += 1; n += 1; }
+      }
+      continue;
+    }
+    out.push( ch );
+  }
+  out
+}
+
+/// `buffers_for` finds the buffers a message belongs in
+fn buffers_for( msg : &message::Message, i : &info::IrcInfo ) -> Vec < String > {
+  let nick = msg.nick( ).unwrap_or( String::new( ) );
+  match msg.code.as_slice( ) {
+    "PRIVMSG" | "NOTICE" => {
+      if msg.is_public( ) {
+        vec![ msg.target( ).unwrap_or( "" ).to_string( ) ]
+      } else if !nick.is_empty( ) {
+        vec![ nick ]
+      } else {
+        Vec::new( )
+      }
+    },
+    "JOIN" | "PART" | "KICK" | "TOPIC" => vec![ msg.param( 1 ).unwrap_or( "" ).to_string( ) ],
+    "MODE" => {
+      let target = msg.param( 1 ).unwrap_or( "" );
+      if target.starts_with( "#" ) { vec![ target.to_string( ) ] } else { Vec::new( ) }
+    },
+    "QUIT" | "NICK" => {
+      let cm = i.casemapping( );
+      let nick = cm.fold( nick.as_slice( ) );
+      i.channels.iter( )
+        .filter( |c| match i.get_channel_names( ( *c ).clone( ) ) {
+          Some ( names ) => names.iter( ).any( |n| cm.fold( strip_prefix( n.as_slice( ) ) ) == nick ),
+          None           => false,
+        } )
+        .map( |c| c.clone( ) ).collect( )
+    },
+    _ => Vec::new( ),
+  }
+}
+
+/// `parse_json` reads a JSON lines log line back into a message
+fn parse_json( line : &str ) -> Option < message::Message > {
+  let obj = match json::Json::from_str( line ) {
+    Ok ( o )  => o,
+    Err ( _ ) => return None,
+  };
+  match obj.find( "raw" ).and_then( |r| r.as_string( ) ) {
+    Some ( raw ) => message::Message::parse( raw ),
+    None         => None,
+  }
+}
+
+/// `parse_irssi` reads the body of an irssi log line back into a message
+fn parse_irssi( body : &str, buf : &str ) -> Option < message::Message > {
+  if body.starts_with( "<" ) {
+    let end = match body.find( "> " ) { Some ( e ) => e, None => return None };
+    let nick = body.slice( 1, end ).trim_left_matches( |c : char| "@+%&~ ".contains_char( c ) );
+    rebuild( format! ( ":{} PRIVMSG {} :{}", nick, buf, body.slice_from( end + 2 ) ) )
+  } else if body.starts_with( " * " ) {
+    let rest = body.slice_from( 3 );
+    let ( nick, act ) = split_word( rest );
+    rebuild( format! ( ":{} PRIVMSG {} :{}", nick, buf, ctcp::tag( format! ( "ACTION {}", act ).as_slice( ) ) ) )
+  } else if body.starts_with( "-" ) && !body.starts_with( "-!- " ) {
+    let end = match body.find( "- " ) { Some ( e ) => e, None => return None };
+    let nick = body.slice( 1, end ).split( ':' ).next( ).unwrap_or( "" );
+    rebuild( format! ( ":{} NOTICE {} :{}", nick, buf, body.slice_from( end + 2 ) ) )
+  } else if body.starts_with( "-!- " ) {
+    let rest = body.slice_from( 4 );
+    let ( nick, rest ) = split_word( rest );
+    if rest.starts_with( "[" ) {
+      let close = match rest.find( "] " ) { Some ( c ) => c, None => return None };
+      let src = format! ( "{}!{}", nick, rest.slice( 1, close ) );
+      let rest = rest.slice_from( close + 2 );
+      if rest.starts_with( "has joined " ) {
+        rebuild( format! ( ":{} JOIN {}", src, buf ) )
+      } else if rest.starts_with( "has left " ) {
+        rebuild( format! ( ":{} PART {} :{}", src, buf, bracketed( rest ) ) )
+      } else if rest.starts_with( "has quit " ) {
+        rebuild( format! ( ":{} QUIT :{}", src, bracketed( rest ) ) )
+      } else {
+        None
+      }
+    } else if rest.starts_with( "is now known as " ) {
+      rebuild( format! ( ":{} NICK {}", nick, rest.slice_from( 16 ) ) )
+    } else if rest.starts_with( "was kicked from " ) {
+      let by = match rest.find( " by " ) { Some ( b ) => b + 4, None => return None };
+      let ( op, reason ) = split_word( rest.slice_from( by ) );
+      rebuild( format! ( ":{} KICK {} {} :{}", op, buf, nick, bracketed( reason ) ) )
+    } else if rest.starts_with( "changed the topic of " ) {
+      let to = match rest.find( " to: " ) { Some ( t ) => t + 5, None => return None };
+      rebuild( format! ( ":{} TOPIC {} :{}", nick, buf, rest.slice_from( to ) ) )
+    } else if nick.starts_with( "mode/" ) {
+      let close = match rest.find( "] by " ) { Some ( c ) => c, None => return None };
+      rebuild( format! ( ":{} MODE {} {}", rest.slice_from( close + 5 ), buf, rest.slice( 1, close ) ) )
+    } else {
+      None
+    }
+  } else {
+    None
+  }
+}
+
+/// `parse_weechat` reads the body of a weechat log line back into a message
+fn parse_weechat( body : &str, buf : &str ) -> Option < message::Message > {
+  let ( prefix, rest ) = match body.find( '\t' ) {
+    Some ( i ) => ( body.slice_to( i ), body.slice_from( i + 1 ) ),
+    None       => return None,
+  };
+  match prefix {
+    " *"  => {
+      let ( nick, act ) = split_word( rest );
+      rebuild( format! ( ":{} PRIVMSG {} :{}", nick, buf, ctcp::tag( format! ( "ACTION {}", act ).as_slice( ) ) ) )
+    },
+    "-->" => {
+      let ( nick, rest ) = split_word( rest );
+      let close = match rest.find( ") " ) { Some ( c ) => c, None => return None };
+      rebuild( format! ( ":{}!{} JOIN {}", nick, rest.slice( 1, close ), buf ) )
+    },
+    "<--" => {
+      let ( nick, rest ) = split_word( rest );
+      if rest.starts_with( "(" ) {
+        let close = match rest.find( ") " ) { Some ( c ) => c, None => return None };
+        let src = format! ( "{}!{}", nick, rest.slice( 1, close ) );
+        let rest = rest.slice_from( close + 2 );
+        let reason = match rest.find( " (" ) {
+          Some ( r ) => rest.slice( r + 2, rest.len( ) - 1 ),
+          None       => "",
+        };
+        if rest.starts_with( "has left " ) {
+          rebuild( format! ( ":{} PART {} :{}", src, buf, reason ) )
+        } else {
+          rebuild( format! ( ":{} QUIT :{}", src, reason ) )
+        }
+      } else if rest.starts_with( "has kicked " ) {
+        let ( victim, reason ) = split_word( rest.slice_from( 11 ) );
+        let reason = reason.trim_left_matches( '(' ).trim_right_matches( ')' );
+        rebuild( format! ( ":{} KICK {} {} :{}", nick, buf, victim, reason ) )
+      } else {
+        None
+      }
+    },
+    "--"  => {
+      if rest.starts_with( "Notice(" ) {
+        let close = match rest.find( "): " ) { Some ( c ) => c, None => return None };
+        rebuild( format! ( ":{} NOTICE {} :{}", rest.slice( 7, close ), buf, rest.slice_from( close + 3 ) ) )
+      } else if rest.starts_with( "Mode " ) {
+        let open = match rest.find( " [" ) { Some ( o ) => o, None => return None };
+        let close = match rest.find( "] by " ) { Some ( c ) => c, None => return None };
+        rebuild( format! ( ":{} MODE {} {}", rest.slice_from( close + 5 ), buf, rest.slice( open + 2, close ) ) )
+      } else {
+        let ( nick, rest ) = split_word( rest );
+        if rest.starts_with( "is now known as " ) {
+          rebuild( format! ( ":{} NICK {}", nick, rest.slice_from( 16 ) ) )
+        } else if rest.starts_with( "has changed topic for " ) {
+          let to = match rest.find( " to \"" ) { Some ( t ) => t + 5, None => return None };
+          rebuild( format! ( ":{} TOPIC {} :{}", nick, buf, rest.slice( to, rest.len( ) - 1 ) ) )
+        } else {
+          None
+        }
+      }
+    },
+    nick  => rebuild( format! ( ":{} PRIVMSG {} :{}",
+      nick.trim_left_matches( |c : char| "@+%&~".contains_char( c ) ), buf, rest ) ),
+  }
+}
+
+/// `rebuild` parses a reconstructed raw line into an incoming message
+fn rebuild( raw : String ) -> Option < message::Message > {
+  message::Message::parse( raw.as_slice( ) )
+}
+
+/// `skip_words` skips the words of a timestamp at the start of a line
+fn skip_words < 'a > ( line : &'a str, timestamp : &str ) -> &'a str {
+  let mut rest = line;
+  for _ in range( 0, timestamp.split( ' ' ).count( ) ) {
+    rest = split_word( rest ).1;
+  }
+  rest
+}
+
+/// `split_word` splits off the first word of a string
+fn split_word( s : &str ) -> ( &str, &str ) {
+  match s.find( ' ' ) {
+    Some ( i ) => ( s.slice_to( i ), s.slice_from( i + 1 ) ),
+    None       => ( s, "" ),
+  }
+}
+
+/// `bracketed` gets the contents of the trailing [reason] of a line
+fn bracketed( s : &str ) -> &str {
+  match ( s.find( '[' ), s.rfind( ']' ) ) {
+    ( Some ( o ), Some ( c ) ) if c > o => s.slice( o + 1, c ),
+    _                                   => "",
+  }
+}
+
+/// `user_host` gets the user@host part of a message source
+fn user_host( msg : &message::Message ) -> String {
+  match msg.source {
+    message::Source::Sender ( ref s ) => match s.as_slice( ).find( '!' ) {
+      Some ( i ) => s.as_slice( ).slice_from( i + 1 ).to_string( ),
+      None       => String::new( ),
+    },
+    message::Source::None             => String::new( ),
+  }
+}
+
+/// `mode_args` gets the mode string and arguments of a MODE message
+fn mode_args( msg : &message::Message ) -> String {
+  match msg.params.as_slice( ).find( ' ' ) {
+    Some ( i ) => msg.params.as_slice( ).slice_from( i + 1 ).replace( ":", "" ),
+    None       => String::new( ),
+  }
+}
+
+/// `strip_prefix` removes channel status prefixes from a NAMES entry
+fn strip_prefix( name : &str ) -> &str {
+  name.trim_left_matches( |c : char| "@+%&~".contains_char( c ) )
+}
+
+/// `file_stem` makes a buffer name safe to use in a filename
+fn file_stem( buf : &str ) -> String {
+  buf.chars( ).map( |c| match c {
+    '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+    c if c.is_control( )                                 => '_',
+    c                                                    => c,
+  } ).collect( )
+}
+
+/// `is_dec` checks for a decimal digit
+fn is_dec( c : char ) -> bool {
+  c.is_digit( 10 )
+}
+
+/// `is_hex` checks for a hexadecimal digit
+fn is_hex( c : char ) -> bool {
+  c.is_digit( 16 )
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[allow(unused_imports)]
+  use std::old_io as io;
+  #[allow(unused_imports)]
+  use info::IrcInfo;
+  #[allow(unused_imports)]
+  use message::Message;
+
+  #[test]
+  fn test_strip_formatting () {
+    assert! ( super::strip_formatting( "\x02bold\x02 and \x1fline" ) == "bold and line" );
+    assert! ( super::strip_formatting( "\x0304,12red on blue\x03 plain" ) == "red on blue plain" );
+    assert! ( super::strip_formatting( "\x033,5x\x0312" ) == "x" );
+    assert! ( super::strip_formatting( "\x03,5 comma stays" ) == ",5 comma stays" );
+    assert! ( super::strip_formatting( "\x04ff0000hex\x0f" ) == "hex" );
+  }
+
+  #[test]
+  fn test_format_lines () {
+    let dir = io::TempDir::new( "rustirc-logs" ).unwrap( );
+    let mut logger = super::ChanLogger::new( dir.path( ).clone( ), super::LogFormat::Irssi, super::Rotation::Never ).unwrap( );
+    let msg = Message::parse( ":Lancey!l@host PRIVMSG #rust :\x02hi\x02 there" ).unwrap( );
+    let join = Message::parse( ":Lancey!l@host JOIN #rust" ).unwrap( );
+    let act = Message::parse( ":Lancey!l@host PRIVMSG #rust :\x01ACTION waves\x01" ).unwrap( );
+    assert! ( logger.format_line( &msg, "#rust", "12:00" ) == Some( "12:00 <Lancey> \x02hi\x02 there".to_string( ) ) );
+    assert! ( logger.format_line( &join, "#rust", "12:00" ) == Some( "12:00 -!- Lancey [l@host] has joined #rust".to_string( ) ) );
+    assert! ( logger.format_line( &act, "#rust", "12:00" ) == Some( "12:00  * Lancey waves".to_string( ) ) );
+    logger.strip = true;
+    assert! ( logger.format_line( &msg, "#rust", "12:00" ) == Some( "12:00 <Lancey> hi there".to_string( ) ) );
+    logger.format = super::LogFormat::Weechat;
+    assert! ( logger.format_line( &join, "#rust", "2015-02-01 12:00:00" ) ==
+      Some( "2015-02-01 12:00:00\t-->\tLancey (l@host) has joined #rust".to_string( ) ) );
+  }
+
+  #[test]
+  fn test_roundtrip () {
+    let lines = vec![
+      ":Lancey!l@host PRIVMSG #rust :hello there",
+      ":Lancey!l@host JOIN #rust",
+      ":Lancey!l@host PART #rust :bye now",
+      ":Lancey!l@host PRIVMSG #rust :\x01ACTION waves\x01",
+      ":Lancey!l@host KICK #rust Detective :no reason",
+      ":Lancey!l@host TOPIC #rust :new topic",
+    ];
+    let info = IrcInfo::gen( "Detective", "Detective", "Detective", vec![] );
+    for format in vec![ super::LogFormat::Irssi, super::LogFormat::Weechat, super::LogFormat::JsonLines ].into_iter( ) {
+      let dir = io::TempDir::new( "rustirc-logs" ).unwrap( );
+      {
+        let mut logger = super::ChanLogger::new( dir.path( ).clone( ), format, super::Rotation::Never ).unwrap( );
+        for line in lines.iter( ) {
+          logger.log( &Message::parse( *line ).unwrap( ), &info ).unwrap( );
+        }
+      }
+      let msgs = super::read_log( &dir.path( ).join( "#rust.log" ), format,
+        format.default_timestamp( ), "#rust" ).unwrap( );
+      assert! ( msgs.len( ) == lines.len( ) );
+      for ( msg, line ) in msgs.iter( ).zip( lines.iter( ) ) {
+        let orig = Message::parse( *line ).unwrap( );
+        assert! ( msg.code == orig.code );
+        assert! ( msg.nick( ) == orig.nick( ) );
+        assert! ( msg.trailing( ) == orig.trailing( ) );
+      }
+    }
+  }
+
+  #[test]
+  fn test_queries () {
+    let dir = io::TempDir::new( "rustirc-logs" ).unwrap( );
+    let info = IrcInfo::gen( "Detective", "Detective", "Detective", vec![] );
+    {
+      let mut logger = super::ChanLogger::new( dir.path( ).clone( ), super::LogFormat::JsonLines, super::Rotation::Never ).unwrap( );
+      logger.log( &Message::parse( ":Lancey!l@host PRIVMSG Detective :psst" ).unwrap( ), &info ).unwrap( );
+      logger.log_sent( &Message::privmsg( "Lancey", "what?" ), &info ).unwrap( );
+    }
+    let msgs = super::read_log( &dir.path( ).join( "lancey.log" ), super::LogFormat::JsonLines, "", "Lancey" ).unwrap( );
+    assert! ( msgs.len( ) == 2 );
+    assert! ( msgs[1].nick( ) == Some( "Detective".to_string( ) ) );
+  }
+  #[test]
+  fn test_quit_buffers () {
+    let mut info = IrcInfo::gen( "Detective", "Detective", "Detective", vec![ "#rust" ] );
+    info.prep_channel_names( Message::parse( ":srv 353 Detective = #rust :Detective @Lancey[m]" ).unwrap( ) );
+    info.set_channel_names( String::from_str( "#rust" ) );
+
+    // nicks are compared with the server's casemapping, rfc1459 by default
+    let quit = Message::parse( ":LANCEY{M}!l@host QUIT :bye" ).unwrap( );
+    assert! ( super::buffers_for( &quit, &info ) == vec![ "#rust".to_string( ) ] );
+  }
+
+  #[test]
+  fn test_rotation () {
+    let dir = io::TempDir::new( "rustirc-logs" ).unwrap( );
+    let mut logger = super::ChanLogger::new( dir.path( ).clone( ), super::LogFormat::Irssi, super::Rotation::Size( 1 ) ).unwrap( );
+    let now = ::time::now( );
+
+    // every line fills the log, and no archive overwrites another
+    for line in [ "one", "two", "three" ].iter( ) {
+      logger.write_line( "#rust", *line, &now ).unwrap( );
+    }
+    logger.close( );
+    assert! ( io::fs::readdir( dir.path( ) ).unwrap( ).len( ) == 3 );
+  }
+}
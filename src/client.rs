@@ -1,31 +1,161 @@
 // import built in modules
 use std::old_io as io;
+use std::old_io::net::ip;
+use std::ops::Deref;
 use std::sync::{Arc, mpsc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+use std::time::Duration;
+use time;
 
 // import custom modules
+use config;
 use connection;
 use ctcp;
+use dcc;
+use encoding;
 use info;
+use mask;
 use message;
+use proxy;
+use query;
 use reader;
+use register;
+use rejoin;
+use responder;
+use throttle;
 use utils::debug;
+use validate;
 
 /// `Client` manages IRC connection and communication.
 ///
 /// # Fields
 ///
-/// * `info` - IrcInfo struct that contains information such as the client
-/// nick, username, and channels to connect to on startup.
 /// * `conn` - ServerConnection struct that maintains the client's connection
 /// to the server.
-/// * `writer` - Buffered writer that controls writing to the TcpStream
+/// * `writer` - Buffered writer that controls writing to the server stream
+/// * `register` - how the client registers: alternate nicks to fall back on
+/// and SASL PLAIN credentials. Changes after `start_thread` have no effect.
+/// * `rate_limit` - how fast lines may be sent, or None to send them as soon
+/// as they're ready. Changes after `start_thread` have no effect.
+/// * `reconnect` - what to do when the server drops the connection. Changes
+/// after `start_thread` have no effect.
+///
+/// # Notes
+///
+/// * The client derefs to its `ClientHandle`, which holds the shared state and
+/// the methods for sending.
+/// * After a reconnect, `conn` still describes the first connection.
 pub struct Client {
-  pub info    : Arc < Mutex < Box < info::IrcInfo > > >,
-  pub conn    : connection::ServerConnection,
-  pub writer  : io::LineBufferedWriter < io::TcpStream >,
+  pub conn     : connection::ServerConnection,
+  pub writer   : io::LineBufferedWriter < connection::IrcStream >,
+  pub register : register::Registration,
+  pub rate_limit : Option < throttle::RateLimit >,
+  pub reconnect  : connection::ReconnectPolicy,
   
-  thread      : Option < thread::Thread >,
+  handle      : ClientHandle,
+  thread      : Option < thread::JoinGuard < 'static, connection::DisconnectReason > >,
+  dialer      : Option < connection::Dialer >,
+  stopping    : Arc < AtomicBool >,
+}
+
+static QUIT_TIMEOUT : i64 = 5000; // ms to wait for the server to close after stop
+static STOP_POLL    : i64 = 100;  // ms between checks for quit while waiting to reconnect
+
+/// `Link` is what the client thread needs from one connection
+///
+/// # Members
+///
+/// * `tcp` - the stream, for the reader and for shutting it down
+/// * `chan` - channel to the handler
+/// * `listen` - the handler's end of `chan`
+/// * `writer` - writer to the server
+/// * `label` - name of the connection for log lines
+/// * `max_line` - longest line to accept, in bytes
+struct Link {
+  tcp      : connection::IrcStream,
+  chan     : mpsc::Sender < connection::ConnEvent >,
+  listen   : mpsc::Receiver < connection::ConnEvent >,
+  writer   : io::LineBufferedWriter < connection::IrcStream >,
+  label    : String,
+  max_line : usize,
+}
+
+impl Link {
+  /// `take` takes the parts of a connection the client thread needs
+  fn take( conn : &mut connection::ServerConnection ) -> Link {
+    Link {
+      tcp      : conn.tcp.clone( ),
+      chan     : conn.chan.clone( ),
+      listen   : conn.listen.take( ).expect( "no receiver found" ),
+      writer   : conn.spin_writer( ),
+      label    : conn.label( ),
+      max_line : conn.max_line,
+    }
+  }
+}
+
+/// `Outbox` writes lines to the server, holding them back while the rate
+/// limit is used up
+///
+/// # Members
+///
+/// * `writer` - writer to the server
+/// * `throttle` - lines waiting for the rate limit
+/// * `conn` - channel to the handler, to wake it when held lines are due
+/// * `scheduled` - whether a wake up is already on its way
+struct Outbox {
+  writer    : io::LineBufferedWriter < connection::IrcStream >,
+  throttle  : throttle::Throttle,
+  conn      : mpsc::Sender < connection::ConnEvent >,
+  scheduled : bool,
+}
+
+impl Outbox {
+  /// `send` writes a line now if the rate limit allows, otherwise queues it
+  fn send( &mut self, line : Vec < u8 > ) {
+    self.throttle.push( line );
+    self.flush_due( );
+  }
+
+  /// `flush_due` writes the queued lines that are due, and asks for a wake up
+  /// when the next one will be
+  fn flush_due( &mut self ) {
+    let now = ( time::precise_time_ns( ) / 1000000 ) as i64;
+    for line in self.throttle.ready( now ).into_iter( ) {
+      self.write( line );
+    }
+    if self.scheduled {
+      return;
+    }
+    match self.throttle.wait( now ) {
+      Some ( ms ) => {
+        self.scheduled = true;
+        let conn = self.conn.clone( );
+        thread::Thread::spawn( move || {
+          io::timer::sleep( Duration::milliseconds( ms ) );
+          let _ = conn.send( connection::ConnEvent::Flush );
+        } );
+      },
+      None        => (),
+    }
+  }
+
+  /// `drain` writes every queued line, ignoring the rate limit, and flushes
+  fn drain( &mut self ) -> io::IoResult < ( ) > {
+    for line in self.throttle.drain( ).into_iter( ) {
+      self.write( line );
+    }
+    self.writer.flush( )
+  }
+
+  /// `write` writes one line
+  fn write( &mut self, line : Vec < u8 > ) {
+    match self.writer.write_all( line.as_slice( ) ) {
+      Ok ( _ )  => (),
+      Err ( e ) => irc_error! ( "writing sent message: {}", e.desc ),
+    }
+  }
 }
 
 impl Client {
@@ -38,6 +168,11 @@ impl Client {
   /// * `pass` - password of the server. Use a blank string if the server has
   /// no password
   /// * `info` - IrcInfo struct that contains the info to use on the client
+  ///
+  /// # Notes
+  ///
+  /// * New code should use `ClientBuilder`, which checks the settings before
+  /// connecting. This is kept for existing callers.
   pub fn connect ( 
     host : &str, 
     port : u16, 
@@ -47,15 +182,60 @@ impl Client {
   {
     let conn : connection::ServerConnection = 
       connection::ServerConnection::connect( host, port, pass );
+    Client::with_connection( conn, info )
+  }
+
+  /// `with_connection` sets up a client on a connection that's already open,
+  /// e.g. one made through a proxy
+  ///
+  /// # Arguments
+  ///
+  /// * `conn` - the connection to the server
+  /// * `info` - IrcInfo struct that contains the info to use on the client
+  pub fn with_connection ( 
+    conn : connection::ServerConnection, 
+    info : Box < info::IrcInfo >
+  ) -> Client
+  {
     let wrt = conn.spin_writer( );
+    let handle = ClientHandle {
+      info    : Arc::new( info::InfoStore::new( *info ) ),
+      ctcp    : Arc::new( Mutex::new( Box::new( responder::CtcpResponder::new( ) ) ) ),
+      queries : Arc::new( Mutex::new( Box::new( query::QueryTracker::new( ) ) ) ),
+      ignores : Arc::new( Mutex::new( Box::new( mask::MaskSet::new( mask::CaseMapping::Rfc1459 ) ) ) ),
+      joins   : Arc::new( Mutex::new( Box::new( rejoin::JoinManager::new( ) ) ) ),
+      codec   : Arc::new( Mutex::new( Box::new( encoding::Codec::new( ) ) ) ),
+      oversized : Arc::new( Mutex::new( Vec::new( ) ) ),
+      chan    : Arc::new( Mutex::new( conn.chan.clone( ) ) ),
+    };
     Client {
-      info        : Arc::new( Mutex::new( info ) ),
+      handle      : handle,
+      register    : register::Registration::new( ),
+      rate_limit  : None,
+      reconnect   : connection::ReconnectPolicy::new( ),
       conn        : conn,
       writer      : wrt,
       thread      : None,
+      dialer      : None,
+      stopping    : Arc::new( AtomicBool::new( false ) ),
     }
   }
-  
+
+  /// `set_dialer` sets how to reach the server again when `reconnect` is on
+  ///
+  /// # Arguments
+  ///
+  /// * `dial` - opens a new connection, or says why it couldn't
+  ///
+  /// # Notes
+  ///
+  /// * Without a dialer the client reconnects to `conn`'s host and port,
+  /// with TLS if `conn` used it and default options otherwise.
+  pub fn set_dialer < F > ( &mut self, dial : F )
+    where F : Fn( ) -> Result < connection::ServerConnection, String > + Send + 'static {
+    self.dialer = Some( Box::new( dial ) );
+  }
+
   /// `close` shuts down the IRC client and frees up memory.
   fn close( &mut self ) {
     self.conn.close( );
@@ -66,54 +246,40 @@ impl Client {
   ///
   /// # Arguments
   ///
-  /// * `w` - mutable reference to the TcpStream writer
+  /// * `w` - the outbox lines to the server go through
+  /// * `e` - the client's codec
   /// * `msg` - original ping message
   fn callback_ping( 
-    w : &mut io::LineBufferedWriter < io::TcpStream >,
+    w : &mut Outbox,
+    e : &encoding::Codec,
     msg : message::Message
   ) {
-    debug::info( "responding to ping request from server..." );
+    irc_debug! ( "responding to ping request from server..." );
     
     // invert the message and send it back to the server
-    match w.write_line( msg.pong( ).raw.as_slice( ) ) {
-      Ok ( _ )  => (),
-      Err ( e ) => debug::err( "ping response", e.desc ),
-    };
+    Client::handle_send( msg.pong( ).raw, w, e );
   }
   
   /// `callback_notice` is called whenever a notice message is received
   ///
   /// # Arguments
   ///
-  /// * `w` - mutable reference to the TcpStream writer
-  /// * `i` - reference to the client info
-  /// * `registered` - reference to the boolean that determines if we're regged
+  /// * `w` - the outbox lines to the server go through
+  /// * `info` - the client info
+  /// * `reg` - the client's registration, started on the first notice
+  /// * `e` - the client's codec
   fn callback_notice( 
-    w : &mut io::LineBufferedWriter < io::TcpStream >,
-    i : &Arc < Mutex < Box < info::IrcInfo > > >,
-    registered : &mut bool
+    w : &mut Outbox,
+    info : &info::IrcInfo,
+    reg : &mut register::Registration,
+    e : &encoding::Codec
   ) {
-    if !*registered {
-      let info = i.lock( ).unwrap( );
-      
-      // assemble our messages to the server
-      debug::info( "registering on server..." );
-      let nickline = format! ( "NICK {}", info.nick_name );
-      let userline = format! ( "USER {} * * :{}", 
-        info.user_name, info.real_name );
-      
+    if !reg.started {
+      irc_debug! ( "registering on server..." );
       // send them (order is important)
-      match w.write_line( nickline.as_slice() ) {
-        Ok ( _ )  => debug::info( "registering nick on server" ),
-        Err ( e ) => debug::err( "nick registration", e.desc ),
-      };
-      match w.write_line( userline.as_slice() ) {
-        Ok ( _ )  => debug::info( "registering username on server" ),
-        Err ( e ) => debug::err( "username registration", e.desc ),
-      };
-      
-      // mark ourselves as registered
-      *registered = true;
+      for line in reg.start( info ).into_iter( ) {
+        Client::handle_send( line, w, e );
+      }
     }
   }
   
@@ -121,80 +287,244 @@ impl Client {
   ///
   /// # Arguments
   ///
-  /// * `w` - mutable reference to the TcpStream writer
-  /// * `i` - reference to the client info
+  /// * `w` - the outbox lines to the server go through
+  /// * `info` - the client info
+  /// * `j` - reference to the client's join manager, for channel keys
+  /// * `e` - the client's codec
   fn callback_welcome(
-    w : &mut io::LineBufferedWriter < io::TcpStream >,
-    i : &Arc < Mutex < Box < info::IrcInfo > > >
+    w : &mut Outbox,
+    info : &info::IrcInfo,
+    j : &Arc < Mutex < Box < rejoin::JoinManager > > >,
+    e : &encoding::Codec
   ) {
-    debug::info( "joining channels..." );
-    for chan in i.lock( ).unwrap( ).channels.iter() {
-      let joinline  = format! ( "JOIN {}", chan );
-      let debugline = format! ( "joining channel {}", chan );
-      match w.write_line( joinline.as_slice( ) ) {
-        Ok ( _ )  => debug::info( debugline.as_slice( ) ),
-        Err ( e ) => debug::err( debugline.as_slice( ), e.desc ),
-      };
+    irc_debug! ( "joining channels..." );
+    for chan in info.channels.iter() {
+      let joinline  = j.lock( ).unwrap( ).join_line( chan.as_slice( ) );
+      irc_debug! ( "joining channel {}", chan );
+      Client::handle_send( joinline, w, e );
     }
   }
   
-  fn callback_names( i : &mut Arc < Mutex < Box < info::IrcInfo > > >, msg : message::Message ) {
-    debug::info( "getting name list..." );
-    i.lock( ).unwrap( ).prep_channel_names( msg );
+  /// `callback_ctcp` is called whenever a PRIVMSG is received and answers any
+  /// CTCP requests it contains
+  ///
+  /// # Arguments
+  ///
+  /// * `w` - the outbox lines to the server go through
+  /// * `info` - the client info
+  /// * `c` - reference to the client's CTCP responder
+  /// * `e` - the client's codec
+  /// * `msg` - the PRIVMSG that was received
+  fn callback_ctcp(
+    w : &mut Outbox,
+    info : &info::IrcInfo,
+    c : &Arc < Mutex < Box < responder::CtcpResponder > > >,
+    e : &encoding::Codec,
+    msg : &message::Message
+  ) {
+    let replies = c.lock( ).unwrap( ).respond( msg, info );
+    for reply in replies.into_iter( ) {
+      Client::handle_send( reply, w, e );
+    }
   }
   
-  fn callback_end_of_names( i : &mut Arc < Mutex < Box < info::IrcInfo > > >, msg : message::Message ) {
-    debug::info( "got name list ok!" );
-    i.lock( ).unwrap( ).set_channel_names( msg.param( 2 ).unwrap( ).to_string( ) );
+  /// `callback_who` sends a WHO for a channel we've just joined, or for the
+  /// next channel in the periodic refresh when one is due
+  ///
+  /// # Arguments
+  ///
+  /// * `w` - the outbox lines to the server go through
+  /// * `info` - the client info
+  /// * `q` - reference to the client's query tracker
+  /// * `e` - the client's codec
+  /// * `msg` - the message that was received
+  fn callback_who(
+    w : &mut Outbox,
+    info : &info::IrcInfo,
+    q : &Arc < Mutex < Box < query::QueryTracker > > >,
+    e : &encoding::Codec,
+    msg : &message::Message
+  ) {
+    let whox = info.get_isupport( "WHOX" ).is_some( );
+    let mut tracker = q.lock( ).unwrap( );
+    let chan = if msg.code.as_slice( ) == "JOIN" && msg.nick( ) == Some( info.nick_name.clone( ) ) {
+      msg.param( 1 ).map( |c| c.to_string( ) )
+    } else {
+      tracker.refresh_due( &info.channels, time::get_time( ).sec )
+    };
+    match chan {
+      Some ( c ) => {
+        irc_debug! ( "updating members of {}", c );
+        // nobody waits on these, the replies update the client info
+        let ( line, _ ) = tracker.who( c.as_slice( ), whox );
+        Client::handle_send( line, w, e );
+      },
+      None       => (),
+    }
+  }
+  
+  /// `ignored` checks whether a message comes from an ignored user
+  ///
+  /// # Arguments
+  ///
+  /// * `info` - the client info
+  /// * `g` - reference to the client's ignore list
+  /// * `msg` - the message that was received
+  fn ignored(
+    info : &info::IrcInfo,
+    g : &Arc < Mutex < Box < mask::MaskSet > > >,
+    msg : &message::Message
+  ) -> bool {
+    // only messages users send us can be ignored
+    match msg.code.as_slice( ) {
+      "PRIVMSG" | "NOTICE" | "INVITE" => (),
+      _                               => return false,
+    }
+    let prefix = match msg.source {
+      message::Source::Sender ( ref s ) if s.as_slice( ).contains_char( '!' ) => s.clone( ),
+      _                                                                       => return false,
+    };
+    let mut ignores = g.lock( ).unwrap( );
+    if ignores.len( ) == 0 {
+      return false;
+    }
+
+    // prefer what we know about the user, which may include their account
+    ignores.set_casemapping( mask::CaseMapping::from_isupport( info.get_isupport( "CASEMAPPING" ) ) );
+    let sender = info::Member::from_prefix( prefix.as_slice( ) );
+    let user = match info.get_member( sender.nick.as_slice( ) ) {
+      Some ( m ) => {
+        let mut m = m.clone( );
+        m.user = sender.user.clone( );
+        m.host = sender.host.clone( );
+        m
+      },
+      None       => sender,
+    };
+    match ignores.matching( &user ) {
+      Some ( m ) => {
+        irc_debug! ( "ignoring {} from {} ({})", msg.code, user.hostmask( ), m );
+        true
+      },
+      None       => false,
+    }
+  }
+  
+  /// `schedule` sends lines to the server, now or after a delay
+  ///
+  /// # Arguments
+  ///
+  /// * `lines` - lines to send, each with the milliseconds to wait first
+  /// * `w` - the outbox lines to the server go through
+  /// * `e` - the client's codec
+  /// * `conn` - channel to the handler, for the delayed lines
+  fn schedule(
+    lines : Vec < ( rejoin::TDELAY, String ) >,
+    w : &mut Outbox,
+    e : &encoding::Codec,
+    conn : &mpsc::Sender < connection::ConnEvent >
+  ) {
+    for ( delay, line ) in lines.into_iter( ) {
+      if delay == 0 {
+        Client::handle_send( line, w, e );
+        continue;
+      }
+      irc_debug! ( "sending '{}' in {} ms", line, delay );
+      let conn = conn.clone( );
+      thread::Thread::spawn( move || {
+        io::timer::sleep( Duration::milliseconds( delay as i64 ) );
+        let _ = conn.send( connection::ConnEvent::Send( line ) );
+      } );
+    }
   }
   
   /// `handle_recv` is called whenever a Recv ConnEvent is read
   ///
   /// # Arguments
   ///
-  /// * `s` - String contents of the ConnEvent, the message received
-  /// * `w` - mutable reference to the TcpStream writer
+  /// * `bytes` - contents of the ConnEvent, the line received
+  /// * `w` - the outbox lines to the server go through
+  /// * `e` - reference to the client's codec
   /// * `i` - reference to the client info
-  /// * `registered` - ref to boolean that determines if we're regged on the server
+  /// * `c` - reference to the client's CTCP responder
+  /// * `q` - reference to the client's query tracker
+  /// * `g` - reference to the client's ignore list
+  /// * `j` - reference to the client's join manager
+  /// * `conn` - channel to the handler, for delayed lines
+  /// * `reg` - the client's registration and CAP negotiation
+  /// * `error` - set to the text of an ERROR from the server
   /// * `chan` - channel to send back our final message on
   fn handle_recv( 
-    s : String,                                        // raw message received
-    w : &mut io::LineBufferedWriter < io::TcpStream >, // writer to output to
-    i : &mut Arc < Mutex < Box < info::IrcInfo > > >,  // irc client info
-    registered : &mut bool,                            // are we registered?
+    bytes : Vec < u8 >,                                // raw line received
+    w : &mut Outbox,                                   // outbox to write to
+    e : &Arc < Mutex < Box < encoding::Codec > > >,   // codec
+    i : &info::InfoStore,                              // irc client info
+    c : &Arc < Mutex < Box < responder::CtcpResponder > > >, // ctcp responder
+    q : &Arc < Mutex < Box < query::QueryTracker > > >,      // query tracker
+    g : &Arc < Mutex < Box < mask::MaskSet > > >,            // ignore list
+    j : &Arc < Mutex < Box < rejoin::JoinManager > > >,      // join manager
+    conn : &mpsc::Sender < connection::ConnEvent >,         // delayed lines
+    reg : &mut register::Registration,                 // registration state
+    error : &mut Option < String >,                    // ERROR text from the server
     chan : &mut mpsc::Sender < message::Message >      // channel to send msg on
   ) {
-    // parse our raw string into a usable message
-    let msg = match message::Message::parse( 
+    // decode and parse our raw line into a usable message
+    let codec = e.lock( ).unwrap( );
+    let s = codec.decode( bytes.as_slice( ) );
+    let mut msg = match message::Message::parse( 
       ctcp::low_level_dequote( s.clone( ) ).as_slice( ) ) {
       Some ( m ) => m,
       None       => {
-        debug::err( "parsing IRC message", "message is not an IRC message" );
-        debug::info( s.as_slice( ) );
+        irc_error! ( "parsing IRC message: message is not an IRC message" );
+        irc_debug! ( "{}", s );
         return;
       },
     };
+    msg.bytes = Some( bytes );
     
-    // update client info if necessary
-    // this is in an unsafe block because i have no idea how to open locks
-    unsafe {
-      (*i.lock( ).unwrap( )).update_info( msg.clone( ) );
+    // update client info if necessary, then work from a snapshot of it.
+    // WHOX replies to someone else's query may have other fields, so only
+    // replies to ours are applied.
+    if msg.code.as_slice( ) != "354" || q.lock( ).unwrap( ).is_ours( &msg ) {
+      i.update( &msg );
+    }
+    let info = i.snapshot( );
+
+    // negotiate caps while the server holds registration for us
+    for line in reg.handle( &msg ).into_iter( ) {
+      Client::handle_send( line, w, &**codec );
+    }
+
+    // rejoin, retry or knock as the join policy says
+    let lines = j.lock( ).unwrap( ).handle( &msg, info.nick_name.as_slice( ) );
+    Client::schedule( lines, w, &**codec, conn );
+
+    // drop messages from ignored users before anything answers them
+    if Client::ignored( &*info, g, &msg ) {
+      return;
     }
     
     // perform basic callbacks
     match msg.code.as_slice( ) {
-      "PING"    => Client::callback_ping( w, msg.clone( ) ),
-      "NOTICE"  => Client::callback_notice( w, i, registered ),
-      "001"     => Client::callback_welcome( w, i ),
-      "353"     => Client::callback_names( i, msg.clone( ) ),
-      "366"     => Client::callback_end_of_names( i, msg.clone( ) ),
+      "PING"    => Client::callback_ping( w, &**codec, msg.clone( ) ),
+      "NOTICE"  => Client::callback_notice( w, &*info, reg, &**codec ),
+      "PRIVMSG" => Client::callback_ctcp( w, &*info, c, &**codec, &msg ),
+      "001"     => Client::callback_welcome( w, &*info, j, &**codec ),
+      "ERROR"   => *error = Some( msg.trailing( ).unwrap_or( "" ).to_string( ) ),
       _         => (),
     };
+    Client::callback_who( w, &*info, q, &**codec, &msg );
+
+    // hand replies to any queries waiting for them, streamed LIST replies
+    // go to their ListStream instead of the user's channel
+    if q.lock( ).unwrap( ).handle( &msg ) {
+      return;
+    }
     
     // send the message back along our channel
     match chan.send( msg ) {
       Ok ( _ )  => (),
-      Err ( _ ) => debug::err( "returning message to user", "" ),
+      Err ( _ ) => irc_error! ( "returning message to user" ),
     }
   }
   
@@ -203,100 +533,228 @@ impl Client {
   /// # Arguments
   ///
   /// * `s` - String contents of the ConnEvent, the message to send
-  /// * `w` - mutable reference to the TcpStream writer
-  fn handle_send( s : String, w : &mut io::LineBufferedWriter < io::TcpStream > ) {
-    match w.write_line( ctcp::low_level_quote( s.clone( ) ).as_slice( ) ) {
-      Ok ( _ )  => (),
-      Err ( e ) => debug::err( "writing sent message", e.desc ),
-    }
-    debug::disp( s.as_slice( ), false );
+  /// * `w` - the outbox lines to the server go through
+  /// * `e` - the client's codec
+  fn handle_send( 
+    s : String, 
+    w : &mut Outbox, 
+    e : &encoding::Codec 
+  ) {
+    let mut line = e.encode( ctcp::low_level_quote( s.clone( ) ).as_slice( ) );
+    line.push( b'\n' );
+    irc_trace! ( "< {}", debug::redact( s.as_slice( ) ) );
+    w.send( line );
   }
   
   /// `start_handler` runs the message handling interface
   ///
   /// # Arguments
   ///
-  /// * `w` - mutable reference to the TcpStream writer
+  /// * `w` - the outbox lines to the server go through
+  /// * `e` - reference to the client's codec
   /// * `i` - reference to client info
+  /// * `c` - reference to the client's CTCP responder
+  /// * `q` - reference to the client's query tracker
+  /// * `g` - reference to the client's ignore list
+  /// * `j` - reference to the client's join manager
+  /// * `conn` - channel to this handler, for delayed lines
   /// * `chan` - channel to send back completed messages on
   /// * `port` - port to receive incoming events on
+  /// * `reg` - how to register, with alternate nicks and SASL credentials
+  /// * `rate` - how fast lines may be sent
+  ///
+  /// # Returns
+  ///
+  /// Why the connection ended
   fn start_handler( 
-    mut w : io::LineBufferedWriter < io::TcpStream >, // writer to send messages to
-    mut i : Arc < Mutex < Box < info::IrcInfo > > >,      // client info
+    w : io::LineBufferedWriter < connection::IrcStream >, // writer to send messages to
+    e : Arc < Mutex < Box < encoding::Codec > > >,    // codec
+    i : Arc < info::InfoStore >,                      // client info
+    c : Arc < Mutex < Box < responder::CtcpResponder > > >, // ctcp responder
+    q : Arc < Mutex < Box < query::QueryTracker > > >,      // query tracker
+    g : Arc < Mutex < Box < mask::MaskSet > > >,            // ignore list
+    j : Arc < Mutex < Box < rejoin::JoinManager > > >,      // join manager
+    o : Arc < Mutex < Vec < mpsc::Sender < usize > > > >,   // oversized line receivers
+    conn : mpsc::Sender < connection::ConnEvent >,         // channel to ourselves
     mut chan : mpsc::Sender < message::Message >,     // channel to send received messages over
-    port : mpsc::Receiver < connection::ConnEvent >   // port to receive data on
-  ) {
-    debug::oper( "starting message handler..." );
-    let mut registered  = false;
+    port : mpsc::Receiver < connection::ConnEvent >,  // port to receive data on
+    mut reg : register::Registration,                 // registration settings
+    rate : Option < throttle::RateLimit >             // rate limit for sent lines
+  ) -> connection::DisconnectReason {
+    irc_info! ( "starting message handler..." );
+    let mut w = Outbox {
+      writer    : w,
+      throttle  : throttle::Throttle::new( rate ),
+      conn      : conn.clone( ),
+      scheduled : false,
+    };
+    let mut quitting    = false;
+    let mut error       = None;
+    let reason;
     loop {
       match port.recv( ) {
         Ok ( t )  => match t {
-          connection::ConnEvent::Send( s ) => Client::handle_send( s, &mut w ),
-          connection::ConnEvent::Recv( s ) => Client::handle_recv( s, &mut w, &mut i, &mut registered, &mut chan ),
-          connection::ConnEvent::Abort( s ) => {
-            let stopline = format! ( "client handler aborted: {}", s );
-            debug::oper( stopline.as_slice( ) );
-            match w.write_line( "QUIT" ) {
+          connection::ConnEvent::Send( s ) => Client::handle_send( s, &mut w, &**e.lock( ).unwrap( ) ),
+          connection::ConnEvent::Recv( s ) => {
+            Client::handle_recv( s, &mut w, &e, &*i, &c, &q, &g, &j, &conn, &mut reg, &mut error, &mut chan );
+
+            // the server answers a QUIT with ERROR and then closes the link
+            if quitting && error.is_some( ) {
+              reason = connection::DisconnectReason::Quit( error.take( ).unwrap( ) );
+              break;
+            }
+          },
+          connection::ConnEvent::Quit( s ) => {
+            irc_info! ( "quitting: {}", s );
+            let line = if s.is_empty( ) { String::from_str( "QUIT" ) } else { format! ( "QUIT :{}", s ) };
+            // lines held back by the rate limit go out before the QUIT
+            Client::handle_send( line, &mut w, &**e.lock( ).unwrap( ) );
+            match w.drain( ) {
               Ok( _ )  => (),
-              Err( e ) => debug::err( "sending quit line", e.desc ),
+              Err( e ) => irc_error! ( "flushing quit line: {}", e.desc ),
+            };
+            quitting = true;
+          },
+          connection::ConnEvent::Timeout if quitting => {
+            irc_warn! ( "server did not close the connection after QUIT" );
+            reason = connection::DisconnectReason::TimedOut;
+            break;
+          },
+          connection::ConnEvent::Timeout => (),
+          connection::ConnEvent::Flush => {
+            w.scheduled = false;
+            w.flush_due( );
+          },
+          connection::ConnEvent::TooLong( n ) => {
+            // drop receivers that hung up
+            o.lock( ).unwrap( ).retain( |tx| tx.send( n ).is_ok( ) );
+          },
+          connection::ConnEvent::Abort( s ) => {
+            irc_info! ( "client handler aborted: {}", s );
+            reason = match error.take( ) {
+              Some ( e ) if quitting => connection::DisconnectReason::Quit( e ),
+              Some ( e )             => connection::DisconnectReason::ServerError( e ),
+              None                   => connection::DisconnectReason::Closed( s ),
             };
             break;
           },
         },
         Err ( _ ) => {
-          debug::err( "client handler", "receive channel closed" );
+          irc_error! ( "client handler: receive channel closed" );
+          reason = connection::DisconnectReason::Closed( String::from_str( "receive channel closed" ) );
           break;
         },
       }
     }
-    debug::oper( "closing message handler..." );
+    q.lock( ).unwrap( ).fail_all( query::QueryError::Disconnected );
+    irc_info! ( "closing message handler..." );
+    reason
   }
   
   /// `start_reader` spins up a new reader thread and starts it
   ///
   /// # Arguments
   ///
-  /// `tcp` - the TcpStream to read from
+  /// `tcp` - the stream to read from
   /// `chan` - the channel to send back messages on
-  fn start_reader( tcp : io::TcpStream, chan : mpsc::Sender < connection::ConnEvent > ) {
-    debug::oper( "starting irc reader thread..." );
-    let rthread = thread::Thread::spawn( move || {
-      let mut rdr = reader::IrcReader::new( tcp, chan );
+  /// `max_line` - longest line to accept, in bytes
+  /// `label` - name of the connection for log lines
+  ///
+  /// # Returns
+  ///
+  /// A guard that joins the reader thread
+  fn start_reader( 
+    tcp : connection::IrcStream, 
+    chan : mpsc::Sender < connection::ConnEvent >, 
+    max_line : usize,
+    label : String 
+  ) -> thread::JoinGuard < 'static, ( ) > {
+    irc_info! ( "starting irc reader thread..." );
+    let rthread = thread::Thread::scoped( move || {
+      let _span = debug::Span::enter( label.as_slice( ) );
+      let mut rdr = reader::IrcReader::new( tcp, chan, max_line );
       rdr.start( );
     } );
-    debug::oper( "irc reader started successfully" );
+    irc_info! ( "irc reader started successfully" );
+    rthread
   }
   
-  /// `get_info` returns a clone of the client's info.
+  /// `run_link` reads and handles one connection until it ends
+  ///
+  /// # Arguments
+  ///
+  /// * `link` - the connection
+  /// * `h` - the client's shared state
+  /// * `tx` - the channel to send received messages over
+  /// * `reg` - registration settings
+  /// * `rate` - how fast lines may be sent
   ///
   /// # Returns
   ///
-  /// A clone of the client's IrcInfo struct
-  pub fn get_info( &self ) -> info::IrcInfo {
-    (**self.info.lock( ).unwrap( )).clone( )
+  /// Why the connection ended
+  fn run_link(
+    link : Link,
+    h : &ClientHandle,
+    tx : mpsc::Sender < message::Message >,
+    reg : register::Registration,
+    rate : Option < throttle::RateLimit >
+  ) -> connection::DisconnectReason {
+    let Link { mut tcp, chan, listen, writer, label, max_line } = link;
+    let _span = debug::Span::enter( label.as_slice( ) );
+    let reader = Client::start_reader( tcp.clone( ), chan.clone( ), max_line, label.clone( ) );
+    let reason = Client::start_handler( writer, h.codec.clone( ), h.info.clone( ), h.ctcp.clone( ), 
+      h.queries.clone( ), h.ignores.clone( ), h.joins.clone( ), h.oversized.clone( ), chan, tx, listen, reg, rate );
+
+    // the reader is blocked on the socket until it's shut
+    let _ = tcp.close_read( );
+    let _ = tcp.close_write( );
+    let _ = reader.join( );
+    reason
   }
-  
-  /// `send_msg` sends a Message struct to the IRC server.
+
+  /// `redial` waits and connects again, as the reconnect policy says
   ///
   /// # Arguments
   ///
-  /// `m` - Message struct to transmit.
-  pub fn send_msg( &mut self, m : message::Message ) {
-    match self.conn.chan.send( connection::ConnEvent::Send( m.raw ) ) {
-      Ok ( _ )  => (),
-      Err ( _ ) => debug::err( "sending message to client", "" ),
-    }
-  }
-  
-  /// `send_str` sends a raw string to the IRC server.
+  /// * `dial` - opens a new connection
+  /// * `policy` - how long to wait and how often to try
+  /// * `stopping` - set once the client is quitting
   ///
-  /// # Arguments
+  /// # Returns
   ///
-  /// `s` - string slice to transmit.
-  pub fn send_str( &mut self, s : &str ) {
-    match self.conn.chan.send( connection::ConnEvent::Send( s.to_string( ) ) ) {
-      Ok ( _ )  => (),
-      Err ( _ ) => debug::err( "sending raw message to client", "" ),
+  /// The new connection, or None if the client gave up or is quitting
+  fn redial(
+    dial : &connection::Dialer,
+    policy : connection::ReconnectPolicy,
+    stopping : &AtomicBool
+  ) -> Option < connection::ServerConnection > {
+    let mut attempt = 0;
+    loop {
+      attempt += 1;
+      if policy.max_attempts > 0 && attempt > policy.max_attempts {
+        irc_warn! ( "reconnecting: giving up after {} attempts", policy.max_attempts );
+        return None;
+      }
+      let delay = policy.delay_for( attempt );
+      irc_info! ( "reconnecting in {} ms (attempt {})...", delay, attempt );
+
+      // wait in steps, so a quit doesn't have to wait out the delay
+      let mut waited = 0;
+      while waited < delay {
+        if stopping.load( Ordering::SeqCst ) {
+          return None;
+        }
+        let step = if delay - waited < STOP_POLL { delay - waited } else { STOP_POLL };
+        io::timer::sleep( Duration::milliseconds( step ) );
+        waited += step;
+      }
+      if stopping.load( Ordering::SeqCst ) {
+        return None;
+      }
+      match ( **dial )( ) {
+        Ok ( conn ) => return Some( conn ),
+        Err ( e )   => irc_warn! ( "reconnecting: {}", e ),
+      }
     }
   }
 
@@ -308,39 +766,346 @@ impl Client {
   /// A tuple containing:
   /// * Receiver the client will send parsed IRC messages to
   /// * A "cooked" version of the client
+  ///
+  /// # Notes
+  ///
+  /// * With `reconnect` enabled, a connection the server drops is replaced
+  /// and the client registers and joins its channels again. Lines sent while
+  /// it's reconnecting are lost.
   pub fn start_thread ( mut self ) -> ( mpsc::Receiver < message::Message >, Client )  {
     match self.thread {
       Some ( _ )  => {
-        debug::err( "starting client thread", "client thread already started" );
+        irc_error! ( "starting client thread: client thread already started" );
         let (_,fakerx) = mpsc::channel( );
         ( fakerx, self )
       },
       None        => {
-        debug::oper( "starting client thread..." );
+        irc_info! ( "starting client thread..." );
         let (tx,rx) = mpsc::channel( );
-        let params  = ( self.conn.tcp.clone( ), self.conn.chan.clone( ), 
-          self.conn.spin_writer( ), self.info.clone( ), 
-          self.conn.listen.expect( "no receiver found" ) );
-        self.thread = Some( thread::Thread::spawn( move || {
-          Client::start_reader( params.0, params.1 );
-          Client::start_handler( params.2, params.3, tx.clone( ), params.4 );
+        let first = Link::take( &mut self.conn );
+        let handle = self.handle.clone( );
+        let reg = self.register.clone( );
+        let rate = self.rate_limit;
+        let policy = self.reconnect;
+        let stopping = self.stopping.clone( );
+        let dial = match self.dialer.take( ) {
+          Some ( d ) => d,
+          None       => {
+            // reach the same server the same way
+            let ( host, port, pass ) = ( self.conn.host.clone( ), self.conn.port, self.conn.pass.clone( ) );
+            let mut opts = connection::ConnectOptions::new( );
+            opts.tls = match self.conn.tcp {
+              connection::IrcStream::Tls( _ ) => true,
+              _                               => false,
+            };
+            let dial : connection::Dialer = Box::new( move || {
+              connection::ServerConnection::connect_with( host.as_slice( ), port, pass.as_slice( ), &opts )
+                .map_err( |e| e.to_string( ) )
+            } );
+            dial
+          },
+        };
+        self.thread = Some( thread::Thread::scoped( move || {
+          let max_line = first.max_line;
+          let mut link = first;
+          loop {
+            let reason = Client::run_link( link, &handle, tx.clone( ), reg.clone( ), rate );
+            let dropped = match reason {
+              connection::DisconnectReason::ServerError( _ ) | connection::DisconnectReason::Closed( _ ) => true,
+              _                                                                                       => false,
+            };
+            if !policy.enabled || !dropped {
+              irc_info! ( "client thread finished" );
+              return reason;
+            }
+            let mut conn = match Client::redial( &dial, policy, &*stopping ) {
+              Some ( c ) => c,
+              None       => {
+                irc_info! ( "client thread finished" );
+                return reason;
+              },
+            };
+            link = Link::take( &mut conn );
+            link.max_line = max_line;
+            *handle.chan.lock( ).unwrap( ) = link.chan.clone( );
+
+            // a quit that came in before the swap went to the old connection
+            if stopping.load( Ordering::SeqCst ) {
+              conn.close( );
+              irc_info! ( "client thread finished" );
+              return reason;
+            }
+          }
         } ) );
-        self.conn.listen = None;
         ( rx, self )
       },
     }
   }
   
-  /// `stop` ends the client thread.
+  /// `stop` ends the client thread, sending a bare QUIT.
   pub fn stop( &mut self ) {
-    match self.conn.chan.send( connection::ConnEvent::Abort( 
-      String::from_str( "stop called from client" ) ) ) {
-      Ok ( _ )  => self.close( ),
-      Err ( _ ) => debug::err( "stopping client", "" ),
+    self.quit( "", QUIT_TIMEOUT );
+  }
+
+  /// `quit` leaves the server and shuts the client down
+  ///
+  /// # Arguments
+  ///
+  /// * `reason` - quit message shown to other users, or empty for none
+  /// * `timeout` - milliseconds to wait for the server to close the link
+  ///
+  /// # Returns
+  ///
+  /// Why the connection ended
+  ///
+  /// # Notes
+  ///
+  /// * Lines sent before `quit` are written first. Once QUIT is written, the
+  /// client waits for the server's ERROR or for it to close the link, then
+  /// joins the reader and handler threads. If the server doesn't close the
+  /// link within the timeout, the client closes it itself.
+  pub fn quit( &mut self, reason : &str, timeout : i64 ) -> connection::DisconnectReason {
+    let guard = match self.thread.take( ) {
+      Some ( g ) => g,
+      None       => {
+        self.close( );
+        return connection::DisconnectReason::Closed( String::from_str( "client thread not started" ) );
+      },
+    };
+    // stop any reconnect first, then reach whichever connection is current
+    self.stopping.store( true, Ordering::SeqCst );
+    let chan = self.handle.chan.lock( ).unwrap( ).clone( );
+    match chan.send( connection::ConnEvent::Quit( reason.to_string( ) ) ) {
+      Ok ( _ )  => {
+        let timer = chan.clone( );
+        thread::Thread::spawn( move || {
+          io::timer::sleep( Duration::milliseconds( timeout ) );
+          let _ = timer.send( connection::ConnEvent::Timeout );
+        } );
+      },
+      Err ( _ ) => irc_debug! ( "quitting: handler already closed" ),
+    }
+    match guard.join( ) {
+      Ok ( r )  => r,
+      Err ( _ ) => connection::DisconnectReason::Closed( String::from_str( "client thread panicked" ) ),
+    }
+  }
+
+  /// `handle` returns a handle for sending from other threads
+  pub fn handle( &self ) -> ClientHandle {
+    self.handle.clone( )
+  }
+}
+
+impl Deref for Client {
+  type Target = ClientHandle;
+
+  fn deref( &self ) -> &ClientHandle {
+    &self.handle
+  }
+}
+
+impl Drop for Client {
+  fn drop ( &mut self ) {
+    // a client dropped without quit keeps running until the server hangs up
+    match self.thread.take( ) {
+      Some ( g ) => g.detach( ),
+      None       => (),
     }
   }
+}
+
+/// `ClientHandle` sends to the server and reads the client's state from any
+/// thread
+///
+/// # Notes
+///
+/// * Get one with `Client::handle`. Handles are cheap to clone and can be
+/// moved into worker threads; they all feed the same connection.
+/// * `Client` derefs to its own handle, so every method here can be called on
+/// the client directly.
+/// * The send helpers check their arguments and refuse anything that could
+/// start another command, such as a line break in a message. `send_raw` is
+/// the way around that for lines you built yourself.
+///
+/// # Fields
+///
+/// * `info` - IrcInfo struct that contains information such as the client
+/// nick, username, and channels to connect to on startup.
+/// * `ctcp` - CtcpResponder that answers CTCP requests once enabled
+/// * `queries` - QueryTracker that matches replies to WHOIS, WHO and friends.
+/// It stays locked until a query's line is sent, so queries reach the server
+/// in the order the tracker recorded them.
+/// * `ignores` - masks of users whose messages are dropped before they reach
+/// the receiver
+/// * `joins` - JoinManager that holds channel keys and the rejoin policy
+/// * `codec` - Codec that decodes received lines and encodes sent ones
+/// * `oversized` - receivers told the size of each line dropped for being
+/// over the limit
+pub struct ClientHandle {
+  pub info    : Arc < info::InfoStore >,
+  pub ctcp    : Arc < Mutex < Box < responder::CtcpResponder > > >,
+  pub queries : Arc < Mutex < Box < query::QueryTracker > > >,
+  pub ignores : Arc < Mutex < Box < mask::MaskSet > > >,
+  pub joins   : Arc < Mutex < Box < rejoin::JoinManager > > >,
+  pub codec   : Arc < Mutex < Box < encoding::Codec > > >,
+
+  oversized   : Arc < Mutex < Vec < mpsc::Sender < usize > > > >,
+  chan        : Arc < Mutex < mpsc::Sender < connection::ConnEvent > > >,
+}
+
+impl Clone for ClientHandle {
+  fn clone( &self ) -> ClientHandle {
+    ClientHandle {
+      info    : self.info.clone( ),
+      ctcp    : self.ctcp.clone( ),
+      queries : self.queries.clone( ),
+      ignores : self.ignores.clone( ),
+      joins   : self.joins.clone( ),
+      codec     : self.codec.clone( ),
+      oversized : self.oversized.clone( ),
+      chan      : self.chan.clone( ),
+    }
+  }
+}
+
+impl ClientHandle {
+  /// `get_info` returns a snapshot of the client's info.
+  ///
+  /// # Returns
+  ///
+  /// The client's IrcInfo as of the last message handled. It doesn't change
+  /// as more messages arrive; call `get_info` again for newer info.
+  pub fn get_info( &self ) -> Arc < info::IrcInfo > {
+    self.info.snapshot( )
+  }
+
+  /// `info_events` returns a receiver for an event each time the client's
+  /// info changes, e.g. to redraw a channel list or nick list
+  pub fn info_events( &self ) -> mpsc::Receiver < info::InfoEvent > {
+    self.info.subscribe( )
+  }
+
+  /// `oversized_lines` returns a receiver for the size in bytes of each line
+  /// from the server that was dropped for being over the line limit
+  pub fn oversized_lines( &self ) -> mpsc::Receiver < usize > {
+    let ( tx, rx ) = mpsc::channel( );
+    self.oversized.lock( ).unwrap( ).push( tx );
+    rx
+  }
   
-  // Abstraction methods
+  /// `send_msg` sends a Message struct to the IRC server.
+  ///
+  /// # Arguments
+  ///
+  /// `m` - Message struct to transmit.
+  ///
+  /// # Returns
+  ///
+  /// Why the message wasn't sent, if it would break out of its line
+  pub fn send_msg( &self, m : message::Message ) -> Result < ( ), validate::SendError > {
+    self.send_str( m.raw.as_slice( ) )
+  }
+  
+  /// `send_str` sends a raw string to the IRC server.
+  ///
+  /// # Arguments
+  ///
+  /// `s` - string slice to transmit.
+  ///
+  /// # Returns
+  ///
+  /// Why the line wasn't sent, if it has a CR, LF or NUL or is too long
+  pub fn send_str( &self, s : &str ) -> Result < ( ), validate::SendError > {
+    try! ( self.check_line( s ) );
+    self.send_raw( s );
+    Ok( ( ) )
+  }
+
+  /// `check_line` makes sure a line is safe to send, measuring it as it will
+  /// go out: quoted, encoded and with room left for our prefix
+  fn check_line( &self, s : &str ) -> Result < ( ), validate::SendError > {
+    let bytes = self.codec.lock( ).unwrap( ).encode( ctcp::low_level_quote( s.to_string( ) ).as_slice( ) ).len( );
+    validate::check_line( s, bytes, &self.limits( ) )
+  }
+
+  /// `send_raw` sends a line to the IRC server without checking it
+  ///
+  /// # Arguments
+  ///
+  /// `s` - line to transmit.
+  ///
+  /// # Notes
+  ///
+  /// * Nothing stops a line break in `s` from starting another command, so
+  /// never pass text from other users here.
+  pub fn send_raw( &self, s : &str ) {
+    match self.chan.lock( ).unwrap( ).send( connection::ConnEvent::Send( s.to_string( ) ) ) {
+      Ok ( _ )  => (),
+      Err ( _ ) => irc_error! ( "sending raw message to client" ),
+    }
+  }
+
+  /// `limits` returns the server's rules for nicks and channel names
+  pub fn limits( &self ) -> validate::Limits {
+    validate::Limits::from_info( &*self.get_info( ) )
+  }
+
+  /// `enable_ctcp` turns the automatic CTCP responder on or off
+  ///
+  /// # Arguments
+  ///
+  /// * `on` - true to answer CTCP requests automatically
+  ///
+  /// # Notes
+  ///
+  /// * The responder is off by default. Once it is on, VERSION, PING, TIME,
+  /// CLIENTINFO, SOURCE, USERINFO and FINGER are answered with a NOTICE unless
+  /// they have been removed with `remove_ctcp_reply`.
+  pub fn enable_ctcp( &self, on : bool ) {
+    self.ctcp.lock( ).unwrap( ).enabled = on;
+  }
+  
+  /// `set_ctcp_reply` sets the automatic reply to a CTCP command
+  ///
+  /// # Arguments
+  ///
+  /// * `cmd` - CTCP command to answer, e.g. "VERSION"
+  /// * `reply` - the way the command should be answered
+  pub fn set_ctcp_reply( &self, cmd : &str, reply : responder::CtcpReply ) {
+    self.ctcp.lock( ).unwrap( ).set_reply( cmd, reply );
+  }
+  
+  /// `remove_ctcp_reply` stops the client from answering a CTCP command
+  ///
+  /// # Arguments
+  ///
+  /// * `cmd` - CTCP command to stop answering
+  pub fn remove_ctcp_reply( &self, cmd : &str ) {
+    self.ctcp.lock( ).unwrap( ).remove_reply( cmd );
+  }
+  
+  /// `set_ctcp_limit` sets the per-sender rate limit of the CTCP responder
+  ///
+  /// # Arguments
+  ///
+  /// * `limit` - number of replies a single sender may receive per window
+  /// * `window` - length of the window in seconds
+  pub fn set_ctcp_limit( &self, limit : u32, window : i64 ) {
+    let mut rsp = self.ctcp.lock( ).unwrap( );
+    rsp.limit  = limit;
+    rsp.window = window;
+  }
+
+  /// `set_ctcp_global_limit` sets how many CTCP replies are sent to everyone
+  /// together per window
+  ///
+  /// # Arguments
+  ///
+  /// * `limit` - number of replies sent to all senders per window
+  pub fn set_ctcp_global_limit( &self, limit : u32 ) {
+    self.ctcp.lock( ).unwrap( ).global_limit = limit;
+  }
   
   /// `send_ctcp` sends a CTCP tagged message to the target
   ///
@@ -348,8 +1113,9 @@ impl Client {
   ///
   /// * `target` - target client of the message
   /// * `message` - ctcp message to send, command and parameters
-  pub fn send_ctcp( &mut self, target : &str, message : &str ) {
-    self.message( target, ctcp::tag( message ).as_slice( ) );
+  pub fn send_ctcp( &self, target : &str, message : &str ) -> Result < ( ), validate::SendError > {
+    try! ( validate::check_text( "CTCP message", message ) );
+    self.message( target, ctcp::tag( message ).as_slice( ) )
   }
   
   /// `send_ctcp_reply` sends a response to a CTCP message
@@ -363,8 +1129,171 @@ impl Client {
   ///
   /// * Unlike `send_ctcp`, `send_ctcp_reply` is sent as a NOTICE, as specified
   /// in the CTCP documentation.
-  pub fn send_ctcp_reply( &mut self, target : &str, message : &str ) {
-    self.notice( target, ctcp::tag( message ).as_slice( ) );
+  pub fn send_ctcp_reply( &self, target : &str, message : &str ) -> Result < ( ), validate::SendError > {
+    try! ( validate::check_text( "CTCP message", message ) );
+    self.notice( target, ctcp::tag( message ).as_slice( ) )
+  }
+  
+  /// `send_dcc` sends a DCC request to the target
+  ///
+  /// # Arguments
+  ///
+  /// * `target` - nick to send the request to
+  /// * `req` - DCC request to send, such as the offer of a `DccListener`
+  pub fn send_dcc( &self, target : &str, req : &dcc::DccRequest ) -> Result < ( ), validate::SendError > {
+    try! ( validate::check_target( target, &self.limits( ) ) );
+    let msg = ctcp::Ctcp::Dcc( req.clone( ) ).to_message( target );
+    self.send_msg( msg )
+  }
+  
+  /// `whois` sends a WHOIS query
+  ///
+  /// # Arguments
+  ///
+  /// * `nick` - nick to look up
+  ///
+  /// # Returns
+  ///
+  /// A handle that resolves to the collected WHOIS replies
+  pub fn whois( &self, nick : &str ) -> query::QueryHandle < query::WhoisReply > {
+    match validate::check_word( nick ) {
+      Ok ( _ )  => (),
+      Err ( e ) => return query::QueryHandle::failed( query::QueryError::Invalid( e.to_string( ) ) ),
+    }
+    let mut queries = self.queries.lock( ).unwrap( );
+    let ( line, handle ) = queries.whois( nick );
+    self.send_raw( line.as_slice( ) );
+    handle
+  }
+
+  /// `who` sends a WHO query
+  ///
+  /// # Arguments
+  ///
+  /// * `mask` - channel or mask to list
+  ///
+  /// # Returns
+  ///
+  /// A handle that resolves to the WHO replies
+  pub fn who( &self, mask : &str ) -> query::QueryHandle < Vec < query::WhoEntry > > {
+    match validate::check_word( mask ) {
+      Ok ( _ )  => (),
+      Err ( e ) => return query::QueryHandle::failed( query::QueryError::Invalid( e.to_string( ) ) ),
+    }
+    let whox = self.get_info( ).get_isupport( "WHOX" ).is_some( );
+    let mut queries = self.queries.lock( ).unwrap( );
+    let ( line, handle ) = queries.who( mask, whox );
+    self.send_raw( line.as_slice( ) );
+    handle
+  }
+
+  /// `set_who_refresh` turns the periodic WHO refresh on or off
+  ///
+  /// # Arguments
+  ///
+  /// * `interval` - seconds between refreshes, or None to turn them off
+  ///
+  /// # Notes
+  ///
+  /// * Each refresh WHOs a single channel, taking turns between channels.
+  /// * Refreshes stop once the server ACKs away-notify and account-notify,
+  /// since those keep the member details current by themselves.
+  pub fn set_who_refresh( &self, interval : Option < i64 > ) {
+    self.queries.lock( ).unwrap( ).refresh = interval;
+  }
+
+  /// `ignore` adds a mask to the ignore list
+  ///
+  /// # Arguments
+  ///
+  /// * `mask` - hostmask or extban to ignore, e.g. "*!*@spam.example" or
+  /// "$a:troll"
+  ///
+  /// # Returns
+  ///
+  /// Why the mask couldn't be added, if it couldn't
+  pub fn ignore( &self, mask : &str ) -> Result < ( ), String > {
+    self.ignores.lock( ).unwrap( ).add( mask )
+  }
+
+  /// `unignore` removes a mask from the ignore list
+  ///
+  /// # Returns
+  ///
+  /// true if the mask was on the list
+  pub fn unignore( &self, mask : &str ) -> bool {
+    self.ignores.lock( ).unwrap( ).remove( mask )
+  }
+
+  /// `list` sends a LIST query
+  ///
+  /// # Arguments
+  ///
+  /// * `filter` - conditions the listed channels must meet
+  ///
+  /// # Returns
+  ///
+  /// A stream of the matching channels. The LIST replies go to the stream
+  /// instead of the client's receiver.
+  ///
+  /// # Notes
+  ///
+  /// * Conditions are sent to the server when its ISUPPORT ELIST token says it
+  /// can check them, so call this after registering.
+  pub fn list( &self, filter : query::ListFilter ) -> query::ListStream {
+    let checked = match filter.mask {
+      Some ( ref m ) => validate::check_word( m.as_slice( ) ),
+      None           => Ok( ( ) ),
+    };
+    match checked {
+      Ok ( _ )  => (),
+      Err ( e ) => return query::ListStream::failed( query::QueryError::Invalid( e.to_string( ) ) ),
+    }
+    let elist = self.get_info( ).get_isupport( "ELIST" ).unwrap_or( "" ).to_string( );
+    let mut queries = self.queries.lock( ).unwrap( );
+    let ( line, stream ) = queries.list( filter, elist.as_slice( ) );
+    self.send_raw( line.as_slice( ) );
+    stream
+  }
+
+  /// `names` sends a NAMES query
+  ///
+  /// # Arguments
+  ///
+  /// * `channel` - channel to list
+  ///
+  /// # Returns
+  ///
+  /// A handle that resolves to the names on the channel
+  pub fn names( &self, channel : &str ) -> query::QueryHandle < query::NamesReply > {
+    match validate::check_channel( channel, &self.limits( ) ) {
+      Ok ( _ )  => (),
+      Err ( e ) => return query::QueryHandle::failed( query::QueryError::Invalid( e.to_string( ) ) ),
+    }
+    let mut queries = self.queries.lock( ).unwrap( );
+    let ( line, handle ) = queries.names( channel );
+    self.send_raw( line.as_slice( ) );
+    handle
+  }
+
+  /// `mode` sends a MODE query
+  ///
+  /// # Arguments
+  ///
+  /// * `target` - channel, or our own nick
+  ///
+  /// # Returns
+  ///
+  /// A handle that resolves to the current modes
+  pub fn mode( &self, target : &str ) -> query::QueryHandle < query::ModeReply > {
+    match validate::check_target( target, &self.limits( ) ) {
+      Ok ( _ )  => (),
+      Err ( e ) => return query::QueryHandle::failed( query::QueryError::Invalid( e.to_string( ) ) ),
+    }
+    let mut queries = self.queries.lock( ).unwrap( );
+    let ( line, handle ) = queries.mode( target );
+    self.send_raw( line.as_slice( ) );
+    handle
   }
   
   /// `identify` identifies with the NickServ service
@@ -372,9 +1301,10 @@ impl Client {
   /// # Arguments
   ///
   /// * `password` - NickServ password to identify with
-  pub fn identify( &mut self, password : &str ) {
+  pub fn identify( &self, password : &str ) -> Result < ( ), validate::SendError > {
+    try! ( validate::check_text( "password", password ) );
     let sendline = format! ( "IDENTIFY {}", password );
-    self.message( "NickServ", sendline.as_slice( ) );
+    self.message( "NickServ", sendline.as_slice( ) )
   }
   
   /// `message` sends a private message to the target
@@ -383,9 +1313,16 @@ impl Client {
   ///
   /// * `target` - target of the message
   /// * `message` - body of the message
-  pub fn message( &mut self, target : &str, message : &str ) {
+  ///
+  /// # Returns
+  ///
+  /// Why the message wasn't sent: a bad target, a line break in the body, or
+  /// a body too long for one line
+  pub fn message( &self, target : &str, message : &str ) -> Result < ( ), validate::SendError > {
+    try! ( validate::check_target( target, &self.limits( ) ) );
+    try! ( validate::check_text( "message", message ) );
     let sendline = format! ( "PRIVMSG {} :{}", target, message );
-    self.send_str( sendline.as_slice( ) );
+    self.send_str( sendline.as_slice( ) )
   }
   
   /// `notice` sends a notice message to the target
@@ -398,9 +1335,11 @@ impl Client {
   /// # Notes
   ///
   /// * NOTICE is different from PRIVMSG because a NOTICE never expects a reply
-  pub fn notice( &mut self, target : &str, message : &str ) {
+  pub fn notice( &self, target : &str, message : &str ) -> Result < ( ), validate::SendError > {
+    try! ( validate::check_target( target, &self.limits( ) ) );
+    try! ( validate::check_text( "notice", message ) );
     let sendline = format! ( "NOTICE {} :{}", target, message );
-    self.send_str( sendline.as_slice( ) );
+    self.send_str( sendline.as_slice( ) )
   }
   
   /// `action` sends a CTCP action message to the target
@@ -413,10 +1352,11 @@ impl Client {
   /// # Notes
   ///
   /// * This is equivalent to doing "/me does an action" in a typical IRC client
-  pub fn action( &mut self, target : &str, message : &str ) {
+  pub fn action( &self, target : &str, message : &str ) -> Result < ( ), validate::SendError > {
+    try! ( validate::check_text( "action", message ) );
     let sendline = format! ( "ACTION {}", 
       ctcp::ctcp_quote( String::from_str( message ) ).as_slice( ) );
-    self.send_ctcp( target, sendline.as_slice( ) );
+    self.send_ctcp( target, sendline.as_slice( ) )
   }
   
   /// `join` joins a new channel
@@ -424,9 +1364,53 @@ impl Client {
   /// # Arguments
   ///
   /// * `channel` - channel to join
-  pub fn join( &mut self, channel : &str ) {
-    let sendline = format! ( "JOIN {}", channel );
-    self.send_str( sendline.as_slice( ) );
+  pub fn join( &self, channel : &str ) -> Result < ( ), validate::SendError > {
+    try! ( validate::check_channel( channel, &self.limits( ) ) );
+
+    // the JOIN only counts as outstanding once it's known to be sendable
+    let mut joins = self.joins.lock( ).unwrap( );
+    let sendline = joins.build_join( channel );
+    try! ( self.check_line( sendline.as_slice( ) ) );
+    joins.mark_pending( channel );
+    self.send_raw( sendline.as_slice( ) );
+    Ok( ( ) )
+  }
+
+  /// `join_key` joins a +k channel, remembering the key for rejoins
+  ///
+  /// # Arguments
+  ///
+  /// * `channel` - channel to join
+  /// * `key` - the channel's key
+  pub fn join_key( &self, channel : &str, key : &str ) -> Result < ( ), validate::SendError > {
+    try! ( validate::check_channel( channel, &self.limits( ) ) );
+    try! ( validate::check_word( key ) );
+    self.joins.lock( ).unwrap( ).set_key( channel, Some( key ) );
+    self.join( channel )
+  }
+
+  /// `knock` asks the ops of an invite only channel for an invite
+  ///
+  /// # Arguments
+  ///
+  /// * `channel` - channel to knock on
+  /// * `message` - message for the ops
+  pub fn knock( &self, channel : &str, message : &str ) -> Result < ( ), validate::SendError > {
+    try! ( validate::check_channel( channel, &self.limits( ) ) );
+    try! ( validate::check_text( "knock message", message ) );
+    let sendline = format! ( "KNOCK {} :{}", channel, message );
+    self.send_str( sendline.as_slice( ) )
+  }
+
+  /// `set_join_policy` sets when the client rejoins channels
+  pub fn set_join_policy( &self, policy : rejoin::JoinPolicy ) {
+    self.joins.lock( ).unwrap( ).policy = policy;
+  }
+
+  /// `channel_events` returns a receiver for events telling why we left a
+  /// channel or couldn't join one
+  pub fn channel_events( &self ) -> mpsc::Receiver < rejoin::ChannelLeft > {
+    self.joins.lock( ).unwrap( ).subscribe( )
   }
   
   /// `part` leaves a channel you're in
@@ -434,9 +1418,10 @@ impl Client {
   /// # Arguments
   ///
   /// * `channel` - channel to part from
-  pub fn part( &mut self, channel : &str ) {
+  pub fn part( &self, channel : &str ) -> Result < ( ), validate::SendError > {
+    try! ( validate::check_channel( channel, &self.limits( ) ) );
     let sendline = format! ( "PART {}", channel );
-    self.send_str( sendline.as_slice( ) );
+    self.send_str( sendline.as_slice( ) )
   }
   
   /// `nick` changes nickname on the server
@@ -444,8 +1429,242 @@ impl Client {
   /// # Arguments
   ///
   /// * `nick` - nickname to change to
-  pub fn nick( &mut self, nick : &str ) {
+  pub fn nick( &self, nick : &str ) -> Result < ( ), validate::SendError > {
+    try! ( validate::check_nick( nick, &self.limits( ) ) );
     let sendline = format! ( "NICK {}", nick );
-    self.send_str( sendline.as_slice( ) );
+    self.send_str( sendline.as_slice( ) )
+  }
+
+  /// `get_ignores` returns the masks on the ignore list
+  pub fn get_ignores( &self ) -> Vec < String > {
+    self.ignores.lock( ).unwrap( ).list( )
   }
-}
\ No newline at end of file
+
+  /// `set_encoding` sets how lines are decoded and encoded
+  ///
+  /// # Arguments
+  ///
+  /// * `fallback` - encoding for received lines that aren't valid UTF-8, or
+  /// None to replace their bad bytes with U+FFFD
+  /// * `outgoing` - encoding for lines we send
+  pub fn set_encoding( &self, fallback : Option < encoding::Encoding >, outgoing : encoding::Encoding ) {
+    let mut codec = self.codec.lock( ).unwrap( );
+    codec.fallback = fallback;
+    codec.outgoing = outgoing;
+  }
+
+  /// `set_channel_encoding` sets the encoding of a channel that doesn't use
+  /// UTF-8, for lines both ways
+  ///
+  /// # Arguments
+  ///
+  /// * `channel` - the channel
+  /// * `encoding` - its encoding, or None to go back to the defaults
+  pub fn set_channel_encoding( &self, channel : &str, encoding : Option < encoding::Encoding > ) {
+    self.codec.lock( ).unwrap( ).set_channel( channel, encoding );
+  }
+}
+
+/// `ClientBuilder` collects the settings for a client one at a time and
+/// checks them together when built
+///
+/// # Notes
+///
+/// * `port`, `tls`, `tls_verify`, `password`, `proxy`, `bind`, `prefer` and
+/// `connect_timeout` apply to the last server added. The port defaults to
+/// 6667, or 6697 with TLS.
+/// * `user` and `realname` default to the nick.
+/// * Problems are reported by `build`, naming the setting, the same way a bad
+/// config file is.
+///
+/// # Example
+///
+/// ```ignore
+/// let config = try! ( ClientBuilder::new( )
+///   .server( "irc.example.net" ).tls( true )
+///   .nick( "rustbot" ).alt_nicks( vec![ "rustbot_" ] )
+///   .channel_with_key( "#secret", "hunter2" )
+///   .build( ) );
+/// let mut client = try! ( config.connect( ) );
+/// ```
+pub struct ClientBuilder {
+  config : config::Config,
+  user   : Option < String >,
+  real   : Option < String >,
+  error  : Option < config::ConfigError >,
+}
+
+impl ClientBuilder {
+  /// `new` starts a builder with no servers and no nick
+  pub fn new( ) -> ClientBuilder {
+    ClientBuilder {
+      config : config::Config::new( "" ),
+      user   : None,
+      real   : None,
+      error  : None,
+    }
+  }
+
+  /// `last_server` applies a change to the last server added, or remembers
+  /// the mistake for `build` if there isn't one
+  fn last_server < F : FnOnce( &mut config::ServerEntry ) > (
+    mut self,
+    setting : &str,
+    f : F
+  ) -> ClientBuilder {
+    let n = self.config.servers.len( );
+    if n == 0 {
+      if self.error.is_none( ) {
+        self.error = Some( config::ConfigError {
+          key     : String::from_str( "servers" ),
+          message : format! ( "{} was set before any server was added", setting ),
+        } );
+      }
+    } else {
+      f( &mut self.config.servers[n - 1] );
+    }
+    self
+  }
+
+  /// `server` adds a server to try, after any added before it
+  pub fn server( mut self, host : &str ) -> ClientBuilder {
+    self.config.servers.push( config::ServerEntry {
+      host     : host.to_string( ),
+      port     : config::DEFAULT_PORT,
+      password : None,
+      proxy    : None,
+      connect  : connection::ConnectOptions::new( ),
+    } );
+    self
+  }
+
+  /// `port` sets the port of the last server
+  pub fn port( self, port : u16 ) -> ClientBuilder {
+    self.last_server( "port", |s| s.port = port )
+  }
+
+  /// `tls` sets whether the last server is reached with TLS
+  ///
+  /// # Notes
+  ///
+  /// * A port still at the default follows the setting, 6697 with TLS and
+  /// 6667 without. Set the port after `tls` to use another one.
+  pub fn tls( self, on : bool ) -> ClientBuilder {
+    self.last_server( "tls", |s| {
+      let default = if s.connect.tls { config::DEFAULT_TLS_PORT } else { config::DEFAULT_PORT };
+      if s.port == default {
+        s.port = if on { config::DEFAULT_TLS_PORT } else { config::DEFAULT_PORT };
+      }
+      s.connect.tls = on;
+    } )
+  }
+
+  /// `tls_verify` sets whether the last server's certificate is checked when
+  /// using TLS
+  pub fn tls_verify( self, on : bool ) -> ClientBuilder {
+    self.last_server( "tls_verify", |s| s.connect.tls_verify = on )
+  }
+
+  /// `password` sets the PASS sent to the last server
+  pub fn password( self, password : &str ) -> ClientBuilder {
+    self.last_server( "password", |s| s.password = Some( password.to_string( ) ) )
+  }
+
+  /// `proxy` sets the proxy the last server is reached through
+  pub fn proxy( self, proxy : proxy::Proxy ) -> ClientBuilder {
+    self.last_server( "proxy", |s| s.proxy = Some( proxy ) )
+  }
+
+  /// `bind` sets the local address to connect to the last server from
+  pub fn bind( self, addr : ip::IpAddr ) -> ClientBuilder {
+    self.last_server( "bind", |s| s.connect.bind = Some( addr ) )
+  }
+
+  /// `prefer` sets which address family of the last server to try first
+  pub fn prefer( self, prefer : connection::AddrPreference ) -> ClientBuilder {
+    self.last_server( "prefer", |s| s.connect.prefer = prefer )
+  }
+
+  /// `connect_timeout` sets how many milliseconds to wait for each address of
+  /// the last server, or for its proxy and the proxy's handshake
+  pub fn connect_timeout( self, ms : i64 ) -> ClientBuilder {
+    self.last_server( "connect_timeout", |s| s.connect.timeout = Some( ms ) )
+  }
+
+  /// `nick` sets the nick to register with
+  pub fn nick( mut self, nick : &str ) -> ClientBuilder {
+    self.config.nick = nick.to_string( );
+    self
+  }
+
+  /// `alt_nicks` sets nicks to try in turn when the nick is taken while
+  /// registering
+  pub fn alt_nicks( mut self, nicks : Vec < &str > ) -> ClientBuilder {
+    self.config.alt_nicks = nicks.iter( ).map( |n| n.to_string( ) ).collect( );
+    self
+  }
+
+  /// `user` sets the username
+  pub fn user( mut self, user : &str ) -> ClientBuilder {
+    self.user = Some( user.to_string( ) );
+    self
+  }
+
+  /// `realname` sets the realname
+  pub fn realname( mut self, real : &str ) -> ClientBuilder {
+    self.real = Some( real.to_string( ) );
+    self
+  }
+
+  /// `channel` adds a channel to join on connect
+  pub fn channel( mut self, name : &str ) -> ClientBuilder {
+    self.config.channels.push( config::ChannelEntry { name : name.to_string( ), key : None } );
+    self
+  }
+
+  /// `channel_with_key` adds a +k channel to join on connect
+  pub fn channel_with_key( mut self, name : &str, key : &str ) -> ClientBuilder {
+    self.config.channels.push( config::ChannelEntry { name : name.to_string( ), key : Some( key.to_string( ) ) } );
+    self
+  }
+
+  /// `sasl_plain` logs in to services with an account and password while
+  /// registering, if the server offers SASL
+  pub fn sasl_plain( mut self, account : &str, password : &str ) -> ClientBuilder {
+    self.config.sasl = Some( config::SaslConfig {
+      mechanism : String::from_str( "PLAIN" ),
+      account   : account.to_string( ),
+      password  : Some( password.to_string( ) ),
+    } );
+    self
+  }
+
+  /// `ctcp` sets whether CTCP requests are answered
+  pub fn ctcp( mut self, enabled : bool ) -> ClientBuilder {
+    self.config.ctcp.enabled = enabled;
+    self
+  }
+
+  /// `build` fills in the defaults and checks the settings
+  ///
+  /// # Returns
+  ///
+  /// The validated config, or the first problem found
+  pub fn build( self ) -> Result < config::Config, config::ConfigError > {
+    match self.error {
+      Some ( e ) => return Err( e ),
+      None       => (),
+    }
+    let mut config = self.config;
+    config.user = self.user.unwrap_or( config.nick.clone( ) );
+    config.real = self.real.unwrap_or( config.nick.clone( ) );
+    try! ( config.validate( ) );
+    Ok( config )
+  }
+
+  /// `connect` builds the config and connects, trying the servers in order
+  /// until one answers
+  pub fn connect( self ) -> Result < Client, config::ConfigError > {
+    try! ( self.build( ) ).connect( )
+  }
+}
@@ -0,0 +1,695 @@
+// import built in modules
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+use time;
+
+// import custom modules
+use info;
+use mask;
+use message;
+use validate;
+
+static DEFAULT_PREFIX : &'static str = "!";   // prefix of commands in channels
+static HELP_COMMAND   : &'static str = "help"; // name of the generated help
+
+/// `ArgKind` is the type of a command argument
+///
+/// # Options
+///
+/// `Str` - any single word
+/// `Int` - a whole number
+/// `Nick` - a valid nickname
+/// `Channel` - a channel name
+/// `Rest` - everything left on the line, must be the last argument
+pub enum ArgKind {
+  Str,
+  Int,
+  Nick,
+  Channel,
+  Rest,
+}
+
+impl Copy for ArgKind {}
+
+/// `ArgSpec` describes one argument of a command
+///
+/// # Members
+///
+/// * `name` - name the argument is looked up by
+/// * `kind` - type of the argument
+/// * `optional` - whether the argument may be left out
+pub struct ArgSpec {
+  pub name      : String,
+  pub kind      : ArgKind,
+  pub optional  : bool,
+}
+
+impl ArgSpec {
+  /// `usage` formats the argument for help text, e.g. "<nick>" or "[reason]"
+  pub fn usage( &self ) -> String {
+    if self.optional {
+      format! ( "[{}]", self.name )
+    } else {
+      format! ( "<{}>", self.name )
+    }
+  }
+}
+
+/// `Arg` is a parsed command argument
+pub enum Arg {
+  Str ( String ),
+  Int ( i64 ),
+  Nick ( String ),
+  Channel ( String ),
+  Rest ( String ),
+}
+
+/// `Args` holds the parsed arguments of a command by name
+pub struct Args {
+  values : Vec < ( String, Arg ) >,
+}
+
+impl Args {
+  /// `get` returns an argument by name
+  pub fn get( &self, name : &str ) -> Option < &Arg > {
+    self.values.iter( ).find( |v| v.0.as_slice( ) == name ).map( |v| &v.1 )
+  }
+
+  /// `str` returns a text argument (string, nick, channel or rest) by name
+  pub fn str( &self, name : &str ) -> Option < &str > {
+    match self.get( name ) {
+      Some ( &Arg::Str ( ref s ) ) | Some ( &Arg::Nick ( ref s ) ) |
+      Some ( &Arg::Channel ( ref s ) ) | Some ( &Arg::Rest ( ref s ) ) => Some( s.as_slice( ) ),
+      _                                                               => None,
+    }
+  }
+
+  /// `int` returns an integer argument by name
+  pub fn int( &self, name : &str ) -> Option < i64 > {
+    match self.get( name ) {
+      Some ( &Arg::Int ( i ) ) => Some( i ),
+      _                        => None,
+    }
+  }
+
+  /// `len` returns the number of arguments that were given
+  pub fn len( &self ) -> usize {
+    self.values.len( )
+  }
+}
+
+/// `Permission` decides who may run a command
+///
+/// # Options
+///
+/// `Anyone` - everyone may run the command
+/// `Hostmask( m )` - only users whose nick!user@host matches the glob m
+/// `ChannelOp` - only operators of the channel the command was sent to
+/// `Account( a )` - only users logged in to the services account a
+/// `AnyOf( v )` - users allowed by any of the permissions in v
+pub enum Permission {
+  Anyone,
+  Hostmask ( String ),
+  ChannelOp,
+  Account ( String ),
+  AnyOf ( Vec < Permission > ),
+}
+
+/// `Context` describes where a command came from
+///
+/// # Members
+///
+/// * `nick` - nick of the sender
+/// * `prefix` - full nick!user@host of the sender
+/// * `channel` - channel the command was sent to, None in a query
+/// * `account` - services account of the sender, if known
+/// * `command` - name the command was invoked with, may be an alias
+pub struct Context {
+  pub nick    : String,
+  pub prefix  : String,
+  pub channel : Option < String >,
+  pub account : Option < String >,
+  pub command : String,
+}
+
+impl Context {
+  /// `reply_target` returns where replies to the command go
+  pub fn reply_target( &self ) -> &str {
+    match self.channel {
+      Some ( ref chan ) => chan.as_slice( ),
+      None              => self.nick.as_slice( ),
+    }
+  }
+}
+
+/// `Command` is a bot command
+///
+/// # Members
+///
+/// * `name` - name the command is invoked with
+/// * `aliases` - other names for the command
+/// * `help` - one line description shown by help
+/// * `args` - arguments the command takes
+/// * `permission` - who may run the command
+/// * `cooldown` - seconds a user must wait between uses, 0 for none
+/// * `handler` - called with the context and arguments, returns the lines to
+/// reply with
+pub struct Command {
+  pub name        : String,
+  pub aliases     : Vec < String >,
+  pub help        : String,
+  pub args        : Vec < ArgSpec >,
+  pub permission  : Permission,
+  pub cooldown    : i64,
+
+  handler         : Box < Fn( &Context, &Args ) -> Vec < String > + Send + 'static >,
+}
+
+impl Command {
+  /// `new` creates a command that anyone may run
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - name of the command, without the prefix
+  /// * `usage` - arguments, e.g. "<nick:nick> <count:int> [reason:rest]".
+  /// <> marks required arguments, [] optional ones. The type after the colon
+  /// is one of str, int, nick, channel or rest, and defaults to str.
+  /// * `handler` - called with the context and arguments, returns the lines to
+  /// reply with
+  ///
+  /// # Returns
+  ///
+  /// The command, or a description of what's wrong with the usage
+  pub fn new < F > ( name : &str, usage : &str, handler : F ) -> Result < Command, String >
+    where F : Fn( &Context, &Args ) -> Vec < String > + Send + 'static {
+    Ok( Command {
+      name        : name.to_ascii_lowercase( ),
+      aliases     : Vec::new( ),
+      help        : String::new( ),
+      args        : try! ( parse_usage( usage ) ),
+      permission  : Permission::Anyone,
+      cooldown    : 0,
+      handler     : Box::new( handler ),
+    } )
+  }
+
+  /// `usage` formats the command's usage for help text
+  ///
+  /// # Arguments
+  ///
+  /// * `prefix` - the router's command prefix
+  pub fn usage( &self, prefix : &str ) -> String {
+    let mut usage = format! ( "{}{}", prefix, self.name );
+    for arg in self.args.iter( ) {
+      usage.push( ' ' );
+      usage.push_str( arg.usage( ).as_slice( ) );
+    }
+    usage
+  }
+
+  /// `answers_to` returns whether the command has the given name or alias
+  fn answers_to( &self, name : &str ) -> bool {
+    self.name.as_slice( ) == name || self.aliases.iter( ).any( |a| a.to_ascii_lowercase( ) == name )
+  }
+}
+
+/// `Router` dispatches bot commands from PRIVMSGs to their handlers
+///
+/// # Members
+///
+/// * `prefix` - prefix of commands sent to channels, "!" by default
+/// * `addressable` - also accept commands addressed as "nick: command"
+/// * `help` - answer the generated help command
+/// * `commands` - registered commands
+/// * `last_used` - when each user last ran each command, for cooldowns
+///
+/// # Notes
+///
+/// * In a query the prefix may be left out.
+/// * Accounts are read from the client info, which learns them from ACCOUNT
+/// messages, extended JOINs and WHO and WHOIS replies.
+pub struct Router {
+  pub prefix      : String,
+  pub addressable : bool,
+  pub help        : bool,
+
+  commands        : Vec < Command >,
+  last_used       : HashMap < ( String, String ), i64 >,
+}
+
+impl Router {
+  /// `new` creates a router without any commands
+  pub fn new( ) -> Router {
+    Router {
+      prefix      : DEFAULT_PREFIX.to_string( ),
+      addressable : true,
+      help        : true,
+      commands    : Vec::new( ),
+      last_used   : HashMap::new( ),
+    }
+  }
+
+  /// `register` adds a command to the router
+  ///
+  /// # Arguments
+  ///
+  /// * `cmd` - the command to add
+  ///
+  /// # Returns
+  ///
+  /// An error naming the clashing name if the command's name or an alias is
+  /// already taken
+  pub fn register( &mut self, cmd : Command ) -> Result < ( ), String > {
+    let mut names = vec![ cmd.name.clone( ) ];
+    names.extend( cmd.aliases.iter( ).map( |a| a.to_ascii_lowercase( ) ) );
+    for name in names.iter( ) {
+      if ( self.help && name.as_slice( ) == HELP_COMMAND ) ||
+        self.commands.iter( ).any( |c| c.answers_to( name.as_slice( ) ) ) {
+        return Err( format! ( "command name '{}' is already taken", name ) );
+      }
+    }
+    self.commands.push( cmd );
+    Ok( ( ) )
+  }
+
+  /// `unregister` removes a command by name
+  ///
+  /// # Returns
+  ///
+  /// true if a command was removed
+  pub fn unregister( &mut self, name : &str ) -> bool {
+    let name = name.to_ascii_lowercase( );
+    let before = self.commands.len( );
+    self.commands.retain( |c| c.name != name );
+    before != self.commands.len( )
+  }
+
+  /// `handle` runs the command in a message, if it contains one
+  ///
+  /// # Arguments
+  ///
+  /// * `msg` - a message received from the server
+  /// * `i` - the client's info, already updated with the message, used for
+  /// our nick, channel operators and accounts
+  ///
+  /// # Returns
+  ///
+  /// Raw lines to send to the server. Replies go to the channel or query
+  /// the command came from, errors go to the sender as a NOTICE.
+  pub fn handle( &mut self, msg : &message::Message, i : &info::IrcInfo ) -> Vec < String > {
+    self.handle_at( msg, i, time::get_time( ).sec )
+  }
+
+  /// `handle_at` is `handle` at a given time in seconds
+  fn handle_at( &mut self, msg : &message::Message, i : &info::IrcInfo, now : i64 ) -> Vec < String > {
+    if msg.code.as_slice( ) != "PRIVMSG" {
+      return Vec::new( );
+    }
+    let ( target, text, nick ) = match ( msg.param( 1 ), msg.trailing( ), msg.nick( ) ) {
+      ( Some ( t ), Some ( x ), Some ( n ) ) => ( t, x, n ),
+      _                                      => return Vec::new( ),
+    };
+    let channel = if target.starts_with( "#" ) || target.starts_with( "&" ) {
+      Some( target.to_string( ) )
+    } else {
+      None
+    };
+    let line = match self.strip_prefix( text, i.nick_name.as_slice( ), channel.is_none( ) ) {
+      Some ( l ) => l,
+      None       => return Vec::new( ),
+    };
+    let ( name, rest ) = match line.find( ' ' ) {
+      Some ( n ) => ( line.slice_to( n ), line.slice_from( n + 1 ).trim_left( ) ),
+      None       => ( line, "" ),
+    };
+    let name = name.to_ascii_lowercase( );
+    if name.is_empty( ) {
+      return Vec::new( );
+    }
+    let ctx = Context {
+      nick    : nick.clone( ),
+      prefix  : match msg.source {
+        message::Source::Sender ( ref s ) => s.as_slice( ).trim_left_matches( ':' ).to_string( ),
+        message::Source::None             => nick.clone( ),
+      },
+      channel : channel,
+      account : i.get_member( nick.as_slice( ) ).and_then( |m| m.account.clone( ) ),
+      command : name.clone( ),
+    };
+
+    if self.help && name.as_slice( ) == HELP_COMMAND {
+      return self.help_lines( &ctx, i, rest );
+    }
+    let cmd = match self.commands.iter( ).find( |c| c.answers_to( name.as_slice( ) ) ) {
+      Some ( c ) => c,
+      None       => return Vec::new( ),
+    };
+    if !allowed( &cmd.permission, &ctx, i ) {
+      irc_debug! ( "{} is not allowed to run {}", ctx.prefix, cmd.name );
+      return vec![ notice( ctx.nick.as_slice( ),
+        format! ( "You don't have permission to use {}{}", self.prefix, cmd.name ).as_slice( ) ) ];
+    }
+    let key = ( cmd.name.clone( ), ctx.nick.to_ascii_lowercase( ) );
+    if cmd.cooldown > 0 {
+      match self.last_used.get( &key ) {
+        Some ( last ) if now - *last < cmd.cooldown => {
+          irc_debug! ( "{} is on cooldown for {}", cmd.name, ctx.nick );
+          return Vec::new( );
+        },
+        _                                          => (),
+      }
+    }
+    let args = match parse_args( &cmd.args, rest, &validate::Limits::from_info( i ) ) {
+      Ok ( a )  => a,
+      Err ( e ) => return vec![ notice( ctx.nick.as_slice( ),
+        format! ( "{}. Usage: {}", e, cmd.usage( self.prefix.as_slice( ) ) ).as_slice( ) ) ],
+    };
+
+    let replies = ( *cmd.handler )( &ctx, &args );
+    let target = ctx.reply_target( ).to_string( );
+    let out = replies.iter( ).map( |r| privmsg( target.as_slice( ), r.as_slice( ) ) ).collect( );
+    if cmd.cooldown > 0 {
+      self.last_used.insert( key, now );
+    }
+    out
+  }
+
+  /// `strip_prefix` finds the command in a message's text
+  ///
+  /// # Arguments
+  ///
+  /// * `text` - text of the PRIVMSG
+  /// * `nick` - our nick, for commands addressed to us
+  /// * `query` - whether the message was sent to us directly
+  ///
+  /// # Returns
+  ///
+  /// The text after the prefix, or None if the message isn't a command
+  fn strip_prefix < 'a > ( &self, text : &'a str, nick : &str, query : bool ) -> Option < &'a str > {
+    if text.starts_with( "\x01" ) {
+      return None;
+    }
+    if !self.prefix.is_empty( ) && text.starts_with( self.prefix.as_slice( ) ) {
+      return Some( text.slice_from( self.prefix.len( ) ) );
+    }
+    if self.addressable && text.len( ) > nick.len( ) && text.is_char_boundary( nick.len( ) ) &&
+      text.slice_to( nick.len( ) ).to_ascii_lowercase( ) == nick.to_ascii_lowercase( ) {
+      let rest = text.slice_from( nick.len( ) );
+      if rest.starts_with( ":" ) || rest.starts_with( "," ) {
+        return Some( rest.slice_from( 1 ).trim_left( ) );
+      }
+    }
+    if query { Some( text ) } else { None }
+  }
+
+  /// `help_lines` answers the generated help command
+  fn help_lines( &self, ctx : &Context, i : &info::IrcInfo, rest : &str ) -> Vec < String > {
+    let target = ctx.reply_target( ).to_string( );
+    let wanted = rest.trim( ).trim_left_matches( self.prefix.as_slice( ) ).to_ascii_lowercase( );
+    if wanted.is_empty( ) {
+      let mut names : Vec < String > = self.commands.iter( )
+        .filter( |c| allowed( &c.permission, ctx, i ) )
+        .map( |c| format! ( "{}{}", self.prefix, c.name ) ).collect( );
+      names.sort( );
+      let text = format! ( "Commands: {}. Use {}{} <command> for details.",
+        names.connect( ", " ), self.prefix, HELP_COMMAND );
+      return vec![ privmsg( target.as_slice( ), text.as_slice( ) ) ];
+    }
+    match self.commands.iter( ).find( |c| c.answers_to( wanted.as_slice( ) ) ) {
+      Some ( cmd ) => {
+        let mut text = cmd.usage( self.prefix.as_slice( ) );
+        if !cmd.help.is_empty( ) {
+          text.push_str( " - " );
+          text.push_str( cmd.help.as_slice( ) );
+        }
+        if !cmd.aliases.is_empty( ) {
+          text.push_str( format! ( " (aliases: {})", cmd.aliases.connect( ", " ) ).as_slice( ) );
+        }
+        vec![ privmsg( target.as_slice( ), text.as_slice( ) ) ]
+      },
+      None         => vec![ notice( ctx.nick.as_slice( ),
+        format! ( "No such command: {}", wanted ).as_slice( ) ) ],
+    }
+  }
+}
+
+/// `allowed` checks a permission against the sender of a command
+fn allowed( perm : &Permission, ctx : &Context, i : &info::IrcInfo ) -> bool {
+  match *perm {
+    Permission::Anyone              => true,
+    Permission::Hostmask ( ref m )  => mask::glob_match( m.as_slice( ), ctx.prefix.as_slice( ) ),
+    Permission::Account ( ref a )   => ctx.account.as_ref( )
+      .map( |acct| acct.to_ascii_lowercase( ) == a.to_ascii_lowercase( ) ).unwrap_or( false ),
+    Permission::ChannelOp           => match ctx.channel {
+      Some ( ref chan ) => match i.get_channel_names( chan.clone( ) ) {
+        Some ( names ) => names.iter( ).any( |n| {
+          let n = n.as_slice( );
+          n.len( ) > 1 && "~&@".contains_char( n.char_at( 0 ) ) &&
+            n.slice_from( 1 ).to_ascii_lowercase( ) == ctx.nick.to_ascii_lowercase( )
+        } ),
+        None           => false,
+      },
+      None              => false,
+    },
+    Permission::AnyOf ( ref perms ) => perms.iter( ).any( |p| allowed( p, ctx, i ) ),
+  }
+}
+
+/// `parse_usage` parses a usage string into argument specs
+fn parse_usage( usage : &str ) -> Result < Vec < ArgSpec >, String > {
+  let mut specs : Vec < ArgSpec > = Vec::new( );
+  for word in usage.split( ' ' ).filter( |w| !w.is_empty( ) ) {
+    let optional = if word.starts_with( "<" ) && word.ends_with( ">" ) {
+      false
+    } else if word.starts_with( "[" ) && word.ends_with( "]" ) {
+      true
+    } else {
+      return Err( format! ( "argument '{}' must be wrapped in <> or []", word ) );
+    };
+    let inner = word.slice( 1, word.len( ) - 1 );
+    let ( name, kind ) = match inner.find( ':' ) {
+      Some ( n ) => ( inner.slice_to( n ), inner.slice_from( n + 1 ) ),
+      None       => ( inner, "str" ),
+    };
+    let kind = match kind {
+      "str"     => ArgKind::Str,
+      "int"     => ArgKind::Int,
+      "nick"    => ArgKind::Nick,
+      "channel" => ArgKind::Channel,
+      "rest"    => ArgKind::Rest,
+      _         => return Err( format! ( "argument '{}' has unknown type '{}'", name, kind ) ),
+    };
+    if name.is_empty( ) {
+      return Err( format! ( "argument '{}' has no name", word ) );
+    }
+    match specs.last( ) {
+      Some ( last ) if last.optional && !optional =>
+        return Err( format! ( "required argument '{}' follows an optional one", name ) ),
+      Some ( last ) if match last.kind { ArgKind::Rest => true, _ => false } =>
+        return Err( format! ( "argument '{}' follows a rest argument", name ) ),
+      _                                           => (),
+    }
+    specs.push( ArgSpec { name : name.to_string( ), kind : kind, optional : optional } );
+  }
+  Ok( specs )
+}
+
+/// `parse_args` parses the text after a command name against its specs
+///
+/// # Arguments
+///
+/// * `specs` - the command's arguments
+/// * `text` - the text after the command name
+/// * `limits` - the server's rules for nicks
+///
+/// # Returns
+///
+/// The parsed arguments, or a description of the first bad one
+fn parse_args( specs : &Vec < ArgSpec >, text : &str, limits : &validate::Limits ) -> Result < Args, String > {
+  let mut values = Vec::new( );
+  let mut rest = text.trim( );
+  for spec in specs.iter( ) {
+    if rest.is_empty( ) {
+      if spec.optional {
+        break;
+      }
+      return Err( format! ( "Missing {}", spec.usage( ) ) );
+    }
+    let ( word, after ) = match spec.kind {
+      ArgKind::Rest => ( rest, "" ),
+      _             => match rest.find( ' ' ) {
+        Some ( n ) => ( rest.slice_to( n ), rest.slice_from( n + 1 ).trim_left( ) ),
+        None       => ( rest, "" ),
+      },
+    };
+    let value = match spec.kind {
+      ArgKind::Str      => Arg::Str( word.to_string( ) ),
+      ArgKind::Rest     => Arg::Rest( word.to_string( ) ),
+      ArgKind::Int      => match word.parse::< i64 >( ).ok( ) {
+        Some ( i ) => Arg::Int( i ),
+        None       => return Err( format! ( "{} must be a number", spec.usage( ) ) ),
+      },
+      ArgKind::Nick     => if validate::check_nick( word, limits ).is_ok( ) {
+        Arg::Nick( word.to_string( ) )
+      } else {
+        return Err( format! ( "{} must be a nick", spec.usage( ) ) );
+      },
+      ArgKind::Channel  => if word.starts_with( "#" ) || word.starts_with( "&" ) {
+        Arg::Channel( word.to_string( ) )
+      } else {
+        return Err( format! ( "{} must be a channel", spec.usage( ) ) );
+      },
+    };
+    values.push( ( spec.name.clone( ), value ) );
+    rest = after;
+  }
+  if !rest.is_empty( ) {
+    return Err( String::from_str( "Too many arguments" ) );
+  }
+  Ok( Args { values : values } )
+}
+
+/// `privmsg` formats a raw PRIVMSG line
+fn privmsg( target : &str, text : &str ) -> String {
+  message::Message::privmsg( target, text ).raw
+}
+
+/// `notice` formats a raw NOTICE line
+fn notice( target : &str, text : &str ) -> String {
+  format! ( "NOTICE {} :{}", target, text )
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[allow(unused_imports)]
+  use info::IrcInfo;
+  #[allow(unused_imports)]
+  use message::Message;
+  #[allow(unused_imports)]
+  use super::{Command, Permission, Router};
+
+  #[allow(dead_code)]
+  fn router( ) -> Router {
+    let mut router = Router::new( );
+    let mut greet = Command::new( "greet", "<who:nick> [times:int]", |ctx, args| {
+      let times = args.int( "times" ).unwrap_or( 1 );
+      range( 0, times ).map( |_| format! ( "{} says hi to {}", ctx.nick, args.str( "who" ).unwrap( ) ) ).collect( )
+    } ).unwrap( );
+    greet.aliases.push( String::from_str( "hi" ) );
+    greet.help = String::from_str( "Greets someone" );
+    router.register( greet ).unwrap( );
+
+    let mut op = Command::new( "topic", "<text:rest>", |_, args| {
+      vec![ format! ( "topic would be: {}", args.str( "text" ).unwrap( ) ) ]
+    } ).unwrap( );
+    op.permission = Permission::AnyOf( vec![ Permission::ChannelOp,
+      Permission::Hostmask( String::from_str( "*!*@admin.example" ) ) ] );
+    router.register( op ).unwrap( );
+
+    let mut slow = Command::new( "slow", "", |_, _| vec![ String::from_str( "ok" ) ] ).unwrap( );
+    slow.cooldown = 30;
+    slow.permission = Permission::Account( String::from_str( "lancey" ) );
+    router.register( slow ).unwrap( );
+    router
+  }
+
+  #[allow(dead_code)]
+  fn info( ) -> IrcInfo {
+    let mut info = IrcInfo::gen( "RustBot", "RustBot", "Bot", vec![ "#rust" ] );
+    info.prep_channel_names( Message::parse( ":srv 353 RustBot = #rust :RustBot @Lancey Detective" ).unwrap( ) );
+    info.set_channel_names( String::from_str( "#rust" ) );
+    info
+  }
+
+  #[allow(dead_code)]
+  fn run( router : &mut Router, line : &str, now : i64 ) -> Vec < String > {
+    run_in( router, &mut info( ), line, now )
+  }
+
+  #[allow(dead_code)]
+  fn run_in( router : &mut Router, info : &mut IrcInfo, line : &str, now : i64 ) -> Vec < String > {
+    let msg = Message::parse( line ).unwrap( );
+    info.update_info( msg.clone( ) );
+    router.handle_at( &msg, info, now )
+  }
+
+  #[test]
+  fn test_parse_usage () {
+    assert! ( Command::new( "a", "<x:int> [y] [z:rest]", |_, _| vec![] ).is_ok( ) );
+    assert! ( Command::new( "a", "[x] <y>", |_, _| vec![] ).is_err( ) );
+    assert! ( Command::new( "a", "<x:rest> <y>", |_, _| vec![] ).is_err( ) );
+    assert! ( Command::new( "a", "<x:float>", |_, _| vec![] ).is_err( ) );
+    assert! ( Command::new( "a", "x", |_, _| vec![] ).is_err( ) );
+  }
+
+  #[test]
+  fn test_dispatch () {
+    let mut r = router( );
+    assert! ( run( &mut r, ":Detective!d@h PRIVMSG #rust :!greet Lancey 2", 0 ) ==
+      vec![ "PRIVMSG #rust :Detective says hi to Lancey", "PRIVMSG #rust :Detective says hi to Lancey" ] );
+    assert! ( run( &mut r, ":Detective!d@h PRIVMSG #rust :RustBot: HI Lancey", 0 ) ==
+      vec![ "PRIVMSG #rust :Detective says hi to Lancey" ] );
+    assert! ( run( &mut r, ":Detective!d@h PRIVMSG RustBot :greet Lancey", 0 ) ==
+      vec![ "PRIVMSG Detective :Detective says hi to Lancey" ] );
+    assert! ( run( &mut r, ":Detective!d@h PRIVMSG #rust :greet Lancey", 0 ).is_empty( ) );
+    assert! ( run( &mut r, ":Detective!d@h PRIVMSG #rust :!nope", 0 ).is_empty( ) );
+  }
+
+  #[test]
+  fn test_bad_args () {
+    let mut r = router( );
+    assert! ( run( &mut r, ":Detective!d@h PRIVMSG #rust :!greet", 0 ) ==
+      vec![ "NOTICE Detective :Missing <who>. Usage: !greet <who> [times]" ] );
+    assert! ( run( &mut r, ":Detective!d@h PRIVMSG #rust :!greet Lancey many", 0 ) ==
+      vec![ "NOTICE Detective :[times] must be a number. Usage: !greet <who> [times]" ] );
+    assert! ( run( &mut r, ":Detective!d@h PRIVMSG #rust :!greet #rust", 0 ) ==
+      vec![ "NOTICE Detective :<who> must be a nick. Usage: !greet <who> [times]" ] );
+    assert! ( run( &mut r, ":Detective!d@h PRIVMSG #rust :!greet Lancey 1 2", 0 ) ==
+      vec![ "NOTICE Detective :Too many arguments. Usage: !greet <who> [times]" ] );
+  }
+
+  #[test]
+  fn test_permissions () {
+    let mut r = router( );
+    assert! ( run( &mut r, ":Lancey!l@h PRIVMSG #rust :!topic Rust 1.0 soon", 0 ) ==
+      vec![ "PRIVMSG #rust :topic would be: Rust 1.0 soon" ] );
+    assert! ( run( &mut r, ":Detective!d@h PRIVMSG #rust :!topic nope", 0 ) ==
+      vec![ "NOTICE Detective :You don't have permission to use !topic" ] );
+    assert! ( run( &mut r, ":Detective!d@admin.example PRIVMSG #rust :!topic yes", 0 ) ==
+      vec![ "PRIVMSG #rust :topic would be: yes" ] );
+    assert! ( run( &mut r, ":Lancey!l@h PRIVMSG RustBot :topic nope", 0 ).len( ) == 1 );
+    assert! ( run( &mut r, ":Lancey!l@h PRIVMSG RustBot :topic nope", 0 )[0].as_slice( ).starts_with( "NOTICE" ) );
+  }
+
+  #[test]
+  fn test_accounts_and_cooldown () {
+    let mut r = router( );
+    let mut i = info( );
+    assert! ( run_in( &mut r, &mut i, ":Lancey!l@h PRIVMSG #rust :!slow", 0 )[0].as_slice( ).starts_with( "NOTICE" ) );
+    run_in( &mut r, &mut i, ":Lancey!l@h ACCOUNT Lancey", 0 );
+    assert! ( run_in( &mut r, &mut i, ":Lancey!l@h PRIVMSG #rust :!slow", 0 ) == vec![ "PRIVMSG #rust :ok" ] );
+    assert! ( run_in( &mut r, &mut i, ":Lancey!l@h PRIVMSG #rust :!slow", 10 ).is_empty( ) );
+    assert! ( run_in( &mut r, &mut i, ":Lancey!l@h PRIVMSG #rust :!slow", 30 ) == vec![ "PRIVMSG #rust :ok" ] );
+    run_in( &mut r, &mut i, ":Lancey!l@h NICK Lance", 30 );
+    assert! ( run_in( &mut r, &mut i, ":Lance!l@h PRIVMSG #rust :!slow", 60 ) == vec![ "PRIVMSG #rust :ok" ] );
+    run_in( &mut r, &mut i, ":Lance!l@h ACCOUNT *", 60 );
+    assert! ( run_in( &mut r, &mut i, ":Lance!l@h PRIVMSG #rust :!slow", 90 )[0].as_slice( ).starts_with( "NOTICE" ) );
+
+    // a WHOIS reply names the account too
+    run_in( &mut r, &mut i, ":srv 330 RustBot Lance lancey :is logged in as", 90 );
+    assert! ( run_in( &mut r, &mut i, ":Lance!l@h PRIVMSG #rust :!slow", 120 ) == vec![ "PRIVMSG #rust :ok" ] );
+  }
+
+  #[test]
+  fn test_help () {
+    let mut r = router( );
+    assert! ( run( &mut r, ":Detective!d@h PRIVMSG #rust :!help", 0 ) ==
+      vec![ "PRIVMSG #rust :Commands: !greet. Use !help <command> for details." ] );
+    assert! ( run( &mut r, ":Lancey!l@h PRIVMSG #rust :!help", 0 ) ==
+      vec![ "PRIVMSG #rust :Commands: !greet, !topic. Use !help <command> for details." ] );
+    assert! ( run( &mut r, ":Detective!d@h PRIVMSG #rust :!help !hi", 0 ) ==
+      vec![ "PRIVMSG #rust :!greet <who> [times] - Greets someone (aliases: hi)" ] );
+    assert! ( r.register( Command::new( "HELP", "", |_, _| vec![] ).unwrap( ) ).is_err( ) );
+    assert! ( r.register( Command::new( "hi", "", |_, _| vec![] ).unwrap( ) ).is_err( ) );
+  }
+}
@@ -0,0 +1,995 @@
+// import built in modules
+use std::ascii::AsciiExt;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt;
+use std::old_io as io;
+use std::old_io::net::ip;
+use std::os;
+use log;
+use rustc_serialize::json;
+use toml;
+use yaml_rust;
+
+// import custom modules
+use client;
+use connection;
+use info;
+use proxy;
+use responder;
+use throttle;
+use utils::debug;
+use validate;
+
+pub static DEFAULT_PORT     : u16 = 6667; // port for plain connections
+pub static DEFAULT_TLS_PORT : u16 = 6697; // port for TLS connections
+
+/// `ConfigError` is a problem with a configuration file
+///
+/// # Members
+///
+/// * `key` - path of the offending key, e.g. "servers[0].port", or empty if
+/// the file couldn't be read or parsed at all
+/// * `message` - what's wrong with it
+pub struct ConfigError {
+  pub key     : String,
+  pub message : String,
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt( &self, f : &mut fmt::Formatter ) -> fmt::Result {
+    if self.key.is_empty( ) {
+      write! ( f, "{}", self.message )
+    } else {
+      write! ( f, "{}: {}", self.key, self.message )
+    }
+  }
+}
+
+impl fmt::Debug for ConfigError {
+  fn fmt( &self, f : &mut fmt::Formatter ) -> fmt::Result {
+    fmt::Display::fmt( self, f )
+  }
+}
+
+/// `error` builds a ConfigError
+fn error( key : &str, message : &str ) -> ConfigError {
+  ConfigError { key : key.to_string( ), message : message.to_string( ) }
+}
+
+/// `Format` is the syntax of a configuration file
+///
+/// # Options
+///
+/// `Toml`, `Json`, `Yaml` - the file is written in that syntax
+#[derive(PartialEq)]
+pub enum Format {
+  Toml,
+  Json,
+  Yaml,
+}
+
+impl Copy for Format {}
+
+/// `ServerEntry` is a server to connect to
+///
+/// # Members
+///
+/// * `host`, `port` - where the server is. The port defaults to 6667, or 6697
+/// with TLS
+/// * `password` - server password sent with PASS
+/// * `proxy` - SOCKS5 or HTTP proxy to reach the server through
+/// * `connect` - local address, address family, timeout and TLS settings to
+/// connect with, from the `bind`, `prefer`, `connect_timeout`, `tls` and
+/// `tls_verify` keys
+pub struct ServerEntry {
+  pub host     : String,
+  pub port     : u16,
+  pub password : Option < String >,
+  pub proxy    : Option < proxy::Proxy >,
+  pub connect  : connection::ConnectOptions,
+}
+
+impl Clone for ServerEntry {
+  fn clone( &self ) -> ServerEntry {
+    ServerEntry {
+      host     : self.host.clone( ),
+      port     : self.port,
+      password : self.password.clone( ),
+      proxy    : self.proxy.clone( ),
+      connect  : self.connect,
+    }
+  }
+}
+
+/// `ChannelEntry` is a channel to join on connect
+///
+/// # Members
+///
+/// * `name` - the channel
+/// * `key` - the channel key, for +k channels
+pub struct ChannelEntry {
+  pub name : String,
+  pub key  : Option < String >,
+}
+
+/// `SaslConfig` is how to log in to services while registering
+///
+/// # Members
+///
+/// * `mechanism` - "PLAIN", the only mechanism the client supports
+/// * `account`, `password` - credentials for PLAIN
+pub struct SaslConfig {
+  pub mechanism : String,
+  pub account   : String,
+  pub password  : Option < String >,
+}
+
+/// `CtcpConfig` sets up the CTCP responder
+///
+/// # Members
+///
+/// * `enabled` - answer CTCP requests
+/// * `replies` - fixed replies by CTCP command, e.g. VERSION
+/// * `limit` - replies per sender per window
+/// * `global_limit` - replies to all senders together per window
+/// * `window` - length of the window in seconds
+///
+/// # Notes
+///
+/// * The limits default to the responder's own.
+pub struct CtcpConfig {
+  pub enabled      : bool,
+  pub replies      : HashMap < String, String >,
+  pub limit        : u32,
+  pub global_limit : u32,
+  pub window       : i64,
+}
+
+impl CtcpConfig {
+  /// `new` creates settings that leave CTCP replies off
+  pub fn new( ) -> CtcpConfig {
+    CtcpConfig {
+      enabled      : false,
+      replies      : HashMap::new( ),
+      limit        : responder::DEFAULT_LIMIT,
+      global_limit : responder::DEFAULT_GLOBAL,
+      window       : responder::DEFAULT_WINDOW,
+    }
+  }
+}
+
+/// `LogConfig` sets up logging
+///
+/// # Members
+///
+/// * `level` - one of error, warn, info, debug or trace
+pub struct LogConfig {
+  pub level : String,
+}
+
+impl LogConfig {
+  /// `new` creates settings that log at info level
+  pub fn new( ) -> LogConfig {
+    LogConfig { level : String::from_str( "info" ) }
+  }
+
+  /// `filter` returns the level as a log filter, or None if it isn't one
+  pub fn filter( &self ) -> Option < log::LogLevelFilter > {
+    match self.level.as_slice( ) {
+      "error" => Some( log::LogLevelFilter::Error ),
+      "warn"  => Some( log::LogLevelFilter::Warn ),
+      "info"  => Some( log::LogLevelFilter::Info ),
+      "debug" => Some( log::LogLevelFilter::Debug ),
+      "trace" => Some( log::LogLevelFilter::Trace ),
+      _       => None,
+    }
+  }
+
+  /// `start` writes the crate's log lines to stderr at this level
+  ///
+  /// # Returns
+  ///
+  /// false if the application already installed a logger, which then decides
+  /// what's logged
+  pub fn start( &self ) -> bool {
+    match self.filter( ) {
+      Some ( level ) => debug::start_logger( level ),
+      None           => false,
+    }
+  }
+}
+
+/// `Config` is everything needed to run a client, loaded from a file
+///
+/// # Members
+///
+/// * `servers` - servers to try, in order
+/// * `nick` - nick to use, and `alt_nicks` to fall back on when it's taken
+/// * `user`, `real` - username and realname, defaulting to the nick
+/// * `channels` - channels to join on connect
+/// * `sasl` - services login, if any
+/// * `ctcp` - CTCP responder settings
+/// * `rate_limit` - flood protection for sent lines
+/// * `reconnect` - what to do when the connection drops
+/// * `logging` - log settings
+///
+/// # Notes
+///
+/// * Secrets can be left out of the file and set in the environment instead:
+/// RUSTIRC_NICK, RUSTIRC_SERVER_PASSWORD, RUSTIRC_SASL_ACCOUNT and
+/// RUSTIRC_SASL_PASSWORD override the values in the file.
+/// * Unknown keys are errors, so typos don't go unnoticed.
+pub struct Config {
+  pub servers    : Vec < ServerEntry >,
+  pub nick       : String,
+  pub alt_nicks  : Vec < String >,
+  pub user       : String,
+  pub real       : String,
+  pub channels   : Vec < ChannelEntry >,
+  pub sasl       : Option < SaslConfig >,
+  pub ctcp       : CtcpConfig,
+  pub rate_limit : Option < throttle::RateLimit >,
+  pub reconnect  : connection::ReconnectPolicy,
+  pub logging    : LogConfig,
+}
+
+impl Config {
+  /// `new` creates a config for a nick with no servers or channels
+  pub fn new( nick : &str ) -> Config {
+    Config {
+      servers    : Vec::new( ),
+      nick       : nick.to_string( ),
+      alt_nicks  : Vec::new( ),
+      user       : nick.to_string( ),
+      real       : nick.to_string( ),
+      channels   : Vec::new( ),
+      sasl       : None,
+      ctcp       : CtcpConfig::new( ),
+      rate_limit : None,
+      reconnect  : connection::ReconnectPolicy::new( ),
+      logging    : LogConfig::new( ),
+    }
+  }
+
+  /// `load` reads a configuration file, picking the syntax from its extension
+  ///
+  /// # Arguments
+  ///
+  /// * `path` - path of a .toml, .json, .yaml or .yml file
+  ///
+  /// # Returns
+  ///
+  /// The validated config with environment overrides applied, or the first
+  /// problem found
+  pub fn load( path : &str ) -> Result < Config, ConfigError > {
+    let format = if path.ends_with( ".toml" ) {
+      Format::Toml
+    } else if path.ends_with( ".json" ) {
+      Format::Json
+    } else if path.ends_with( ".yaml" ) || path.ends_with( ".yml" ) {
+      Format::Yaml
+    } else {
+      return Err( error( "", format! ( "{}: unknown config format, use .toml, .json or .yaml", path ).as_slice( ) ) );
+    };
+    let text = match io::File::open( &Path::new( path ) ).read_to_string( ) {
+      Ok ( t )  => t,
+      Err ( e ) => return Err( error( "", format! ( "reading {}: {}", path, e.desc ).as_slice( ) ) ),
+    };
+    let mut config = try! ( Config::parse_raw( text.as_slice( ), format ) );
+    config.apply_env( |name| os::getenv( name ) );
+    try! ( config.validate( ) );
+    Ok( config )
+  }
+
+  /// `parse` reads a configuration from a string
+  ///
+  /// # Arguments
+  ///
+  /// * `text` - the configuration
+  /// * `format` - the syntax it's written in
+  ///
+  /// # Returns
+  ///
+  /// The validated config, without environment overrides
+  pub fn parse( text : &str, format : Format ) -> Result < Config, ConfigError > {
+    let config = try! ( Config::parse_raw( text, format ) );
+    try! ( config.validate( ) );
+    Ok( config )
+  }
+
+  /// `parse_raw` reads a configuration without validating it
+  fn parse_raw( text : &str, format : Format ) -> Result < Config, ConfigError > {
+    let tree = match format {
+      Format::Json => match json::Json::from_str( text ) {
+        Ok ( j )  => j,
+        Err ( e ) => return Err( error( "", format! ( "invalid JSON: {:?}", e ).as_slice( ) ) ),
+      },
+      Format::Toml => {
+        let mut parser = toml::Parser::new( text );
+        match parser.parse( ) {
+          Some ( table ) => toml_to_json( toml::Value::Table( table ) ),
+          None           => {
+            let ( desc, line ) = match parser.errors.first( ) {
+              Some ( e ) => ( e.desc.clone( ), parser.to_linecol( e.lo ).0 + 1 ),
+              None       => ( String::from_str( "unknown error" ), 0 ),
+            };
+            return Err( error( "", format! ( "invalid TOML on line {}: {}", line, desc ).as_slice( ) ) );
+          },
+        }
+      },
+      Format::Yaml => match yaml_rust::YamlLoader::load_from_str( text ) {
+        Ok ( mut docs ) => if docs.is_empty( ) {
+          json::Json::Object( BTreeMap::new( ) )
+        } else {
+          try! ( yaml_to_json( docs.remove( 0 ), "" ) )
+        },
+        Err ( e )       => return Err( error( "", format! ( "invalid YAML: {:?}", e ).as_slice( ) ) ),
+      },
+    };
+    Config::from_json( &tree )
+  }
+
+  /// `from_json` reads a configuration from a parsed tree
+  fn from_json( tree : &json::Json ) -> Result < Config, ConfigError > {
+    let root = try! ( Table::new( tree, "" ) );
+    try! ( root.check( &[ "servers", "nick", "alt_nicks", "user", "real", "channels", "sasl", "ctcp",
+      "rate_limit", "reconnect", "logging" ] ) );
+
+    let mut servers = Vec::new( );
+    for ( path, value ) in try! ( root.list( "servers" ) ).into_iter( ) {
+      let t = try! ( Table::new( value, path.as_slice( ) ) );
+      try! ( t.check( &[ "host", "port", "tls", "tls_verify", "password", "proxy", "bind", "prefer", "connect_timeout" ] ) );
+      let connect = try! ( connect_from_json( &t ) );
+      let default = if connect.tls { DEFAULT_TLS_PORT } else { DEFAULT_PORT };
+      servers.push( ServerEntry {
+        host     : try! ( t.required( "host" ) ),
+        port     : try! ( t.int( "port", 1, 65535 ) ).map( |p| p as u16 ).unwrap_or( default ),
+        password : try! ( t.str( "password" ) ),
+        proxy    : match try! ( t.table( "proxy" ) ) {
+          Some ( p ) => Some( try! ( proxy_from_json( &p ) ) ),
+          None       => None,
+        },
+        connect  : connect,
+      } );
+    }
+
+    let mut channels = Vec::new( );
+    for ( path, value ) in try! ( root.list( "channels" ) ).into_iter( ) {
+      // channels are either "#name" or a table with a key
+      match value.as_string( ) {
+        Some ( name ) => channels.push( ChannelEntry { name : name.to_string( ), key : None } ),
+        None          => {
+          let t = try! ( Table::new( value, path.as_slice( ) ) );
+          try! ( t.check( &[ "name", "key" ] ) );
+          channels.push( ChannelEntry { name : try! ( t.required( "name" ) ), key : try! ( t.str( "key" ) ) } );
+        },
+      }
+    }
+
+    let sasl = match try! ( root.table( "sasl" ) ) {
+      Some ( t ) => {
+        try! ( t.check( &[ "mechanism", "account", "password" ] ) );
+        Some( SaslConfig {
+          mechanism : try! ( t.str( "mechanism" ) ).unwrap_or( String::from_str( "PLAIN" ) ).to_ascii_uppercase( ),
+          account   : try! ( t.str( "account" ) ).unwrap_or( String::new( ) ),
+          password  : try! ( t.str( "password" ) ),
+        } )
+      },
+      None       => None,
+    };
+
+    let mut ctcp = CtcpConfig::new( );
+    match try! ( root.table( "ctcp" ) ) {
+      Some ( t ) => {
+        try! ( t.check( &[ "enabled", "replies", "limit", "global_limit", "window" ] ) );
+        ctcp.enabled = try! ( t.bool( "enabled" ) ).unwrap_or( true );
+        ctcp.limit = try! ( t.int( "limit", 0, 1000 ) ).map_or( ctcp.limit, |l| l as u32 );
+        ctcp.global_limit = try! ( t.int( "global_limit", 0, 1000 ) ).map_or( ctcp.global_limit, |l| l as u32 );
+        ctcp.window = try! ( t.int( "window", 1, 86400 ) ).unwrap_or( ctcp.window );
+        match try! ( t.table( "replies" ) ) {
+          Some ( r ) => for key in r.obj.keys( ) {
+            let reply = try! ( r.required( key.as_slice( ) ) );
+            ctcp.replies.insert( key.to_ascii_uppercase( ), reply );
+          },
+          None       => (),
+        }
+      },
+      None       => (),
+    }
+
+    let rate_limit = match try! ( root.table( "rate_limit" ) ) {
+      Some ( t ) => {
+        try! ( t.check( &[ "messages", "per" ] ) );
+        Some( throttle::RateLimit {
+          messages : try! ( t.int( "messages", 1, 1000 ) ).unwrap_or( 4 ) as u32,
+          per      : try! ( t.int( "per", 1, 3600000 ) ).unwrap_or( 2000 ),
+        } )
+      },
+      None       => None,
+    };
+
+    let mut reconnect = connection::ReconnectPolicy::new( );
+    match try! ( root.table( "reconnect" ) ) {
+      Some ( t ) => {
+        try! ( t.check( &[ "enabled", "delay", "max_delay", "max_attempts" ] ) );
+        reconnect.enabled = try! ( t.bool( "enabled" ) ).unwrap_or( true );
+        reconnect.delay = try! ( t.int( "delay", 0, 86400000 ) ).unwrap_or( reconnect.delay );
+        reconnect.max_delay = try! ( t.int( "max_delay", 0, 86400000 ) ).unwrap_or( reconnect.max_delay );
+        reconnect.max_attempts = try! ( t.int( "max_attempts", 0, 1000000 ) ).unwrap_or( 0 ) as u32;
+      },
+      None       => (),
+    }
+
+    let mut logging = LogConfig::new( );
+    match try! ( root.table( "logging" ) ) {
+      Some ( t ) => {
+        try! ( t.check( &[ "level" ] ) );
+        logging.level = try! ( t.str( "level" ) ).unwrap_or( logging.level ).to_ascii_lowercase( );
+      },
+      None       => (),
+    }
+
+    let nick = try! ( root.str( "nick" ) ).unwrap_or( String::new( ) );
+    Ok( Config {
+      servers    : servers,
+      alt_nicks  : try! ( root.strs( "alt_nicks" ) ),
+      user       : try! ( root.str( "user" ) ).unwrap_or( nick.clone( ) ),
+      real       : try! ( root.str( "real" ) ).unwrap_or( nick.clone( ) ),
+      nick       : nick,
+      channels   : channels,
+      sasl       : sasl,
+      ctcp       : ctcp,
+      rate_limit : rate_limit,
+      reconnect  : reconnect,
+      logging    : logging,
+    } )
+  }
+
+  /// `apply_env` overrides secrets with environment variables
+  ///
+  /// # Arguments
+  ///
+  /// * `lookup` - looks up an environment variable
+  pub fn apply_env < F > ( &mut self, lookup : F ) where F : Fn( &str ) -> Option < String > {
+    match lookup( "RUSTIRC_NICK" ) {
+      Some ( n ) => {
+        // user and real default to the nick
+        if self.user.is_empty( ) || self.user == self.nick {
+          self.user = n.clone( );
+        }
+        if self.real.is_empty( ) || self.real == self.nick {
+          self.real = n.clone( );
+        }
+        self.nick = n;
+      },
+      None       => (),
+    }
+    match lookup( "RUSTIRC_SERVER_PASSWORD" ) {
+      Some ( p ) => for s in self.servers.iter_mut( ) {
+        s.password = Some( p.clone( ) );
+      },
+      None       => (),
+    }
+    match lookup( "RUSTIRC_PROXY_PASSWORD" ) {
+      Some ( p ) => for s in self.servers.iter_mut( ) {
+        match s.proxy {
+          Some ( ref mut proxy ) => proxy.password = Some( p.clone( ) ),
+          None                   => (),
+        }
+      },
+      None       => (),
+    }
+    let account = lookup( "RUSTIRC_SASL_ACCOUNT" );
+    let password = lookup( "RUSTIRC_SASL_PASSWORD" );
+    if ( account.is_some( ) || password.is_some( ) ) && self.sasl.is_none( ) {
+      self.sasl = Some( SaslConfig {
+        mechanism : String::from_str( "PLAIN" ),
+        account   : self.nick.clone( ),
+        password  : None,
+      } );
+    }
+    match self.sasl {
+      Some ( ref mut sasl ) => {
+        match account {
+          Some ( a ) => sasl.account = a,
+          None       => (),
+        }
+        match password {
+          Some ( p ) => sasl.password = Some( p ),
+          None       => (),
+        }
+      },
+      None                  => (),
+    }
+  }
+
+  /// `validate` checks the values make sense together
+  ///
+  /// # Returns
+  ///
+  /// The first problem found, naming the key it's in
+  pub fn validate( &self ) -> Result < ( ), ConfigError > {
+    if self.servers.is_empty( ) {
+      return Err( error( "servers", "at least one server is needed" ) );
+    }
+    for ( n, s ) in self.servers.iter( ).enumerate( ) {
+      if s.host.is_empty( ) || s.host.as_slice( ).contains_char( ' ' ) {
+        return Err( error( format! ( "servers[{}].host", n ).as_slice( ), "not a host name" ) );
+      }
+      if s.port == 0 {
+        return Err( error( format! ( "servers[{}].port", n ).as_slice( ), "must be between 1 and 65535" ) );
+      }
+      if s.connect.timeout.map_or( false, |t| t < 1 ) {
+        return Err( error( format! ( "servers[{}].connect_timeout", n ).as_slice( ), "must be at least 1 ms" ) );
+      }
+      if s.proxy.is_some( ) && s.connect.bind.is_some( ) {
+        return Err( error( format! ( "servers[{}].bind", n ).as_slice( ), "can't be used with a proxy" ) );
+      }
+      match s.proxy {
+        Some ( ref p ) if p.host.is_empty( ) || p.host.as_slice( ).contains_char( ' ' ) =>
+          return Err( error( format! ( "servers[{}].proxy.host", n ).as_slice( ), "not a host name" ) ),
+        Some ( ref p ) if p.port == 0 =>
+          return Err( error( format! ( "servers[{}].proxy.port", n ).as_slice( ), "must be between 1 and 65535" ) ),
+        _                             => (),
+      }
+    }
+    if self.nick.is_empty( ) {
+      return Err( error( "nick", "missing, set it in the file or in RUSTIRC_NICK" ) );
+    }
+    // the server's ISUPPORT isn't known yet, so names are checked against
+    // the defaults the client uses until it is
+    let limits = validate::Limits::new( );
+    match validate::check_nick( self.nick.as_slice( ), &limits ) {
+      Err ( e ) => return Err( error( "nick", e.to_string( ).as_slice( ) ) ),
+      Ok ( _ )  => (),
+    }
+    for ( n, alt ) in self.alt_nicks.iter( ).enumerate( ) {
+      match validate::check_nick( alt.as_slice( ), &limits ) {
+        Err ( e ) => return Err( error( format! ( "alt_nicks[{}]", n ).as_slice( ), e.to_string( ).as_slice( ) ) ),
+        Ok ( _ )  => (),
+      }
+    }
+    if self.user.is_empty( ) || self.user.as_slice( ).contains_char( ' ' ) {
+      return Err( error( "user", "must be one word" ) );
+    }
+    for ( n, c ) in self.channels.iter( ).enumerate( ) {
+      match validate::check_channel( c.name.as_slice( ), &limits ) {
+        Err ( e ) => return Err( error( format! ( "channels[{}]", n ).as_slice( ), e.to_string( ).as_slice( ) ) ),
+        Ok ( _ )  => (),
+      }
+    }
+    match self.sasl {
+      Some ( ref sasl ) => match sasl.mechanism.as_slice( ) {
+        "PLAIN"    if sasl.account.is_empty( )  => return Err( error( "sasl.account", "PLAIN needs an account" ) ),
+        "PLAIN"    if sasl.password.is_none( )  =>
+          return Err( error( "sasl.password", "PLAIN needs a password, set it in the file or in RUSTIRC_SASL_PASSWORD" ) ),
+        "PLAIN"                                 => (),
+        "EXTERNAL"                              =>
+          return Err( error( "sasl.mechanism", "EXTERNAL needs a client certificate, which the client can't send" ) ),
+        _                                       => return Err( error( "sasl.mechanism", "must be PLAIN" ) ),
+      },
+      None              => (),
+    }
+    if self.reconnect.max_delay < self.reconnect.delay {
+      return Err( error( "reconnect.max_delay", "must not be less than reconnect.delay" ) );
+    }
+    if self.logging.filter( ).is_none( ) {
+      return Err( error( "logging.level", "must be error, warn, info, debug or trace" ) );
+    }
+    Ok( ( ) )
+  }
+
+  /// `info` builds the IrcInfo for this config
+  pub fn info( &self ) -> info::IrcInfo {
+    let chans : Vec < &str > = self.channels.iter( ).map( |c| c.name.as_slice( ) ).collect( );
+    info::IrcInfo::gen( self.nick.as_slice( ), self.user.as_slice( ), self.real.as_slice( ), chans )
+  }
+
+  /// `connect` connects to the first server that answers and sets the
+  /// client up
+  ///
+  /// # Returns
+  ///
+  /// The client, ready for `start_thread`
+  ///
+  /// # Notes
+  ///
+  /// * Servers are tried in order. If none can be reached, the error names
+  /// the last one.
+  /// * Alternate nicks and the SASL login are used while registering.
+  /// * Reconnects go through the servers in order again.
+  /// * Log lines go to stderr at `logging.level`, unless the application
+  /// installed its own logger first.
+  pub fn connect( &self ) -> Result < client::Client, ConfigError > {
+    if !self.logging.start( ) {
+      irc_debug! ( "config: a logger is already installed, leaving logging.level to it" );
+    }
+    let conn = try! ( dial( self.servers.as_slice( ) ) );
+    let mut client = client::Client::with_connection( conn, Box::new( self.info( ) ) );
+    client.register.alt_nicks = self.alt_nicks.clone( );
+    client.rate_limit = self.rate_limit;
+    client.reconnect = self.reconnect;
+    let servers = self.servers.clone( );
+    client.set_dialer( move || dial( servers.as_slice( ) ).map_err( |e| e.to_string( ) ) );
+    client.register.sasl = match self.sasl {
+      Some ( ref sasl ) => Some( ( sasl.account.clone( ), sasl.password.clone( ).unwrap_or( String::new( ) ) ) ),
+      None              => None,
+    };
+    for c in self.channels.iter( ) {
+      match c.key {
+        Some ( ref k ) => client.joins.lock( ).unwrap( ).set_key( c.name.as_slice( ), Some( k.as_slice( ) ) ),
+        None           => (),
+      }
+    }
+    client.enable_ctcp( self.ctcp.enabled );
+    for ( cmd, reply ) in self.ctcp.replies.iter( ) {
+      client.set_ctcp_reply( cmd.as_slice( ), responder::CtcpReply::Text( reply.clone( ) ) );
+    }
+    client.set_ctcp_limit( self.ctcp.limit, self.ctcp.window );
+    client.set_ctcp_global_limit( self.ctcp.global_limit );
+    Ok( client )
+  }
+}
+
+/// `dial` connects to the first server that answers
+///
+/// # Arguments
+///
+/// * `servers` - servers to try, in order
+///
+/// # Returns
+///
+/// The connection, or an error naming the last server tried
+fn dial( servers : &[ServerEntry] ) -> Result < connection::ServerConnection, ConfigError > {
+  let mut failure = error( "servers", "no servers to connect to" );
+  for ( n, s ) in servers.iter( ).enumerate( ) {
+    let password = s.password.clone( ).unwrap_or( String::new( ) );
+    let ( result, key ) = match s.proxy {
+      Some ( ref p ) => ( connection::ServerConnection::connect_proxy( s.host.as_slice( ), s.port, password.as_slice( ), p, &s.connect ),
+        format! ( "servers[{}].proxy", n ) ),
+      None           => ( connection::ServerConnection::connect_with( s.host.as_slice( ), s.port, password.as_slice( ), &s.connect ),
+        format! ( "servers[{}]", n ) ),
+    };
+    match result {
+      Ok ( c )  => return Ok( c ),
+      Err ( e ) => {
+        irc_warn! ( "config: couldn't connect to {}:{}: {}", s.host, s.port, e );
+        failure = error( key.as_slice( ), format! ( "couldn't connect: {}", e ).as_slice( ) );
+      },
+    }
+  }
+  Err( failure )
+}
+
+/// `proxy_from_json` reads a server's proxy table
+fn proxy_from_json( t : &Table ) -> Result < proxy::Proxy, ConfigError > {
+  try! ( t.check( &[ "type", "host", "port", "username", "password" ] ) );
+  let kind = match try! ( t.str( "type" ) ).unwrap_or( String::from_str( "socks5" ) ).to_ascii_lowercase( ).as_slice( ) {
+    "socks5" => proxy::ProxyKind::Socks5,
+    "http"   => proxy::ProxyKind::Http,
+    _        => return Err( error( t.key( "type" ).as_slice( ), "must be socks5 or http" ) ),
+  };
+  let port = match try! ( t.int( "port", 1, 65535 ) ) {
+    Some ( p ) => p as u16,
+    None       => return Err( error( t.key( "port" ).as_slice( ), "missing" ) ),
+  };
+  Ok( proxy::Proxy {
+    kind     : kind,
+    host     : try! ( t.required( "host" ) ),
+    port     : port,
+    username : try! ( t.str( "username" ) ),
+    password : try! ( t.str( "password" ) ),
+  } )
+}
+
+/// `connect_from_json` reads how to connect to a server from its table
+fn connect_from_json( t : &Table ) -> Result < connection::ConnectOptions, ConfigError > {
+  let mut opts = connection::ConnectOptions::new( );
+  match try! ( t.str( "bind" ) ) {
+    Some ( b ) => match b.parse::< ip::IpAddr >( ) {
+      Ok ( a )  => opts.bind = Some( a ),
+      Err ( _ ) => return Err( error( t.key( "bind" ).as_slice( ), "not an IP address" ) ),
+    },
+    None       => (),
+  }
+  opts.prefer = match try! ( t.str( "prefer" ) ).unwrap_or( String::from_str( "system" ) ).to_ascii_lowercase( ).as_slice( ) {
+    "system" => connection::AddrPreference::System,
+    "ipv4"   => connection::AddrPreference::Ipv4,
+    "ipv6"   => connection::AddrPreference::Ipv6,
+    _        => return Err( error( t.key( "prefer" ).as_slice( ), "must be system, ipv4 or ipv6" ) ),
+  };
+  match try! ( t.int( "connect_timeout", 1, 600000 ) ) {
+    Some ( ms ) => opts.timeout = Some( ms ),
+    None        => (),
+  }
+  opts.tls = try! ( t.bool( "tls" ) ).unwrap_or( false );
+  opts.tls_verify = try! ( t.bool( "tls_verify" ) ).unwrap_or( true );
+  Ok( opts )
+}
+
+/// `Table` is an object in the parsed config, with the path that leads to it
+/// for error messages
+struct Table < 'a > {
+  path : String,
+  obj  : &'a json::Object,
+}
+
+impl < 'a > Table < 'a > {
+  /// `new` wraps a value that should be a table
+  fn new( value : &'a json::Json, path : &str ) -> Result < Table < 'a >, ConfigError > {
+    match value.as_object( ) {
+      Some ( obj ) => Ok( Table { path : path.to_string( ), obj : obj } ),
+      None         => Err( error( path, "expected a table" ) ),
+    }
+  }
+
+  /// `key` returns the full path of a key in this table
+  fn key( &self, key : &str ) -> String {
+    if self.path.is_empty( ) { key.to_string( ) } else { format! ( "{}.{}", self.path, key ) }
+  }
+
+  /// `check` makes sure the table only has the given keys
+  fn check( &self, allowed : &[&str] ) -> Result < ( ), ConfigError > {
+    for key in self.obj.keys( ) {
+      if !allowed.iter( ).any( |a| *a == key.as_slice( ) ) {
+        return Err( error( self.key( key.as_slice( ) ).as_slice( ), "unknown key" ) );
+      }
+    }
+    Ok( ( ) )
+  }
+
+  /// `str` reads an optional string
+  fn str( &self, key : &str ) -> Result < Option < String >, ConfigError > {
+    match self.obj.get( key ) {
+      Some ( v ) => match v.as_string( ) {
+        Some ( s ) => Ok( Some( s.to_string( ) ) ),
+        None       => Err( error( self.key( key ).as_slice( ), "expected a string" ) ),
+      },
+      None       => Ok( None ),
+    }
+  }
+
+  /// `required` reads a string that must be there
+  fn required( &self, key : &str ) -> Result < String, ConfigError > {
+    match try! ( self.str( key ) ) {
+      Some ( s ) => Ok( s ),
+      None       => Err( error( self.key( key ).as_slice( ), "missing" ) ),
+    }
+  }
+
+  /// `int` reads an optional integer between min and max
+  fn int( &self, key : &str, min : i64, max : i64 ) -> Result < Option < i64 >, ConfigError > {
+    match self.obj.get( key ) {
+      Some ( v ) => match v.as_i64( ) {
+        Some ( i ) if i >= min && i <= max => Ok( Some( i ) ),
+        Some ( _ )                         =>
+          Err( error( self.key( key ).as_slice( ), format! ( "must be between {} and {}", min, max ).as_slice( ) ) ),
+        None                               => Err( error( self.key( key ).as_slice( ), "expected an integer" ) ),
+      },
+      None       => Ok( None ),
+    }
+  }
+
+  /// `bool` reads an optional boolean
+  fn bool( &self, key : &str ) -> Result < Option < bool >, ConfigError > {
+    match self.obj.get( key ) {
+      Some ( v ) => match v.as_boolean( ) {
+        Some ( b ) => Ok( Some( b ) ),
+        None       => Err( error( self.key( key ).as_slice( ), "expected true or false" ) ),
+      },
+      None       => Ok( None ),
+    }
+  }
+
+  /// `table` reads an optional table
+  fn table( &self, key : &str ) -> Result < Option < Table < 'a > >, ConfigError > {
+    match self.obj.get( key ) {
+      Some ( v ) => Table::new( v, self.key( key ).as_slice( ) ).map( |t| Some( t ) ),
+      None       => Ok( None ),
+    }
+  }
+
+  /// `list` reads an optional array, pairing each item with its path
+  fn list( &self, key : &str ) -> Result < Vec < ( String, &'a json::Json ) >, ConfigError > {
+    match self.obj.get( key ) {
+      Some ( v ) => match v.as_array( ) {
+        Some ( a ) => Ok( a.iter( ).enumerate( ).map( |( n, item )| ( format! ( "{}[{}]", self.key( key ), n ), item ) ).collect( ) ),
+        None       => Err( error( self.key( key ).as_slice( ), "expected a list" ) ),
+      },
+      None       => Ok( Vec::new( ) ),
+    }
+  }
+
+  /// `strs` reads an optional array of strings
+  fn strs( &self, key : &str ) -> Result < Vec < String >, ConfigError > {
+    let mut out = Vec::new( );
+    for ( path, item ) in try! ( self.list( key ) ).into_iter( ) {
+      match item.as_string( ) {
+        Some ( s ) => out.push( s.to_string( ) ),
+        None       => return Err( error( path.as_slice( ), "expected a string" ) ),
+      }
+    }
+    Ok( out )
+  }
+}
+
+/// `toml_to_json` converts a parsed TOML value to the tree the config is read
+/// from
+fn toml_to_json( value : toml::Value ) -> json::Json {
+  match value {
+    toml::Value::String ( s )   => json::Json::String( s ),
+    toml::Value::Integer ( i )  => json::Json::I64( i ),
+    toml::Value::Float ( f )    => json::Json::F64( f ),
+    toml::Value::Boolean ( b )  => json::Json::Boolean( b ),
+    toml::Value::Datetime ( d ) => json::Json::String( d ),
+    toml::Value::Array ( a )    => json::Json::Array( a.into_iter( ).map( toml_to_json ).collect( ) ),
+    toml::Value::Table ( t )    => json::Json::Object( t.into_iter( ).map( |( k, v )| ( k, toml_to_json( v ) ) ).collect( ) ),
+  }
+}
+
+/// `yaml_to_json` converts a parsed YAML document to the tree the config is
+/// read from
+///
+/// # Arguments
+///
+/// * `value` - the YAML value
+/// * `path` - where the value is, for error messages
+fn yaml_to_json( value : yaml_rust::Yaml, path : &str ) -> Result < json::Json, ConfigError > {
+  let at = |key : &str| if path.is_empty( ) { key.to_string( ) } else { format! ( "{}.{}", path, key ) };
+  Ok( match value {
+    yaml_rust::Yaml::String ( s )   => json::Json::String( s ),
+    yaml_rust::Yaml::Integer ( i )  => json::Json::I64( i ),
+    yaml_rust::Yaml::Real ( r )     => match r.parse::< f64 >( ).ok( ) {
+      Some ( f ) => json::Json::F64( f ),
+      None       => return Err( error( path, "not a number" ) ),
+    },
+    yaml_rust::Yaml::Boolean ( b )  => json::Json::Boolean( b ),
+    yaml_rust::Yaml::Null           => json::Json::Null,
+    yaml_rust::Yaml::Array ( a )    => {
+      let mut out = Vec::new( );
+      for ( n, item ) in a.into_iter( ).enumerate( ) {
+        out.push( try! ( yaml_to_json( item, format! ( "{}[{}]", path, n ).as_slice( ) ) ) );
+      }
+      json::Json::Array( out )
+    },
+    yaml_rust::Yaml::Hash ( h )     => {
+      let mut out = BTreeMap::new( );
+      for ( k, v ) in h.into_iter( ) {
+        let key = match k {
+          yaml_rust::Yaml::String ( s ) => s,
+          _                             => return Err( error( path, "keys must be strings" ) ),
+        };
+        let v = try! ( yaml_to_json( v, at( key.as_slice( ) ).as_slice( ) ) );
+        out.insert( key, v );
+      }
+      json::Json::Object( out )
+    },
+    _                               => return Err( error( path, "aliases and unparsable values aren't supported" ) ),
+  } )
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[allow(unused_imports)]
+  use super::{Config, Format};
+  #[allow(unused_imports)]
+  use std::old_io::net::ip::IpAddr;
+  #[allow(unused_imports)]
+  use connection::AddrPreference;
+  #[allow(unused_imports)]
+  use proxy::ProxyKind;
+  #[allow(unused_imports)]
+  use log;
+
+  #[allow(dead_code)]
+  fn key_of( text : &str, format : Format ) -> String {
+    match Config::parse( text, format ) {
+      Ok ( _ )  => panic! ( "expected an error" ),
+      Err ( e ) => e.key,
+    }
+  }
+
+  #[test]
+  fn test_toml () {
+    let c = Config::parse( r#"
+nick = "rustbot"
+alt_nicks = [ "rustbot_" ]
+
+[[channels]]
+name = "#rust"
+
+[[channels]]
+name = "#secret"
+key = "hunter2"
+
+[[servers]]
+host = "irc.example.org"
+bind = "192.0.2.7"
+prefer = "IPv6"
+connect_timeout = 3000
+
+[[servers]]
+host = "irc2.example.org"
+tls = true
+[servers.proxy]
+host = "proxy.internal"
+port = 1080
+username = "rustbot"
+
+[ctcp]
+limit = 3
+global_limit = 20
+[ctcp.replies]
+version = "rustbot 1.0"
+"#, Format::Toml ).ok( ).unwrap( );
+    assert! ( c.nick == "rustbot" && c.user == "rustbot" && c.alt_nicks == vec![ "rustbot_" ] );
+    assert! ( c.servers[0].port == 6667 && c.servers[1].port == 6697 );
+    assert! ( !c.servers[0].connect.tls && c.servers[1].connect.tls && c.servers[1].connect.tls_verify );
+    assert! ( c.servers[0].proxy.is_none( ) );
+    assert! ( c.servers[0].connect.prefer == AddrPreference::Ipv6 && c.servers[0].connect.timeout == Some( 3000 ) );
+    assert! ( c.servers[0].connect.bind == Some( IpAddr::Ipv4Addr( 192, 0, 2, 7 ) ) );
+    assert! ( c.servers[1].connect.prefer == AddrPreference::System && c.servers[1].connect.bind.is_none( ) );
+    let proxy = c.servers[1].proxy.clone( ).unwrap( );
+    assert! ( proxy.kind == ProxyKind::Socks5 && proxy.port == 1080 && proxy.username == Some( "rustbot".to_string( ) ) );
+    assert! ( c.channels[1].name == "#secret" && c.channels[1].key == Some( "hunter2".to_string( ) ) );
+    assert! ( c.ctcp.enabled && c.ctcp.limit == 3 && c.ctcp.global_limit == 20 && c.ctcp.window == 10 );
+    assert! ( c.ctcp.replies.get( "VERSION" ) == Some( &"rustbot 1.0".to_string( ) ) );
+    assert! ( !c.reconnect.enabled && c.logging.level == "info" );
+  }
+
+  #[test]
+  fn test_json_yaml () {
+    let json = r#"{ "nick" : "rustbot", "servers" : [ { "host" : "irc.example.org", "port" : 7000 } ],
+      "channels" : [ "#rust", { "name" : "#secret", "key" : "hunter2" } ],
+      "rate_limit" : { "messages" : 5 }, "reconnect" : { "delay" : 1000 } }"#;
+    let c = Config::parse( json, Format::Json ).ok( ).unwrap( );
+    assert! ( c.servers[0].port == 7000 && c.reconnect.enabled && c.reconnect.delay == 1000 );
+    assert! ( c.rate_limit.map( |r| ( r.messages, r.per ) ) == Some( ( 5, 2000 ) ) );
+    assert! ( c.channels[0].key.is_none( ) && c.channels[1].key == Some( "hunter2".to_string( ) ) );
+
+    let yaml = "nick: rustbot\nservers:\n  - host: irc.example.org\nlogging:\n  level: DEBUG\n";
+    let c = Config::parse( yaml, Format::Yaml ).ok( ).unwrap( );
+    assert! ( c.servers[0].host == "irc.example.org" && c.logging.level == "debug" );
+    assert! ( c.logging.filter( ) == Some( log::LogLevelFilter::Debug ) );
+  }
+
+  #[test]
+  fn test_errors () {
+    let server = "[[servers]]\nhost = \"irc.example.org\"\n";
+    assert! ( key_of( "nick = \"rustbot\"", Format::Toml ) == "servers" );
+    assert! ( key_of( format! ( "nick = \"rustbot\"\n{}port = 70000\n", server ).as_slice( ), Format::Toml ) == "servers[0].port" );
+    assert! ( key_of( format! ( "nick = \"rustbot\"\nchannels = [ \"rust\" ]\n{}", server ).as_slice( ), Format::Toml ) == "channels[0]" );
+    assert! ( key_of( format! ( "nick = \"9bot\"\n{}", server ).as_slice( ), Format::Toml ) == "nick" );
+    assert! ( key_of( format! ( "nick = \"rustbot\"\nalt_nicks = [ \"rust bot\" ]\n{}", server ).as_slice( ), Format::Toml ) == "alt_nicks[0]" );
+    assert! ( key_of( format! ( "nick = \"rustbot\"\nnickk = \"typo\"\n{}", server ).as_slice( ), Format::Toml ) == "nickk" );
+    assert! ( key_of( format! ( "nick = \"rustbot\"\n{}tls = \"yes\"\n", server ).as_slice( ), Format::Toml ) == "servers[0].tls" );
+    assert! ( key_of( format! ( "nick = \"rustbot\"\n{}[reconnect]\ndelay = 600000\n", server ).as_slice( ), Format::Toml ) == "reconnect.max_delay" );
+    assert! ( key_of( format! ( "nick = \"rustbot\"\n{}[rate_limit]\nmessages = 0\n", server ).as_slice( ), Format::Toml ) == "rate_limit.messages" );
+    assert! ( key_of( format! ( "nick = \"rustbot\"\n{}[sasl]\nmechanism = \"external\"\n", server ).as_slice( ), Format::Toml ) == "sasl.mechanism" );
+    assert! ( key_of( format! ( "nick = \"rustbot\"\n{}[sasl]\naccount = \"bot\"\n", server ).as_slice( ), Format::Toml ) == "sasl.password" );
+    assert! ( key_of( format! ( "nick = \"rustbot\"\n{}[servers.proxy]\ntype = \"ftp\"\nhost = \"p\"\nport = 21\n", server ).as_slice( ), Format::Toml ) == "servers[0].proxy.type" );
+    assert! ( key_of( format! ( "nick = \"rustbot\"\n{}[servers.proxy]\nhost = \"p\"\n", server ).as_slice( ), Format::Toml ) == "servers[0].proxy.port" );
+    assert! ( key_of( format! ( "nick = \"rustbot\"\n{}prefer = \"ipv5\"\n", server ).as_slice( ), Format::Toml ) == "servers[0].prefer" );
+    assert! ( key_of( format! ( "nick = \"rustbot\"\n{}bind = \"vhost\"\n", server ).as_slice( ), Format::Toml ) == "servers[0].bind" );
+    assert! ( key_of( format! ( "nick = \"rustbot\"\n{}bind = \"127.0.0.1\"\n[servers.proxy]\nhost = \"p\"\nport = 1080\n", server ).as_slice( ), Format::Toml ) == "servers[0].bind" );
+    assert! ( key_of( "nick = ", Format::Toml ) == "" );
+  }
+
+  #[test]
+  fn test_env () {
+    let text = "[[servers]]\nhost = \"irc.example.org\"\n[servers.proxy]\nhost = \"proxy.internal\"\nport = 1080\n";
+    let mut c = Config::parse_raw( text, Format::Toml ).ok( ).unwrap( );
+    assert! ( c.validate( ).is_err( ) );
+    c.apply_env( |name| match name {
+      "RUSTIRC_NICK"           => Some( "envbot".to_string( ) ),
+      "RUSTIRC_SASL_PASSWORD"  => Some( "s3cret".to_string( ) ),
+      "RUSTIRC_PROXY_PASSWORD" => Some( "pr0xy".to_string( ) ),
+      _                        => None,
+    } );
+    assert! ( c.validate( ).is_ok( ) );
+    let sasl = c.sasl.unwrap( );
+    assert! ( sasl.account == "envbot" && sasl.password == Some( "s3cret".to_string( ) ) );
+    assert! ( c.servers[0].proxy.clone( ).unwrap( ).password == Some( "pr0xy".to_string( ) ) );
+  }
+}
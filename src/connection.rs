@@ -1,19 +1,239 @@
 use std::old_io as io;
+use std::old_io::net::{addrinfo, ip};
 use std::sync::mpsc;
+use std::time::Duration;
 
+use proxy;
+use reader;
+use socket;
+use tls;
 use utils::debug;
 
+pub static CONNECT_TIMEOUT : i64 = 10000; // ms to wait for each address by default
+
 /// `ConnEvent` defines the various actions our connection might use
 ///
 /// # Options
 ///
 /// `Send` - send this message to the server
-/// `Recv` - this message was received from the server
+/// `Recv` - this line was received from the server, as bytes without the line
+/// ending
 /// `Abort` - shut down connection and close
+/// `Quit` - send QUIT with this reason and wait for the server to close
+/// `Timeout` - the server took too long to close after a QUIT
+/// `TooLong` - a line of this many bytes was over the limit and was dropped
+/// `Flush` - lines held back by the rate limit may be due
 pub enum ConnEvent {
   Send( String ),
-  Recv( String ),
+  Recv( Vec < u8 > ),
   Abort( String ),
+  Quit( String ),
+  Timeout,
+  TooLong( usize ),
+  Flush,
+}
+
+/// `DisconnectReason` is why a client's connection ended
+///
+/// # Options
+///
+/// `Quit` - we quit and the server closed the link, with its ERROR text
+/// `ServerError` - the server closed the link on its own, with its ERROR text
+/// `Closed` - the connection dropped without an ERROR, with a description
+/// `TimedOut` - we quit but the server never closed the link
+#[derive(PartialEq)]
+pub enum DisconnectReason {
+  Quit( String ),
+  ServerError( String ),
+  Closed( String ),
+  TimedOut,
+}
+
+impl Clone for DisconnectReason {
+  fn clone( &self ) -> DisconnectReason {
+    match *self {
+      DisconnectReason::Quit( ref s )        => DisconnectReason::Quit( s.clone( ) ),
+      DisconnectReason::ServerError( ref s ) => DisconnectReason::ServerError( s.clone( ) ),
+      DisconnectReason::Closed( ref s )      => DisconnectReason::Closed( s.clone( ) ),
+      DisconnectReason::TimedOut             => DisconnectReason::TimedOut,
+    }
+  }
+}
+
+/// `AddrPreference` is which kind of address to try first when a server has
+/// both
+///
+/// # Options
+///
+/// `System` - start with the family of the first address the resolver gives
+/// `Ipv4` - start with IPv4 addresses
+/// `Ipv6` - start with IPv6 addresses
+#[derive(PartialEq)]
+pub enum AddrPreference {
+  System,
+  Ipv4,
+  Ipv6,
+}
+
+impl Copy for AddrPreference {}
+
+/// `ConnectOptions` are how to reach a server's addresses
+///
+/// # Members
+///
+/// * `bind` - local address to connect from, e.g. to pick a vhost
+/// * `prefer` - which address family to try first
+/// * `timeout` - milliseconds to wait for each address, or None to wait as
+/// long as the system does
+/// * `tls` - speak TLS to the server
+/// * `tls_verify` - check the server's certificate when using TLS
+///
+/// # Notes
+///
+/// * Every address the server's name resolves to is tried, alternating
+/// between IPv6 and IPv4 from the preferred family, so one dead record
+/// only costs one timeout.
+/// * With `bind`, only the server's addresses in the same family are tried.
+pub struct ConnectOptions {
+  pub bind       : Option < ip::IpAddr >,
+  pub prefer     : AddrPreference,
+  pub timeout    : Option < i64 >,
+  pub tls        : bool,
+  pub tls_verify : bool,
+}
+
+impl Copy for ConnectOptions {}
+
+impl ConnectOptions {
+  /// `new` returns options that try every address, as the resolver orders
+  /// them, for up to CONNECT_TIMEOUT each, without TLS
+  pub fn new( ) -> ConnectOptions {
+    ConnectOptions {
+      bind       : None,
+      prefer     : AddrPreference::System,
+      timeout    : Some( CONNECT_TIMEOUT ),
+      tls        : false,
+      tls_verify : true,
+    }
+  }
+}
+
+/// `ReconnectPolicy` says what to do when the connection drops
+///
+/// # Members
+///
+/// * `enabled` - reconnect at all
+/// * `delay` - milliseconds before the first attempt
+/// * `max_delay` - the delay doubles after each failure up to this
+/// * `max_attempts` - attempts before giving up, 0 for no limit
+pub struct ReconnectPolicy {
+  pub enabled      : bool,
+  pub delay        : i64,
+  pub max_delay    : i64,
+  pub max_attempts : u32,
+}
+
+impl Copy for ReconnectPolicy {}
+
+impl ReconnectPolicy {
+  /// `new` creates a policy that doesn't reconnect
+  pub fn new( ) -> ReconnectPolicy {
+    ReconnectPolicy { enabled : false, delay : 5000, max_delay : 300000, max_attempts : 0 }
+  }
+
+  /// `delay_for` returns how long to wait before an attempt
+  ///
+  /// # Arguments
+  ///
+  /// * `attempt` - the attempt, counting from 1
+  ///
+  /// # Returns
+  ///
+  /// Milliseconds to wait
+  pub fn delay_for( &self, attempt : u32 ) -> i64 {
+    let mut delay = self.delay;
+    for _ in 1..attempt {
+      if delay >= self.max_delay {
+        break;
+      }
+      delay = delay * 2;
+    }
+    if delay > self.max_delay { self.max_delay } else { delay }
+  }
+}
+
+/// `Dialer` opens a new connection to the server, or says why it couldn't
+pub type Dialer = Box < Fn( ) -> Result < ServerConnection, String > + Send >;
+
+/// `IrcStream` is the stream to a server
+///
+/// # Options
+///
+/// `Plain` - a plain TCP stream
+/// `Tls` - a TLS session over a TCP stream
+pub enum IrcStream {
+  Plain( io::TcpStream ),
+  Tls( tls::TlsStream ),
+}
+
+impl Clone for IrcStream {
+  fn clone( &self ) -> IrcStream {
+    match *self {
+      IrcStream::Plain( ref s ) => IrcStream::Plain( s.clone( ) ),
+      IrcStream::Tls( ref s )   => IrcStream::Tls( s.clone( ) ),
+    }
+  }
+}
+
+impl Reader for IrcStream {
+  fn read( &mut self, buf : &mut [u8] ) -> io::IoResult < usize > {
+    match *self {
+      IrcStream::Plain( ref mut s ) => s.read( buf ),
+      IrcStream::Tls( ref mut s )   => s.read( buf ),
+    }
+  }
+}
+
+impl Writer for IrcStream {
+  fn write_all( &mut self, buf : &[u8] ) -> io::IoResult < ( ) > {
+    match *self {
+      IrcStream::Plain( ref mut s ) => s.write_all( buf ),
+      IrcStream::Tls( ref mut s )   => s.write_all( buf ),
+    }
+  }
+
+  fn flush( &mut self ) -> io::IoResult < ( ) > {
+    match *self {
+      IrcStream::Plain( ref mut s ) => s.flush( ),
+      IrcStream::Tls( ref mut s )   => s.flush( ),
+    }
+  }
+}
+
+impl IrcStream {
+  /// `peer_name` returns the address of the other end
+  pub fn peer_name( &mut self ) -> io::IoResult < ip::SocketAddr > {
+    match *self {
+      IrcStream::Plain( ref mut s ) => s.peer_name( ),
+      IrcStream::Tls( ref mut s )   => s.peer_name( ),
+    }
+  }
+
+  /// `close_read` shuts the reading half, ending any read in progress
+  pub fn close_read( &mut self ) -> io::IoResult < ( ) > {
+    match *self {
+      IrcStream::Plain( ref mut s ) => s.close_read( ),
+      IrcStream::Tls( ref mut s )   => s.close_read( ),
+    }
+  }
+
+  /// `close_write` shuts the writing half
+  pub fn close_write( &mut self ) -> io::IoResult < ( ) > {
+    match *self {
+      IrcStream::Plain( ref mut s ) => s.close_write( ),
+      IrcStream::Tls( ref mut s )   => s.close_write( ),
+    }
+  }
 }
 
 /// `ServerConnection` manages an IRC connection
@@ -23,15 +243,20 @@ pub enum ConnEvent {
 /// `host` - the host of the server we're connected to
 /// `port` - the port we're connected to the server through
 /// `pass` - the password of the server
-/// `tcp` - the TcpStream to the server
+/// `tcp` - the stream to the server, plain or TLS
 /// `chan` - transmission half of our thread channel
 /// `listen` - listener half of our thread channel
+/// `max_line` - longest line the server may send, in bytes including the line
+/// ending. Longer lines are dropped. Set it before starting the client.
+/// `addr` - the address we're connected to, or the proxy's when using one
 pub struct ServerConnection {
   pub host  : String,
   pub port  : u16,
   pub pass  : String,
+  pub max_line : usize,
+  pub addr  : Option < ip::SocketAddr >,
 
-  pub tcp   : io::TcpStream,
+  pub tcp   : IrcStream,
 
   pub chan  : mpsc::Sender < ConnEvent >,
   pub listen: Option < mpsc::Receiver < ConnEvent > >,
@@ -49,21 +274,100 @@ impl ServerConnection {
   /// # Returns
   ///
   /// A new ServerConnection struct that is connected to the target server
+  ///
+  /// # Notes
+  ///
+  /// * This panics if no address of the server can be reached. Use
+  /// `connect_with` to handle the failure.
   pub fn connect ( host : &str, port : u16, pass : &str ) 
     -> ServerConnection {
+    match ServerConnection::connect_with( host, port, pass, &ConnectOptions::new( ) ) {
+      Ok ( conn ) => conn,
+      Err ( _ )   => panic! ( "connection failure is not implemented" ),
+    }
+  }
+
+  /// `connect_with` establishes a new connection, trying each of the
+  /// server's addresses in turn
+  ///
+  /// # Arguments
+  ///
+  /// `host` - server host to connect to
+  /// `port` - port number to connect to the server on
+  /// `pass` - password for the irc server
+  /// `opts` - how to reach the server's addresses
+  ///
+  /// # Returns
+  ///
+  /// A new ServerConnection struct, or the error from the last address tried
+  pub fn connect_with ( host : &str, port : u16, pass : &str, opts : &ConnectOptions ) 
+    -> io::IoResult < ServerConnection > {
     // Format the server address and attempt a connection
     let target = format!( "{}:{}", host, port );
-    let out = format!( "establishing connection to {}...", target );
-    debug::oper( out.as_slice( ) );
-    let tcp = match io::TcpStream::connect( target.as_slice( ) ) {
-      Ok (res)  => res,
-      Err (e)   => {
-        debug::err( "establishing server connection", e.desc );
-        panic! ( "connection failure is not implemented" );
+    let _span = debug::Span::enter( target.as_slice( ) );
+    irc_info! ( "establishing connection to {}...", target );
+    // use an address as it is, otherwise try everything the name resolves to
+    let addrs = match host.parse::< ip::IpAddr >( ) {
+      Ok ( a )  => vec![ a ],
+      Err ( _ ) => match addrinfo::get_host_addresses( host ) {
+        Ok ( a )  => order_addrs( a, opts.prefer ),
+        Err ( e ) => {
+          irc_error! ( "resolving {}: {}", host, e.desc );
+          return Err( e );
+        },
       },
     };
-    debug::oper( "connection established!" );
-    
+    let tcp = try! ( connect_addrs( addrs, port, opts.bind, opts.timeout ) );
+    irc_info! ( "connection established!" );
+    let stream = if opts.tls {
+      IrcStream::Tls( try! ( tls::connect( tcp, host, opts.tls_verify, opts.timeout ) ) )
+    } else {
+      IrcStream::Plain( tcp )
+    };
+    Ok( ServerConnection::from_stream( host, port, pass, stream ) )
+  }
+
+  /// `connect_proxy` establishes a new connection through a proxy
+  ///
+  /// # Arguments
+  ///
+  /// `host` - server host to connect to, looked up by the proxy
+  /// `port` - port number to connect to the server on
+  /// `pass` - password for the irc server
+  /// `proxy` - the proxy to connect through
+  /// `opts` - how to reach the server; `timeout` bounds the connection to
+  /// the proxy, its handshake and the TLS handshake, and `tls` and
+  /// `tls_verify` apply to the server. `bind` and `prefer` don't apply, as
+  /// the proxy looks the server up and connects to it.
+  ///
+  /// # Returns
+  ///
+  /// A new ServerConnection struct, or why the proxy couldn't reach the server
+  ///
+  /// # Notes
+  ///
+  /// * TLS runs over the proxied stream, end to end with the server.
+  pub fn connect_proxy ( host : &str, port : u16, pass : &str, proxy : &proxy::Proxy, opts : &ConnectOptions ) 
+    -> io::IoResult < ServerConnection > {
+    let target = format!( "{}:{}", host, port );
+    let _span = debug::Span::enter( target.as_slice( ) );
+    let tcp = match proxy.connect( host, port, opts.timeout ) {
+      Ok ( res ) => res,
+      Err ( e )  => {
+        irc_error! ( "establishing server connection through proxy: {}", e );
+        return Err( e );
+      },
+    };
+    let stream = if opts.tls {
+      IrcStream::Tls( try! ( tls::connect( tcp, host, opts.tls_verify, opts.timeout ) ) )
+    } else {
+      IrcStream::Plain( tcp )
+    };
+    Ok( ServerConnection::from_stream( host, port, pass, stream ) )
+  }
+
+  /// `from_stream` sets up a ServerConnection on a connected stream
+  fn from_stream ( host : &str, port : u16, pass : &str, mut tcp : IrcStream ) -> ServerConnection {
     // Create a channel for communication between spawned threads
     let( tx, rx ) = mpsc::channel( );
     
@@ -71,7 +375,7 @@ impl ServerConnection {
     if !pass.is_empty( ) {
       match tx.send( ConnEvent::Send( format! ( "PASS {}", pass ) ) ) {
         Ok ( _ )  => (),
-        Err ( _ ) => debug::err( "sending password to server", "" ),
+        Err ( _ ) => irc_error! ( "sending password to server" ),
       }
     }
     
@@ -80,36 +384,211 @@ impl ServerConnection {
       host    : host.to_string( ),
       port    : port,
       pass    : pass.to_string( ),
+      max_line: reader::MAX_LINE,
+      addr    : tcp.peer_name( ).ok( ),
       tcp     : tcp,
       chan    : tx,
       listen  : Some( rx ),
     }
   }
 
+  /// `label` names the connection for log lines, as "host:port"
+  pub fn label( &self ) -> String {
+    format! ( "{}:{}", self.host, self.port )
+  }
+
   /// `close` severs the connection with the server and shuts down the stream
   pub fn close( &mut self ) {
-    let out = format! ( "closing connection to {}:{}...", self.host, self.port );
-    debug::oper( out.as_slice( ) );
+    let _span = debug::Span::enter( self.label( ).as_slice( ) );
+    irc_info! ( "closing connection to {}:{}...", self.host, self.port );
     
     // Close the read stream
     match self.tcp.close_read( ) {
-      Err(e) => debug::err( "closing server read connection", e.desc ),
-      _      => debug::info( "read closed successfully" ),
+      Err(e) => irc_error! ( "closing server read connection: {}", e.desc ),
+      _      => irc_debug! ( "read closed successfully" ),
     };
     
     // Close the write stream
     match self.tcp.close_write( ) {
-      Err(e) => debug::err( "closing server write connection", e.desc ),
-      _      => debug::info( "write closed successfully" ),
+      Err(e) => irc_error! ( "closing server write connection: {}", e.desc ),
+      _      => irc_debug! ( "write closed successfully" ),
     }
     
     // Now close ourselves
     drop( self.tcp.clone( ) );
-    debug::oper( "server connection closed successfully" );
+    irc_info! ( "server connection closed successfully" );
   }
   
   /// `spin_writer` spins up a new IrcWriter and returns a handle to it
-  pub fn spin_writer( &self ) -> io::LineBufferedWriter < io::TcpStream > {
+  pub fn spin_writer( &self ) -> io::LineBufferedWriter < IrcStream > {
     io::LineBufferedWriter::new( self.tcp.clone() )
   }
-}
\ No newline at end of file
+}
+
+/// `order_addrs` puts a server's addresses in the order to try them
+///
+/// # Arguments
+///
+/// * `addrs` - the addresses, as the resolver gave them
+/// * `prefer` - which family to start with
+///
+/// # Returns
+///
+/// The addresses without duplicates, alternating between families and
+/// starting with the preferred one
+fn order_addrs( addrs : Vec < ip::IpAddr >, prefer : AddrPreference ) -> Vec < ip::IpAddr > {
+  let mut unique = Vec::new( );
+  for a in addrs.into_iter( ) {
+    if !unique.contains( &a ) {
+      unique.push( a );
+    }
+  }
+  let v6_first = match prefer {
+    AddrPreference::System => unique.first( ).map_or( false, |a| is_ipv6( a ) ),
+    AddrPreference::Ipv4   => false,
+    AddrPreference::Ipv6   => true,
+  };
+  let ( first, second ) : ( Vec < ip::IpAddr >, Vec < ip::IpAddr > ) =
+    unique.into_iter( ).partition( |a| is_ipv6( a ) == v6_first );
+
+  let mut ordered = Vec::new( );
+  let mut first = first.into_iter( );
+  let mut second = second.into_iter( );
+  loop {
+    match ( first.next( ), second.next( ) ) {
+      ( None, None )       => break,
+      ( a, b )             => {
+        ordered.extend( a.into_iter( ) );
+        ordered.extend( b.into_iter( ) );
+      },
+    }
+  }
+  ordered
+}
+
+/// `is_ipv6` returns whether an address is IPv6
+fn is_ipv6( a : &ip::IpAddr ) -> bool {
+  match *a {
+    ip::IpAddr::Ipv6Addr( .. ) => true,
+    ip::IpAddr::Ipv4Addr( .. ) => false,
+  }
+}
+
+/// `connect_addrs` tries each address in turn until one connects
+///
+/// # Arguments
+///
+/// * `addrs` - the addresses, in the order to try them
+/// * `port` - the port to connect to
+/// * `bind` - local address to connect from, or None to let the system pick
+/// * `timeout` - milliseconds to wait for each address, or None to wait as
+/// long as the system does
+///
+/// # Returns
+///
+/// The first stream that connects, or the error from the last address
+///
+/// # Notes
+///
+/// * Addresses of another family than `bind` are skipped.
+fn connect_addrs( addrs : Vec < ip::IpAddr >, port : u16, bind : Option < ip::IpAddr >, timeout : Option < i64 > ) 
+  -> io::IoResult < io::TcpStream > {
+  let mut last = io::standard_error( io::InvalidInput );
+  let addrs : Vec < ip::IpAddr > = match bind {
+    Some ( local ) => {
+      let usable : Vec < ip::IpAddr > = addrs.into_iter( ).filter( |a| is_ipv6( a ) == is_ipv6( &local ) ).collect( );
+      if usable.is_empty( ) {
+        last = io::IoError {
+          kind   : io::InvalidInput,
+          desc   : "no address of the server can be reached from the bind address",
+          detail : Some( format! ( "bound to {}", local ) ),
+        };
+      }
+      usable
+    },
+    None           => addrs,
+  };
+  for ip in addrs.into_iter( ) {
+    let addr = ip::SocketAddr { ip : ip, port : port };
+    irc_debug! ( "trying {}...", addr );
+    let attempt = match ( bind, timeout ) {
+      ( Some ( local ), _ ) => socket::connect_from( local, addr, timeout ),
+      ( None, Some ( ms ) ) => io::TcpStream::connect_timeout( addr, Duration::milliseconds( ms ) ),
+      ( None, None )        => io::TcpStream::connect( addr ),
+    };
+    match attempt {
+      Ok ( tcp ) => return Ok( tcp ),
+      Err ( e )  => {
+        irc_warn! ( "connecting to {}: {}", addr, e.desc );
+        last = e;
+      },
+    }
+  }
+  irc_error! ( "establishing server connection: {}", last.desc );
+  Err( last )
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[allow(unused_imports)]
+  use std::old_io::{Acceptor, Listener, TcpListener};
+  #[allow(unused_imports)]
+  use std::old_io::net::ip::IpAddr;
+  #[allow(unused_imports)]
+  use super::{AddrPreference, ConnectOptions, order_addrs, ReconnectPolicy, ServerConnection};
+
+  #[test]
+  fn test_delay_for () {
+    let policy = ReconnectPolicy { enabled : true, delay : 1000, max_delay : 5000, max_attempts : 0 };
+    assert! ( policy.delay_for( 1 ) == 1000 );
+    assert! ( policy.delay_for( 2 ) == 2000 );
+    assert! ( policy.delay_for( 3 ) == 4000 );
+    assert! ( policy.delay_for( 4 ) == 5000 );
+    assert! ( policy.delay_for( 100 ) == 5000 );
+  }
+
+  #[test]
+  fn test_order_addrs () {
+    let v4a = IpAddr::Ipv4Addr( 192, 0, 2, 1 );
+    let v4b = IpAddr::Ipv4Addr( 192, 0, 2, 2 );
+    let v6a = IpAddr::Ipv6Addr( 0x2001, 0xdb8, 0, 0, 0, 0, 0, 1 );
+    let v6b = IpAddr::Ipv6Addr( 0x2001, 0xdb8, 0, 0, 0, 0, 0, 2 );
+    let addrs = vec![ v6a, v6b, v4a, v6a, v4b ];
+    assert! ( order_addrs( addrs.clone( ), AddrPreference::System ) == vec![ v6a, v4a, v6b, v4b ] );
+    assert! ( order_addrs( addrs.clone( ), AddrPreference::Ipv4 ) == vec![ v4a, v6a, v4b, v6b ] );
+    assert! ( order_addrs( vec![ v4a, v4b ], AddrPreference::Ipv6 ) == vec![ v4a, v4b ] );
+  }
+
+  #[test]
+  fn test_connect_with () {
+    let mut acceptor = TcpListener::bind( "127.0.0.1:0" ).unwrap( ).listen( ).unwrap( );
+    let port = acceptor.socket_name( ).unwrap( ).port;
+    let mut opts = ConnectOptions::new( );
+    opts.timeout = Some( 2000 );
+    let conn = ServerConnection::connect_with( "127.0.0.1", port, "", &opts ).ok( ).unwrap( );
+    assert! ( acceptor.accept( ).is_ok( ) );
+    assert! ( conn.addr.map( |a| a.port ) == Some( port ) );
+
+    // connecting from a bound address, which the server sees
+    opts.bind = Some( IpAddr::Ipv4Addr( 127, 0, 0, 1 ) );
+    let conn = ServerConnection::connect_with( "127.0.0.1", port, "", &opts ).ok( ).unwrap( );
+    let mut peer = acceptor.accept( ).unwrap( );
+    assert! ( peer.peer_name( ).unwrap( ).ip == IpAddr::Ipv4Addr( 127, 0, 0, 1 ) );
+    assert! ( conn.addr.map( |a| a.port ) == Some( port ) );
+
+    // addresses of the other family are skipped
+    opts.bind = Some( IpAddr::Ipv6Addr( 0, 0, 0, 0, 0, 0, 0, 1 ) );
+    assert! ( ServerConnection::connect_with( "127.0.0.1", port, "", &opts ).is_err( ) );
+  }
+
+  #[test]
+  fn test_connect_fallback () {
+    let mut acceptor = TcpListener::bind( "127.0.0.1:0" ).unwrap( ).listen( ).unwrap( );
+    let port = acceptor.socket_name( ).unwrap( ).port;
+
+    // the first address never answers, the second does
+    let addrs = vec![ IpAddr::Ipv4Addr( 192, 0, 2, 1 ), IpAddr::Ipv4Addr( 127, 0, 0, 1 ) ];
+    let tcp = super::connect_addrs( addrs, port, None, Some( 500 ) );
+    assert! ( tcp.is_ok( ) && acceptor.accept( ).is_ok( ) );
+  }
+}
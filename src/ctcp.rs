@@ -1,7 +1,8 @@
+use std::ascii::AsciiExt;
 use regex;
 
+use dcc;
 use message;
-use utils::debug;
 
 static X_DELIM : char                             = '\x01';
 static M_CNVRT : [(&'static str,&'static str); 4] = [ ("\x14","\x14\x14"),
@@ -97,6 +98,171 @@ impl ToString for CtcpRequest {
   }
 }
 
+/// `Ctcp` is a typed CTCP message
+///
+/// # Options
+///
+/// `Action( s )` - an action, as in "/me s"
+/// `Version( r )` - a VERSION request, or a reply if r is Some
+/// `Ping( t )` - a PING request or reply carrying the token t
+/// `Time( r )` - a TIME request, or a reply if r is Some
+/// `ClientInfo( r )` - a CLIENTINFO request, or a reply if r is Some
+/// `Dcc( d )` - a DCC request
+/// `Custom( c )` - any other CTCP request
+pub enum Ctcp {
+  Action ( String ),
+  Version ( Option < String > ),
+  Ping ( String ),
+  Time ( Option < String > ),
+  ClientInfo ( Option < String > ),
+  Dcc ( dcc::DccRequest ),
+  Custom ( CtcpRequest ),
+}
+
+impl Clone for Ctcp {
+  fn clone ( &self ) -> Ctcp {
+    match *self {
+      Ctcp::Action ( ref s )     => Ctcp::Action( s.clone( ) ),
+      Ctcp::Version ( ref r )    => Ctcp::Version( r.clone( ) ),
+      Ctcp::Ping ( ref t )       => Ctcp::Ping( t.clone( ) ),
+      Ctcp::Time ( ref r )       => Ctcp::Time( r.clone( ) ),
+      Ctcp::ClientInfo ( ref r ) => Ctcp::ClientInfo( r.clone( ) ),
+      Ctcp::Dcc ( ref d )        => Ctcp::Dcc( d.clone( ) ),
+      Ctcp::Custom ( ref c )     => Ctcp::Custom( c.clone( ) ),
+    }
+  }
+}
+
+impl Ctcp {
+  /// `from_request` types a CTCP request, as returned by `extract_msg`
+  ///
+  /// # Arguments
+  ///
+  /// * `req` - the CTCP request to type, still CTCP quoted
+  ///
+  /// # Returns
+  ///
+  /// The typed CTCP message. Malformed DCC requests are returned as `Custom`.
+  pub fn from_request ( req : &CtcpRequest ) -> Ctcp {
+    let req = req.dequote( );
+    let reply = if req.params.is_empty( ) {
+      None
+    } else {
+      Some( req.params.clone( ) )
+    };
+    match req.command.to_ascii_uppercase( ).as_slice( ) {
+      "ACTION"     => Ctcp::Action( req.params.clone( ) ),
+      "VERSION"    => Ctcp::Version( reply ),
+      "PING"       => Ctcp::Ping( req.params.clone( ) ),
+      "TIME"       => Ctcp::Time( reply ),
+      "CLIENTINFO" => Ctcp::ClientInfo( reply ),
+      "DCC"        => match dcc::DccRequest::parse( req.params.as_slice( ) ) {
+        Some ( d ) => Ctcp::Dcc( d ),
+        None       => Ctcp::Custom( req ),
+      },
+      _            => Ctcp::Custom( req ),
+    }
+  }
+
+  /// `from_message` gets the CTCP message carried by a PRIVMSG or NOTICE
+  ///
+  /// # Arguments
+  ///
+  /// * `msg` - the message to read the CTCP message from
+  ///
+  /// # Returns
+  ///
+  /// The typed CTCP message, or None if the message isn't a CTCP message
+  ///
+  /// # Notes
+  ///
+  /// * This follows the modern convention of one CTCP message per PRIVMSG or
+  /// NOTICE: the trailing parameter must start with the delimiter and the
+  /// whole of it is the CTCP message. The closing delimiter may be left off.
+  pub fn from_message ( msg : &message::Message ) -> Option < Ctcp > {
+    if !msg.is_message( ) {
+      return None;
+    }
+    let trail = match msg.trailing( ) {
+      Some ( t ) => t,
+      None       => return None,
+    };
+    if !trail.starts_with( "\x01" ) {
+      return None;
+    }
+    let body = trail.slice_from( 1 );
+    let body = if body.ends_with( "\x01" ) {
+      body.slice_to( body.len( ) - 1 )
+    } else {
+      body
+    };
+    if body.is_empty( ) {
+      return None;
+    }
+    Some( Ctcp::from_request( &parse_cmd( body.to_string( ) ) ) )
+  }
+
+  /// `to_request` converts the typed message back to an unquoted request
+  ///
+  /// # Returns
+  ///
+  /// A CtcpRequest with the command and parameters of the message
+  pub fn to_request ( &self ) -> CtcpRequest {
+    let ( cmd, params ) = match *self {
+      Ctcp::Action ( ref s )     => ( "ACTION", s.clone( ) ),
+      Ctcp::Version ( ref r )    => ( "VERSION", r.clone( ).unwrap_or( String::new( ) ) ),
+      Ctcp::Ping ( ref t )       => ( "PING", t.clone( ) ),
+      Ctcp::Time ( ref r )       => ( "TIME", r.clone( ).unwrap_or( String::new( ) ) ),
+      Ctcp::ClientInfo ( ref r ) => ( "CLIENTINFO", r.clone( ).unwrap_or( String::new( ) ) ),
+      Ctcp::Dcc ( ref d )        => ( "DCC", d.to_params( ) ),
+      Ctcp::Custom ( ref c )     => return c.clone( ),
+    };
+    CtcpRequest::new( cmd.to_string( ), params )
+  }
+
+  /// `tagged` formats the message as a quoted CTCP tag ready for sending
+  ///
+  /// # Returns
+  ///
+  /// The CTCP message quoted with `ctcp_quote` and wrapped with `tag`
+  pub fn tagged ( &self ) -> String {
+    let req = self.to_request( );
+    let body = if req.params.is_empty( ) {
+      req.command.clone( )
+    } else {
+      format! ( "{} {}", req.command, req.params )
+    };
+    tag( ctcp_quote( body ).as_slice( ) )
+  }
+
+  /// `to_message` creates a PRIVMSG carrying the CTCP message as a request
+  ///
+  /// # Arguments
+  ///
+  /// * `target` - nick or channel to send the request to
+  ///
+  /// # Returns
+  ///
+  /// A PRIVMSG Message struct ready to be sent to the target
+  pub fn to_message ( &self, target : &str ) -> message::Message {
+    message::Message::privmsg( target, self.tagged( ).as_slice( ) )
+  }
+
+  /// `to_reply` creates a NOTICE carrying the CTCP message as a reply
+  ///
+  /// # Arguments
+  ///
+  /// * `target` - nick to send the reply to
+  ///
+  /// # Returns
+  ///
+  /// A NOTICE Message struct ready to be sent to the target
+  pub fn to_reply ( &self, target : &str ) -> message::Message {
+    let params = format! ( "{} :{}", target, self.tagged( ) );
+    message::Message::new( message::Source::None, "NOTICE", params.as_slice( ) )
+  }
+}
+
 /// `low_level_quote` wraps a message for transmission by obscuring low-level
 /// characters.
 ///
@@ -198,7 +364,7 @@ pub fn has_tag ( s : &str, t : &str ) -> bool {
   let re   = match regex::Regex::new( patt.as_slice( ) ) {
     Ok ( re ) => re,
     Err ( e ) => {
-      debug::err( "creating ctcp regex", e.msg );
+      irc_error! ( "creating ctcp regex: {}", e.msg );
       return false;
     },
   };
@@ -221,7 +387,7 @@ pub fn get_tag ( s : &str, t : &str ) -> Option < CtcpRequest > {
   let re   = match regex::Regex::new( patt.as_slice( ) ) {
     Ok ( re ) => re,
     Err ( e ) => {
-      debug::err( "creating ctcp regex", e.msg );
+      irc_error! ( "creating ctcp regex: {}", e.msg );
       return None;
     },
   };
@@ -266,7 +432,7 @@ pub fn combine ( s : String, cmd : CtcpRequest ) -> String {
 /// message returns unaltered.
 pub fn combine_msg( msg : message::Message, cmd : CtcpRequest ) -> message::Message {
   if !msg.is_message( ) {
-    debug::warn( "ctcp combine message", 
+    irc_warn! ( "ctcp combine message: {}",
       "CTCP requests can only be combined with a PRIVMSG or NOTICE based message" );
     return msg;
   }
@@ -284,23 +450,12 @@ pub fn combine_msg( msg : message::Message, cmd : CtcpRequest ) -> message::Mess
 ///
 /// A CTCP request that represents the given tag
 fn parse_cmd ( s : String ) -> CtcpRequest {
-  let data : Vec < &str > = s.as_slice( ).split_str( " " ).collect( ); // break up the params
-  let cmd : &str = data[0];         // the first word is the command
-  let mut params = String::new( );  // the string of parameters
-  
-  // loop through all our remaining parameters and push them to the param str
-  for param in data.iter( ) {
-    if param.as_slice( ) == cmd { continue };
-    params.push_str( *param );
-    params.push( ' ' );
+  // the first word is the command, everything after the first space is params
+  match s.as_slice( ).find( ' ' ) {
+    Some ( i ) => CtcpRequest::new( s.as_slice( ).slice_to( i ).to_string( ),
+      s.as_slice( ).slice_from( i + 1 ).to_string( ) ),
+    None       => CtcpRequest::new( s.clone( ), String::new( ) ),
   }
-  
-  if params.len( ) > 0 {
-    params.pop( );
-  }
-  
-  // construct our request
-  CtcpRequest::new( cmd.to_string( ), params )
 }
 
 /// `extract` gets all the CTCP requests from a string
@@ -336,10 +491,14 @@ pub fn extract ( s : &str ) -> ( String, Vec < CtcpRequest > ) {
     }
   }
   
-  // if we still have a section left over (perhaps from an unclosed ctcp tag),
-  // push it to the parsed string
+  // if we still have a section left over, push it to the right place. an
+  // unclosed ctcp tag at the end is still a request, since modern clients
+  // often leave off the closing delimiter
   if !section.is_empty( ) {
-    newstring.push_str( section.as_slice( ) );
+    match intag {
+      false => newstring.push_str( section.as_slice( ) ),
+      true  => cmds.push( parse_cmd( section ) ),
+    }
   }
   
   // construct our tuple
@@ -365,7 +524,7 @@ pub fn extract ( s : &str ) -> ( String, Vec < CtcpRequest > ) {
 /// message back with no CTCP requests.
 pub fn extract_msg ( msg : message::Message ) -> ( message::Message, Vec < CtcpRequest > ) {
   if !msg.is_message( ) {
-    debug::warn( "ctcp extract message",
+    irc_warn! ( "ctcp extract message: {}",
       "CTCP requests can only be extracted from a NOTICE or PRIVMSG based message" );
     return ( msg, Vec::new( ) );
   }
@@ -464,4 +623,53 @@ mod test {
     let combined2 = super::combine_msg( failmsg.clone( ), request.clone( ) );
     assert! ( failmsg.raw == combined2.raw );
   }
+  
+  #[test]
+  fn test_parse_repeated_words () {
+    let (_,rqs) = super::extract( "\x01ACTION ACTION figures\x01" );
+    assert! ( rqs[0].command.as_slice( ) == "ACTION" );
+    assert! ( rqs[0].params.as_slice( ) == "ACTION figures" );
+    let (ext,rqs) = super::extract( "hi \x01VERSION" );
+    assert! ( ext.as_slice( ) == "hi " );
+    assert! ( rqs[0].command.as_slice( ) == "VERSION" );
+  }
+  
+  #[test]
+  fn test_ctcp_from_message () {
+    let action = Message::parse( ":Lancey!l@host PRIVMSG #rust :\x01ACTION ACTION\x01" ).unwrap( );
+    let ping = Message::parse( ":Lancey!l@host PRIVMSG Detective :\x01PING 1234" ).unwrap( );
+    let version = Message::parse( ":Lancey!l@host NOTICE Detective :\x01VERSION rustirc\x01" ).unwrap( );
+    let dcc = Message::parse( ":Lancey!l@host PRIVMSG Detective :\x01DCC CHAT chat 2130706433 5000\x01" ).unwrap( );
+    let plain = Message::parse( ":Lancey!l@host PRIVMSG #rust :hello \x01VERSION\x01" ).unwrap( );
+    match super::Ctcp::from_message( &action ) {
+      Some ( super::Ctcp::Action ( s ) ) => assert! ( s.as_slice( ) == "ACTION" ),
+      _                                  => panic! ( "expected an action" ),
+    }
+    match super::Ctcp::from_message( &ping ) {
+      Some ( super::Ctcp::Ping ( t ) ) => assert! ( t.as_slice( ) == "1234" ),
+      _                                => panic! ( "expected a ping" ),
+    }
+    match super::Ctcp::from_message( &version ) {
+      Some ( super::Ctcp::Version ( Some ( r ) ) ) => assert! ( r.as_slice( ) == "rustirc" ),
+      _                                            => panic! ( "expected a version reply" ),
+    }
+    match super::Ctcp::from_message( &dcc ) {
+      Some ( super::Ctcp::Dcc ( d ) ) => assert! ( d.port == 5000 ),
+      _                               => panic! ( "expected a dcc request" ),
+    }
+    assert! ( super::Ctcp::from_message( &plain ).is_none( ) );
+  }
+  
+  #[test]
+  fn test_ctcp_serialize () {
+    let action = super::Ctcp::Action( "says \x01hello\\".to_string( ) );
+    assert! ( action.tagged( ) == "\x01ACTION says \\ahello\\\\\x01" );
+    assert! ( action.to_message( "#rust" ).raw == "PRIVMSG #rust :\x01ACTION says \\ahello\\\\\x01" );
+    let pong = super::Ctcp::Ping( "1234".to_string( ) ).to_reply( "Lancey" );
+    assert! ( pong.raw == "NOTICE Lancey :\x01PING 1234\x01" );
+    match super::Ctcp::from_message( &action.to_message( "#rust" ) ) {
+      Some ( super::Ctcp::Action ( s ) ) => assert! ( s.as_slice( ) == "says \x01hello\\" ),
+      _                                  => panic! ( "expected an action" ),
+    }
+  }
 }
\ No newline at end of file
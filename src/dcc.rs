@@ -0,0 +1,642 @@
+// import built in modules
+use std::ascii::AsciiExt;
+use std::old_io as io;
+use std::old_io::{Acceptor, Listener};
+use std::old_io::net::ip::{IpAddr, SocketAddr};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+// import custom modules
+use reader;
+
+pub type TTIMEOUT                 = u64;
+
+static DCC_CONNECT_TIMEOUT : TTIMEOUT = 30000; // ms to wait for a connection
+static DCC_ACCEPT_TIMEOUT  : TTIMEOUT = 120000; // ms to wait for a peer to dial
+
+/// `DccRequest` is an abstraction of the parameters of a DCC CTCP request
+///
+/// # Members
+///
+/// * `kind` - type of the DCC request, e.g. "CHAT" or "SEND"
+/// * `argument` - argument of the request, "chat" for a chat or a filename
+/// * `host` - address the sender is listening on, as it was sent
+/// * `port` - port the sender is listening on, 0 for a passive request
+/// * `extra` - any remaining parameters, such as a file size or token
+///
+/// # Notes
+///
+/// * RESUME and ACCEPT requests carry no address, so `host` is left empty and
+/// `extra` holds the position followed by the token if there is one.
+pub struct DccRequest {
+  pub kind      : String,
+  pub argument  : String,
+  pub host      : String,
+  pub port      : u16,
+  pub extra     : Vec < String >,
+}
+
+impl Clone for DccRequest {
+  fn clone( &self ) -> DccRequest {
+    DccRequest {
+      kind      : self.kind.clone( ),
+      argument  : self.argument.clone( ),
+      host      : self.host.clone( ),
+      port      : self.port,
+      extra     : self.extra.clone( ),
+    }
+  }
+}
+
+impl DccRequest {
+  /// `new` creates a DCC request from its parts
+  ///
+  /// # Arguments
+  ///
+  /// * `kind` - type of the DCC request, e.g. "CHAT"
+  /// * `argument` - argument of the request
+  /// * `host` - address the sender is listening on
+  /// * `port` - port the sender is listening on
+  /// * `extra` - any remaining parameters
+  ///
+  /// # Returns
+  ///
+  /// A DccRequest with the kind converted to upper case
+  pub fn new(
+    kind : &str,
+    argument : &str,
+    host : &str,
+    port : u16,
+    extra : Vec < String >
+  ) -> DccRequest {
+    DccRequest {
+      kind      : kind.to_ascii_uppercase( ),
+      argument  : argument.to_string( ),
+      host      : host.to_string( ),
+      port      : port,
+      extra     : extra,
+    }
+  }
+
+  /// `parse` creates a DCC request from the parameters of a DCC CTCP tag
+  ///
+  /// # Arguments
+  ///
+  /// * `params` - everything after "DCC ", e.g. "CHAT chat 2130706433 5000"
+  ///
+  /// # Returns
+  ///
+  /// The parsed request, or None if a required parameter is missing or the
+  /// port is not a number
+  pub fn parse( params : &str ) -> Option < DccRequest > {
+    let words = split_quoted( params );
+    if words.len( ) < 3 {
+      return None;
+    }
+    let kind = words[0].to_ascii_uppercase( );
+    match kind.as_slice( ) {
+      // RESUME and ACCEPT don't carry an address
+      "RESUME" | "ACCEPT" => {
+        let port = match words[2].parse::< u16 >( ).ok( ) {
+          Some ( p ) => p,
+          None       => return None,
+        };
+        Some( DccRequest::new( kind.as_slice( ), words[1].as_slice( ), "",
+          port, words.slice_from( 3 ).to_vec( ) ) )
+      },
+      _                   => {
+        if words.len( ) < 4 {
+          return None;
+        }
+        let port = match words[3].parse::< u16 >( ).ok( ) {
+          Some ( p ) => p,
+          None       => return None,
+        };
+        Some( DccRequest::new( kind.as_slice( ), words[1].as_slice( ),
+          words[2].as_slice( ), port, words.slice_from( 4 ).to_vec( ) ) )
+      },
+    }
+  }
+
+  /// `address` decodes the address the sender is listening on
+  ///
+  /// # Returns
+  ///
+  /// The address as an IpAddr, or None if it can't be decoded
+  ///
+  /// # Notes
+  ///
+  /// * IPv4 addresses are usually sent as a single unsigned 32-bit integer,
+  /// but dotted addresses and IPv6 addresses are accepted too.
+  pub fn address( &self ) -> Option < IpAddr > {
+    decode_address( self.host.as_slice( ) )
+  }
+
+  /// `socket` gets the socket address the sender is listening on
+  ///
+  /// # Returns
+  ///
+  /// The socket address, or None if the address can't be decoded or the
+  /// request is passive
+  pub fn socket( &self ) -> Option < SocketAddr > {
+    if self.is_passive( ) {
+      return None;
+    }
+    match self.address( ) {
+      Some ( ip ) => Some( SocketAddr { ip : ip, port : self.port } ),
+      None        => None,
+    }
+  }
+
+  /// `is_passive` returns whether the request is a passive (reverse) request
+  ///
+  /// # Returns
+  ///
+  /// true if the sender isn't listening and wants us to listen instead
+  pub fn is_passive( &self ) -> bool {
+    self.port == 0 && self.token( ).is_some( )
+  }
+
+  /// `token` gets the token of a passive request
+  ///
+  /// # Returns
+  ///
+  /// The token if the request carries one, otherwise None
+  pub fn token( &self ) -> Option < &str > {
+    let idx = match self.kind.as_slice( ) {
+      "CHAT"              => 0,
+      "SEND"              => 1,
+      "RESUME" | "ACCEPT" => 1,
+      _                   => return None,
+    };
+    if self.extra.len( ) > idx {
+      Some( self.extra[idx].as_slice( ) )
+    } else {
+      None
+    }
+  }
+
+  /// `to_params` formats the request as the parameters of a DCC CTCP tag
+  ///
+  /// # Returns
+  ///
+  /// A String of parameters, with the argument quoted if it contains spaces
+  pub fn to_params( &self ) -> String {
+    let arg = if self.argument.as_slice( ).contains( " " ) {
+      format! ( "\"{}\"", self.argument )
+    } else {
+      self.argument.clone( )
+    };
+    let mut out = if self.host.is_empty( ) {
+      format! ( "{} {} {}", self.kind, arg, self.port )
+    } else {
+      format! ( "{} {} {} {}", self.kind, arg, self.host, self.port )
+    };
+    for ex in self.extra.iter( ) {
+      out.push( ' ' );
+      out.push_str( ex.as_slice( ) );
+    }
+    out
+  }
+}
+
+/// `DccEvent` defines the events of a DCC CHAT session
+///
+/// # Options
+///
+/// `Line( s )` - the peer sent the line s
+/// `Closed( s )` - the session closed for the reason s
+pub enum DccEvent {
+  Line ( String ),
+  Closed ( String ),
+}
+
+/// `DccChat` is a line-oriented DCC CHAT session with a peer
+///
+/// # Members
+///
+/// * `peer` - address of the peer on the other end of the session
+/// * `tcp` - the TcpStream to the peer
+pub struct DccChat {
+  pub peer  : SocketAddr,
+  tcp       : io::TcpStream,
+}
+
+impl DccChat {
+  /// `connect` accepts a DCC CHAT offer by dialing the sender
+  ///
+  /// # Arguments
+  ///
+  /// * `req` - the DCC CHAT request received from the sender
+  /// * `timeout` - milliseconds to wait for the connection, or None for the
+  /// default
+  ///
+  /// # Returns
+  ///
+  /// The connected session, or an error if the request can't be dialed
+  pub fn connect( req : &DccRequest, timeout : Option < TTIMEOUT > ) -> io::IoResult < DccChat > {
+    let addr = match req.socket( ) {
+      Some ( a ) => a,
+      None       => return Err( invalid( "request has no address to dial" ) ),
+    };
+    DccChat::dial( addr, timeout )
+  }
+
+  /// `dial` opens a DCC CHAT session to a socket address
+  ///
+  /// # Arguments
+  ///
+  /// * `addr` - address of the peer
+  /// * `timeout` - milliseconds to wait for the connection, or None for the
+  /// default
+  ///
+  /// # Returns
+  ///
+  /// The connected session, or an error if the connection failed
+  pub fn dial( addr : SocketAddr, timeout : Option < TTIMEOUT > ) -> io::IoResult < DccChat > {
+    let ms = timeout.unwrap_or( DCC_CONNECT_TIMEOUT ) as i64;
+    irc_info! ( "opening dcc chat to {}...", addr );
+    let tcp = try! ( io::TcpStream::connect_timeout( addr, Duration::milliseconds( ms ) ) );
+    Ok( DccChat { peer : addr, tcp : tcp } )
+  }
+
+  /// `send_line` sends a line of text to the peer
+  ///
+  /// # Arguments
+  ///
+  /// * `line` - the line to send, without a line ending
+  pub fn send_line( &mut self, line : &str ) -> io::IoResult < ( ) > {
+    if line.contains_char( '\r' ) || line.contains_char( '\n' ) {
+      return Err( invalid( "dcc chat lines can't contain line breaks" ) );
+    }
+    try! ( self.tcp.write_str( line ) );
+    self.tcp.write_str( "\n" )
+  }
+
+  /// `start` spins up a reader for the session on a new thread
+  ///
+  /// # Returns
+  ///
+  /// A tuple containing:
+  /// * Receiver the session will send its events to
+  /// * The session, which can still be used to send lines
+  ///
+  /// # Notes
+  ///
+  /// * Lines are framed the same way as the server's, so a peer that never
+  /// sends a line break can't use up our memory. Longer lines are dropped.
+  pub fn start( self ) -> ( mpsc::Receiver < DccEvent >, DccChat ) {
+    let ( tx, rx ) = mpsc::channel( );
+    let tcp = self.tcp.clone( );
+    let peer = self.peer;
+    thread::Thread::spawn( move || {
+      let mut read = reader::LineFramer::new( tcp, reader::MAX_LINE );
+      loop {
+        let event = match read.next_line( ) {
+          Ok ( reader::Frame::Line ( line ) ) => DccEvent::Line(
+            String::from_utf8_lossy( line.as_slice( ) ).into_owned( ) ),
+          Ok ( reader::Frame::TooLong ( len ) ) => {
+            irc_warn! ( "dropping {} byte line from dcc chat with {}", len, peer );
+            continue;
+          },
+          Err ( e ) => match e.kind {
+            io::IoErrorKind::EndOfFile => DccEvent::Closed( String::from_str( "peer closed the session" ) ),
+            _                          => DccEvent::Closed( e.desc.to_string( ) ),
+          },
+        };
+        let done = match event {
+          DccEvent::Closed ( _ ) => true,
+          _                      => false,
+        };
+        if tx.send( event ).is_err( ) || done {
+          break;
+        }
+      }
+    } );
+    ( rx, self )
+  }
+
+  /// `close` shuts down the session
+  pub fn close( &mut self ) {
+    match self.tcp.close_read( ) {
+      Err ( e ) => irc_error! ( "closing dcc chat read: {}", e.desc ),
+      _         => (),
+    }
+    match self.tcp.close_write( ) {
+      Err ( e ) => irc_error! ( "closing dcc chat write: {}", e.desc ),
+      _         => (),
+    }
+  }
+}
+
+/// `DccListener` waits for a peer to dial in to a DCC session we offered
+///
+/// # Members
+///
+/// * `local` - address the listener is bound to
+/// * `token` - token of the passive request this listener answers, if any
+/// * `acceptor` - the underlying TcpAcceptor
+pub struct DccListener {
+  pub local : SocketAddr,
+  pub token : Option < String >,
+  acceptor  : io::net::tcp::TcpAcceptor,
+}
+
+impl DccListener {
+  /// `bind` opens a listener on the given address with a free port
+  ///
+  /// # Arguments
+  ///
+  /// * `ip` - local address to listen on
+  ///
+  /// # Returns
+  ///
+  /// A listener ready to accept a peer
+  pub fn bind( ip : IpAddr ) -> io::IoResult < DccListener > {
+    let listener = try! ( io::TcpListener::bind( ( ip, 0u16 ) ) );
+    let mut acceptor = try! ( listener.listen( ) );
+    let local = try! ( acceptor.socket_name( ) );
+    Ok( DccListener { local : local, token : None, acceptor : acceptor } )
+  }
+
+  /// `offer` creates the DCC CHAT request that points a peer at this listener
+  ///
+  /// # Arguments
+  ///
+  /// * `public` - address the peer should dial, which may differ from the
+  /// local address behind NAT
+  ///
+  /// # Returns
+  ///
+  /// A DCC CHAT request to send to the peer. If the listener answers a
+  /// passive request, the token is included.
+  pub fn offer( &self, public : IpAddr ) -> DccRequest {
+    let extra = match self.token {
+      Some ( ref t ) => vec![ t.clone( ) ],
+      None           => Vec::new( ),
+    };
+    DccRequest::new( "CHAT", "chat", encode_address( public ).as_slice( ),
+      self.local.port, extra )
+  }
+
+  /// `accept` waits for the peer to dial in
+  ///
+  /// # Arguments
+  ///
+  /// * `timeout` - milliseconds to wait for the peer, or None for the default
+  ///
+  /// # Returns
+  ///
+  /// The connected session, or an error if nobody dialed in time
+  pub fn accept( self, timeout : Option < TTIMEOUT > ) -> io::IoResult < DccChat > {
+    let mut tcp = try! ( self.accept_stream( timeout ) );
+    let peer = try! ( tcp.peer_name( ) );
+    irc_info! ( "dcc chat accepted from {}", peer );
+    Ok( DccChat { peer : peer, tcp : tcp } )
+  }
+
+  /// `accept_stream` waits for the peer to dial in and returns the raw stream
+  ///
+  /// # Arguments
+  ///
+  /// * `timeout` - milliseconds to wait for the peer, or None for the default
+  ///
+  /// # Returns
+  ///
+  /// The TcpStream to the peer, or an error if nobody dialed in time
+  pub fn accept_stream( mut self, timeout : Option < TTIMEOUT > ) -> io::IoResult < io::TcpStream > {
+    self.acceptor.set_timeout( Some( timeout.unwrap_or( DCC_ACCEPT_TIMEOUT ) ) );
+    self.acceptor.accept( )
+  }
+}
+
+/// `passive_offer` creates a passive DCC CHAT request, asking the peer to
+/// listen and dial back
+///
+/// # Arguments
+///
+/// * `public` - our address, which peers expect even in a passive request
+/// * `token` - token identifying the offer, echoed back by the peer
+///
+/// # Returns
+///
+/// A DCC CHAT request with port 0 and the given token
+pub fn passive_offer( public : IpAddr, token : &str ) -> DccRequest {
+  DccRequest::new( "CHAT", "chat", encode_address( public ).as_slice( ), 0,
+    vec![ token.to_string( ) ] )
+}
+
+/// `answer_passive` listens for a peer that sent us a passive request
+///
+/// # Arguments
+///
+/// * `req` - the passive request received from the peer
+/// * `ip` - local address to listen on
+///
+/// # Returns
+///
+/// A listener carrying the request's token. Send the peer the request
+/// returned by its `offer` and then `accept`.
+pub fn answer_passive( req : &DccRequest, ip : IpAddr ) -> io::IoResult < DccListener > {
+  let token = match req.token( ) {
+    Some ( t ) if req.port == 0 => t.to_string( ),
+    _                           => return Err( invalid( "request is not passive" ) ),
+  };
+  let mut listener = try! ( DccListener::bind( ip ) );
+  listener.token = Some( token );
+  Ok( listener )
+}
+
+/// `decode_address` decodes a DCC address
+///
+/// # Arguments
+///
+/// * `host` - an integer IPv4 address, a dotted IPv4 address or an IPv6
+/// address
+///
+/// # Returns
+///
+/// The decoded address, or None if it isn't an address
+pub fn decode_address( host : &str ) -> Option < IpAddr > {
+  match host.parse::< u32 >( ).ok( ) {
+    Some ( n ) => Some( IpAddr::Ipv4Addr( ( n >> 24 ) as u8, ( n >> 16 ) as u8,
+      ( n >> 8 ) as u8, n as u8 ) ),
+    None       => host.parse::< IpAddr >( ).ok( ),
+  }
+}
+
+/// `encode_address` encodes an address for a DCC request
+///
+/// # Arguments
+///
+/// * `ip` - address to encode
+///
+/// # Returns
+///
+/// The integer form of an IPv4 address, or the string form of an IPv6 address
+pub fn encode_address( ip : IpAddr ) -> String {
+  match ip {
+    IpAddr::Ipv4Addr( a, b, c, d ) => {
+      let n = ( a as u32 << 24 ) | ( b as u32 << 16 ) | ( c as u32 << 8 ) | d as u32;
+      n.to_string( )
+    },
+    IpAddr::Ipv6Addr( .. )         => ip.to_string( ),
+  }
+}
+
+/// `invalid` creates an IoError for a bad DCC argument
+fn invalid( desc : &'static str ) -> io::IoError {
+  io::IoError {
+    kind    : io::IoErrorKind::InvalidInput,
+    desc    : desc,
+    detail  : None,
+  }
+}
+
+/// `split_quoted` splits DCC parameters on spaces, keeping quoted strings
+/// together
+///
+/// # Arguments
+///
+/// * `s` - parameter string to split
+///
+/// # Returns
+///
+/// A vector of parameters with any surrounding quotes removed
+fn split_quoted( s : &str ) -> Vec < String > {
+  let mut words   = Vec::new( );
+  let mut word    = String::new( );
+  let mut quoted  = false;
+  for ch in s.chars( ) {
+    match ch {
+      '"'                => quoted = !quoted,
+      ' ' if !quoted     => {
+        if !word.is_empty( ) {
+          words.push( word.clone( ) );
+          word.clear( );
+        }
+      },
+      _                  => word.push( ch ),
+    }
+  }
+  if !word.is_empty( ) {
+    words.push( word );
+  }
+  words
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[allow(unused_imports)]
+  use std::old_io::net::ip::IpAddr;
+  #[allow(unused_imports)]
+  use std::sync::mpsc;
+  #[allow(unused_imports)]
+  use std::thread::Thread;
+
+  #[test]
+  fn test_parse_chat () {
+    let req = super::DccRequest::parse( "CHAT chat 2130706433 5000" ).unwrap( );
+    assert! ( req.kind == "CHAT" );
+    assert! ( req.argument == "chat" );
+    assert! ( req.host == "2130706433" );
+    assert! ( req.port == 5000 );
+    assert! ( req.extra.is_empty( ) );
+    assert! ( req.to_params( ) == "CHAT chat 2130706433 5000" );
+  }
+
+  #[test]
+  fn test_parse_send () {
+    let req = super::DccRequest::parse( "SEND \"my log.txt\" 2130706433 0 1024 7" ).unwrap( );
+    assert! ( req.argument == "my log.txt" );
+    assert! ( req.port == 0 );
+    assert! ( req.extra == vec![ "1024".to_string( ), "7".to_string( ) ] );
+    assert! ( req.to_params( ) == "SEND \"my log.txt\" 2130706433 0 1024 7" );
+  }
+
+  #[test]
+  fn test_parse_resume () {
+    let req = super::DccRequest::parse( "RESUME log.txt 5000 512" ).unwrap( );
+    assert! ( req.host.is_empty( ) );
+    assert! ( req.port == 5000 );
+    assert! ( req.extra == vec![ "512".to_string( ) ] );
+    assert! ( req.to_params( ) == "RESUME log.txt 5000 512" );
+    assert! ( super::DccRequest::parse( "CHAT chat 2130706433" ).is_none( ) );
+    assert! ( super::DccRequest::parse( "CHAT chat 2130706433 port" ).is_none( ) );
+  }
+
+  #[test]
+  fn test_address () {
+    let v4 = super::DccRequest::parse( "CHAT chat 2130706433 5000" ).unwrap( );
+    let dotted = super::DccRequest::parse( "CHAT chat 127.0.0.1 5000" ).unwrap( );
+    let v6 = super::DccRequest::parse( "CHAT chat ::1 5000" ).unwrap( );
+    let passive = super::DccRequest::parse( "CHAT chat 2130706433 0 42" ).unwrap( );
+    assert! ( v4.address( ) == Some( IpAddr::Ipv4Addr( 127, 0, 0, 1 ) ) );
+    assert! ( dotted.address( ) == Some( IpAddr::Ipv4Addr( 127, 0, 0, 1 ) ) );
+    assert! ( v6.address( ) == Some( IpAddr::Ipv6Addr( 0, 0, 0, 0, 0, 0, 0, 1 ) ) );
+    assert! ( super::encode_address( IpAddr::Ipv4Addr( 127, 0, 0, 1 ) ) == "2130706433" );
+    assert! ( super::encode_address( IpAddr::Ipv6Addr( 0, 0, 0, 0, 0, 0, 0, 1 ) ) == "::1" );
+    assert! ( !v4.is_passive( ) );
+    assert! ( passive.is_passive( ) );
+    assert! ( passive.token( ) == Some( "42" ) );
+    assert! ( passive.socket( ).is_none( ) );
+  }
+
+  #[test]
+  fn test_chat_loopback () {
+    let listener = super::DccListener::bind( IpAddr::Ipv4Addr( 127, 0, 0, 1 ) ).unwrap( );
+    let offer = listener.offer( IpAddr::Ipv4Addr( 127, 0, 0, 1 ) );
+    let ( tx, rx ) = mpsc::channel( );
+    Thread::spawn( move || {
+      let chat = listener.accept( Some( 5000 ) ).unwrap( );
+      let ( events, mut chat ) = chat.start( );
+      chat.send_line( "hello from the offerer" ).unwrap( );
+      match events.recv( ).unwrap( ) {
+        super::DccEvent::Line ( l ) => tx.send( l ).unwrap( ),
+        super::DccEvent::Closed ( _ ) => panic! ( "closed early" ),
+      }
+      chat.close( );
+    } );
+
+    let req = super::DccRequest::parse( offer.to_params( ).as_slice( ) ).unwrap( );
+    let chat = super::DccChat::connect( &req, Some( 5000 ) ).unwrap( );
+    let ( events, mut chat ) = chat.start( );
+    chat.send_line( "hello from the accepter" ).unwrap( );
+    match events.recv( ).unwrap( ) {
+      super::DccEvent::Line ( l ) => assert! ( l.as_slice( ) == "hello from the offerer" ),
+      super::DccEvent::Closed ( _ ) => panic! ( "closed early" ),
+    }
+    assert! ( rx.recv( ).unwrap( ).as_slice( ) == "hello from the accepter" );
+    match events.recv( ).unwrap( ) {
+      super::DccEvent::Closed ( _ ) => (),
+      super::DccEvent::Line ( _ )   => panic! ( "expected the session to close" ),
+    }
+  }
+
+  #[test]
+  fn test_passive_loopback () {
+    let local = IpAddr::Ipv4Addr( 127, 0, 0, 1 );
+    let request = super::passive_offer( local, "7" );
+    let incoming = super::DccRequest::parse( request.to_params( ).as_slice( ) ).unwrap( );
+    let listener = super::answer_passive( &incoming, local ).unwrap( );
+    let answer = listener.offer( local );
+    assert! ( answer.token( ) == Some( "7" ) );
+    Thread::spawn( move || {
+      let mut chat = listener.accept( Some( 5000 ) ).unwrap( );
+      chat.send_line( "passive works" ).unwrap( );
+    } );
+    let chat = super::DccChat::connect( &answer, Some( 5000 ) ).unwrap( );
+    let ( events, _ ) = chat.start( );
+    match events.recv( ).unwrap( ) {
+      super::DccEvent::Line ( l ) => assert! ( l.as_slice( ) == "passive works" ),
+      super::DccEvent::Closed ( _ ) => panic! ( "closed early" ),
+    }
+  }
+
+  #[test]
+  fn test_accept_timeout () {
+    let listener = super::DccListener::bind( IpAddr::Ipv4Addr( 127, 0, 0, 1 ) ).unwrap( );
+    assert! ( listener.accept( Some( 50 ) ).is_err( ) );
+  }
+}
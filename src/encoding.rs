@@ -0,0 +1,232 @@
+// import built in modules
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+
+/// `CP1252_HIGH` is what CP1252 maps 0x80-0x9F to. The five bytes CP1252
+/// leaves undefined map to the C1 controls of the same value, as Windows does,
+/// so no byte is lost.
+static CP1252_HIGH : [char; 32] = [
+  '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+  '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+  '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+  '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+/// `Encoding` is a character encoding used on the wire
+///
+/// # Options
+///
+/// `Utf8` - UTF-8
+/// `Latin1` - ISO-8859-1
+/// `Cp1252` - Windows-1252, Latin-1 with printable characters in 0x80-0x9F
+#[derive(PartialEq)]
+pub enum Encoding {
+  Utf8,
+  Latin1,
+  Cp1252,
+}
+
+impl Copy for Encoding {}
+
+impl Encoding {
+  /// `from_name` looks up an encoding by name, e.g. "utf-8" or "windows-1252"
+  pub fn from_name( name : &str ) -> Option < Encoding > {
+    match name.to_ascii_lowercase( ).as_slice( ) {
+      "utf-8" | "utf8"                             => Some( Encoding::Utf8 ),
+      "latin1" | "latin-1" | "iso-8859-1"          => Some( Encoding::Latin1 ),
+      "cp1252" | "windows-1252"                    => Some( Encoding::Cp1252 ),
+      _                                            => None,
+    }
+  }
+
+  /// `decode` turns bytes into text
+  ///
+  /// # Returns
+  ///
+  /// The text, or None if the bytes aren't valid UTF-8. Latin-1 and CP1252
+  /// can decode anything.
+  pub fn decode( &self, bytes : &[u8] ) -> Option < String > {
+    match *self {
+      Encoding::Utf8   => String::from_utf8( bytes.to_vec( ) ).ok( ),
+      Encoding::Latin1 => Some( bytes.iter( ).map( |b| *b as char ).collect( ) ),
+      Encoding::Cp1252 => Some( bytes.iter( ).map( |b| match *b {
+        0x80 ... 0x9F => CP1252_HIGH[( *b - 0x80 ) as usize],
+        _             => *b as char,
+      } ).collect( ) ),
+    }
+  }
+
+  /// `encode` turns text into bytes
+  ///
+  /// # Notes
+  ///
+  /// * Characters the encoding can't hold are sent as '?'.
+  pub fn encode( &self, text : &str ) -> Vec < u8 > {
+    match *self {
+      Encoding::Utf8   => text.as_bytes( ).to_vec( ),
+      Encoding::Latin1 => text.chars( ).map( |c| if ( c as u32 ) < 0x100 { c as u8 } else { b'?' } ).collect( ),
+      Encoding::Cp1252 => text.chars( ).map( |c| match c as u32 {
+        0x00 ... 0x7F | 0xA0 ... 0xFF => c as u8,
+        _                             => match CP1252_HIGH.iter( ).position( |h| *h == c ) {
+          Some ( n ) => 0x80 + n as u8,
+          None       => b'?',
+        },
+      } ).collect( ),
+    }
+  }
+}
+
+/// `Codec` decodes the lines a client receives and encodes the lines it sends
+///
+/// # Members
+///
+/// * `fallback` - encoding for lines that aren't valid UTF-8, or None to
+/// decode strictly, replacing the bad bytes with U+FFFD
+/// * `outgoing` - encoding for lines we send
+/// * `channels` - encodings for channels that don't use UTF-8, by lowercased
+/// name
+///
+/// # Notes
+///
+/// * The raw bytes of every line are kept on the Message, so nothing is lost
+/// whichever way the line was decoded.
+pub struct Codec {
+  pub fallback : Option < Encoding >,
+  pub outgoing : Encoding,
+  channels     : HashMap < String, Encoding >,
+}
+
+impl Clone for Codec {
+  fn clone( &self ) -> Codec {
+    Codec { fallback : self.fallback, outgoing : self.outgoing, channels : self.channels.clone( ) }
+  }
+}
+
+impl Codec {
+  /// `new` creates a codec that sends UTF-8 and reads UTF-8, falling back to
+  /// CP1252
+  pub fn new( ) -> Codec {
+    Codec { fallback : Some( Encoding::Cp1252 ), outgoing : Encoding::Utf8, channels : HashMap::new( ) }
+  }
+
+  /// `set_channel` sets the encoding of one channel, both ways
+  ///
+  /// # Arguments
+  ///
+  /// * `channel` - the channel
+  /// * `encoding` - its encoding, or None to go back to the defaults
+  pub fn set_channel( &mut self, channel : &str, encoding : Option < Encoding > ) {
+    let key = channel.to_ascii_lowercase( );
+    match encoding {
+      Some ( e ) => { self.channels.insert( key, e ); },
+      None       => { self.channels.remove( &key ); },
+    }
+  }
+
+  /// `channel` returns the encoding set for a channel, if any
+  pub fn channel( &self, channel : &str ) -> Option < Encoding > {
+    self.channels.get( &channel.to_ascii_lowercase( ) ).map( |e| *e )
+  }
+
+  /// `decode` turns a received line into text
+  ///
+  /// # Arguments
+  ///
+  /// * `line` - the line's bytes, without the line ending
+  pub fn decode( &self, line : &[u8] ) -> String {
+    match self.channel_of( line ) {
+      Some ( e ) => return e.decode( line ).unwrap_or_else( || String::from_utf8_lossy( line ).into_owned( ) ),
+      None       => (),
+    }
+    match ( Encoding::Utf8.decode( line ), self.fallback ) {
+      ( Some ( s ), _ )       => s,
+      ( None, Some ( e ) )    => e.decode( line ).unwrap( ),
+      ( None, None )          => {
+        irc_debug! ( "replacing bytes that aren't UTF-8 in a received line" );
+        String::from_utf8_lossy( line ).into_owned( )
+      },
+    }
+  }
+
+  /// `encode` turns a line we're sending into bytes
+  ///
+  /// # Arguments
+  ///
+  /// * `line` - the line, without the line ending
+  pub fn encode( &self, line : &str ) -> Vec < u8 > {
+    let encoding = self.channel_of( line.as_bytes( ) ).unwrap_or( self.outgoing );
+    encoding.encode( line )
+  }
+
+  /// `channel_of` returns the encoding of the first channel a line names
+  /// before its trailing parameter, if one is set
+  fn channel_of( &self, line : &[u8] ) -> Option < Encoding > {
+    if self.channels.is_empty( ) {
+      return None;
+    }
+
+    // the words before the trailing parameter are plain ASCII in any of our
+    // encodings, so Latin-1 reads them without guessing
+    let text = Encoding::Latin1.decode( line ).unwrap( );
+    let mut rest = text.as_slice( );
+    for prefix in [ "@", ":" ].iter( ) {
+      if rest.starts_with( *prefix ) {
+        rest = match rest.find( ' ' ) {
+          Some ( n ) => rest.slice_from( n + 1 ).trim_left( ),
+          None       => "",
+        };
+      }
+    }
+    let rest = match rest.find_str( " :" ) {
+      Some ( n ) => rest.slice_to( n ),
+      None       => rest,
+    };
+    for word in rest.split( ' ' ) {
+      match self.channel( word ) {
+        Some ( e ) => return Some( e ),
+        None       => (),
+      }
+    }
+    None
+  }
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[allow(unused_imports)]
+  use super::{Codec, Encoding};
+
+  #[test]
+  fn test_encodings () {
+    assert! ( Encoding::from_name( "Windows-1252" ) == Some( Encoding::Cp1252 ) );
+    assert! ( Encoding::from_name( "ebcdic" ).is_none( ) );
+    assert! ( Encoding::Utf8.decode( b"caf\xC3\xA9" ) == Some( "café".to_string( ) ) );
+    assert! ( Encoding::Utf8.decode( b"caf\xE9" ).is_none( ) );
+    assert! ( Encoding::Latin1.decode( b"caf\xE9" ) == Some( "café".to_string( ) ) );
+    assert! ( Encoding::Cp1252.decode( b"\x93hi\x94 \x80" ) == Some( "\u{201C}hi\u{201D} \u{20AC}".to_string( ) ) );
+    assert! ( Encoding::Cp1252.decode( b"\x81" ) == Some( "\u{0081}".to_string( ) ) );
+    assert! ( Encoding::Latin1.encode( "café \u{20AC}" ) == b"caf\xE9 ?".to_vec( ) );
+    assert! ( Encoding::Cp1252.encode( "café \u{20AC}" ) == b"caf\xE9 \x80".to_vec( ) );
+    assert! ( Encoding::Utf8.encode( "é" ) == b"\xC3\xA9".to_vec( ) );
+  }
+
+  #[test]
+  fn test_codec () {
+    let mut codec = Codec::new( );
+    assert! ( codec.decode( b":a!b@c PRIVMSG #rust :caf\xC3\xA9" ).as_slice( ).ends_with( "café" ) );
+    assert! ( codec.decode( b":a!b@c PRIVMSG #rust :\x93hi\x94" ).as_slice( ).ends_with( "\u{201C}hi\u{201D}" ) );
+
+    // strict decoding replaces what isn't UTF-8
+    codec.fallback = None;
+    assert! ( codec.decode( b":a!b@c PRIVMSG #rust :caf\xE9" ).as_slice( ).ends_with( "caf\u{FFFD}" ) );
+
+    // channels can have their own encoding, both ways
+    codec.set_channel( "#Vieux", Some( Encoding::Latin1 ) );
+    assert! ( codec.decode( b":a!b@c PRIVMSG #vieux :caf\xE9" ).as_slice( ).ends_with( "café" ) );
+    assert! ( codec.decode( b":a!b@c PRIVMSG #rust :#vieux caf\xC3\xA9" ).as_slice( ).ends_with( "café" ) );
+    assert! ( codec.encode( "PRIVMSG #vieux :café" ) == b"PRIVMSG #vieux :caf\xE9".to_vec( ) );
+    assert! ( codec.encode( "PRIVMSG #rust :café" ) == b"PRIVMSG #rust :caf\xC3\xA9".to_vec( ) );
+    codec.outgoing = Encoding::Cp1252;
+    assert! ( codec.encode( "PRIVMSG #rust :\u{2122}" ) == b"PRIVMSG #rust :\x99".to_vec( ) );
+  }
+}
@@ -1,9 +1,157 @@
 // use modules
+use std::ascii::AsciiExt;
 use std::collections;
 use std::str;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::{channel, Receiver, Sender};
 
+use mask;
 use message;
-use utils::debug;
+
+static NAME_PREFIXES : &'static str = "~&@%+"; // status prefixes in NAMES replies
+static MEMBER_SHARDS : usize        = 64;      // pieces the member map is split into
+
+/// `Member` is what we know about a user sharing a channel with us
+///
+/// # Members
+///
+/// * `nick` - the user's current nick
+/// * `user`, `host` - the user's hostmask, empty until we've seen it
+/// * `real` - the user's realname, from WHO
+/// * `account` - services account, None if logged out or unknown
+/// * `away` - whether the user is marked away
+pub struct Member {
+  pub nick    : String,
+  pub user    : String,
+  pub host    : String,
+  pub real    : String,
+  pub account : Option < String >,
+  pub away    : bool,
+}
+
+impl Clone for Member {
+  fn clone( &self ) -> Member {
+    Member {
+      nick    : self.nick.clone( ),
+      user    : self.user.clone( ),
+      host    : self.host.clone( ),
+      real    : self.real.clone( ),
+      account : self.account.clone( ),
+      away    : self.away,
+    }
+  }
+}
+
+impl Member {
+  /// `new` creates a member we only know the nick of
+  fn new( nick : &str ) -> Member {
+    Member {
+      nick    : nick.to_string( ),
+      user    : String::new( ),
+      host    : String::new( ),
+      real    : String::new( ),
+      account : None,
+      away    : false,
+    }
+  }
+
+  /// `from_prefix` creates a member from a message source
+  ///
+  /// # Arguments
+  ///
+  /// * `prefix` - the source, e.g. "nick!user@host", with or without a colon
+  pub fn from_prefix( prefix : &str ) -> Member {
+    let prefix = if prefix.starts_with( ":" ) { prefix.slice_from( 1 ) } else { prefix };
+    let nick = match prefix.find( '!' ) {
+      Some ( n ) => prefix.slice_to( n ),
+      None       => prefix,
+    };
+    let mut member = Member::new( nick );
+    set_hostmask( &mut member, prefix );
+    member
+  }
+
+  /// `hostmask` returns the member's nick!user@host
+  pub fn hostmask( &self ) -> String {
+    format! ( "{}!{}@{}", self.nick, self.user, self.host )
+  }
+}
+
+/// `Members` maps folded nicks to what we know about the users
+///
+/// # Members
+///
+/// * `shards` - the map, split by a hash of the key
+///
+/// # Notes
+///
+/// * Clones share the shards. A change copies only the shard it touches, and
+/// only while a clone still holds it, so a snapshot of a big network costs
+/// MEMBER_SHARDS pointers instead of a copy of every member.
+struct Members {
+  shards : Vec < Arc < collections::HashMap < String, Arc < Member > > > >,
+}
+
+impl Clone for Members {
+  fn clone( &self ) -> Members {
+    Members { shards : self.shards.clone( ) }
+  }
+}
+
+impl Members {
+  /// `new` creates an empty member map
+  fn new( ) -> Members {
+    Members { shards : range( 0, MEMBER_SHARDS ).map( |_| Arc::new( collections::HashMap::new( ) ) ).collect( ) }
+  }
+
+  /// `get` finds a member by key
+  fn get( &self, key : &str ) -> Option < &Member > {
+    self.shards[shard_of( key )].get( key ).map( |m| &**m )
+  }
+
+  /// `get_mut` finds a member by key for editing, adding it if it's new
+  ///
+  /// # Arguments
+  ///
+  /// * `key` - the folded nick
+  /// * `nick` - the nick as the server sent it, for a new member
+  fn get_mut( &mut self, key : &str, nick : &str ) -> &mut Member {
+    let shard = self.shards[shard_of( key )].make_unique( );
+    if !shard.contains_key( key ) {
+      shard.insert( key.to_string( ), Arc::new( Member::new( nick ) ) );
+    }
+    shard.get_mut( key ).unwrap( ).make_unique( )
+  }
+
+  /// `insert` adds a member under a key
+  fn insert( &mut self, key : String, member : Arc < Member > ) {
+    self.shards[shard_of( key.as_slice( ) )].make_unique( ).insert( key, member );
+  }
+
+  /// `remove` takes a member out of the map
+  fn remove( &mut self, key : &str ) -> Option < Arc < Member > > {
+    let n = shard_of( key );
+    if !self.shards[n].contains_key( key ) {
+      return None;
+    }
+    self.shards[n].make_unique( ).remove( key )
+  }
+
+  /// `drain` takes every member out of the map
+  fn drain( &mut self ) -> Vec < Arc < Member > > {
+    let mut all = Vec::new( );
+    for shard in self.shards.iter_mut( ) {
+      let old = ::std::mem::replace( shard, Arc::new( collections::HashMap::new( ) ) );
+      all.extend( old.values( ).map( |m| m.clone( ) ) );
+    }
+    all
+  }
+
+  /// `keys` lists the keys of every member
+  fn keys( &self ) -> Vec < String > {
+    self.shards.iter( ).flat_map( |s| s.keys( ) ).map( |k| k.clone( ) ).collect( )
+  }
+}
 
 /// `IrcInfo` contains general client information, including the current channel
 /// list
@@ -14,14 +162,27 @@ use utils::debug;
 /// * `user_name` - username of the client
 /// * `real_name` - realname of the client
 /// * `channels` - list of channels the client is currently in
+/// * `isupport` - ISUPPORT tokens the server advertised in RPL_ISUPPORT
+/// * `members` - users we've seen, by nick folded with the server's
+/// casemapping
+///
+/// # Notes
+///
+/// * Name lists, ISUPPORT tokens and members are shared between clones until
+/// one of them changes, so snapshots of a big network stay cheap. Each
+/// channel's name list is shared on its own, and members are shared in
+/// shards, so a change copies only the part it touches.
+/// * A NAMES reply still being collected isn't cloned.
 pub struct IrcInfo {
   pub nick_name : String,
   pub user_name : String,
   pub real_name : String,
 
   pub channels  : Vec < String >,
-  names         : collections::HashMap < String, Vec < String > >,
+  names         : collections::HashMap < String, Arc < Vec < String > > >,
   prep_names    : Vec < String >,
+  isupport      : Arc < collections::HashMap < String, String > >,
+  members       : Members,
 }
 
 impl Clone for IrcInfo {
@@ -33,6 +194,8 @@ impl Clone for IrcInfo {
       channels  : self.channels.clone( ),
       names     : self.names.clone( ),
       prep_names: Vec::new( ),
+      isupport  : self.isupport.clone( ),
+      members   : self.members.clone( ),
     }
   }
 }
@@ -50,6 +213,11 @@ impl IrcInfo {
   /// # Returns
   ///
   /// A new IrcInfo struct
+  ///
+  /// # Notes
+  ///
+  /// * `client::ClientBuilder` builds this for you from checked settings;
+  /// `gen` is kept for code that calls `Client::connect` directly.
   pub fn gen( nick : &str, user : &str, real : &str, chans : Vec < &str > ) -> IrcInfo {
     let mut cvec : Vec < String > = Vec::new();
     for chan in chans.iter( ) {
@@ -62,6 +230,8 @@ impl IrcInfo {
       channels  : cvec,
       names     : collections::HashMap::new( ),
       prep_names: Vec::new( ),
+      isupport  : Arc::new( collections::HashMap::new( ) ),
+      members   : Members::new( ),
     }
   }
 
@@ -72,7 +242,15 @@ impl IrcInfo {
   ///
   /// * `msg` - the raw message received from the server
   pub fn update_info( &mut self, msg : message::Message ) {
+    self.update_members( &msg );
+    let lists = self.names.len( );
     match msg.code.as_slice( ) {
+      // the welcome names the nick we registered with, which may be an
+      // alternate one
+      "001"  => match msg.param( 1 ) {
+        Some ( n ) => self.nick_name = n.to_string( ),
+        None       => (),
+      },
       // update nickname on NICK message
       "NICK" => {
         if msg.nick( ).unwrap_or( String::from_str( "" ) ) == self.nick_name {
@@ -103,18 +281,233 @@ impl IrcInfo {
           self.remove_from_channel( msg.param( 1 ).unwrap( ).to_string( ), msg.nick( ).unwrap_or( String::from_str( "" ) ) );
         }
       },
-      // remove channels on channel errors
+      // remove channels when we're kicked, or the kicked nick otherwise
+      "KICK" => {
+        let chan = msg.param( 1 ).unwrap_or( "" ).to_string( );
+        let nick = msg.param( 2 ).unwrap_or( "" ).to_string( );
+        if nick == self.nick_name {
+          match in_vec( &self.channels, chan.clone( ) ) {
+            Some( i ) => {
+              self.drop_channel_names( chan );
+              self.channels.remove( i );
+            },
+            None      => (),
+          }
+        } else {
+          self.remove_from_channel( chan, nick );
+        }
+      },
+      // remove channels on channel errors, which name the channel after our nick
       "403" | "405" | "437" | "471" | "473" | "474" | "475" | "476" => {
-        match in_vec( &self.channels, msg.param( 1 ).unwrap( ).to_string( ) ) {
+        match in_vec( &self.channels, msg.param( 2 ).unwrap_or( "" ).to_string( ) ) {
           Some( i ) => {
-            self.drop_channel_names( msg.param( 1 ).unwrap( ).to_string( ) );
+            self.drop_channel_names( msg.param( 2 ).unwrap( ).to_string( ) );
             self.channels.remove( i );
           },
           None      => (),
         }
       },
+      // remember what the server supports
+      "005"  => self.update_isupport( &msg ),
+      // collect NAMES replies and swap them in at the end
+      "353"  => self.prep_channel_names( msg.clone( ) ),
+      "366"  => self.set_channel_names( msg.param( 2 ).unwrap_or( "" ).to_string( ) ),
       _   => (),
     }
+
+    // forget users we no longer share a channel with
+    if self.names.len( ) < lists {
+      self.prune_members( None );
+    } else {
+      match msg.code.as_slice( ) {
+        "PART" => self.prune_members( msg.nick( ) ),
+        "KICK" => self.prune_members( msg.param( 2 ).map( |n| n.to_string( ) ) ),
+        _      => (),
+      }
+    }
+  }
+
+  /// `get_member` returns what we know about a user
+  ///
+  /// # Arguments
+  ///
+  /// * `nick` - nick of the user, without channel prefixes
+  ///
+  /// # Returns
+  ///
+  /// The user's details, or None if we haven't seen them
+  pub fn get_member( &self, nick : &str ) -> Option < &Member > {
+    self.members.get( self.fold( nick ).as_slice( ) )
+  }
+
+  /// `update_members` keeps hostmasks, accounts and away states up to date
+  ///
+  /// # Arguments
+  ///
+  /// * `msg` - the raw message received from the server
+  fn update_members( &mut self, msg : &message::Message ) {
+    let nick = msg.nick( ).unwrap_or( String::new( ) );
+    let key = self.fold( nick.as_slice( ) );
+    match msg.code.as_slice( ) {
+      "JOIN" | "AWAY" | "ACCOUNT" if nick.is_empty( ) => return,
+      "JOIN"    => {
+        let member = self.member_mut( nick.as_slice( ) );
+        match msg.source {
+          message::Source::Sender ( ref s ) => set_hostmask( member, s.as_slice( ) ),
+          message::Source::None             => (),
+        }
+        // extended-join adds the account and realname
+        match msg.param( 2 ) {
+          Some ( "*" )  => member.account = None,
+          Some ( acct ) => {
+            member.account = Some( acct.to_string( ) );
+            member.real = msg.param( 3 ).unwrap_or( "" ).to_string( );
+          },
+          None          => (),
+        }
+      },
+      "NICK"    => match self.members.remove( key.as_slice( ) ) {
+        Some ( mut member ) => {
+          member.make_unique( ).nick = msg.param( 1 ).unwrap_or( "" ).to_string( );
+          let key = self.fold( member.nick.as_slice( ) );
+          self.members.insert( key, member );
+        },
+        None                => (),
+      },
+      "QUIT"    => { self.members.remove( key.as_slice( ) ); },
+      // away-notify and account-notify
+      "AWAY"    => self.member_mut( nick.as_slice( ) ).away = msg.param( 1 ).is_some( ),
+      "ACCOUNT" => self.member_mut( nick.as_slice( ) ).account = match msg.param( 1 ) {
+        Some ( "*" ) | None => None,
+        Some ( acct )       => Some( acct.to_string( ) ),
+      },
+      // RPL_WHOREPLY: <chan> <user> <host> <server> <nick> <flags> :<hops> <real>
+      "352"     => {
+        let real = msg.param( 8 ).unwrap_or( "" );
+        let real = match real.find( ' ' ) {
+          Some ( n ) => real.slice_from( n + 1 ),
+          None       => "",
+        };
+        let member = self.member_mut( msg.param( 6 ).unwrap_or( "" ) );
+        member.user = msg.param( 3 ).unwrap_or( "" ).to_string( );
+        member.host = msg.param( 4 ).unwrap_or( "" ).to_string( );
+        member.away = msg.param( 7 ).unwrap_or( "" ).starts_with( "G" );
+        member.real = real.to_string( );
+      },
+      // RPL_WHOISACCOUNT, only for users we already know
+      "330"     => {
+        let who = msg.param( 2 ).unwrap_or( "" );
+        if self.get_member( who ).is_some( ) {
+          self.member_mut( who ).account = msg.param( 3 ).map( |a| a.to_string( ) );
+        }
+      },
+      // RPL_WHOSPCRPL for our own WHOX fields, see query::WHOX_FIELDS. The
+      // handler only passes on replies to our own queries.
+      "354"     => {
+        let member = self.member_mut( msg.param( 6 ).unwrap_or( "" ) );
+        member.user = msg.param( 4 ).unwrap_or( "" ).to_string( );
+        member.host = msg.param( 5 ).unwrap_or( "" ).to_string( );
+        member.away = msg.param( 7 ).unwrap_or( "" ).starts_with( "G" );
+        member.account = match msg.param( 8 ) {
+          Some ( "0" ) | None => None,
+          Some ( acct )       => Some( acct.to_string( ) ),
+        };
+        member.real = msg.param( 9 ).unwrap_or( "" ).to_string( );
+      },
+      _         => (),
+    }
+  }
+
+  /// `prune_members` forgets the users that aren't on any of our channels'
+  /// name lists
+  ///
+  /// # Arguments
+  ///
+  /// * `nick` - the user to check, or None to check everyone
+  fn prune_members( &mut self, nick : Option < String > ) {
+    let keys : Vec < String > = match nick {
+      Some ( n ) => vec![ self.fold( n.as_slice( ) ) ],
+      None       => self.members.keys( ),
+    };
+    let cm = self.casemapping( );
+    let shared : collections::HashSet < String > = self.names.values( )
+      .flat_map( |list| list.iter( ) )
+      .map( |name| name_key( name.as_slice( ), cm ) )
+      .collect( );
+    for key in keys.iter( ) {
+      if !shared.contains( key ) {
+        self.members.remove( key.as_slice( ) );
+      }
+    }
+  }
+
+  /// `member_mut` returns a user's details, adding the user if they're new
+  fn member_mut( &mut self, nick : &str ) -> &mut Member {
+    let key = self.fold( nick );
+    self.members.get_mut( key.as_slice( ), nick )
+  }
+
+  /// `casemapping` returns how the server folds the case of nicks
+  pub fn casemapping( &self ) -> mask::CaseMapping {
+    mask::CaseMapping::from_isupport( self.get_isupport( "CASEMAPPING" ) )
+  }
+
+  /// `fold` returns the key of a nick in the member map
+  fn fold( &self, nick : &str ) -> String {
+    self.casemapping( ).fold( nick )
+  }
+
+  /// `get_isupport` returns the value of an ISUPPORT token
+  ///
+  /// # Arguments
+  ///
+  /// * `key` - token to look up, e.g. "ELIST"
+  ///
+  /// # Returns
+  ///
+  /// The token's value, "" for tokens without one, or None if the server
+  /// didn't advertise it
+  pub fn get_isupport( &self, key : &str ) -> Option < &str > {
+    self.isupport.get( &key.to_ascii_uppercase( ) ).map( |v| v.as_slice( ) )
+  }
+
+  /// `update_isupport` records the tokens in an RPL_ISUPPORT
+  ///
+  /// # Arguments
+  ///
+  /// * `msg` - the 005 message received from the server
+  fn update_isupport( &mut self, msg : &message::Message ) {
+    let casemapping = self.casemapping( );
+    {
+      let isupport = self.isupport.make_unique( );
+
+      // the tokens sit between our nick and the trailing description
+      let params = msg.params.as_slice( );
+      let params = match params.find_str( " :" ) {
+        Some ( n ) => params.slice_to( n ),
+        None       => params,
+      };
+      for token in params.split( ' ' ).skip( 1 ).filter( |t| !t.is_empty( ) ) {
+        // "-TOKEN" withdraws a token advertised earlier
+        if token.starts_with( "-" ) {
+          isupport.remove( &token.slice_from( 1 ).to_ascii_uppercase( ) );
+          continue;
+        }
+        let ( key, value ) = match token.find( '=' ) {
+          Some ( n ) => ( token.slice_to( n ), token.slice_from( n + 1 ) ),
+          None       => ( token, "" ),
+        };
+        isupport.insert( key.to_ascii_uppercase( ), value.to_string( ) );
+      }
+    }
+
+    // members seen before the server named its casemapping are filed again
+    if self.casemapping( ) != casemapping {
+      for member in self.members.drain( ).into_iter( ) {
+        let key = self.fold( member.nick.as_slice( ) );
+        self.members.insert( key, member );
+      }
+    }
   }
 
   /// `prep_channel_names` parses a NAMES reply from the server and prepares to
@@ -146,17 +539,9 @@ impl IrcInfo {
       self.drop_channel_names( chan.clone( ) );
     }
 
-    // insert the name list into out map and get a pointer to it
-    self.names.insert( chan.clone( ), Vec::new( ) );
-    let chan_list = self.names.get_mut( &chan ).unwrap( );
-
-    // populate the vector
-    for name in self.prep_names.iter( ) {
-      chan_list.push( name.clone( ) );
-    }
-
-    // clear our prep list
-    self.prep_names.clear( );
+    // move the prepared names into our map
+    let list = ::std::mem::replace( &mut self.prep_names, Vec::new( ) );
+    self.names.insert( chan, Arc::new( list ) );
   }
 
   /// `get_channel_names` returns the name list of a particular channel.
@@ -169,7 +554,7 @@ impl IrcInfo {
   ///
   /// A String vector that contains the names of everyone on the given channel
   pub fn get_channel_names( &self, chan : String ) -> Option< &Vec < String > > {
-    self.names.get( &chan )
+    self.names.get( &chan ).map( |l| &**l )
   }
 
   /// `drop_channel_names` drops a channel's name list.
@@ -182,21 +567,12 @@ impl IrcInfo {
     let chan = strip_colon( &ch );
     
     // give a short debug message
-    let debugline = format! ( "dropping {} from name lists", chan );
-    debug::info( debugline.as_slice( ) );
-    
-    // get and clear the channel name list
-    match self.names.get_mut( &chan ) {
-      Some( list )  => list.clear( ),
-      None          => {
-        let eline = format! ( "name list '{}' does not exist", chan );
-        debug::warn( "drop name list", eline.as_slice( ) );
-        return;
-      },
-    }
+    irc_debug! ( "dropping {} from name lists", chan );
     
     // remove the name list from our name map
-    self.names.remove( &chan );
+    if self.names.remove( &chan ).is_none( ) {
+      irc_warn! ( "drop name list: name list '{}' does not exist", chan );
+    }
   }
 
   /// `add_to_channel` adds a nick to a channel's name list.
@@ -211,15 +587,13 @@ impl IrcInfo {
     let nick = strip_colon( &ni );
     
     // print a debug message
-    let debugline = format! ( "adding {} to {}'s name list", nick, chan );
-    debug::info( debugline.as_slice( ) );
+    irc_debug! ( "adding {} to {}'s name list", nick, chan );
     
     // get the channel name list and add the nick
     match self.names.get_mut( &chan ) {
-      Some( list )  => list.push( nick ),
+      Some( list )  => list.make_unique( ).push( nick ),
       None          => {
-        let eline = format! ( "name list '{}' does not exist", chan );
-        debug::warn( "add nick to name list", eline.as_slice( ) );
+        irc_warn! ( "add nick to name list: name list '{}' does not exist", chan );
       },
     }
   }
@@ -236,15 +610,13 @@ impl IrcInfo {
     let nick = strip_colon( &ni );
     
     // print a debug message
-    let debugline = format! ( "removing {} from {}'s name list", nick, chan );
-    debug::info( debugline.as_slice( ) );
+    irc_debug! ( "removing {} from {}'s name list", nick, chan );
     
     // get the channel name list
     let chan_list = match self.names.get_mut( &chan ) {
-      Some( list )  => list,
+      Some( list )  => list.make_unique( ),
       None          => {
-        let eline = format! ( "name list '{}' does not exist", chan );
-        debug::warn( "remove nick from name list", eline.as_slice( ) );
+        irc_warn! ( "remove nick from name list: name list '{}' does not exist", chan );
         return;
       },
     };
@@ -259,14 +631,229 @@ impl IrcInfo {
 
 impl Drop for IrcInfo {
   fn drop ( &mut self ) {
-    for (_,list) in self.names.iter_mut( ) {
-      list.clear( );
-    }
     self.names.clear( );
     self.channels.clear( );
   }
 }
 
+/// `InfoChange` is the part of the client info a message changed
+///
+/// # Options
+///
+/// `Nick` - our nick
+/// `Channels` - the channels we're in
+/// `Names` - the name list of this channel
+/// `Members` - hostmasks, accounts or away states of users
+/// `Isupport` - the server's ISUPPORT tokens
+#[derive(PartialEq)]
+pub enum InfoChange {
+  Nick,
+  Channels,
+  Names( String ),
+  Members,
+  Isupport,
+}
+
+impl Clone for InfoChange {
+  fn clone( &self ) -> InfoChange {
+    match *self {
+      InfoChange::Nick            => InfoChange::Nick,
+      InfoChange::Channels        => InfoChange::Channels,
+      InfoChange::Names( ref c )  => InfoChange::Names( c.clone( ) ),
+      InfoChange::Members         => InfoChange::Members,
+      InfoChange::Isupport        => InfoChange::Isupport,
+    }
+  }
+}
+
+/// `InfoEvent` tells subscribers the client info changed
+///
+/// # Members
+///
+/// * `change` - what changed
+/// * `info` - the info after the change
+pub struct InfoEvent {
+  pub change : InfoChange,
+  pub info   : Arc < IrcInfo >,
+}
+
+/// `InfoStore` holds the client info for the message handler and hands out
+/// snapshots of it
+///
+/// # Notes
+///
+/// * Snapshots never change. The handler updates a working copy in place and
+/// publishes a new snapshot of it when something changes, so readers only
+/// wait while a pointer is swapped, and the handler never waits on a reader.
+/// * NAMES and WHO replies are collected without publishing, and published
+/// once at RPL_ENDOFNAMES or RPL_ENDOFWHO, so a burst of replies from a big
+/// channel doesn't copy the info once per reply.
+/// * Only the message handler should call `update`.
+pub struct InfoStore {
+  current  : RwLock < Arc < IrcInfo > >,
+  working  : Mutex < Working >,
+  watchers : Mutex < Vec < Sender < InfoEvent > > >,
+}
+
+/// `Working` is the info the handler updates between snapshots
+///
+/// # Members
+///
+/// * `info` - the latest info
+/// * `pending` - whether WHO replies changed it since the last snapshot
+struct Working {
+  info    : IrcInfo,
+  pending : bool,
+}
+
+impl InfoStore {
+  /// `new` creates a store holding the given info
+  pub fn new( info : IrcInfo ) -> InfoStore {
+    InfoStore {
+      current  : RwLock::new( Arc::new( info.clone( ) ) ),
+      working  : Mutex::new( Working { info : info, pending : false } ),
+      watchers : Mutex::new( Vec::new( ) ),
+    }
+  }
+
+  /// `snapshot` returns the current info
+  pub fn snapshot( &self ) -> Arc < IrcInfo > {
+    self.current.read( ).unwrap( ).clone( )
+  }
+
+  /// `subscribe` returns a receiver for an event each time the info changes
+  pub fn subscribe( &self ) -> Receiver < InfoEvent > {
+    let ( tx, rx ) = channel( );
+    self.watchers.lock( ).unwrap( ).push( tx );
+    rx
+  }
+
+  /// `update` applies a message from the server to the info
+  ///
+  /// # Arguments
+  ///
+  /// * `msg` - the message received from the server
+  ///
+  /// # Returns
+  ///
+  /// What changed, or None if the message changed nothing subscribers can
+  /// see yet
+  pub fn update( &self, msg : &message::Message ) -> Option < InfoChange > {
+    let mut work = self.working.lock( ).unwrap( );
+
+    // the end of a WHO burst publishes everything it collected
+    if msg.code.as_slice( ) == "315" {
+      if !work.pending {
+        return None;
+      }
+      work.pending = false;
+      self.publish( &work.info, InfoChange::Members );
+      return Some( InfoChange::Members );
+    }
+
+    let change = match change_of( msg, work.info.nick_name.as_slice( ) ) {
+      Some ( c ) => c,
+      None       => return None,
+    };
+    work.info.update_info( msg.clone( ) );
+    match msg.code.as_slice( ) {
+      "353"               => return None,
+      "352" | "354"       => {
+        work.pending = true;
+        return None;
+      },
+      _                   => (),
+    }
+    work.pending = false;
+    self.publish( &work.info, change.clone( ) );
+    Some( change )
+  }
+
+  /// `publish` swaps in a snapshot of the info and tells subscribers
+  ///
+  /// # Arguments
+  ///
+  /// * `info` - the working info to take a snapshot of
+  /// * `change` - what changed since the last snapshot
+  fn publish( &self, info : &IrcInfo, change : InfoChange ) {
+    let next = Arc::new( info.clone( ) );
+    *self.current.write( ).unwrap( ) = next.clone( );
+    let mut watchers = self.watchers.lock( ).unwrap( );
+    watchers.retain( |w| w.send( InfoEvent { change : change.clone( ), info : next.clone( ) } ).is_ok( ) );
+  }
+}
+
+/// `change_of` works out what part of the info a message changes
+///
+/// # Arguments
+///
+/// * `msg` - the message received from the server
+/// * `nick` - our nick before the message
+///
+/// # Returns
+///
+/// What the message changes, or None if it doesn't touch the info
+fn change_of( msg : &message::Message, nick : &str ) -> Option < InfoChange > {
+  let ours = msg.nick( ).map_or( false, |n| n.as_slice( ) == nick );
+  let chan = msg.param( 1 ).unwrap_or( "" ).to_string( );
+  match msg.code.as_slice( ) {
+    "NICK"                 if ours => Some( InfoChange::Nick ),
+    "001"                  if msg.param( 1 ).map_or( false, |n| n != nick ) => Some( InfoChange::Nick ),
+    "JOIN" | "PART"        if ours => Some( InfoChange::Channels ),
+    "KICK"                 if msg.param( 2 ) == Some( nick ) => Some( InfoChange::Channels ),
+    "JOIN" | "PART" | "KICK"       => Some( InfoChange::Names( chan ) ),
+    "NICK" | "QUIT" | "AWAY" | "ACCOUNT" | "330" | "352" | "354" => Some( InfoChange::Members ),
+    "403" | "405" | "437" | "471" | "473" | "474" | "475" | "476" => Some( InfoChange::Channels ),
+    "005"                          => Some( InfoChange::Isupport ),
+    "353"                          => Some( InfoChange::Names( msg.param( 3 ).unwrap_or( "" ).to_string( ) ) ),
+    "366"                          => Some( InfoChange::Names( msg.param( 2 ).unwrap_or( "" ).to_string( ) ) ),
+    _                              => None,
+  }
+}
+
+/// `set_hostmask` fills in a member's user and host from a message source
+///
+/// # Arguments
+///
+/// * `member` - member to update
+/// * `source` - the message source, e.g. ":nick!user@host"
+fn set_hostmask( member : &mut Member, source : &str ) {
+  let bang = source.find( '!' );
+  let at = source.find( '@' );
+  match ( bang, at ) {
+    ( Some ( b ), Some ( a ) ) if b < a => {
+      member.user = source.slice( b + 1, a ).to_string( );
+      member.host = source.slice_from( a + 1 ).to_string( );
+    },
+    _                                   => (),
+  }
+}
+
+/// `name_key` returns the key of a NAMES entry in the member map
+///
+/// # Arguments
+///
+/// * `name` - the entry, e.g. "@Lancey" or "@+Lancey!l@example.org"
+///
+/// * `casemapping` - how the server folds nicks
+///
+/// # Returns
+///
+/// The folded nick, without status prefixes or hostmask
+fn name_key( name : &str, casemapping : mask::CaseMapping ) -> String {
+  let nick = name.trim_left_matches( |c : char| NAME_PREFIXES.contains_char( c ) );
+  let nick = match nick.find( '!' ) {
+    Some ( n ) => nick.slice_to( n ),
+    None       => nick,
+  };
+  casemapping.fold( nick )
+}
+
+/// `shard_of` picks the shard of the member map a key lives in
+fn shard_of( key : &str ) -> usize {
+  key.bytes( ).fold( 0, |h, b| ( h * 31 + b as usize ) % MEMBER_SHARDS )
+}
+
 /// `in_vec` checks if an element is in a vector
 ///
 /// # Arguments
@@ -1,12 +1,43 @@
 #![feature(slicing_syntax)]
 #![allow(unstable)]
+extern crate libc;
+#[macro_use]
+extern crate log;
+extern crate openssl;
 extern crate regex;
+extern crate "rustc-serialize" as rustc_serialize;
+extern crate time;
+extern crate toml;
+extern crate "yaml-rust" as yaml_rust;
+
+// logging macros must be declared before the modules that use them
+#[macro_use]
+mod utils;
 
 // identify custom modules
+pub mod chanlog;
+pub mod bouncer;
 pub mod client;
+pub mod commands;
+pub mod config;
 pub mod connection;
 pub mod ctcp;
+pub mod dcc;
+pub mod encoding;
 pub mod info;
+pub mod mask;
 pub mod message;
+pub mod mock;
+pub mod proxy;
+pub mod query;
 pub mod reader;
-mod utils;
\ No newline at end of file
+pub mod register;
+pub mod rejoin;
+pub mod responder;
+pub mod server;
+pub mod socket;
+pub mod throttle;
+pub mod tls;
+pub mod transfer;
+pub mod validate;
+pub mod writer;
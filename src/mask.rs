@@ -0,0 +1,604 @@
+// import built in modules
+use std::collections::HashMap;
+use std::old_io::net::ip::IpAddr;
+
+// import custom modules
+use info;
+
+/// `CaseMapping` is how a server folds the case of nicks and channels
+///
+/// # Options
+///
+/// `Ascii` - only A-Z are folded to a-z
+/// `Rfc1459` - A-Z, and []\~ are folded to {}|^
+/// `StrictRfc1459` - A-Z, and []\ are folded to {}|
+#[derive(PartialEq)]
+pub enum CaseMapping {
+  Ascii,
+  Rfc1459,
+  StrictRfc1459,
+}
+
+impl Copy for CaseMapping {}
+
+impl CaseMapping {
+  /// `from_isupport` picks the casemapping a server advertised
+  ///
+  /// # Arguments
+  ///
+  /// * `value` - the ISUPPORT CASEMAPPING value, None if it wasn't sent
+  ///
+  /// # Returns
+  ///
+  /// The matching CaseMapping. Servers that don't say use rfc1459.
+  pub fn from_isupport( value : Option < &str > ) -> CaseMapping {
+    match value {
+      Some ( "ascii" )          => CaseMapping::Ascii,
+      Some ( "strict-rfc1459" ) => CaseMapping::StrictRfc1459,
+      _                         => CaseMapping::Rfc1459,
+    }
+  }
+
+  /// `fold_char` folds the case of one character
+  pub fn fold_char( &self, c : char ) -> char {
+    match ( *self, c ) {
+      ( _, 'A'...'Z' )                    => ( c as u8 + 32 ) as char,
+      ( CaseMapping::Ascii, _ )           => c,
+      ( _, '[' )                          => '{',
+      ( _, ']' )                          => '}',
+      ( _, '\\' )                         => '|',
+      ( CaseMapping::Rfc1459, '~' )       => '^',
+      _                                   => c,
+    }
+  }
+
+  /// `fold` folds the case of a string, e.g. for comparing nicks
+  pub fn fold( &self, s : &str ) -> String {
+    s.chars( ).map( |c| self.fold_char( c ) ).collect( )
+  }
+}
+
+/// `Glob` is one compiled piece of a glob
+///
+/// # Options
+///
+/// `Lit( c )` - matches the character c
+/// `One` - matches any one character, written ?
+/// `Any` - matches any run of characters, written *
+#[derive(PartialEq)]
+enum Glob {
+  Lit ( char ),
+  One,
+  Any,
+}
+
+impl Copy for Glob {}
+
+/// `HostPattern` is the host part of a hostmask
+///
+/// # Options
+///
+/// `Glob( g )` - hosts matching the glob g
+/// `Cidr( addr, bits )` - IP hosts whose first `bits` bits equal addr's
+enum HostPattern {
+  Glob ( Vec < Glob > ),
+  Cidr ( Vec < u8 >, usize ),
+}
+
+/// `MaskKind` is what a mask matches against
+///
+/// # Options
+///
+/// `Hostmask( nick, user, host )` - a nick!user@host mask
+/// `Account( a )` - $a, users logged in to an account matching a, or to any
+/// account if a is None
+/// `Realname( r )` - $r, users with a realname matching r
+enum MaskKind {
+  Hostmask ( Vec < Glob >, Vec < Glob >, HostPattern ),
+  Account ( Option < Vec < Glob > > ),
+  Realname ( Vec < Glob > ),
+}
+
+/// `Mask` is a compiled ban or ignore mask
+///
+/// # Members
+///
+/// * `text` - the mask as it was written
+/// * `kind` - what the mask matches against
+/// * `negate` - set for extbans written $~x, which match when $x doesn't
+/// * `casemapping` - casemapping the mask was compiled with
+///
+/// # Notes
+///
+/// * Hostmasks are completed the way servers complete bans, so "nick" means
+/// "nick!*@*" and "user@host" means "*!user@host".
+/// * \ escapes the next character, so "\*" matches a literal *.
+/// * A host part like "192.168.0.0/16" or "2001:db8::/32" is matched as a
+/// CIDR range against hosts that are IP addresses.
+pub struct Mask {
+  pub text    : String,
+
+  kind        : MaskKind,
+  negate      : bool,
+  casemapping : CaseMapping,
+}
+
+impl Mask {
+  /// `parse` compiles a mask
+  ///
+  /// # Arguments
+  ///
+  /// * `text` - a hostmask such as "*!*@*.example.com", or an extban such
+  /// as "$a:account", "$~a" or "$r:*bot*"
+  /// * `casemapping` - casemapping of the server the mask is used on
+  ///
+  /// # Returns
+  ///
+  /// The compiled mask, or why it couldn't be compiled
+  pub fn parse( text : &str, casemapping : CaseMapping ) -> Result < Mask, String > {
+    if text.is_empty( ) {
+      return Err( String::from_str( "empty mask" ) );
+    }
+    let glob = |s : &str| compile( s, casemapping );
+
+    let ( kind, negate ) = if text.starts_with( "$" ) {
+      let ext = text.slice_from( 1 );
+      let ( negate, ext ) = if ext.starts_with( "~" ) { ( true, ext.slice_from( 1 ) ) } else { ( false, ext ) };
+      let ( name, arg ) = match ext.find( ':' ) {
+        Some ( n ) => ( ext.slice_to( n ), Some( ext.slice_from( n + 1 ) ) ),
+        None       => ( ext, None ),
+      };
+      let kind = match ( name, arg ) {
+        ( "a", None )       => MaskKind::Account( None ),
+        ( "a", Some ( a ) ) => MaskKind::Account( Some( glob( a ) ) ),
+        ( "r", Some ( r ) ) => MaskKind::Realname( glob( r ) ),
+        _                   => return Err( format! ( "unsupported extban '{}'", text ) ),
+      };
+      ( kind, negate )
+    } else {
+      // fill in the parts that were left out
+      let ( nick, rest ) = match text.find( '!' ) {
+        Some ( n )                            => ( text.slice_to( n ), text.slice_from( n + 1 ) ),
+        None if text.contains_char( '@' )     => ( "*", text ),
+        None                                  => ( text, "*" ),
+      };
+      let ( user, host ) = match rest.find( '@' ) {
+        Some ( n ) => ( rest.slice_to( n ), rest.slice_from( n + 1 ) ),
+        None       => ( rest, "*" ),
+      };
+      if nick.is_empty( ) || user.is_empty( ) || host.is_empty( ) {
+        return Err( format! ( "incomplete hostmask '{}'", text ) );
+      }
+      let host = match parse_cidr( host ) {
+        Some ( ( addr, bits ) ) => HostPattern::Cidr( addr, bits ),
+        None                    => HostPattern::Glob( glob( host ) ),
+      };
+      ( MaskKind::Hostmask( glob( nick ), glob( user ), host ), false )
+    };
+
+    Ok( Mask {
+      text        : text.to_string( ),
+      kind        : kind,
+      negate      : negate,
+      casemapping : casemapping,
+    } )
+  }
+
+  /// `matches` checks a user against the mask
+  ///
+  /// # Arguments
+  ///
+  /// * `user` - the user's details, as tracked in the client info
+  pub fn matches( &self, user : &info::Member ) -> bool {
+    let cm = self.casemapping;
+    let found = match self.kind {
+      MaskKind::Hostmask ( ref n, ref u, ref h ) =>
+        glob_tokens( n.as_slice( ), user.nick.as_slice( ), cm ) &&
+        glob_tokens( u.as_slice( ), user.user.as_slice( ), cm ) &&
+        match *h {
+          HostPattern::Glob ( ref g )         => glob_tokens( g.as_slice( ), user.host.as_slice( ), cm ),
+          HostPattern::Cidr ( ref a, bits )   => match ip_bytes( user.host.as_slice( ) ) {
+            Some ( ref ip ) if ip.len( ) == a.len( ) => prefix_eq( a.as_slice( ), ip.as_slice( ), bits ),
+            _                                         => false,
+          },
+        },
+      MaskKind::Account ( ref a )                => match ( a, &user.account ) {
+        ( &Some ( ref g ), &Some ( ref acct ) ) => glob_tokens( g.as_slice( ), acct.as_slice( ), cm ),
+        ( &None, &Some ( _ ) )                  => true,
+        _                                       => false,
+      },
+      MaskKind::Realname ( ref r )               => glob_tokens( r.as_slice( ), user.real.as_slice( ), cm ),
+    };
+    found != self.negate
+  }
+
+  /// `literal` returns the folded nick!user@host if the mask has no
+  /// wildcards, so it can be looked up instead of matched
+  fn literal( &self ) -> Option < String > {
+    let lit = |g : &Vec < Glob >| -> Option < String > {
+      let mut s = String::new( );
+      for t in g.iter( ) {
+        match *t {
+          Glob::Lit ( c ) => s.push( c ),
+          _               => return None,
+        }
+      }
+      Some( s )
+    };
+    match self.kind {
+      MaskKind::Hostmask ( ref n, ref u, HostPattern::Glob ( ref h ) ) =>
+        match ( lit( n ), lit( u ), lit( h ) ) {
+          ( Some ( n ), Some ( u ), Some ( h ) ) => Some( format! ( "{}!{}@{}", n, u, h ) ),
+          _                                      => None,
+        },
+      _                                                                => None,
+    }
+  }
+}
+
+/// `MaskSet` is a list of masks compiled for matching many users quickly, such
+/// as a ban or ignore list
+///
+/// # Members
+///
+/// * `casemapping` - casemapping the masks are compiled with
+/// * `masks` - masks with wildcards, extbans and CIDR ranges
+/// * `exact` - masks without wildcards by their folded hostmask
+///
+/// # Notes
+///
+/// * Masks without wildcards are looked up in a map, so only the rest have to
+/// be matched one by one.
+/// * Masks written differently can fold to the same hostmask, e.g.
+/// "Nick!u@h" and "nick!u@h", so each key keeps all of them in the order they
+/// were added.
+pub struct MaskSet {
+  casemapping : CaseMapping,
+  masks       : Vec < Mask >,
+  exact       : HashMap < String, Vec < String > >,
+}
+
+impl MaskSet {
+  /// `new` creates an empty set
+  ///
+  /// # Arguments
+  ///
+  /// * `casemapping` - casemapping of the server the masks are used on
+  pub fn new( casemapping : CaseMapping ) -> MaskSet {
+    MaskSet {
+      casemapping : casemapping,
+      masks       : Vec::new( ),
+      exact       : HashMap::new( ),
+    }
+  }
+
+  /// `add` compiles a mask and adds it to the set
+  ///
+  /// # Returns
+  ///
+  /// Why the mask couldn't be compiled, if it couldn't
+  pub fn add( &mut self, text : &str ) -> Result < ( ), String > {
+    if self.contains( text ) {
+      return Ok( ( ) );
+    }
+    let mask = try! ( Mask::parse( text, self.casemapping ) );
+    match mask.literal( ) {
+      Some ( l ) => {
+        if !self.exact.contains_key( &l ) {
+          self.exact.insert( l.clone( ), Vec::new( ) );
+        }
+        self.exact.get_mut( &l ).unwrap( ).push( mask.text );
+      },
+      None       => self.masks.push( mask ),
+    }
+    Ok( ( ) )
+  }
+
+  /// `remove` removes a mask, written the same way it was added
+  ///
+  /// # Returns
+  ///
+  /// true if the mask was in the set
+  pub fn remove( &mut self, text : &str ) -> bool {
+    let before = self.len( );
+    self.masks.retain( |m| m.text.as_slice( ) != text );
+    let key = self.exact.iter( )
+      .find( |&( _, ts )| ts.iter( ).any( |t| t.as_slice( ) == text ) )
+      .map( |( k, _ )| k.clone( ) );
+    match key {
+      Some ( k ) => {
+        let empty = {
+          let texts = self.exact.get_mut( &k ).unwrap( );
+          texts.retain( |t| t.as_slice( ) != text );
+          texts.is_empty( )
+        };
+        if empty {
+          self.exact.remove( &k );
+        }
+      },
+      None       => (),
+    }
+    self.len( ) != before
+  }
+
+  /// `contains` checks whether a mask is in the set, written the same way it
+  /// was added
+  pub fn contains( &self, text : &str ) -> bool {
+    self.masks.iter( ).any( |m| m.text.as_slice( ) == text ) ||
+      self.exact.values( ).any( |ts| ts.iter( ).any( |t| t.as_slice( ) == text ) )
+  }
+
+  /// `len` returns the number of masks in the set
+  pub fn len( &self ) -> usize {
+    self.masks.len( ) + self.exact.values( ).fold( 0, |n, ts| n + ts.len( ) )
+  }
+
+  /// `list` returns the masks in the set as they were written
+  pub fn list( &self ) -> Vec < String > {
+    let mut list : Vec < String > = self.exact.values( ).flat_map( |ts| ts.iter( ) ).map( |t| t.clone( ) ).collect( );
+    list.extend( self.masks.iter( ).map( |m| m.text.clone( ) ) );
+    list
+  }
+
+  /// `set_casemapping` recompiles the set for another casemapping, e.g. once
+  /// the server has sent its ISUPPORT
+  pub fn set_casemapping( &mut self, casemapping : CaseMapping ) {
+    if casemapping == self.casemapping {
+      return;
+    }
+    let list = self.list( );
+    self.casemapping = casemapping;
+    self.masks.clear( );
+    self.exact.clear( );
+    for text in list.iter( ) {
+      let _ = self.add( text.as_slice( ) );
+    }
+  }
+
+  /// `matching` finds a mask in the set that matches a user
+  ///
+  /// # Arguments
+  ///
+  /// * `user` - the user's details, as tracked in the client info
+  ///
+  /// # Returns
+  ///
+  /// The first matching mask as it was written, or None
+  pub fn matching( &self, user : &info::Member ) -> Option < &str > {
+    let hostmask = self.casemapping.fold( user.hostmask( ).as_slice( ) );
+    match self.exact.get( &hostmask ) {
+      Some ( ts ) => return Some( ts[0].as_slice( ) ),
+      None        => (),
+    }
+    self.masks.iter( ).find( |m| m.matches( user ) ).map( |m| m.text.as_slice( ) )
+  }
+
+  /// `matches` checks whether any mask in the set matches a user
+  pub fn matches( &self, user : &info::Member ) -> bool {
+    self.matching( user ).is_some( )
+  }
+}
+
+/// `glob_match` matches text against a case insensitive glob where * matches
+/// any run of characters, ? matches one character and \ escapes the next
+pub fn glob_match( glob : &str, text : &str ) -> bool {
+  glob_tokens( compile( glob, CaseMapping::Ascii ).as_slice( ), text, CaseMapping::Ascii )
+}
+
+/// `compile` turns a glob into folded tokens
+fn compile( glob : &str, casemapping : CaseMapping ) -> Vec < Glob > {
+  let mut tokens = Vec::new( );
+  let mut escaped = false;
+  for c in glob.chars( ) {
+    let token = match c {
+      _ if escaped  => Glob::Lit( casemapping.fold_char( c ) ),
+      '\\'          => {
+        escaped = true;
+        continue;
+      },
+      '*'           => Glob::Any,
+      '?'           => Glob::One,
+      _             => Glob::Lit( casemapping.fold_char( c ) ),
+    };
+    escaped = false;
+    // runs of * match the same as one
+    if !( token == Glob::Any && tokens.last( ) == Some( &Glob::Any ) ) {
+      tokens.push( token );
+    }
+  }
+  // a trailing \ is taken literally
+  if escaped {
+    tokens.push( Glob::Lit( casemapping.fold_char( '\\' ) ) );
+  }
+  tokens
+}
+
+/// `glob_tokens` matches text against compiled tokens, backtracking to the
+/// last * on a mismatch
+fn glob_tokens( g : &[Glob], text : &str, casemapping : CaseMapping ) -> bool {
+  let t : Vec < char > = text.chars( ).map( |c| casemapping.fold_char( c ) ).collect( );
+  let ( mut gi, mut ti ) = ( 0, 0 );
+  let mut star : Option < ( usize, usize ) > = None;
+  while ti < t.len( ) {
+    let step = gi < g.len( ) && match g[gi] {
+      Glob::One       => true,
+      Glob::Lit ( c ) => c == t[ti],
+      Glob::Any       => false,
+    };
+    if step {
+      gi += 1;
+      ti += 1;
+    } else if gi < g.len( ) && g[gi] == Glob::Any {
+      star = Some( ( gi, ti ) );
+      gi += 1;
+    } else {
+      match star {
+        Some ( ( sg, st ) ) => {
+          gi = sg + 1;
+          ti = st + 1;
+          star = Some( ( sg, st + 1 ) );
+        },
+        None                => return false,
+      }
+    }
+  }
+  while gi < g.len( ) && g[gi] == Glob::Any {
+    gi += 1;
+  }
+  gi == g.len( )
+}
+
+/// `parse_cidr` parses a host part such as "10.0.0.0/8"
+///
+/// # Returns
+///
+/// The address bytes and prefix length, or None if the host isn't a range
+fn parse_cidr( host : &str ) -> Option < ( Vec < u8 >, usize ) > {
+  let slash = match host.find( '/' ) {
+    Some ( n ) => n,
+    None       => return None,
+  };
+  let addr = match ip_bytes( host.slice_to( slash ) ) {
+    Some ( a ) => a,
+    None       => return None,
+  };
+  match host.slice_from( slash + 1 ).parse::< usize >( ).ok( ) {
+    Some ( bits ) if bits <= addr.len( ) * 8 => Some( ( addr, bits ) ),
+    _                                        => None,
+  }
+}
+
+/// `ip_bytes` parses an IPv4 or IPv6 address into its bytes
+fn ip_bytes( host : &str ) -> Option < Vec < u8 > > {
+  match host.parse::< IpAddr >( ).ok( ) {
+    Some ( IpAddr::Ipv4Addr ( a, b, c, d ) )            => Some( vec![ a, b, c, d ] ),
+    Some ( IpAddr::Ipv6Addr ( a, b, c, d, e, f, g, h ) ) => {
+      let mut bytes = Vec::new( );
+      for part in [ a, b, c, d, e, f, g, h ].iter( ) {
+        bytes.push( ( *part >> 8 ) as u8 );
+        bytes.push( ( *part & 0xff ) as u8 );
+      }
+      Some( bytes )
+    },
+    None                                                => None,
+  }
+}
+
+/// `prefix_eq` checks whether the first `bits` bits of two addresses are equal
+fn prefix_eq( a : &[u8], b : &[u8], bits : usize ) -> bool {
+  let whole = bits / 8;
+  if a.slice_to( whole ) != b.slice_to( whole ) {
+    return false;
+  }
+  let rest = bits % 8;
+  if rest == 0 {
+    return true;
+  }
+  let mask = 0xffu8 << ( 8 - rest );
+  ( a[whole] & mask ) == ( b[whole] & mask )
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[allow(unused_imports)]
+  use info::Member;
+  #[allow(unused_imports)]
+  use super::{CaseMapping, Mask, MaskSet};
+
+  #[allow(dead_code)]
+  fn member( prefix : &str, account : Option < &str > ) -> Member {
+    let mut m = Member::from_prefix( prefix );
+    m.account = account.map( |a| a.to_string( ) );
+    m.real = String::from_str( "Lancey Real" );
+    m
+  }
+
+  #[test]
+  fn test_glob () {
+    assert! ( super::glob_match( "*!*@*.example", "Lancey!l@host.example" ) );
+    assert! ( super::glob_match( "lan?ey!*", "Lancey!l@h" ) );
+    assert! ( !super::glob_match( "*!*@admin", "Lancey!l@h" ) );
+    assert! ( super::glob_match( "a\\*b", "a*b" ) );
+    assert! ( !super::glob_match( "a\\*b", "axb" ) );
+    assert! ( super::glob_match( "***", "" ) );
+  }
+
+  #[test]
+  fn test_casemapping () {
+    let rfc = CaseMapping::from_isupport( None );
+    assert! ( rfc.fold( "Nick[A]~" ) == "nick{a}^" );
+    assert! ( CaseMapping::from_isupport( Some( "strict-rfc1459" ) ).fold( "[~]" ) == "{~}" );
+    assert! ( CaseMapping::from_isupport( Some( "ascii" ) ).fold( "[A]" ) == "[a]" );
+
+    let mask = Mask::parse( "nick[away]", rfc ).ok( ).unwrap( );
+    assert! ( mask.matches( &member( "NICK{AWAY}!u@h", None ) ) );
+  }
+
+  #[test]
+  fn test_hostmasks () {
+    let cm = CaseMapping::Rfc1459;
+    let m = member( "Lancey!lancey@host.example.com", None );
+    assert! ( Mask::parse( "*!*@*.example.com", cm ).ok( ).unwrap( ).matches( &m ) );
+    assert! ( Mask::parse( "lancey", cm ).ok( ).unwrap( ).matches( &m ) );
+    assert! ( Mask::parse( "lancey@*.com", cm ).ok( ).unwrap( ).matches( &m ) );
+    assert! ( !Mask::parse( "*!root@*", cm ).ok( ).unwrap( ).matches( &m ) );
+    assert! ( Mask::parse( "!u@h", cm ).is_err( ) );
+    assert! ( Mask::parse( "$z:what", cm ).is_err( ) );
+  }
+
+  #[test]
+  fn test_cidr () {
+    let cm = CaseMapping::Rfc1459;
+    let v4 = member( "a!b@192.168.12.34", None );
+    let v6 = member( "a!b@2001:db8::1", None );
+    assert! ( Mask::parse( "*!*@192.168.0.0/16", cm ).ok( ).unwrap( ).matches( &v4 ) );
+    assert! ( Mask::parse( "*!*@192.168.12.32/28", cm ).ok( ).unwrap( ).matches( &v4 ) );
+    assert! ( !Mask::parse( "*!*@192.168.12.0/27", cm ).ok( ).unwrap( ).matches( &v4 ) );
+    assert! ( !Mask::parse( "*!*@192.168.0.0/16", cm ).ok( ).unwrap( ).matches( &v6 ) );
+    assert! ( Mask::parse( "*!*@2001:db8::/32", cm ).ok( ).unwrap( ).matches( &v6 ) );
+  }
+
+  #[test]
+  fn test_extbans () {
+    let cm = CaseMapping::Rfc1459;
+    let logged_in = member( "a!b@c", Some( "lancey" ) );
+    let guest = member( "a!b@c", None );
+    let any = Mask::parse( "$a", cm ).ok( ).unwrap( );
+    assert! ( any.matches( &logged_in ) && !any.matches( &guest ) );
+    let none = Mask::parse( "$~a", cm ).ok( ).unwrap( );
+    assert! ( !none.matches( &logged_in ) && none.matches( &guest ) );
+    assert! ( Mask::parse( "$a:LAN*", cm ).ok( ).unwrap( ).matches( &logged_in ) );
+    assert! ( Mask::parse( "$r:*real", cm ).ok( ).unwrap( ).matches( &guest ) );
+  }
+
+  #[test]
+  fn test_mask_set () {
+    let mut set = MaskSet::new( CaseMapping::Ascii );
+    assert! ( set.add( "Spammer!spam@spam.example" ).is_ok( ) );
+    assert! ( set.add( "*!*@*.evil" ).is_ok( ) );
+    assert! ( set.add( "$a:troll" ).is_ok( ) );
+    assert! ( set.add( "$q" ).is_err( ) );
+    assert! ( set.len( ) == 3 );
+
+    assert! ( set.matching( &member( "spammer!SPAM@spam.example", None ) ) == Some( "Spammer!spam@spam.example" ) );
+    assert! ( set.matches( &member( "x!y@host.evil", None ) ) );
+    assert! ( set.matches( &member( "x!y@z", Some( "troll" ) ) ) );
+    assert! ( !set.matches( &member( "x!y@z", None ) ) );
+
+    // rfc1459 folds [] to {}
+    assert! ( set.add( "nick[1]" ).is_ok( ) );
+    assert! ( !set.matches( &member( "nick{1}!u@h", None ) ) );
+    set.set_casemapping( CaseMapping::Rfc1459 );
+    assert! ( set.matches( &member( "nick{1}!u@h", None ) ) );
+
+    assert! ( set.remove( "*!*@*.evil" ) && set.remove( "Spammer!spam@spam.example" ) );
+    assert! ( !set.remove( "*!*@*.evil" ) && set.len( ) == 2 );
+
+    // masks that fold to the same hostmask are kept apart
+    assert! ( set.add( "Twin!u@h" ).is_ok( ) && set.add( "twin!U@H" ).is_ok( ) );
+    assert! ( set.len( ) == 4 && set.contains( "twin!U@H" ) );
+    assert! ( set.remove( "Twin!u@h" ) );
+    assert! ( set.matching( &member( "TWIN!u@h", None ) ) == Some( "twin!U@H" ) );
+    assert! ( set.remove( "twin!U@H" ) && set.len( ) == 2 );
+  }
+}
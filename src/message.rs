@@ -1,15 +1,14 @@
 use regex::Regex;
 
-use utils::debug;
-
 pub type TPARAMINDEX              = usize;
 
 static PARAM_INDEX  : TPARAMINDEX = 1;  // index of the first msg parameter
 static PARAM_TGROUP : TPARAMINDEX = 1;  // capture group for trailing param
 static PARAM_PGROUP : TPARAMINDEX = 2;  // capture group for all other params
-static PARSE_SOURCE : TPARAMINDEX = 1;  // capture group of source in parse()
-static PARSE_CODE   : TPARAMINDEX = 2;  // capture group of code in parse()
-static PARSE_PARAMS : TPARAMINDEX = 3;  // capture group of params in parse()
+static PARSE_TAGS   : TPARAMINDEX = 1;  // capture group of tags in parse()
+static PARSE_SOURCE : TPARAMINDEX = 2;  // capture group of source in parse()
+static PARSE_CODE   : TPARAMINDEX = 3;  // capture group of code in parse()
+static PARSE_PARAMS : TPARAMINDEX = 4;  // capture group of params in parse()
 static TARGET_STD   : TPARAMINDEX = 1;  // index of target for a standard cmd
 static TARGET_KICK  : TPARAMINDEX = 2;  // index of target for a kick cmd
 static TARGET_OTHER : TPARAMINDEX = 1;  // index of target for other msg codes
@@ -52,16 +51,21 @@ impl Copy for Direction {}
 /// # Members
 ///
 /// `dir` - the direction of the message flow
+/// `tags` - the IRCv3 message tags, without the leading @
 /// `source` - the source of this message
 /// `code` - the code associated with the message action
 /// `params` - the message parameters
 /// `raw` - the original message without formatting and parsing
+/// `bytes` - the line exactly as it was received, before decoding, or None for
+/// messages we made
 pub struct Message {
   pub dir     : Direction,
+  pub tags    : Option < String >,
   pub source  : Source,
   pub code    : String,
   pub params  : String,
   pub raw     : String,
+  pub bytes   : Option < Vec < u8 > >,
 }
 
 impl Message {
@@ -83,10 +87,12 @@ impl Message {
   pub fn new( source : Source, code : &str, params : &str ) -> Message {
     Message {
       dir     : Direction::Outgoing,  // new is designed for outgoing messages
+      tags    : None,
       source  : source.clone( ),
       code    : code.to_string( ),
       params  : params.to_string( ),
       raw     : raw_from_data( source, code, params ),
+      bytes   : None,
     }
   }
 
@@ -105,10 +111,10 @@ impl Message {
   /// - The direction of a message created by `parse` is always `Incoming`
   pub fn parse( msg : &str ) -> Option < Message > {
     // set up our regex
-    let re      = match Regex::new( r"^(:\S+)?\s*(\S+)\s+(.*)\r?$" ) {
+    let re      = match Regex::new( r"^(?:@(\S+)\s+)?(:\S+)?\s*(\S+)\s+(.*)\r?$" ) {
       Ok ( re ) => re,
       Err( e  ) => {
-        debug::err( "creating message parser", e.msg );
+        irc_error! ( "creating message parser: {}", e.msg );
         return None;
       },
     };
@@ -118,6 +124,7 @@ impl Message {
       Some ( cap )  => {
         Some( Message {
           dir     : Direction::Incoming,
+          tags    : cap.at( PARSE_TAGS ).map( |t| t.to_string( ) ),
           source  : match cap.at( PARSE_SOURCE ) {
             None        => Source::None,
             Some( src ) => Source::Sender( src.to_string( ) ),
@@ -131,6 +138,7 @@ impl Message {
             Some( prm ) => prm.to_string( ),
           },
           raw     : msg.to_string( ),
+          bytes   : None,
         } )
       },
       None          => None,
@@ -151,10 +159,12 @@ impl Message {
     let params = format! ( "{} :{}", target, message );
     Message {
       dir     : Direction::Outgoing,
+      tags    : None,
       source  : Source::None,
       code    : String::from_str( "PRIVMSG" ),
       params  : params.clone( ),
       raw     : raw_from_data( Source::None, "PRIVMSG", params.as_slice( ) ),
+      bytes   : None,
     }
   }
 
@@ -194,7 +204,7 @@ impl Message {
         let re = match Regex::new( r":([\w]+)!?" ) {
           Ok ( re ) => re,
           Err ( e ) => {
-            debug::err( "creating nick parser", e.msg );
+            irc_error! ( "creating nick parser: {}", e.msg );
             return None;
           },
         };
@@ -225,7 +235,7 @@ impl Message {
     let re = match Regex::new( r":([\S ]*)|(\S+)" ) {
       Ok ( re ) => re,
       Err( e  ) => {
-        debug::err( "creating msg parameter parser", e.msg );
+        irc_error! ( "creating msg parameter parser: {}", e.msg );
         return None;
       },
     };
@@ -250,10 +260,12 @@ impl Message {
   pub fn pong( &self ) -> Message {
     Message {
       dir     : Direction::Outgoing,
+      tags    : None,
       source  : Source::None,
       code    : "PONG".to_string( ),
       params  : self.params.clone( ),
       raw     : raw_from_data( Source::None, "PONG", self.params.as_slice( ) ),
+      bytes   : None,
     }
   }
 
@@ -273,6 +285,33 @@ impl Message {
     }
   }
 
+  /// `tag` gets the value of an IRCv3 message tag
+  ///
+  /// # Arguments
+  ///
+  /// `key` - name of the tag, e.g. "label" or "time"
+  ///
+  /// # Returns
+  ///
+  /// The unescaped value of the tag, an empty String if the tag has no value,
+  /// or None if the message doesn't carry the tag
+  pub fn tag( &self, key : &str ) -> Option < String > {
+    let tags = match self.tags {
+      Some ( ref t ) => t,
+      None           => return None,
+    };
+    for tag in tags.as_slice( ).split( ';' ) {
+      let ( name, value ) = match tag.find( '=' ) {
+        Some ( n ) => ( tag.slice_to( n ), tag.slice_from( n + 1 ) ),
+        None       => ( tag, "" ),
+      };
+      if name == key {
+        return Some( unescape_tag( value ) );
+      }
+    }
+    None
+  }
+
   /// `trailing` gets the trailing parameter of a message (the last one)
   ///
   /// # Returns
@@ -282,7 +321,7 @@ impl Message {
     let re = match Regex::new( r":([\S ]*)" ) {
       Ok ( re ) => re,
       Err( e  ) => {
-        debug::err( "creating msg trailing parser", e.msg.as_slice( ) );
+        irc_error! ( "creating msg trailing parser: {}", e.msg );
         return None;
       },
     };
@@ -297,12 +336,35 @@ impl Clone for Message {
   fn clone ( &self ) -> Message {
     Message {
       dir     : self.dir,
+      tags    : self.tags.clone( ),
       source  : self.source.clone( ),
       code    : self.code.clone( ),
       params  : self.params.clone( ),
       raw     : self.raw.clone( ),
+      bytes   : self.bytes.clone( ),
+    }
+  }
+}
+
+/// `unescape_tag` undoes the escaping of an IRCv3 tag value
+fn unescape_tag( value : &str ) -> String {
+  let mut out = String::new( );
+  let mut chars = value.chars( );
+  loop {
+    match chars.next( ) {
+      Some ( '\\' ) => match chars.next( ) {
+        Some ( ':' )  => out.push( ';' ),
+        Some ( 's' )  => out.push( ' ' ),
+        Some ( 'r' )  => out.push( '\r' ),
+        Some ( 'n' )  => out.push( '\n' ),
+        Some ( c )    => out.push( c ),
+        None          => break,
+      },
+      Some ( c )    => out.push( c ),
+      None          => break,
     }
   }
+  out
 }
 
 /// `raw_from_data` generates a raw message from a set of data
@@ -410,6 +472,20 @@ mod test {
     assert! ( pongmessage.param( 1 ).unwrap( ) == "tolsun.oulu.fi" );
   }
 
+  #[test]
+  fn test_tags () {
+    let tagged = super::Message::parse( "@label=q1;time=2015-02-01T12:00:00.000Z;msg=a\\sb\\:c :srv 318 me Lancey :End" ).unwrap( );
+    assert! ( tagged.code == "318" );
+    assert! ( tagged.nick( ).unwrap( ) == "srv" );
+    assert! ( tagged.param( 2 ).unwrap( ) == "Lancey" );
+    assert! ( tagged.tag( "label" ).unwrap( ) == "q1" );
+    assert! ( tagged.tag( "msg" ).unwrap( ) == "a b;c" );
+    assert! ( tagged.tag( "missing" ).is_none( ) );
+    let plain = super::Message::parse( ":Lancey PRIVMSG Detective :Hello!" ).unwrap( );
+    assert! ( plain.tags.is_none( ) );
+    assert! ( plain.tag( "label" ).is_none( ) );
+  }
+
   #[test]
   fn test_trailing () {
     let mymessage = super::Message::parse( "PRIVMSG param1 param2 param3 :param4 param5" ).unwrap( );
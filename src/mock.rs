@@ -0,0 +1,163 @@
+// import built in modules
+use std::old_io as io;
+use std::old_io::{Acceptor, Listener};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use regex::Regex;
+
+pub type TTIMEOUT                 = u64;
+
+static MOCK_TIMEOUT : TTIMEOUT    = 5000; // ms to wait for a client or a line
+
+/// `Step` is one step of a mock server script
+///
+/// # Options
+///
+/// `Expect( p )` - read lines until one matches the regex p. Lines that don't
+/// match are skipped, but still recorded in the transcript.
+/// `Send( s )` - send the line s, adding the line ending
+/// `SendRaw( b )` - send the bytes b exactly as given, for malformed input
+/// `Sleep( ms )` - wait for ms milliseconds
+/// `Disconnect` - close the connection to the client
+pub enum Step {
+  Expect ( String ),
+  Send ( String ),
+  SendRaw ( Vec < u8 > ),
+  Sleep ( u64 ),
+  Disconnect,
+}
+
+/// `expect` creates an Expect step
+pub fn expect( pattern : &str ) -> Step {
+  Step::Expect( pattern.to_string( ) )
+}
+
+/// `send` creates a Send step
+pub fn send( line : &str ) -> Step {
+  Step::Send( line.to_string( ) )
+}
+
+/// `MockServer` is a scripted IRC server for testing clients on loopback
+///
+/// # Members
+///
+/// * `host` - host the server is listening on
+/// * `port` - port the server is listening on
+/// * `done` - receives the outcome of the script once it has run
+pub struct MockServer {
+  pub host  : String,
+  pub port  : u16,
+
+  done      : mpsc::Receiver < Result < Vec < String >, String > >,
+}
+
+impl MockServer {
+  /// `start` starts a server that runs a script against the first client
+  /// that connects
+  ///
+  /// # Arguments
+  ///
+  /// * `script` - steps to run once a client connects
+  ///
+  /// # Returns
+  ///
+  /// The running server
+  pub fn start( script : Vec < Step > ) -> io::IoResult < MockServer > {
+    MockServer::start_with_timeout( script, MOCK_TIMEOUT )
+  }
+
+  /// `start_with_timeout` starts a server with a custom timeout
+  ///
+  /// # Arguments
+  ///
+  /// * `script` - steps to run once a client connects
+  /// * `timeout` - milliseconds to wait for the client and for each expected
+  /// line
+  ///
+  /// # Returns
+  ///
+  /// The running server
+  pub fn start_with_timeout( script : Vec < Step >, timeout : TTIMEOUT ) -> io::IoResult < MockServer > {
+    let listener = try! ( io::TcpListener::bind( "127.0.0.1:0" ) );
+    let mut acceptor = try! ( listener.listen( ) );
+    let local = try! ( acceptor.socket_name( ) );
+    let ( tx, rx ) = mpsc::channel( );
+    thread::Thread::spawn( move || {
+      acceptor.set_timeout( Some( timeout ) );
+      let result = match acceptor.accept( ) {
+        Ok ( tcp ) => run_script( tcp, script, timeout ),
+        Err ( e )  => Err( format! ( "no client connected: {}", e.desc ) ),
+      };
+      let _ = tx.send( result );
+    } );
+    Ok( MockServer {
+      host  : local.ip.to_string( ),
+      port  : local.port,
+      done  : rx,
+    } )
+  }
+
+  /// `finish` waits for the script to finish running
+  ///
+  /// # Returns
+  ///
+  /// Every line the client sent, or a description of the step that failed
+  pub fn finish( self ) -> Result < Vec < String >, String > {
+    match self.done.recv( ) {
+      Ok ( result ) => result,
+      Err ( _ )     => Err( String::from_str( "mock server thread died" ) ),
+    }
+  }
+}
+
+/// `run_script` runs a script against a connected client
+fn run_script( tcp : io::TcpStream, script : Vec < Step >, timeout : TTIMEOUT )
+  -> Result < Vec < String >, String > {
+  let mut transcript = Vec::new( );
+  let mut writer = tcp.clone( );
+  let mut rstream = tcp.clone( );
+  rstream.set_read_timeout( Some( timeout ) );
+  let mut reader = io::BufferedReader::new( rstream );
+
+  for ( n, step ) in script.into_iter( ).enumerate( ) {
+    match step {
+      Step::Expect ( pattern ) => {
+        let re = match Regex::new( pattern.as_slice( ) ) {
+          Ok ( re ) => re,
+          Err ( e ) => return Err( format! ( "step {}: bad pattern: {}", n, e.msg ) ),
+        };
+        loop {
+          let line = match reader.read_line( ) {
+            Ok ( l )  => l.as_slice( ).trim_right_matches( |c : char| c == '\r' || c == '\n' ).to_string( ),
+            Err ( e ) => return Err( format! ( "step {}: expected /{}/ but got {} after {:?}",
+              n, pattern, e.desc, transcript ) ),
+          };
+          transcript.push( line.clone( ) );
+          if re.is_match( line.as_slice( ) ) {
+            break;
+          }
+        }
+      },
+      Step::Send ( line )      => {
+        match writer.write_str( line.as_slice( ) ).and_then( |_| writer.write_str( "\r\n" ) ) {
+          Ok ( _ )  => (),
+          Err ( e ) => return Err( format! ( "step {}: sending '{}': {}", n, line, e.desc ) ),
+        }
+      },
+      Step::SendRaw ( bytes )  => {
+        match writer.write_all( bytes.as_slice( ) ) {
+          Ok ( _ )  => (),
+          Err ( e ) => return Err( format! ( "step {}: sending raw bytes: {}", n, e.desc ) ),
+        }
+      },
+      Step::Sleep ( ms )       => io::timer::sleep( Duration::milliseconds( ms as i64 ) ),
+      Step::Disconnect         => {
+        let _ = writer.close_read( );
+        let _ = writer.close_write( );
+        return Ok( transcript );
+      },
+    }
+  }
+  Ok( transcript )
+}
@@ -0,0 +1,352 @@
+// import built in modules
+use std::old_io as io;
+use std::old_io::net::ip;
+use std::time::Duration;
+use rustc_serialize::base64::{STANDARD, ToBase64};
+
+static SOCKS_VERSION   : u8    = 5;    // SOCKS protocol version
+static SOCKS_NO_AUTH   : u8    = 0x00; // method: no authentication
+static SOCKS_USER_PASS : u8    = 0x02; // method: username and password
+static SOCKS_NO_METHOD : u8    = 0xFF; // reply: none of our methods will do
+static SOCKS_CONNECT   : u8    = 0x01; // command: open a TCP stream
+static MAX_HTTP_REPLY  : usize = 8192; // bytes of HTTP reply headers we'll read
+
+/// `ProxyKind` is the protocol a proxy speaks
+///
+/// # Options
+///
+/// `Socks5` - SOCKS5, with the server name resolved by the proxy
+/// `Http` - an HTTP proxy that allows CONNECT
+#[derive(PartialEq)]
+pub enum ProxyKind {
+  Socks5,
+  Http,
+}
+
+impl Copy for ProxyKind {}
+
+/// `Proxy` is a proxy to reach a server through
+///
+/// # Members
+///
+/// * `kind` - the protocol the proxy speaks
+/// * `host`, `port` - where the proxy is
+/// * `username`, `password` - credentials, if the proxy needs them
+///
+/// # Notes
+///
+/// * Server names are passed to the proxy as they are, so they're looked up
+/// on the proxy's side. Nothing about the server leaks through local DNS.
+/// * The proxy only carries a TCP stream. TLS to the server is started on the
+/// stream it returns, so the proxy never sees the IRC traffic.
+pub struct Proxy {
+  pub kind     : ProxyKind,
+  pub host     : String,
+  pub port     : u16,
+  pub username : Option < String >,
+  pub password : Option < String >,
+}
+
+impl Clone for Proxy {
+  fn clone( &self ) -> Proxy {
+    Proxy {
+      kind     : self.kind,
+      host     : self.host.clone( ),
+      port     : self.port,
+      username : self.username.clone( ),
+      password : self.password.clone( ),
+    }
+  }
+}
+
+impl Proxy {
+  /// `socks5` creates a SOCKS5 proxy without credentials
+  pub fn socks5( host : &str, port : u16 ) -> Proxy {
+    Proxy { kind : ProxyKind::Socks5, host : host.to_string( ), port : port, username : None, password : None }
+  }
+
+  /// `http` creates an HTTP CONNECT proxy without credentials
+  pub fn http( host : &str, port : u16 ) -> Proxy {
+    Proxy { kind : ProxyKind::Http, host : host.to_string( ), port : port, username : None, password : None }
+  }
+
+  /// `auth` sets the credentials to give the proxy
+  pub fn auth( mut self, username : &str, password : &str ) -> Proxy {
+    self.username = Some( username.to_string( ) );
+    self.password = Some( password.to_string( ) );
+    self
+  }
+
+  /// `connect` opens a stream to a server through the proxy
+  ///
+  /// # Arguments
+  ///
+  /// * `host` - server to reach, a name or an address
+  /// * `port` - port of the server
+  /// * `timeout` - milliseconds to wait for the proxy to accept the
+  /// connection, and again for the handshake, or None to wait as long as the
+  /// system does
+  ///
+  /// # Returns
+  ///
+  /// A stream to the server, or why the proxy couldn't open one
+  pub fn connect( &self, host : &str, port : u16, timeout : Option < i64 > ) -> io::IoResult < io::TcpStream > {
+    let target = format! ( "{}:{}", self.host, self.port );
+    irc_info! ( "connecting to {}:{} through proxy {}...", host, port, target );
+    let mut tcp = try! ( match timeout {
+      Some ( ms ) => io::TcpStream::connect_timeout( target.as_slice( ), Duration::milliseconds( ms ) ),
+      None        => io::TcpStream::connect( target.as_slice( ) ),
+    } );
+
+    // a proxy that accepts and then stalls mustn't hang us, but the IRC
+    // stream after the handshake has no deadline
+    tcp.set_timeout( timeout.map( |ms| ms as u64 ) );
+    match self.kind {
+      ProxyKind::Socks5 => try! ( self.socks5_handshake( &mut tcp, host, port ) ),
+      ProxyKind::Http   => try! ( self.http_handshake( &mut tcp, host, port ) ),
+    }
+    tcp.set_timeout( None );
+    irc_info! ( "proxy {} connected", target );
+    Ok( tcp )
+  }
+
+  /// `socks5_handshake` asks a SOCKS5 proxy for a stream to the server
+  fn socks5_handshake( &self, tcp : &mut io::TcpStream, host : &str, port : u16 ) -> io::IoResult < ( ) > {
+    // offer to log in only if we can
+    let method = if self.username.is_some( ) { SOCKS_USER_PASS } else { SOCKS_NO_AUTH };
+    try! ( tcp.write_all( &[ SOCKS_VERSION, 1, method ] ) );
+    let reply = try! ( tcp.read_exact( 2 ) );
+    if reply[0] != SOCKS_VERSION {
+      return Err( proxy_error( "not a SOCKS5 proxy", format! ( "version {}", reply[0] ) ) );
+    }
+    if reply[1] == SOCKS_NO_METHOD {
+      return Err( proxy_error( "SOCKS5 proxy refused our authentication", String::new( ) ) );
+    }
+    if reply[1] != method {
+      return Err( proxy_error( "SOCKS5 proxy chose a method we didn't offer", format! ( "method {}", reply[1] ) ) );
+    }
+    if method == SOCKS_USER_PASS {
+      try! ( self.socks5_login( tcp ) );
+    }
+
+    // the name goes to the proxy as it is, addresses go as addresses
+    let mut request = vec![ SOCKS_VERSION, SOCKS_CONNECT, 0 ];
+    match host.parse::< ip::IpAddr >( ) {
+      Ok ( ip::IpAddr::Ipv4Addr( a, b, c, d ) )            => request.push_all( &[ 0x01, a, b, c, d ] ),
+      Ok ( ip::IpAddr::Ipv6Addr( a, b, c, d, e, f, g, h ) ) => {
+        request.push( 0x04 );
+        for part in [ a, b, c, d, e, f, g, h ].iter( ) {
+          request.push_all( &[ ( *part >> 8 ) as u8, *part as u8 ] );
+        }
+      },
+      Err ( _ )                                            => {
+        if host.len( ) > 255 {
+          return Err( proxy_error( "server name too long for SOCKS5", host.to_string( ) ) );
+        }
+        request.push_all( &[ 0x03, host.len( ) as u8 ] );
+        request.push_all( host.as_bytes( ) );
+      },
+    }
+    request.push_all( &[ ( port >> 8 ) as u8, port as u8 ] );
+    try! ( tcp.write_all( request.as_slice( ) ) );
+
+    // the reply ends with the address the proxy bound, which we don't need
+    let reply = try! ( tcp.read_exact( 4 ) );
+    if reply[1] != 0 {
+      return Err( proxy_error( "SOCKS5 proxy couldn't connect", socks5_reason( reply[1] ).to_string( ) ) );
+    }
+    let skip = match reply[3] {
+      0x01 => 4,
+      0x04 => 16,
+      0x03 => try! ( tcp.read_byte( ) ) as usize,
+      t    => return Err( proxy_error( "bad SOCKS5 reply", format! ( "address type {}", t ) ) ),
+    };
+    try! ( tcp.read_exact( skip + 2 ) );
+    Ok( ( ) )
+  }
+
+  /// `socks5_login` gives a SOCKS5 proxy our username and password
+  fn socks5_login( &self, tcp : &mut io::TcpStream ) -> io::IoResult < ( ) > {
+    let user = self.username.clone( ).unwrap_or( String::new( ) );
+    let pass = self.password.clone( ).unwrap_or( String::new( ) );
+    if user.len( ) > 255 || pass.len( ) > 255 {
+      return Err( proxy_error( "SOCKS5 credentials too long", String::new( ) ) );
+    }
+    let mut login = vec![ 1, user.len( ) as u8 ];
+    login.push_all( user.as_bytes( ) );
+    login.push( pass.len( ) as u8 );
+    login.push_all( pass.as_bytes( ) );
+    try! ( tcp.write_all( login.as_slice( ) ) );
+    let reply = try! ( tcp.read_exact( 2 ) );
+    if reply[1] != 0 {
+      return Err( proxy_error( "SOCKS5 proxy rejected our login", user ) );
+    }
+    Ok( ( ) )
+  }
+
+  /// `http_handshake` asks an HTTP proxy to CONNECT to the server
+  fn http_handshake( &self, tcp : &mut io::TcpStream, host : &str, port : u16 ) -> io::IoResult < ( ) > {
+    let authority = if host.contains_char( ':' ) { format! ( "[{}]:{}", host, port ) } else { format! ( "{}:{}", host, port ) };
+    let mut request = format! ( "CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority );
+    match self.username {
+      Some ( ref user ) => {
+        let creds = format! ( "{}:{}", user, self.password.clone( ).unwrap_or( String::new( ) ) );
+        request.push_str( format! ( "Proxy-Authorization: Basic {}\r\n", creds.as_bytes( ).to_base64( STANDARD ) ).as_slice( ) );
+      },
+      None              => (),
+    }
+    request.push_str( "\r\n" );
+    irc_trace! ( "proxy < CONNECT {}", authority );
+    try! ( tcp.write_str( request.as_slice( ) ) );
+
+    // read the reply a byte at a time, anything after it is the server's
+    let mut reply = Vec::new( );
+    while !reply.ends_with( b"\r\n\r\n" ) && !reply.ends_with( b"\n\n" ) {
+      if reply.len( ) >= MAX_HTTP_REPLY {
+        return Err( proxy_error( "HTTP proxy reply too long", String::new( ) ) );
+      }
+      reply.push( try! ( tcp.read_byte( ) ) );
+    }
+    let reply = String::from_utf8_lossy( reply.as_slice( ) ).into_owned( );
+    let status = reply.as_slice( ).lines_any( ).next( ).unwrap_or( "" ).to_string( );
+    let code = status.as_slice( ).split( ' ' ).nth( 1 ).unwrap_or( "" );
+    if !status.as_slice( ).starts_with( "HTTP/1." ) || !code.starts_with( "2" ) {
+      return Err( proxy_error( "HTTP proxy refused CONNECT", status.clone( ) ) );
+    }
+    Ok( ( ) )
+  }
+}
+
+/// `proxy_error` builds the error for a failed proxy handshake
+fn proxy_error( desc : &'static str, detail : String ) -> io::IoError {
+  io::IoError {
+    kind   : io::OtherIoError,
+    desc   : desc,
+    detail : if detail.is_empty( ) { None } else { Some( detail ) },
+  }
+}
+
+/// `socks5_reason` describes a SOCKS5 reply code
+fn socks5_reason( code : u8 ) -> &'static str {
+  match code {
+    0x01 => "general failure",
+    0x02 => "not allowed by ruleset",
+    0x03 => "network unreachable",
+    0x04 => "host unreachable",
+    0x05 => "connection refused",
+    0x06 => "TTL expired",
+    0x07 => "command not supported",
+    0x08 => "address type not supported",
+    _    => "unknown error",
+  }
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[allow(unused_imports)]
+  use std::old_io as io;
+  #[allow(unused_imports)]
+  use std::old_io::{Acceptor, Listener};
+  #[allow(unused_imports)]
+  use std::thread::Thread;
+  #[allow(unused_imports)]
+  use super::Proxy;
+
+  /// `stand_in` runs a one-shot proxy on a local port
+  ///
+  /// # Returns
+  ///
+  /// The port, and a guard returning what the proxy read from the client
+  #[allow(dead_code)]
+  fn stand_in < F > ( serve : F ) -> ( u16, ::std::thread::JoinGuard < 'static, Vec < u8 > > )
+    where F : FnOnce( &mut io::TcpStream ) -> Vec < u8 > + Send + 'static {
+    let listener = io::TcpListener::bind( "127.0.0.1:0" ).unwrap( );
+    let mut acceptor = listener.listen( ).unwrap( );
+    let port = acceptor.socket_name( ).unwrap( ).port;
+    let guard = Thread::scoped( move || {
+      let mut tcp = acceptor.accept( ).unwrap( );
+      serve( &mut tcp )
+    } );
+    ( port, guard )
+  }
+
+  #[test]
+  fn test_socks5 () {
+    let ( port, guard ) = stand_in( |tcp| {
+      let mut seen = tcp.read_exact( 3 ).unwrap( );
+      tcp.write_all( &[ 5, 2 ] ).unwrap( );
+      seen.push_all( tcp.read_exact( 2 + 4 + 1 + 6 ).unwrap( ).as_slice( ) );
+      tcp.write_all( &[ 1, 0 ] ).unwrap( );
+      seen.push_all( tcp.read_exact( 4 + 1 + 15 + 2 ).unwrap( ).as_slice( ) );
+      tcp.write_all( &[ 5, 0, 0, 1, 127, 0, 0, 1, 0x1A, 0x0B ] ).unwrap( );
+      tcp.write_str( "PING :tunnel\r\n" ).unwrap( );
+      seen
+    } );
+    let proxy = Proxy::socks5( "127.0.0.1", port ).auth( "user", "secret" );
+    let tcp = proxy.connect( "irc.example.org", 6667, Some( 1000 ) ).unwrap( );
+    let mut read = io::BufferedReader::new( tcp );
+    assert! ( read.read_line( ).unwrap( ) == "PING :tunnel\r\n" );
+
+    // the server name reached the proxy unresolved
+    let mut expected = vec![ 5, 1, 2, 1, 4 ];
+    expected.push_all( b"user\x06secret\x05\x01\x00\x03\x0firc.example.org\x1a\x0b" );
+    assert! ( guard.join( ) == expected );
+  }
+
+  #[test]
+  fn test_socks5_refused () {
+    let ( port, _guard ) = stand_in( |tcp| {
+      let seen = tcp.read_exact( 3 ).unwrap( );
+      tcp.write_all( &[ 5, 0 ] ).unwrap( );
+      let _ = tcp.read_exact( 4 + 1 + 15 + 2 );
+      tcp.write_all( &[ 5, 5, 0, 1, 0, 0, 0, 0, 0, 0 ] ).unwrap( );
+      seen
+    } );
+    let err = Proxy::socks5( "127.0.0.1", port ).connect( "irc.example.org", 6667, Some( 1000 ) ).err( ).unwrap( );
+    assert! ( err.detail == Some( String::from_str( "connection refused" ) ) );
+  }
+
+  #[test]
+  fn test_stalled_handshake () {
+    let ( port, _guard ) = stand_in( |tcp| {
+      let seen = tcp.read_exact( 3 ).unwrap( );
+      io::timer::sleep( ::std::time::Duration::milliseconds( 500 ) );
+      seen
+    } );
+    let err = Proxy::socks5( "127.0.0.1", port ).connect( "irc.example.org", 6667, Some( 100 ) ).err( ).unwrap( );
+    assert! ( err.kind == io::TimedOut );
+  }
+
+  #[test]
+  fn test_http () {
+    let ( port, guard ) = stand_in( |tcp| {
+      let mut seen = Vec::new( );
+      while !seen.ends_with( b"\r\n\r\n" ) {
+        seen.push( tcp.read_byte( ).unwrap( ) );
+      }
+      tcp.write_str( "HTTP/1.1 200 Connection established\r\n\r\nPING :tunnel\r\n" ).unwrap( );
+      seen
+    } );
+    let proxy = Proxy::http( "127.0.0.1", port ).auth( "user", "secret" );
+    let tcp = proxy.connect( "irc.example.org", 6697, Some( 1000 ) ).unwrap( );
+    let mut read = io::BufferedReader::new( tcp );
+    assert! ( read.read_line( ).unwrap( ) == "PING :tunnel\r\n" );
+    let seen = String::from_utf8( guard.join( ) ).unwrap( );
+    assert! ( seen.as_slice( ).starts_with( "CONNECT irc.example.org:6697 HTTP/1.1\r\n" ) );
+    assert! ( seen.as_slice( ).contains( "Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n" ) );
+  }
+
+  #[test]
+  fn test_http_refused () {
+    let ( port, _guard ) = stand_in( |tcp| {
+      let mut seen = Vec::new( );
+      while !seen.ends_with( b"\r\n\r\n" ) {
+        seen.push( tcp.read_byte( ).unwrap( ) );
+      }
+      tcp.write_str( "HTTP/1.1 403 Forbidden\r\n\r\n" ).unwrap( );
+      seen
+    } );
+    let err = Proxy::http( "127.0.0.1", port ).connect( "irc.example.org", 6667, Some( 1000 ) ).err( ).unwrap( );
+    assert! ( err.detail == Some( String::from_str( "HTTP/1.1 403 Forbidden" ) ) );
+  }
+}
@@ -0,0 +1,1233 @@
+// import built in modules
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+use std::old_io as io;
+use std::sync::mpsc;
+use std::time::Duration;
+use time;
+
+// import custom modules
+use mask;
+use message;
+
+pub type TTIMEOUT                 = u64;
+
+static QUERY_TIMEOUT : TTIMEOUT   = 30000; // ms `wait` blocks for a reply
+static QUERY_EXPIRE  : i64        = 120;   // s before an unanswered query is dropped
+static QUERY_POLL    : TTIMEOUT   = 10;    // ms between checks for a reply
+
+static WHOX_TOKENS   : u32        = 999;   // WHOX tokens are 1 to 3 digits
+
+pub static WHOX_FIELDS : &'static str = "tcuhnfar"; // fields we ask WHOX for
+
+/// `QueryError` is why a query failed
+///
+/// # Options
+///
+/// `NoSuchNick( n )` - the server doesn't know the nick n
+/// `NoSuchChannel( c )` - the server doesn't know the channel c
+/// `Server( code, text )` - the server answered with another error numeric
+/// `Timeout` - no complete reply arrived in time
+/// `Disconnected` - the client closed before the reply arrived
+/// `Invalid( why )` - the query wasn't sent because its arguments were unsafe
+pub enum QueryError {
+  NoSuchNick ( String ),
+  NoSuchChannel ( String ),
+  Server ( String, String ),
+  Timeout,
+  Disconnected,
+  Invalid ( String ),
+}
+
+impl Clone for QueryError {
+  fn clone( &self ) -> QueryError {
+    match *self {
+      QueryError::NoSuchNick ( ref n )      => QueryError::NoSuchNick( n.clone( ) ),
+      QueryError::NoSuchChannel ( ref c )   => QueryError::NoSuchChannel( c.clone( ) ),
+      QueryError::Server ( ref c, ref t )   => QueryError::Server( c.clone( ), t.clone( ) ),
+      QueryError::Timeout                   => QueryError::Timeout,
+      QueryError::Disconnected              => QueryError::Disconnected,
+      QueryError::Invalid ( ref w )         => QueryError::Invalid( w.clone( ) ),
+    }
+  }
+}
+
+/// `WhoisReply` collects the replies to a WHOIS
+///
+/// # Members
+///
+/// * `nick`, `user`, `host`, `real` - from RPL_WHOISUSER
+/// * `server`, `server_info` - from RPL_WHOISSERVER
+/// * `operator` - whether RPL_WHOISOPERATOR was sent
+/// * `idle`, `signon` - seconds idle and signon time, from RPL_WHOISIDLE
+/// * `channels` - channels with their prefixes, from RPL_WHOISCHANNELS
+/// * `account` - services account, from RPL_WHOISACCOUNT
+/// * `away` - away message, from RPL_AWAY
+/// * `secure` - whether RPL_WHOISSECURE was sent
+pub struct WhoisReply {
+  pub nick        : String,
+  pub user        : String,
+  pub host        : String,
+  pub real        : String,
+  pub server      : String,
+  pub server_info : String,
+  pub operator    : bool,
+  pub idle        : Option < u64 >,
+  pub signon      : Option < i64 >,
+  pub channels    : Vec < String >,
+  pub account     : Option < String >,
+  pub away        : Option < String >,
+  pub secure      : bool,
+}
+
+impl WhoisReply {
+  /// `new` creates an empty reply for a nick
+  fn new( nick : &str ) -> WhoisReply {
+    WhoisReply {
+      nick        : nick.to_string( ),
+      user        : String::new( ),
+      host        : String::new( ),
+      real        : String::new( ),
+      server      : String::new( ),
+      server_info : String::new( ),
+      operator    : false,
+      idle        : None,
+      signon      : None,
+      channels    : Vec::new( ),
+      account     : None,
+      away        : None,
+      secure      : false,
+    }
+  }
+}
+
+/// `WhoEntry` is one RPL_WHOREPLY, or one RPL_WHOSPCRPL to a WHOX query
+///
+/// # Notes
+///
+/// * WHOX replies don't carry `server` or `hops`, so those are empty and 0.
+/// * `account` is only known from WHOX replies.
+pub struct WhoEntry {
+  pub channel : String,
+  pub user    : String,
+  pub host    : String,
+  pub server  : String,
+  pub nick    : String,
+  pub flags   : String,
+  pub hops    : u32,
+  pub real    : String,
+  pub account : Option < String >,
+}
+
+/// `ChannelListEntry` is one RPL_LIST
+pub struct ChannelListEntry {
+  pub name  : String,
+  pub users : u32,
+  pub topic : String,
+}
+
+/// `ListFilter` narrows down the channels a LIST returns
+///
+/// # Members
+///
+/// * `mask` - glob the channel name must match
+/// * `min_users`, `max_users` - bounds on the user count, inclusive
+/// * `topic` - glob the topic must match
+/// * `newer_than`, `older_than` - bounds on the channel's age in minutes
+///
+/// # Notes
+///
+/// * Conditions the server advertises in ISUPPORT ELIST are sent along with
+/// the LIST so the server does the filtering. The rest are checked as the
+/// entries arrive.
+/// * RPL_LIST doesn't carry creation times, so the age bounds are only
+/// honored by servers with ELIST=C.
+pub struct ListFilter {
+  pub mask       : Option < String >,
+  pub min_users  : Option < u32 >,
+  pub max_users  : Option < u32 >,
+  pub topic      : Option < String >,
+  pub newer_than : Option < u32 >,
+  pub older_than : Option < u32 >,
+}
+
+impl ListFilter {
+  /// `new` creates a filter that lets every channel through
+  pub fn new( ) -> ListFilter {
+    ListFilter {
+      mask       : None,
+      min_users  : None,
+      max_users  : None,
+      topic      : None,
+      newer_than : None,
+      older_than : None,
+    }
+  }
+
+  /// `params` builds the LIST parameter for the conditions the server can
+  /// check itself
+  ///
+  /// # Arguments
+  ///
+  /// * `elist` - value of the server's ISUPPORT ELIST token, e.g. "CMNTU"
+  ///
+  /// # Returns
+  ///
+  /// The comma separated conditions, or an empty string for a plain LIST
+  pub fn params( &self, elist : &str ) -> String {
+    let elist = elist.to_ascii_uppercase( );
+    let has = |c : char| elist.as_slice( ).contains_char( c );
+    let mut conds : Vec < String > = Vec::new( );
+    match self.mask {
+      Some ( ref m ) => {
+        // a plain channel name works everywhere, wildcards need ELIST=M
+        let wild = m.contains_char( '*' ) || m.contains_char( '?' );
+        if has( 'M' ) || !wild {
+          conds.push( m.clone( ) );
+        }
+      },
+      None           => (),
+    }
+    if has( 'U' ) {
+      match self.min_users {
+        Some ( n ) if n > 0 => conds.push( format! ( ">{}", n - 1 ) ),
+        _                   => (),
+      }
+      match self.max_users {
+        Some ( n ) => conds.push( format! ( "<{}", n + 1 ) ),
+        None       => (),
+      }
+    }
+    if has( 'C' ) {
+      match self.newer_than {
+        Some ( n ) => conds.push( format! ( "C<{}", n ) ),
+        None       => (),
+      }
+      match self.older_than {
+        Some ( n ) => conds.push( format! ( "C>{}", n ) ),
+        None       => (),
+      }
+    }
+    conds.connect( "," )
+  }
+
+  /// `matches` checks an entry against the conditions that RPL_LIST can show
+  ///
+  /// # Arguments
+  ///
+  /// * `entry` - channel listed by the server
+  pub fn matches( &self, entry : &ChannelListEntry ) -> bool {
+    self.mask.as_ref( ).map( |m| mask::glob_match( m.as_slice( ), entry.name.as_slice( ) ) ).unwrap_or( true ) &&
+      self.min_users.map( |n| entry.users >= n ).unwrap_or( true ) &&
+      self.max_users.map( |n| entry.users <= n ).unwrap_or( true ) &&
+      self.topic.as_ref( ).map( |t| mask::glob_match( t.as_slice( ), entry.topic.as_slice( ) ) ).unwrap_or( true )
+  }
+}
+
+/// `NamesReply` collects the replies to a NAMES
+pub struct NamesReply {
+  pub channel : String,
+  pub names   : Vec < String >,
+}
+
+/// `ModeReply` is the answer to a MODE query
+///
+/// # Members
+///
+/// * `target` - channel or nick that was queried
+/// * `modes` - mode string with its arguments, e.g. "+ntk key"
+/// * `created` - channel creation time from RPL_CREATIONTIME, if sent
+pub struct ModeReply {
+  pub target  : String,
+  pub modes   : String,
+  pub created : Option < i64 >,
+}
+
+/// `QueryReply` is the completed reply to any query
+pub enum QueryReply {
+  Whois ( WhoisReply ),
+  Who ( Vec < WhoEntry > ),
+  List ( usize ),
+  Names ( NamesReply ),
+  Mode ( ModeReply ),
+}
+
+/// `QueryResult` is what a query resolves to
+pub type QueryResult = Result < QueryReply, QueryError >;
+
+/// `QueryHandle` waits for the reply to a query
+///
+/// # Members
+///
+/// * `rx` - receives the result from the tracker
+/// * `extract` - takes the typed reply out of the QueryReply
+pub struct QueryHandle < T > {
+  rx      : mpsc::Receiver < QueryResult >,
+  extract : fn( QueryReply ) -> Option < T >,
+}
+
+impl < T > QueryHandle < T > {
+  /// `failed` returns a handle for a query that failed before it was sent
+  pub fn failed( err : QueryError ) -> QueryHandle < T > {
+    let ( tx, rx ) = mpsc::channel( );
+    let _ = tx.send( Err( err ) );
+    QueryHandle { rx : rx, extract : no_reply::< T > }
+  }
+
+  /// `wait` blocks until the reply arrives or 30 seconds have passed
+  pub fn wait( &self ) -> Result < T, QueryError > {
+    self.wait_timeout( QUERY_TIMEOUT )
+  }
+
+  /// `wait_timeout` blocks until the reply arrives or the timeout passes
+  ///
+  /// # Arguments
+  ///
+  /// * `timeout` - milliseconds to wait
+  ///
+  /// # Returns
+  ///
+  /// The reply, or why there isn't one. After a Timeout the reply may still
+  /// arrive, so the handle can be waited on again.
+  pub fn wait_timeout( &self, timeout : TTIMEOUT ) -> Result < T, QueryError > {
+    let deadline = time::precise_time_ns( ) + timeout * 1000000;
+    loop {
+      match self.try_result( ) {
+        Some ( r ) => return r,
+        None       => (),
+      }
+      if time::precise_time_ns( ) >= deadline {
+        return Err( QueryError::Timeout );
+      }
+      io::timer::sleep( Duration::milliseconds( QUERY_POLL as i64 ) );
+    }
+  }
+
+  /// `try_result` checks for the reply without blocking
+  ///
+  /// # Returns
+  ///
+  /// None if the reply hasn't arrived yet
+  pub fn try_result( &self ) -> Option < Result < T, QueryError > > {
+    match self.rx.try_recv( ) {
+      Ok ( Ok ( reply ) )                       => Some( match ( self.extract )( reply ) {
+        Some ( t ) => Ok( t ),
+        None       => Err( QueryError::Server( String::new( ), String::from_str( "unexpected reply" ) ) ),
+      } ),
+      Ok ( Err ( e ) )                          => Some( Err( e ) ),
+      Err ( mpsc::TryRecvError::Empty )         => None,
+      Err ( mpsc::TryRecvError::Disconnected )  => Some( Err( QueryError::Disconnected ) ),
+    }
+  }
+}
+
+/// `ListStream` yields the channels of a LIST as they arrive
+///
+/// # Members
+///
+/// * `entries` - receives matching entries from the tracker
+/// * `end` - resolves to the number of matching entries once the LIST ends
+/// * `error` - why the LIST ended early, if it did
+/// * `finished` - set once the stream has ended
+/// * `timeout` - milliseconds to wait for the next entry
+///
+/// # Notes
+///
+/// * The stream ends at RPL_LISTEND. Use `error` afterwards to tell a complete
+/// list from a failed one.
+pub struct ListStream {
+  entries     : mpsc::Receiver < ChannelListEntry >,
+  end         : QueryHandle < usize >,
+  error       : Option < QueryError >,
+  finished    : bool,
+  pub timeout : TTIMEOUT,
+}
+
+impl ListStream {
+  /// `failed` returns a stream for a LIST that failed before it was sent
+  pub fn failed( err : QueryError ) -> ListStream {
+    let ( _, rx ) = mpsc::channel( );
+    ListStream { entries : rx, end : QueryHandle::failed( err ), error : None, finished : false, timeout : QUERY_TIMEOUT }
+  }
+
+  /// `error` is why the LIST ended early, if it did
+  pub fn error( &self ) -> Option < &QueryError > {
+    self.error.as_ref( )
+  }
+}
+
+impl Iterator for ListStream {
+  type Item = ChannelListEntry;
+
+  /// `next` blocks until the next entry arrives or the LIST ends
+  fn next( &mut self ) -> Option < ChannelListEntry > {
+    if self.finished {
+      return None;
+    }
+    let deadline = time::precise_time_ns( ) + self.timeout * 1000000;
+    loop {
+      match self.entries.try_recv( ) {
+        Ok ( entry )                              => return Some( entry ),
+        Err ( mpsc::TryRecvError::Empty )         => (),
+        Err ( mpsc::TryRecvError::Disconnected )  => {
+          // the tracker has dropped the query, so its result is waiting
+          self.finished = true;
+          match self.end.try_result( ) {
+            Some ( Err ( e ) ) => self.error = Some( e ),
+            _                  => (),
+          }
+          return None;
+        },
+      }
+      if time::precise_time_ns( ) >= deadline {
+        self.finished = true;
+        self.error = Some( QueryError::Timeout );
+        return None;
+      }
+      io::timer::sleep( Duration::milliseconds( QUERY_POLL as i64 ) );
+    }
+  }
+}
+
+/// `QueryKind` is the command a pending query was sent with
+#[derive(PartialEq)]
+enum QueryKind {
+  Whois,
+  Who,
+  List,
+  Names,
+  Mode,
+}
+
+impl Copy for QueryKind {}
+
+/// `Pending` is a query waiting for its reply
+///
+/// # Members
+///
+/// * `kind` - command the query was sent with
+/// * `target` - lowercased nick, channel or mask the query is about
+/// * `label` - labeled-response label, if the query was labeled
+/// * `token` - WHOX token, if the query was a WHOX query
+/// * `reply` - the reply collected so far
+/// * `error` - an error that will end the query at its end-of-reply numeric
+/// * `done` - set once the query can be resolved
+/// * `lingering` - waiting for an optional trailing numeric, e.g. 329
+/// * `sent` - when the query was sent, in seconds
+/// * `tx` - sends the result to the handle
+/// * `sink` - where a streamed LIST sends its entries
+struct Pending {
+  kind      : QueryKind,
+  target    : String,
+  label     : Option < String >,
+  token     : Option < String >,
+  reply     : QueryReply,
+  error     : Option < QueryError >,
+  done      : bool,
+  lingering : bool,
+  sent      : i64,
+  tx        : mpsc::Sender < QueryResult >,
+  sink      : Option < ListSink >,
+}
+
+/// `ListSink` is the streaming end of a LIST
+///
+/// # Members
+///
+/// * `tx` - sends matching entries to the ListStream
+/// * `filter` - conditions entries must meet
+struct ListSink {
+  tx     : mpsc::Sender < ChannelListEntry >,
+  filter : ListFilter,
+}
+
+/// `QueryTracker` matches server replies to the queries that caused them
+///
+/// # Members
+///
+/// * `labeled` - label queries, set once the server ACKs labeled-response
+/// * `expire` - seconds before an unanswered query is dropped
+/// * `pending` - queries waiting for replies, oldest first
+/// * `batches` - labeled-response batch references by the label they answer
+/// * `next_label` - counter used to make labels
+/// * `next_token` - counter used to make WHOX tokens
+/// * `refresh` - seconds between periodic WHO refreshes, None to turn them off
+/// * `notify` - set once the server ACKs away-notify and account-notify
+/// * `last_refresh` - when the last periodic WHO was sent, in seconds
+/// * `next_refresh` - index of the channel to refresh next
+///
+/// # Notes
+///
+/// * Without labeled-response, replies are matched to the oldest pending
+/// query of the same kind and target, which works because servers answer
+/// queries in order.
+/// * Every WHOX query gets a token of its own, which its RPL_WHOSPCRPLs
+/// carry, so they're matched on that instead.
+/// * labeled-response is requested during registration when the server
+/// offers it, see `register::Registration`. The tracker notices the ACK by
+/// itself.
+/// * Periodic refreshes WHO one channel at a time so a client in many channels
+/// doesn't flood itself off, and stop once the notify caps make them pointless.
+pub struct QueryTracker {
+  pub labeled : bool,
+  pub expire  : i64,
+  pub refresh : Option < i64 >,
+
+  pending       : Vec < Pending >,
+  batches       : HashMap < String, String >,
+  next_label    : u64,
+  next_token    : u32,
+  notify        : ( bool, bool ),
+  last_refresh  : i64,
+  next_refresh  : usize,
+}
+
+impl QueryTracker {
+  /// `new` creates a tracker without pending queries
+  pub fn new( ) -> QueryTracker {
+    QueryTracker {
+      labeled       : false,
+      expire        : QUERY_EXPIRE,
+      refresh       : None,
+      pending       : Vec::new( ),
+      batches       : HashMap::new( ),
+      next_label    : 0,
+      next_token    : 0,
+      notify        : ( false, false ),
+      last_refresh  : time::get_time( ).sec,
+      next_refresh  : 0,
+    }
+  }
+
+  /// `whois` starts a WHOIS query
+  ///
+  /// # Arguments
+  ///
+  /// * `nick` - nick to look up
+  ///
+  /// # Returns
+  ///
+  /// The line to send to the server and the handle to wait on
+  pub fn whois( &mut self, nick : &str ) -> ( String, QueryHandle < WhoisReply > ) {
+    let line = format! ( "WHOIS {}", nick );
+    self.start( QueryKind::Whois, nick, line, QueryReply::Whois( WhoisReply::new( nick ) ), as_whois )
+  }
+
+  /// `who` starts a WHO query
+  ///
+  /// # Arguments
+  ///
+  /// * `mask` - channel or mask to list
+  /// * `whox` - whether the server supports WHOX, so accounts can be asked for
+  pub fn who( &mut self, mask : &str, whox : bool ) -> ( String, QueryHandle < Vec < WhoEntry > > ) {
+    let token = if whox {
+      self.next_token = self.next_token % WHOX_TOKENS + 1;
+      Some( self.next_token.to_string( ) )
+    } else {
+      None
+    };
+    let line = match token {
+      Some ( ref t ) => format! ( "WHO {} %{},{}", mask, WHOX_FIELDS, t ),
+      None           => format! ( "WHO {}", mask ),
+    };
+    let ( line, handle ) = self.start( QueryKind::Who, mask, line, QueryReply::Who( Vec::new( ) ), as_who );
+    let last = self.pending.len( ) - 1;
+    self.pending[last].token = token;
+    ( line, handle )
+  }
+
+  /// `is_ours` checks whether a RPL_WHOSPCRPL answers one of our WHOX
+  /// queries, and so has the fields in WHOX_FIELDS
+  pub fn is_ours( &self, msg : &message::Message ) -> bool {
+    let token = msg.param( 2 );
+    msg.code.as_slice( ) == "354" && self.pending.iter( ).any( |p| p.token.as_ref( ).map( |t| t.as_slice( ) ) == token )
+  }
+
+  /// `refresh_due` picks the next channel for a periodic WHO refresh
+  ///
+  /// # Arguments
+  ///
+  /// * `channels` - channels we're in
+  /// * `now` - current time in seconds
+  ///
+  /// # Returns
+  ///
+  /// The channel to WHO, or None if no refresh is due
+  pub fn refresh_due( &mut self, channels : &Vec < String >, now : i64 ) -> Option < String > {
+    let interval = match self.refresh {
+      Some ( i ) => i,
+      None       => return None,
+    };
+    if channels.is_empty( ) || self.notify == ( true, true ) || now - self.last_refresh < interval {
+      return None;
+    }
+    let chan = channels[self.next_refresh % channels.len( )].clone( );
+    self.last_refresh = now;
+    self.next_refresh = ( self.next_refresh + 1 ) % channels.len( );
+    Some( chan )
+  }
+
+  /// `list` starts a streamed LIST query
+  ///
+  /// # Arguments
+  ///
+  /// * `filter` - conditions the listed channels must meet
+  /// * `elist` - value of the server's ISUPPORT ELIST token, or ""
+  ///
+  /// # Returns
+  ///
+  /// The line to send to the server and the stream of matching channels
+  pub fn list( &mut self, filter : ListFilter, elist : &str ) -> ( String, ListStream ) {
+    let params = filter.params( elist );
+    let line = if params.is_empty( ) { String::from_str( "LIST" ) } else { format! ( "LIST {}", params ) };
+    let ( line, end ) = self.start( QueryKind::List, "", line, QueryReply::List( 0 ), as_list );
+    let ( tx, rx ) = mpsc::channel( );
+    let last = self.pending.len( ) - 1;
+    self.pending[last].sink = Some( ListSink { tx : tx, filter : filter } );
+    ( line, ListStream { entries : rx, end : end, error : None, finished : false, timeout : QUERY_TIMEOUT } )
+  }
+
+  /// `names` starts a NAMES query
+  ///
+  /// # Arguments
+  ///
+  /// * `chan` - channel to list
+  pub fn names( &mut self, chan : &str ) -> ( String, QueryHandle < NamesReply > ) {
+    let line = format! ( "NAMES {}", chan );
+    let reply = QueryReply::Names( NamesReply { channel : chan.to_string( ), names : Vec::new( ) } );
+    self.start( QueryKind::Names, chan, line, reply, as_names )
+  }
+
+  /// `mode` starts a MODE query
+  ///
+  /// # Arguments
+  ///
+  /// * `target` - channel, or our own nick
+  pub fn mode( &mut self, target : &str ) -> ( String, QueryHandle < ModeReply > ) {
+    let line = format! ( "MODE {}", target );
+    let reply = QueryReply::Mode( ModeReply { target : target.to_string( ), modes : String::new( ), created : None } );
+    self.start( QueryKind::Mode, target, line, reply, as_mode )
+  }
+
+  /// `start` records a pending query
+  fn start < T > (
+    &mut self,
+    kind : QueryKind,
+    target : &str,
+    line : String,
+    reply : QueryReply,
+    extract : fn( QueryReply ) -> Option < T >
+  ) -> ( String, QueryHandle < T > ) {
+    let ( tx, rx ) = mpsc::channel( );
+    let label = if self.labeled {
+      self.next_label += 1;
+      Some( format! ( "rustirc{}", self.next_label ) )
+    } else {
+      None
+    };
+    let line = match label {
+      Some ( ref l ) => format! ( "@label={} {}", l, line ),
+      None           => line,
+    };
+    self.pending.push( Pending {
+      kind      : kind,
+      target    : target.to_ascii_lowercase( ),
+      label     : label,
+      token     : None,
+      reply     : reply,
+      error     : None,
+      done      : false,
+      lingering : false,
+      sent      : time::get_time( ).sec,
+      tx        : tx,
+      sink      : None,
+    } );
+    ( line, QueryHandle { rx : rx, extract : extract } )
+  }
+
+  /// `handle` feeds a message from the server to the pending queries
+  ///
+  /// # Arguments
+  ///
+  /// * `msg` - a message received from the server
+  ///
+  /// # Returns
+  ///
+  /// true if the message was part of a streamed LIST, and so has already been
+  /// delivered to its ListStream
+  pub fn handle( &mut self, msg : &message::Message ) -> bool {
+    self.handle_at( msg, time::get_time( ).sec )
+  }
+
+  /// `handle_at` is `handle` at a given time in seconds
+  fn handle_at( &mut self, msg : &message::Message, now : i64 ) -> bool {
+    let code = msg.code.as_slice( );
+    let mut streamed = false;
+
+    // a query waiting for an optional numeric is done once anything else arrives
+    let lingering = code != "329";
+    for p in self.pending.iter_mut( ) {
+      if p.lingering && lingering {
+        p.done = true;
+      }
+    }
+
+    match code {
+      "CAP"   => self.observe_cap( msg ),
+      "BATCH" => self.observe_batch( msg ),
+      _       => match self.find( msg ) {
+        Some ( i ) => {
+          streamed = self.pending[i].sink.is_some( ) && ( code == "321" || code == "322" || code == "323" );
+          update( &mut self.pending[i], msg );
+        },
+        None       => (),
+      },
+    }
+
+    // a labeled single-line reply ends its query
+    match msg.tag( "label" ) {
+      Some ( ref l ) if code != "BATCH" => for p in self.pending.iter_mut( ) {
+        if p.label.as_ref( ) == Some( l ) {
+          p.done = true;
+        }
+      },
+      _                                 => (),
+    }
+    self.resolve( now );
+    streamed
+  }
+
+  /// `fail_all` ends every pending query, e.g. when the client disconnects
+  pub fn fail_all( &mut self, err : QueryError ) {
+    for p in self.pending.drain( ) {
+      let _ = p.tx.send( Err( err.clone( ) ) );
+    }
+    self.batches.clear( );
+  }
+
+  /// `resolve` sends the finished queries to their handles and drops the
+  /// expired ones
+  fn resolve( &mut self, now : i64 ) {
+    let expire = self.expire;
+    let mut i = 0;
+    while i < self.pending.len( ) {
+      let finished = self.pending[i].done;
+      let expired = now - self.pending[i].sent > expire;
+      if finished || expired {
+        let p = self.pending.remove( i );
+        let result = if !finished {
+          Err( QueryError::Timeout )
+        } else {
+          match p.error {
+            Some ( e ) => Err( e ),
+            None       => Ok( p.reply ),
+          }
+        };
+        match p.label {
+          Some ( ref l ) => { self.batches.remove( l ); },
+          None           => (),
+        }
+        let _ = p.tx.send( result );
+      } else {
+        i += 1;
+      }
+    }
+  }
+
+  /// `observe_cap` turns labels on when the server ACKs labeled-response,
+  /// and notes the notify caps that make WHO refreshes unnecessary
+  fn observe_cap( &mut self, msg : &message::Message ) {
+    let caps = msg.trailing( ).unwrap_or( "" );
+    let has = |name : &str| caps.split( ' ' ).any( |c| c == name || c == format! ( "draft/{}", name ).as_slice( ) );
+    let on = match msg.param( 2 ) {
+      Some ( "ACK" ) => true,
+      Some ( "DEL" ) => false,
+      _              => return,
+    };
+    if has( "labeled-response" ) {
+      self.labeled = on;
+    }
+    if has( "away-notify" ) {
+      self.notify.0 = on;
+    }
+    if has( "account-notify" ) {
+      self.notify.1 = on;
+    }
+  }
+
+  /// `observe_batch` tracks labeled-response batches
+  fn observe_batch( &mut self, msg : &message::Message ) {
+    let reference = msg.param( 1 ).unwrap_or( "" );
+    if reference.starts_with( "+" ) {
+      match msg.tag( "label" ) {
+        Some ( l ) => { self.batches.insert( l, reference.slice_from( 1 ).to_string( ) ); },
+        None       => (),
+      }
+    } else if reference.starts_with( "-" ) {
+      let reference = reference.slice_from( 1 );
+      let label = self.batches.iter( ).find( |&( _, r )| r.as_slice( ) == reference ).map( |( l, _ )| l.clone( ) );
+      match label {
+        Some ( l ) => for p in self.pending.iter_mut( ) {
+          if p.label.as_ref( ) == Some( &l ) {
+            p.done = true;
+          }
+        },
+        None       => (),
+      }
+    }
+  }
+
+  /// `find` finds the pending query a reply belongs to
+  fn find( &self, msg : &message::Message ) -> Option < usize > {
+    // labeled replies name their query
+    let label = match msg.tag( "batch" ) {
+      Some ( b ) => self.batches.iter( ).find( |&( _, r )| *r == b ).map( |( l, _ )| l.clone( ) ),
+      None       => msg.tag( "label" ),
+    };
+    match label {
+      Some ( l ) => return self.pending.iter( ).position( |p| p.label.as_ref( ) == Some( &l ) ),
+      None       => (),
+    }
+
+    // WHOX replies name their query by its token
+    if msg.code.as_slice( ) == "354" {
+      let token = msg.param( 2 );
+      return self.pending.iter( ).position( |p| !p.done && p.token.as_ref( ).map( |t| t.as_slice( ) ) == token );
+    }
+
+    // otherwise match the oldest query of the right kind and target. Plain
+    // WHO replies can't answer a WHOX query.
+    let ( kind, target ) = match reply_key( msg ) {
+      Some ( k ) => k,
+      None       => return None,
+    };
+    let plain = msg.code.as_slice( ) == "352";
+    self.pending.iter( ).position( |p| {
+      !p.done && p.label.is_none( ) && !( plain && p.token.is_some( ) ) && match kind {
+        Some ( k ) => p.kind == k,
+        None       => p.kind != QueryKind::List,
+      } && match target {
+        Some ( ref t ) => p.target == *t,
+        None           => true,
+      }
+    } )
+  }
+}
+
+/// `reply_key` works out which kind of query a numeric answers, and the
+/// target it is about
+///
+/// # Returns
+///
+/// None if the message isn't a query reply. The kind is None for errors that
+/// can answer any query about the target. The target is None for replies
+/// that don't name one.
+fn reply_key( msg : &message::Message ) -> Option < ( Option < QueryKind >, Option < String > ) > {
+  let param = |n| msg.param( n ).map( |p| p.to_ascii_lowercase( ) );
+  let ( kind, target ) = match msg.code.as_slice( ) {
+    "311" | "312" | "313" | "317" | "319" | "330" | "671" | "318" | "301" =>
+      ( Some( QueryKind::Whois ), param( 2 ) ),
+    "352"                         => ( Some( QueryKind::Who ), None ),
+    "315"                         => ( Some( QueryKind::Who ), param( 2 ) ),
+    "321" | "322" | "323" | "263" => ( Some( QueryKind::List ), None ),
+    "353"                         => ( Some( QueryKind::Names ), param( 3 ) ),
+    "366"                         => ( Some( QueryKind::Names ), param( 2 ) ),
+    "324" | "329" | "221"         => ( Some( QueryKind::Mode ), param( 2 ) ),
+    "401" | "402" | "403"         => ( None, param( 2 ) ),
+    _                             => return None,
+  };
+  // RPL_UMODEIS names our modes, not our nick
+  if msg.code.as_slice( ) == "221" {
+    return Some( ( kind, msg.param( 1 ).map( |p| p.to_ascii_lowercase( ) ) ) );
+  }
+  Some( ( kind, target ) )
+}
+
+/// `update` adds a reply numeric to a pending query
+fn update( p : &mut Pending, msg : &message::Message ) {
+  let param = |n| msg.param( n ).unwrap_or( "" ).to_string( );
+  match msg.code.as_slice( ) {
+    "401" | "402" => {
+      p.error = Some( QueryError::NoSuchNick( param( 2 ) ) );
+      // a WHOIS still gets its RPL_ENDOFWHOIS
+      if p.kind != QueryKind::Whois {
+        p.done = true;
+      }
+      return;
+    },
+    "403"         => {
+      p.error = Some( QueryError::NoSuchChannel( param( 2 ) ) );
+      p.done = true;
+      return;
+    },
+    "263"         => {
+      p.error = Some( QueryError::Server( msg.code.clone( ), msg.trailing( ).unwrap_or( "" ).to_string( ) ) );
+      p.done = true;
+      return;
+    },
+    _             => (),
+  }
+
+  match p.reply {
+    QueryReply::Whois ( ref mut w ) => match msg.code.as_slice( ) {
+      "311" => {
+        w.nick = param( 2 );
+        w.user = param( 3 );
+        w.host = param( 4 );
+        w.real = param( 6 );
+      },
+      "312" => {
+        w.server = param( 3 );
+        w.server_info = param( 4 );
+      },
+      "313" => w.operator = true,
+      "317" => {
+        w.idle = msg.param( 3 ).and_then( |i| i.parse::< u64 >( ).ok( ) );
+        w.signon = msg.param( 4 ).and_then( |s| s.parse::< i64 >( ).ok( ) );
+      },
+      "319" => w.channels.extend( param( 3 ).as_slice( ).split( ' ' )
+        .filter( |c| !c.is_empty( ) ).map( |c| c.to_string( ) ) ),
+      "330" => w.account = Some( param( 3 ) ),
+      "301" => w.away = Some( param( 3 ) ),
+      "671" => w.secure = true,
+      "318" => p.done = true,
+      _     => (),
+    },
+    QueryReply::Who ( ref mut v )   => match msg.code.as_slice( ) {
+      "352" => {
+        // the last parameter is "<hops> <realname>"
+        let last = param( 8 );
+        let ( hops, real ) = match last.as_slice( ).find( ' ' ) {
+          Some ( n ) => ( last.as_slice( ).slice_to( n ).parse::< u32 >( ).ok( ).unwrap_or( 0 ),
+            last.as_slice( ).slice_from( n + 1 ).to_string( ) ),
+          None       => ( last.as_slice( ).parse::< u32 >( ).ok( ).unwrap_or( 0 ), String::new( ) ),
+        };
+        v.push( WhoEntry {
+          channel : param( 2 ),
+          user    : param( 3 ),
+          host    : param( 4 ),
+          server  : param( 5 ),
+          nick    : param( 6 ),
+          flags   : param( 7 ),
+          hops    : hops,
+          real    : real,
+          account : None,
+        } );
+      },
+      // our WHOX fields: <token> <chan> <user> <host> <nick> <flags> <account> :<real>
+      "354" => v.push( WhoEntry {
+        channel : param( 3 ),
+        user    : param( 4 ),
+        host    : param( 5 ),
+        server  : String::new( ),
+        nick    : param( 6 ),
+        flags   : param( 7 ),
+        hops    : 0,
+        real    : param( 9 ),
+        account : match msg.param( 8 ) {
+          Some ( "0" ) | None => None,
+          Some ( a )          => Some( a.to_string( ) ),
+        },
+      } ),
+      "315" => p.done = true,
+      _     => (),
+    },
+    QueryReply::List ( ref mut n )  => match msg.code.as_slice( ) {
+      "322" => {
+        let entry = ChannelListEntry {
+          name  : param( 2 ),
+          users : msg.param( 3 ).and_then( |u| u.parse::< u32 >( ).ok( ) ).unwrap_or( 0 ),
+          topic : param( 4 ),
+        };
+        match p.sink {
+          Some ( ref s ) if s.filter.matches( &entry ) => {
+            *n += 1;
+            let _ = s.tx.send( entry );
+          },
+          _                                            => (),
+        }
+      },
+      "323" => p.done = true,
+      _     => (),
+    },
+    QueryReply::Names ( ref mut n ) => match msg.code.as_slice( ) {
+      "353" => n.names.extend( param( 4 ).as_slice( ).split( ' ' )
+        .filter( |c| !c.is_empty( ) ).map( |c| c.to_string( ) ) ),
+      "366" => p.done = true,
+      _     => (),
+    },
+    QueryReply::Mode ( ref mut m )  => match msg.code.as_slice( ) {
+      "324" => {
+        let mut modes = Vec::new( );
+        let mut n = 3;
+        while let Some ( part ) = msg.param( n ) {
+          modes.push( part.to_string( ) );
+          n += 1;
+        }
+        m.modes = modes.connect( " " );
+        p.lingering = true;
+      },
+      "329" => {
+        m.created = msg.param( 3 ).and_then( |c| c.parse::< i64 >( ).ok( ) );
+        p.done = true;
+      },
+      "221" => {
+        m.modes = param( 2 );
+        p.done = true;
+      },
+      _     => (),
+    },
+  }
+}
+
+/// `no_reply` is the extractor of a query that was never sent
+fn no_reply < T > ( _ : QueryReply ) -> Option < T > {
+  None
+}
+
+/// `as_whois` takes a WhoisReply out of a QueryReply
+fn as_whois( r : QueryReply ) -> Option < WhoisReply > {
+  match r { QueryReply::Whois ( w ) => Some( w ), _ => None }
+}
+
+/// `as_who` takes WHO entries out of a QueryReply
+fn as_who( r : QueryReply ) -> Option < Vec < WhoEntry > > {
+  match r { QueryReply::Who ( w ) => Some( w ), _ => None }
+}
+
+/// `as_list` takes the LIST entry count out of a QueryReply
+fn as_list( r : QueryReply ) -> Option < usize > {
+  match r { QueryReply::List ( l ) => Some( l ), _ => None }
+}
+
+/// `as_names` takes a NamesReply out of a QueryReply
+fn as_names( r : QueryReply ) -> Option < NamesReply > {
+  match r { QueryReply::Names ( n ) => Some( n ), _ => None }
+}
+
+/// `as_mode` takes a ModeReply out of a QueryReply
+fn as_mode( r : QueryReply ) -> Option < ModeReply > {
+  match r { QueryReply::Mode ( m ) => Some( m ), _ => None }
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[allow(unused_imports)]
+  use message::Message;
+  #[allow(unused_imports)]
+  use super::{ChannelListEntry, ListFilter, QueryError, QueryTracker};
+
+  #[allow(dead_code)]
+  fn feed( t : &mut QueryTracker, lines : Vec < &str > ) {
+    for line in lines.iter( ) {
+      t.handle_at( &Message::parse( *line ).unwrap( ), 0 );
+    }
+  }
+
+  #[test]
+  fn test_whois () {
+    let mut t = QueryTracker::new( );
+    let ( line, handle ) = t.whois( "Lancey" );
+    assert! ( line == "WHOIS Lancey" );
+    feed( &mut t, vec![
+      ":srv 311 me Lancey lancey example.org * :Lancey Real",
+      ":other!o@h PRIVMSG #rust :interleaved traffic",
+      ":srv 319 me Lancey :@#rust +#irc",
+      ":srv 312 me Lancey irc.example.org :Example server",
+      ":srv 317 me Lancey 42 1422748800 :seconds idle, signon time",
+      ":srv 330 me Lancey lancey_acct :is logged in as",
+    ] );
+    assert! ( handle.try_result( ).is_none( ) );
+    feed( &mut t, vec![ ":srv 318 me lancey :End of /WHOIS list." ] );
+    let w = handle.try_result( ).unwrap( ).ok( ).unwrap( );
+    assert! ( w.nick == "Lancey" && w.user == "lancey" && w.host == "example.org" );
+    assert! ( w.real == "Lancey Real" && w.server == "irc.example.org" );
+    assert! ( w.channels == vec![ "@#rust", "+#irc" ] );
+    assert! ( w.idle == Some( 42 ) && w.signon == Some( 1422748800 ) );
+    assert! ( w.account == Some( "lancey_acct".to_string( ) ) );
+    assert! ( !w.operator && w.away.is_none( ) );
+  }
+
+  #[test]
+  fn test_whois_errors () {
+    let mut t = QueryTracker::new( );
+    let ( _, first ) = t.whois( "Ghost" );
+    let ( _, second ) = t.whois( "Lancey" );
+    feed( &mut t, vec![
+      ":srv 401 me Ghost :No such nick/channel",
+      ":srv 318 me Ghost :End of /WHOIS list.",
+    ] );
+    match first.try_result( ) {
+      Some ( Err ( QueryError::NoSuchNick ( n ) ) ) => assert! ( n == "Ghost" ),
+      _                                             => panic! ( "expected NoSuchNick" ),
+    }
+    assert! ( second.try_result( ).is_none( ) );
+
+    // unanswered queries expire
+    t.handle_at( &Message::parse( "PING :x" ).unwrap( ), ::time::get_time( ).sec + 1000 );
+    match second.try_result( ) {
+      Some ( Err ( QueryError::Timeout ) ) => (),
+      _                                    => panic! ( "expected Timeout" ),
+    }
+    assert! ( second.wait_timeout( 0 ).is_err( ) );
+  }
+
+  #[test]
+  fn test_who_names_list () {
+    let mut t = QueryTracker::new( );
+    let ( _, who ) = t.who( "#rust", false );
+    let ( _, names ) = t.names( "#rust" );
+    let ( line, list ) = t.list( ListFilter::new( ), "" );
+    assert! ( line == "LIST" );
+    feed( &mut t, vec![
+      ":srv 352 me #rust lancey example.org irc.example.org Lancey H@ :0 Lancey Real",
+      ":srv 352 me #rust det host.net irc.example.org Detective G :2 Det",
+      ":srv 315 me #rust :End of /WHO list.",
+      ":srv 353 me = #rust :@Lancey Detective",
+      ":srv 366 me #rust :End of /NAMES list.",
+      ":srv 321 me Channel :Users  Name",
+      ":srv 322 me #rust 2 :Rust talk",
+      ":srv 322 me #irc 10 :",
+      ":srv 323 me :End of /LIST",
+    ] );
+    let who = who.try_result( ).unwrap( ).ok( ).unwrap( );
+    assert! ( who.len( ) == 2 );
+    assert! ( who[0].nick == "Lancey" && who[0].flags == "H@" && who[0].real == "Lancey Real" );
+    assert! ( who[1].hops == 2 && who[1].real == "Det" );
+    let names = names.try_result( ).unwrap( ).ok( ).unwrap( );
+    assert! ( names.names == vec![ "@Lancey", "Detective" ] );
+    let list : Vec < ChannelListEntry > = list.collect( );
+    assert! ( list.len( ) == 2 && list[1].name == "#irc" && list[1].users == 10 );
+  }
+
+  #[test]
+  fn test_list_filter () {
+    let mut filter = ListFilter::new( );
+    filter.mask = Some( "#rust*".to_string( ) );
+    filter.min_users = Some( 5 );
+    filter.max_users = Some( 100 );
+    filter.newer_than = Some( 60 );
+    assert! ( filter.params( "CMNTU" ) == "#rust*,>4,<101,C<60" );
+    assert! ( filter.params( "" ) == "" );
+
+    // without ELIST everything is filtered as it arrives
+    let mut t = QueryTracker::new( );
+    let ( line, mut list ) = t.list( filter, "" );
+    assert! ( line == "LIST" );
+    assert! ( t.handle_at( &Message::parse( ":srv 321 me Channel :Users  Name" ).unwrap( ), 0 ) );
+    feed( &mut t, vec![
+      ":srv 322 me #rust 20 :Rust talk",
+      ":srv 322 me #rust-offtopic 2 :Too quiet",
+      ":srv 322 me #irc 50 :Wrong name",
+    ] );
+    let first = list.next( ).unwrap( );
+    assert! ( first.name == "#rust" && first.users == 20 && first.topic == "Rust talk" );
+    assert! ( !t.handle_at( &Message::parse( ":srv NOTICE me :hi" ).unwrap( ), 0 ) );
+    feed( &mut t, vec![ ":srv 323 me :End of /LIST" ] );
+    assert! ( list.next( ).is_none( ) && list.error( ).is_none( ) );
+
+    // servers that refuse the LIST end the stream with an error
+    let ( _, mut list ) = t.list( ListFilter::new( ), "U" );
+    feed( &mut t, vec![ ":srv 263 me LIST :Server load is temporarily too heavy" ] );
+    assert! ( list.next( ).is_none( ) && list.error( ).is_some( ) );
+  }
+
+  #[test]
+  fn test_whox () {
+    let mut t = QueryTracker::new( );
+    let ( line, who ) = t.who( "#rust", true );
+    assert! ( line == "WHO #rust %tcuhnfar,1" );
+    let ( line, other ) = t.who( "#irc", true );
+    assert! ( line == "WHO #irc %tcuhnfar,2" );
+    let ours = Message::parse( ":srv 354 me 2 #irc det host.net Detective G 0 :Det" ).unwrap( );
+    let theirs = Message::parse( ":srv 354 me 999 #rust someone else.org Other H * :Not ours" ).unwrap( );
+    assert! ( t.is_ours( &ours ) && !t.is_ours( &theirs ) );
+
+    // replies go to the query with their token, even out of order
+    feed( &mut t, vec![
+      ":srv 354 me 999 #rust someone else.org Other H * :Not ours",
+      ":srv 354 me 2 #irc det host.net Detective G 0 :Det",
+      ":srv 354 me 1 #rust lancey example.org Lancey H@ lancey_acct :Lancey Real",
+      ":srv 352 me #rust x y.org irc.example.org Plain H :0 Not WHOX",
+      ":srv 315 me #rust :End of /WHO list.",
+      ":srv 315 me #irc :End of /WHO list.",
+    ] );
+    let who = who.try_result( ).unwrap( ).ok( ).unwrap( );
+    assert! ( who.len( ) == 1 && who[0].nick == "Lancey" && who[0].real == "Lancey Real" );
+    assert! ( who[0].account == Some( "lancey_acct".to_string( ) ) );
+    let other = other.try_result( ).unwrap( ).ok( ).unwrap( );
+    assert! ( other.len( ) == 1 && other[0].nick == "Detective" && other[0].account.is_none( ) );
+  }
+
+  #[test]
+  fn test_who_refresh () {
+    let mut t = QueryTracker::new( );
+    let chans = vec![ "#rust".to_string( ), "#irc".to_string( ) ];
+    let now = ::time::get_time( ).sec;
+    assert! ( t.refresh_due( &chans, now + 1000 ).is_none( ) );
+    t.refresh = Some( 300 );
+    assert! ( t.refresh_due( &chans, now + 10 ).is_none( ) );
+    assert! ( t.refresh_due( &chans, now + 300 ) == Some( "#rust".to_string( ) ) );
+    assert! ( t.refresh_due( &chans, now + 400 ).is_none( ) );
+    assert! ( t.refresh_due( &chans, now + 600 ) == Some( "#irc".to_string( ) ) );
+
+    // the notify caps keep members current without refreshing
+    feed( &mut t, vec![ ":srv CAP me ACK :away-notify account-notify" ] );
+    assert! ( t.refresh_due( &chans, now + 900 ).is_none( ) );
+  }
+
+  #[test]
+  fn test_mode () {
+    let mut t = QueryTracker::new( );
+    let ( _, mode ) = t.mode( "#rust" );
+    feed( &mut t, vec![ ":srv 324 me #rust +ntk secret" ] );
+    assert! ( mode.try_result( ).is_none( ) );
+    feed( &mut t, vec![ ":srv 329 me #rust 1422748800" ] );
+    let mode = mode.try_result( ).unwrap( ).ok( ).unwrap( );
+    assert! ( mode.modes == "+ntk secret" && mode.created == Some( 1422748800 ) );
+
+    // servers that don't send RPL_CREATIONTIME
+    let ( _, mode ) = t.mode( "#irc" );
+    feed( &mut t, vec![ ":srv 324 me #irc +nt", ":srv NOTICE me :something else" ] );
+    let mode = mode.try_result( ).unwrap( ).ok( ).unwrap( );
+    assert! ( mode.modes == "+nt" && mode.created.is_none( ) );
+
+    let ( _, missing ) = t.mode( "#nowhere" );
+    feed( &mut t, vec![ ":srv 403 me #nowhere :No such channel" ] );
+    match missing.try_result( ) {
+      Some ( Err ( QueryError::NoSuchChannel ( c ) ) ) => assert! ( c == "#nowhere" ),
+      _                                                => panic! ( "expected NoSuchChannel" ),
+    }
+  }
+
+  #[test]
+  fn test_labeled () {
+    let mut t = QueryTracker::new( );
+    feed( &mut t, vec![ ":srv CAP me ACK :labeled-response batch" ] );
+    assert! ( t.labeled );
+    let ( line, first ) = t.whois( "Lancey" );
+    let ( _, second ) = t.whois( "Lancey" );
+    assert! ( line == "@label=rustirc1 WHOIS Lancey" );
+    feed( &mut t, vec![
+      "@label=rustirc2 :srv BATCH +b2 labeled-response",
+      "@batch=b2 :srv 311 me Lancey second host * :Second",
+      "@batch=b2 :srv 318 me Lancey :End of /WHOIS list.",
+      ":srv BATCH -b2",
+    ] );
+    assert! ( first.try_result( ).is_none( ) );
+    assert! ( second.try_result( ).unwrap( ).ok( ).unwrap( ).user == "second" );
+    feed( &mut t, vec![ "@label=rustirc1 :srv 401 me Lancey :No such nick/channel" ] );
+    assert! ( first.try_result( ).unwrap( ).is_err( ) );
+  }
+
+  #[test]
+  fn test_fail_all () {
+    let mut t = QueryTracker::new( );
+    let ( _, handle ) = t.whois( "Lancey" );
+    t.fail_all( QueryError::Disconnected );
+    match handle.wait( ) {
+      Err ( QueryError::Disconnected ) => (),
+      _                                => panic! ( "expected Disconnected" ),
+    }
+  }
+}
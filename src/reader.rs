@@ -1,10 +1,11 @@
 use std::old_io as io;
+use std::mem;
 use std::sync::mpsc::Sender;  // sender channel for passing back data
 use std::time::Duration;      // used for the sleep timer
 
 // import custom modules
 use connection::ConnEvent;    // used for passing back messages to the client
-use utils::debug;             // output debug for logging errors
+use connection::IrcStream;    // the stream to read from
 
 type   TTRY                    = u8;
 type   TTIMEOUT                = i64;
@@ -16,15 +17,111 @@ static IRC_TRY_LIMIT    : TTRY = 5;  // maximum number of tries before failing
 static IRC_READ_TIMEOUT : TTIMEOUT = 5;  // initial time between irc reads
 static IRC_READ_MULT    : TTIMEOUT = 2;  // multiply timeout by this on fail
 
+pub static MAX_LINE : usize = 512 + 8191; // bytes in a line: 512 for the message, 8191 for tags
+
+/// `Frame` is one line read from a stream
+///
+/// # Options
+///
+/// `Line( b )` - the bytes of a line, without the line ending
+/// `TooLong( n )` - a line of n bytes, more than the limit, was dropped
+pub enum Frame {
+  Line ( Vec < u8 > ),
+  TooLong ( usize ),
+}
+
+/// `LineFramer` splits a stream into lines
+///
+/// # Members
+///
+/// * `read` - buffered stream to read from
+/// * `max` - longest line allowed, in bytes including the line ending
+/// * `line` - bytes of the line being read so far
+/// * `len` - length of the line being read so far, counting dropped bytes
+///
+/// # Notes
+///
+/// * Lines may end in CRLF or a bare LF. Only the line ending is removed, so
+/// spaces at the end of a trailing parameter are kept.
+/// * A line over the limit is dropped as it's read, so it never takes more
+/// than `max` bytes of memory.
+/// * The line being read is kept in the framer, so a read error part way
+/// through a line doesn't lose the bytes already read.
+pub struct LineFramer < R > {
+  read : io::BufferedReader < R >,
+  max  : usize,
+  line : Vec < u8 >,
+  len  : usize,
+}
+
+impl < R : Reader > LineFramer < R > {
+  /// `new` creates a LineFramer reading from a stream
+  ///
+  /// # Arguments
+  ///
+  /// * `read` - the stream
+  /// * `max` - longest line allowed, in bytes including the line ending
+  pub fn new( read : R, max : usize ) -> LineFramer < R > {
+    LineFramer {
+      read : io::BufferedReader::new( read ),
+      max  : max,
+      line : Vec::new( ),
+      len  : 0,
+    }
+  }
+
+  /// `next_line` reads the next line from the stream
+  ///
+  /// # Returns
+  ///
+  /// The next frame, or the stream's error. After an error, the next call
+  /// carries on with the line it was part way through; a partial line
+  /// before EOF is never returned.
+  pub fn next_line( &mut self ) -> io::IoResult < Frame > {
+    loop {
+      let ( used, done ) = {
+        let buf = try! ( self.read.fill_buf( ) );
+        let ( used, done ) = match buf.iter( ).position( |b| *b == b'\n' ) {
+          Some ( n ) => ( n + 1, true ),
+          None       => ( buf.len( ), false ),
+        };
+        if self.len + used <= self.max {
+          self.line.push_all( buf.slice_to( used ) );
+        }
+        ( used, done )
+      };
+      self.read.consume( used );
+      self.len += used;
+      if done {
+        break;
+      }
+    }
+
+    let len = self.len;
+    let mut line = mem::replace( &mut self.line, Vec::new( ) );
+    self.len = 0;
+    if len > self.max {
+      return Ok( Frame::TooLong( len ) );
+    }
+    line.pop( );
+    if line.last( ) == Some( &b'\r' ) {
+      line.pop( );
+    }
+    Ok( Frame::Line( line ) )
+  }
+}
+
 /// `IrcReader` handles reading from an IRC stream
 ///
 /// # Members
 ///
-/// `tcp` - TcpStream through which IRC is connected
+/// `tcp` - stream through which IRC is connected
 /// `chan` - Send half of the channel used to communicate
+/// `max_line` - longest line to accept, in bytes including the line ending
 pub struct IrcReader {
-  tcp   : io::TcpStream,
-  chan  : Sender < ConnEvent >,
+  tcp      : IrcStream,
+  chan     : Sender < ConnEvent >,
+  max_line : usize,
 }
 
 impl IrcReader {
@@ -32,15 +129,18 @@ impl IrcReader {
   ///
   /// # Arguments
   ///
-  /// `tcp` - TcpStream of the IRC client
+  /// `tcp` - stream of the IRC client
   /// `tx` - Transmission channel used to talk to the program
+  /// `max_line` - longest line to accept, usually MAX_LINE
   pub fn new ( 
-    tcp : io::TcpStream,
-    tx : Sender < ConnEvent > 
+    tcp : IrcStream,
+    tx : Sender < ConnEvent >,
+    max_line : usize
     ) -> IrcReader {
     IrcReader {
-      tcp   : tcp,
-      chan  : tx,
+      tcp      : tcp,
+      chan     : tx,
+      max_line : max_line,
     }
   }
   
@@ -54,15 +154,15 @@ impl IrcReader {
     match self.tcp.peer_name() {
       // we did it, we're connected properly
       Ok ( peer ) => {
-        debug::oper( format! ( "irc reader opened at {} successfully", peer ) );
+        irc_info! ( "irc reader opened at {} successfully", peer );
         true
       },
       
       // couldn't connect for some reason
       Err ( e )   => {
-        debug::err( "opening irc reader", e.desc );
+        irc_error! ( "opening irc reader: {}", e.desc );
         match e.detail {
-          Some ( det ) => debug::info( det.as_slice( ) ),
+          Some ( det ) => irc_debug! ( "{}", det ),
           None         => (),
         };
         false
@@ -70,34 +170,36 @@ impl IrcReader {
     }
   }
   
-  /// `handle_read_success` parses and sends back a line read from IRC
+  /// `handle_read_success` sends back a line read from IRC
   ///
   /// # Arguments
   ///
-  /// * `line` - the line read from the TcpStream
+  /// * `line` - the bytes read from the stream, without the line ending
   /// * `try` - the attempt number
   ///
   /// # Returns
   ///
   /// IRC_TRY_SUCCESS if the operation completed successfully. Otherwise it
   /// returns try plus IRC_TRY_FAILURE.
-  fn handle_read_success ( &self, line : String, try : TTRY ) -> TTRY {
-    // trim whitespace (including the newline)
-    let pass = line.as_slice( ).trim_right( );
-    
+  ///
+  /// # Notes
+  ///
+  /// * Lines are passed back as bytes. Decoding is up to the client, so a line
+  /// that isn't UTF-8 is not a read error.
+  fn handle_read_success ( &self, line : Vec < u8 >, try : TTRY ) -> TTRY {
     // pass our message back to the client
-    match self.chan.send( ConnEvent::Recv( pass.to_string( ) ) ) {
+    match self.chan.send( ConnEvent::Recv( line ) ) {
       // it worked, reset the try counter
       Ok ( _ )  => {
         if try > IRC_TRY_SUCCESS {
-          debug::info( format! ( "successful read after {} attempts", try ) );
+          irc_debug! ( "successful read after {} attempts", try );
         }
         IRC_TRY_SUCCESS
       },
       
       // an error occurred
       Err ( _ ) => {
-        debug::err( "irc reader send", "receiver hung up" );
+        irc_error! ( "irc reader send: receiver hung up" );
         IRC_TRY_LIMIT
       },
     }
@@ -107,7 +209,7 @@ impl IrcReader {
   ///
   /// # Arguments
   ///
-  /// * `e` - the error returned by the TcpStream
+  /// * `e` - the error returned by the stream
   /// * `try` - the attempt number
   ///
   /// # Returns
@@ -118,15 +220,15 @@ impl IrcReader {
     match e.kind {
       // eof means the tcp connection was closed
       io::IoErrorKind::EndOfFile => {
-        debug::err( "irc reader receive", "eof reported, closing connection" );
+        irc_error! ( "irc reader receive: eof reported, closing connection" );
         IRC_TRY_LIMIT
       },
       
       // all other errors
       _                          => {
-        debug::err( "irc reader receive", e.desc );
+        irc_error! ( "irc reader receive: {}", e.desc );
         match e.detail {
-          Some ( det ) => debug::info( det.as_slice( ) ),
+          Some ( det ) => irc_debug! ( "{}", det ),
           None         => (),
         };
         try + IRC_TRY_FAILURE
@@ -149,12 +251,12 @@ impl IrcReader {
   fn get_next_try ( &self, try : TTRY, time : TTIMEOUT ) -> Option < TTIMEOUT > {
     // end the reader if we've gone over the try limit
     if try >= IRC_TRY_LIMIT {
-      debug::err( "irc reader", format! ( "failed after {} retries", try ) );
+      irc_error! ( "irc reader: failed after {} retries", try );
       None
       
     // if we've failed, don't retry immediately
     } else if try > IRC_TRY_SUCCESS {
-      debug::info( format! ( "retrying after {} seconds...", time ) );
+      irc_debug! ( "retrying after {} seconds...", time );
       io::timer::sleep( Duration::seconds( time ) );
       Some( time * IRC_READ_MULT )
       
@@ -173,7 +275,7 @@ impl IrcReader {
   pub fn start ( &mut self ) {
     let mut try   = IRC_TRY_INITIAL;
     let mut time  = IRC_READ_TIMEOUT;
-    let mut read  = io::BufferedReader::new( self.tcp.clone( ) );
+    let mut read  = LineFramer::new( self.tcp.clone( ), self.max_line );
     // Check that we're connected to a peer
     if !self.has_peer( ) {
       return;
@@ -181,9 +283,17 @@ impl IrcReader {
     
     // Read loop
     loop {
-      try = match read.read_line( ) {
-        Ok ( line ) => self.handle_read_success( line, try ),
-        Err ( e )   => self.handle_read_failure( e, try ),
+      try = match read.next_line( ) {
+        Ok ( Frame::Line ( line ) )  => self.handle_read_success( line, try ),
+        Ok ( Frame::TooLong ( n ) )  => {
+          // the stream is still in step, so this isn't a failed read
+          irc_warn! ( "irc reader receive: dropped a {} byte line, the limit is {}", n, self.max_line );
+          match self.chan.send( ConnEvent::TooLong( n ) ) {
+            Ok ( _ )  => try,
+            Err ( _ ) => IRC_TRY_LIMIT,
+          }
+        },
+        Err ( e )                    => self.handle_read_failure( e, try ),
       };
         
       // Fail automatically after 5 tries
@@ -193,10 +303,94 @@ impl IrcReader {
       };
     }
     
-    debug::oper( "closing irc reader..." );
+    irc_info! ( "closing irc reader..." );
     match self.chan.send( ConnEvent::Abort( String::from_str( "irc reader closed" ) ) ) {
       Ok ( _ )  => (),
-      Err ( _ ) => debug::err( "closing irc reader", "" ),
+      Err ( _ ) => irc_error! ( "closing irc reader" ),
     }
   }
-}
\ No newline at end of file
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[allow(unused_imports)]
+  use std::old_io as io;
+  #[allow(unused_imports)]
+  use super::{Frame, LineFramer};
+
+  #[allow(dead_code)]
+  fn line( framer : &mut LineFramer < io::MemReader > ) -> Vec < u8 > {
+    match framer.next_line( ) {
+      Ok ( Frame::Line ( l ) )    => l,
+      Ok ( Frame::TooLong ( n ) ) => panic! ( "unexpected {} byte line", n ),
+      Err ( e )                   => panic! ( "read failed: {}", e.desc ),
+    }
+  }
+
+  #[test]
+  fn test_framing () {
+    let input = b"PING :a\r\nPING :b\nPRIVMSG #rust :spaced  \r\n\r\nPART #rust".to_vec( );
+    let mut framer = LineFramer::new( io::MemReader::new( input ), 512 );
+    assert! ( line( &mut framer ) == b"PING :a".to_vec( ) );
+    assert! ( line( &mut framer ) == b"PING :b".to_vec( ) );
+    assert! ( line( &mut framer ) == b"PRIVMSG #rust :spaced  ".to_vec( ) );
+    assert! ( line( &mut framer ) == b"".to_vec( ) );
+
+    // a line cut off by EOF is never returned
+    assert! ( framer.next_line( ).is_err( ) );
+  }
+
+  // reads one chunk per call, failing with a timeout on an empty chunk
+  #[allow(dead_code)]
+  struct Flaky {
+    chunks : Vec < Vec < u8 > >,
+  }
+
+  impl Reader for Flaky {
+    fn read( &mut self, buf : &mut [u8] ) -> io::IoResult < usize > {
+      if self.chunks.is_empty( ) {
+        return Err( io::standard_error( io::EndOfFile ) );
+      }
+      let chunk = self.chunks.remove( 0 );
+      if chunk.is_empty( ) {
+        return Err( io::standard_error( io::TimedOut ) );
+      }
+      ::std::slice::bytes::copy_memory( buf, chunk.as_slice( ) );
+      Ok( chunk.len( ) )
+    }
+  }
+
+  #[test]
+  fn test_error_mid_line () {
+    let chunks = vec! ( b"PRIVMSG #ru".to_vec( ), Vec::new( ), b"st :hi\r\nPING".to_vec( ),
+      Vec::new( ), b" :ok\r\n".to_vec( ) );
+    let mut framer = LineFramer::new( Flaky { chunks : chunks }, 512 );
+    assert! ( framer.next_line( ).is_err( ) );
+    match framer.next_line( ) {
+      Ok ( Frame::Line ( l ) ) => assert! ( l == b"PRIVMSG #rust :hi".to_vec( ) ),
+      _                        => panic! ( "expected the rest of the line" ),
+    }
+    assert! ( framer.next_line( ).is_err( ) );
+    match framer.next_line( ) {
+      Ok ( Frame::Line ( l ) ) => assert! ( l == b"PING :ok".to_vec( ) ),
+      _                        => panic! ( "expected the rest of the line" ),
+    }
+  }
+
+  #[test]
+  fn test_too_long () {
+    let mut input = ::std::iter::repeat( b'a' ).take( 100 ).collect::< Vec < u8 > >( );
+    input.push_all( b"\r\nPING :ok\r\n" );
+    let mut framer = LineFramer::new( io::MemReader::new( input ), 64 );
+    match framer.next_line( ) {
+      Ok ( Frame::TooLong ( n ) ) => assert! ( n == 102 ),
+      _                           => panic! ( "expected an oversized line" ),
+    }
+    assert! ( line( &mut framer ) == b"PING :ok".to_vec( ) );
+
+    // the limit counts the line ending
+    let mut framer = LineFramer::new( io::MemReader::new( b"abcd\r\nabcde\r\n".to_vec( ) ), 6 );
+    assert! ( line( &mut framer ) == b"abcd".to_vec( ) );
+    assert! ( match framer.next_line( ) { Ok ( Frame::TooLong ( 7 ) ) => true, _ => false } );
+  }
+}
@@ -0,0 +1,336 @@
+// import built in modules
+use std::ascii::AsciiExt;
+use rustc_serialize::base64::{STANDARD, ToBase64};
+
+// import custom modules
+use info;
+use message;
+
+static CAP_VERSION : &'static str = "302"; // CAP LS version we speak
+static SASL_CHUNK  : usize = 400;         // bytes of base64 per AUTHENTICATE line
+static WANTED_CAPS : [&'static str; 6] = [ // caps the client knows how to use
+  "labeled-response", "draft/labeled-response", "batch",
+  "away-notify", "account-notify", "extended-join",
+];
+
+/// `Registration` registers the client and negotiates IRCv3 capabilities
+///
+/// # Members
+///
+/// * `alt_nicks` - nicks to try in order if ours is taken while registering
+/// * `sasl` - account and password to log in with SASL PLAIN, if any
+/// * `started` - set once NICK and USER have been sent
+/// * `negotiating` - set while the server is holding registration for CAP
+/// * `registered` - set once the server welcomed us
+/// * `authenticating` - set while a SASL exchange is under way
+/// * `next_alt` - index of the next alternate nick to try
+/// * `offered` - caps the server listed in CAP LS so far
+/// * `enabled` - caps the server acknowledged
+///
+/// # Notes
+///
+/// * CAP LS is sent before NICK and USER. A server that knows CAP waits for
+/// CAP END before finishing registration, and one that doesn't ignores it or
+/// answers with ERR_UNKNOWNCOMMAND and registers us anyway.
+/// * Only caps the client knows how to use are requested, so the server never
+/// sends anything the handler can't make sense of.
+/// * A failed SASL login is logged and registration carries on without it,
+/// as it would for a server that doesn't offer SASL.
+pub struct Registration {
+  pub alt_nicks   : Vec < String >,
+  pub sasl        : Option < ( String, String ) >,
+  pub started     : bool,
+  pub negotiating : bool,
+  pub registered  : bool,
+
+  authenticating  : bool,
+  next_alt        : usize,
+  offered         : Vec < String >,
+  enabled         : Vec < String >,
+}
+
+impl Clone for Registration {
+  fn clone( &self ) -> Registration {
+    Registration {
+      alt_nicks      : self.alt_nicks.clone( ),
+      sasl           : self.sasl.clone( ),
+      started        : self.started,
+      negotiating    : self.negotiating,
+      registered     : self.registered,
+      authenticating : self.authenticating,
+      next_alt       : self.next_alt,
+      offered        : self.offered.clone( ),
+      enabled        : self.enabled.clone( ),
+    }
+  }
+}
+
+impl Registration {
+  /// `new` creates a registration that hasn't started
+  pub fn new( ) -> Registration {
+    Registration {
+      alt_nicks      : Vec::new( ),
+      sasl           : None,
+      started        : false,
+      negotiating    : false,
+      registered     : false,
+      authenticating : false,
+      next_alt       : 0,
+      offered        : Vec::new( ),
+      enabled        : Vec::new( ),
+    }
+  }
+
+  /// `start` builds the lines that begin registration
+  ///
+  /// # Arguments
+  ///
+  /// * `i` - the client info, for our nick, username and realname
+  ///
+  /// # Returns
+  ///
+  /// The lines to send to the server, in order, or nothing if registration
+  /// already started
+  pub fn start( &mut self, i : &info::IrcInfo ) -> Vec < String > {
+    if self.started {
+      return Vec::new( );
+    }
+    self.started = true;
+    self.negotiating = true;
+    vec![
+      format! ( "CAP LS {}", CAP_VERSION ),
+      format! ( "NICK {}", i.nick_name ),
+      format! ( "USER {} * * :{}", i.user_name, i.real_name ),
+    ]
+  }
+
+  /// `handle` answers CAP negotiation, SASL and nick errors while the server
+  /// registers us
+  ///
+  /// # Arguments
+  ///
+  /// * `msg` - message received from the server
+  ///
+  /// # Returns
+  ///
+  /// The lines to send back to the server
+  pub fn handle( &mut self, msg : &message::Message ) -> Vec < String > {
+    let mut lines = Vec::new( );
+    match msg.code.as_slice( ) {
+      "CAP" if self.negotiating => match msg.param( 2 ).map( |s| s.to_ascii_uppercase( ) ) {
+        Some ( ref s ) if s.as_slice( ) == "LS" => {
+          // a "*" before the list means more LS lines follow
+          let ( more, caps ) = match ( msg.param( 3 ), msg.param( 4 ) ) {
+            ( Some ( "*" ), Some ( c ) ) => ( true, c ),
+            ( Some ( c ), _ )            => ( false, c ),
+            _                            => ( false, "" ),
+          };
+          for cap in caps.split( ' ' ).filter( |c| !c.is_empty( ) ) {
+            let name = cap.split( '=' ).next( ).unwrap_or( "" );
+            self.offered.push( name.to_ascii_lowercase( ) );
+          }
+          if !more {
+            if self.sasl.is_some( ) && !self.offered.iter( ).any( |c| c.as_slice( ) == "sasl" ) {
+              irc_warn! ( "the server doesn't offer SASL, registering without logging in" );
+            }
+            let wanted = self.wanted( );
+            if wanted.is_empty( ) {
+              lines.push( self.end( ) );
+            } else {
+              lines.push( format! ( "CAP REQ :{}", wanted.connect( " " ) ) );
+            }
+          }
+        },
+        Some ( ref s ) if s.as_slice( ) == "ACK" => {
+          for cap in msg.param( 3 ).unwrap_or( "" ).split( ' ' ).filter( |c| !c.is_empty( ) ) {
+            self.enabled.push( cap.to_ascii_lowercase( ) );
+          }
+          if self.sasl.is_some( ) && self.enabled( "sasl" ) && !self.authenticating {
+            self.authenticating = true;
+            lines.push( String::from_str( "AUTHENTICATE PLAIN" ) );
+          } else if !self.authenticating {
+            lines.push( self.end( ) );
+          }
+        },
+        Some ( ref s ) if s.as_slice( ) == "NAK" => lines.push( self.end( ) ),
+        _                                        => (),
+      },
+      // the server is ready for our credentials
+      "AUTHENTICATE" if self.authenticating && msg.param( 1 ) == Some( "+" ) => {
+        lines.push_all( self.credentials( ).as_slice( ) );
+      },
+      // RPL_SASLSUCCESS, then the failures: ERR_NICKLOCKED, ERR_SASLFAIL,
+      // ERR_SASLTOOLONG, ERR_SASLABORTED and ERR_SASLALREADY
+      "903"          if self.authenticating => {
+        irc_info! ( "logged in with SASL" );
+        self.authenticating = false;
+        lines.push( self.end( ) );
+      },
+      "902" | "904" | "905" | "906" | "907" if self.authenticating => {
+        irc_warn! ( "SASL login failed: {}", msg.trailing( ).unwrap_or( msg.code.as_slice( ) ) );
+        self.authenticating = false;
+        lines.push( self.end( ) );
+      },
+      // ERR_ERRONEUSNICKNAME, ERR_NICKNAMEINUSE and ERR_NICKCOLLISION while
+      // registering mean we have no nick yet, so try the next one
+      "432" | "433" | "436" if !self.registered => {
+        match self.alt_nicks.get( self.next_alt ) {
+          Some ( n ) => {
+            irc_info! ( "nick {} is unavailable, trying {}", msg.param( 2 ).unwrap_or( "" ), n );
+            lines.push( format! ( "NICK {}", n ) );
+          },
+          None       => irc_warn! ( "nick {} is unavailable and there are no more to try",
+            msg.param( 2 ).unwrap_or( "" ) ),
+        }
+        self.next_alt += 1;
+      },
+      // the server registered us, so there's nothing left to negotiate
+      "001"                     => {
+        self.negotiating = false;
+        self.authenticating = false;
+        self.registered = true;
+      },
+      _                         => (),
+    }
+    lines
+  }
+
+  /// `enabled` checks whether the server acknowledged a cap
+  ///
+  /// # Arguments
+  ///
+  /// * `cap` - name of the cap, e.g. "batch"
+  pub fn enabled( &self, cap : &str ) -> bool {
+    let cap = cap.to_ascii_lowercase( );
+    self.enabled.iter( ).any( |c| *c == cap )
+  }
+
+  /// `wanted` lists the offered caps we know how to use
+  fn wanted( &self ) -> Vec < String > {
+    let mut wanted : Vec < String > = WANTED_CAPS.iter( )
+      .filter( |w| self.offered.iter( ).any( |o| o.as_slice( ) == **w ) )
+      .map( |w| w.to_string( ) )
+      .collect( );
+    if self.sasl.is_some( ) && self.offered.iter( ).any( |o| o.as_slice( ) == "sasl" ) {
+      wanted.push( String::from_str( "sasl" ) );
+    }
+    wanted
+  }
+
+  /// `credentials` builds the AUTHENTICATE lines carrying our SASL PLAIN
+  /// credentials
+  ///
+  /// # Notes
+  ///
+  /// * The payload is split into 400 byte lines. A payload that fills its
+  /// last line exactly is followed by "AUTHENTICATE +" so the server knows it
+  /// has ended.
+  fn credentials( &self ) -> Vec < String > {
+    let ( account, password ) = match self.sasl {
+      Some ( ( ref a, ref p ) ) => ( a.as_slice( ), p.as_slice( ) ),
+      None                      => return vec![ String::from_str( "AUTHENTICATE *" ) ],
+    };
+    let plain = format! ( "{}\0{}\0{}", account, account, password );
+    let payload = plain.as_bytes( ).to_base64( STANDARD );
+    let mut lines = Vec::new( );
+    let mut rest = payload.as_slice( );
+    while rest.len( ) >= SASL_CHUNK {
+      lines.push( format! ( "AUTHENTICATE {}", rest.slice_to( SASL_CHUNK ) ) );
+      rest = rest.slice_from( SASL_CHUNK );
+    }
+    if rest.is_empty( ) {
+      lines.push( String::from_str( "AUTHENTICATE +" ) );
+    } else {
+      lines.push( format! ( "AUTHENTICATE {}", rest ) );
+    }
+    lines
+  }
+
+  /// `end` finishes CAP negotiation so the server can register us
+  fn end( &mut self ) -> String {
+    self.negotiating = false;
+    String::from_str( "CAP END" )
+  }
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[allow(unused_imports)]
+  use info::IrcInfo;
+  #[allow(unused_imports)]
+  use message::Message;
+
+  #[allow(dead_code)]
+  fn feed( reg : &mut super::Registration, line : &str ) -> Vec < String > {
+    reg.handle( &Message::parse( line ).unwrap( ) )
+  }
+
+  #[test]
+  fn test_start () {
+    let mut reg = super::Registration::new( );
+    let info = IrcInfo::gen( "Lancey", "lance", "Lance", vec![] );
+    assert! ( reg.start( &info ) == vec![ "CAP LS 302".to_string( ), "NICK Lancey".to_string( ),
+      "USER lance * * :Lance".to_string( ) ] );
+    assert! ( reg.start( &info ).is_empty( ) );
+  }
+
+  #[test]
+  fn test_request () {
+    let mut reg = super::Registration::new( );
+    reg.start( &IrcInfo::gen( "Lancey", "lance", "Lance", vec![] ) );
+    assert! ( feed( &mut reg, ":srv CAP * LS * :multi-prefix batch sasl=PLAIN" ).is_empty( ) );
+    assert! ( feed( &mut reg, ":srv CAP * LS :labeled-response away-notify" ) ==
+      vec![ "CAP REQ :labeled-response batch away-notify".to_string( ) ] );
+    assert! ( feed( &mut reg, ":srv CAP * ACK :labeled-response batch away-notify" ) ==
+      vec![ "CAP END".to_string( ) ] );
+    assert! ( reg.enabled( "batch" ) && !reg.enabled( "sasl" ) );
+    assert! ( !reg.negotiating );
+    assert! ( feed( &mut reg, ":srv CAP * ACK :batch" ).is_empty( ) );
+  }
+
+  #[test]
+  fn test_nothing_wanted () {
+    let mut reg = super::Registration::new( );
+    reg.start( &IrcInfo::gen( "Lancey", "lance", "Lance", vec![] ) );
+    assert! ( feed( &mut reg, ":srv CAP * LS :multi-prefix" ) == vec![ "CAP END".to_string( ) ] );
+
+    let mut nak = super::Registration::new( );
+    nak.start( &IrcInfo::gen( "Lancey", "lance", "Lance", vec![] ) );
+    feed( &mut nak, ":srv CAP * LS :batch" );
+    assert! ( feed( &mut nak, ":srv CAP * NAK :batch" ) == vec![ "CAP END".to_string( ) ] );
+    assert! ( !nak.enabled( "batch" ) );
+  }
+
+  #[test]
+  fn test_sasl () {
+    let mut reg = super::Registration::new( );
+    reg.sasl = Some( ( "lance".to_string( ), "hunter2".to_string( ) ) );
+    reg.start( &IrcInfo::gen( "Lancey", "lance", "Lance", vec![] ) );
+    assert! ( feed( &mut reg, ":srv CAP * LS :sasl=PLAIN,EXTERNAL batch" ) ==
+      vec![ "CAP REQ :batch sasl".to_string( ) ] );
+    assert! ( feed( &mut reg, ":srv CAP * ACK :batch sasl" ) == vec![ "AUTHENTICATE PLAIN".to_string( ) ] );
+    assert! ( feed( &mut reg, "AUTHENTICATE +" ) ==
+      vec![ "AUTHENTICATE bGFuY2UAbGFuY2UAaHVudGVyMg==".to_string( ) ] );
+    assert! ( feed( &mut reg, ":srv 900 Lancey Lancey!lance@host lance :You are now logged in" ).is_empty( ) );
+    assert! ( feed( &mut reg, ":srv 903 Lancey :SASL authentication successful" ) == vec![ "CAP END".to_string( ) ] );
+
+    let mut bad = super::Registration::new( );
+    bad.sasl = Some( ( "lance".to_string( ), "wrong".to_string( ) ) );
+    bad.start( &IrcInfo::gen( "Lancey", "lance", "Lance", vec![] ) );
+    feed( &mut bad, ":srv CAP * LS :sasl" );
+    feed( &mut bad, ":srv CAP * ACK :sasl" );
+    assert! ( feed( &mut bad, ":srv 904 Lancey :SASL authentication failed" ) == vec![ "CAP END".to_string( ) ] );
+  }
+
+  #[test]
+  fn test_alt_nicks () {
+    let mut reg = super::Registration::new( );
+    reg.alt_nicks = vec![ "Lancey_".to_string( ), "Lancey__".to_string( ) ];
+    reg.start( &IrcInfo::gen( "Lancey", "lance", "Lance", vec![] ) );
+    assert! ( feed( &mut reg, ":srv 433 * Lancey :Nickname is already in use" ) == vec![ "NICK Lancey_".to_string( ) ] );
+    assert! ( feed( &mut reg, ":srv 433 * Lancey_ :Nickname is already in use" ) == vec![ "NICK Lancey__".to_string( ) ] );
+    assert! ( feed( &mut reg, ":srv 433 * Lancey__ :Nickname is already in use" ).is_empty( ) );
+    feed( &mut reg, ":srv 001 Lancey__ :Welcome" );
+    assert! ( feed( &mut reg, ":srv 433 Lancey__ Other :Nickname is already in use" ).is_empty( ) );
+  }
+}
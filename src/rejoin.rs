@@ -0,0 +1,449 @@
+// import built in modules
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+use std::sync::mpsc;
+
+// import custom modules
+use message;
+
+pub type TDELAY                   = u64;
+
+static ARG_MODES_SET   : &'static str = "beIkloqahvfj"; // modes that take an argument when set
+static ARG_MODES_UNSET : &'static str = "beIkoqahv";    // modes that take an argument when unset
+static CHAN_PREFIXES   : &'static str = "#&+!";         // prefixes a channel name can start with
+
+/// `LeaveReason` is why we left a channel, or never got into it
+///
+/// # Options
+///
+/// `Parted( reason )` - we parted the channel
+/// `Kicked( by, reason )` - we were kicked by the nick by
+/// `JoinFailed( code, reason )` - the server refused our JOIN with the numeric
+/// code, e.g. 471 when the channel is full
+pub enum LeaveReason {
+  Parted ( String ),
+  Kicked ( String, String ),
+  JoinFailed ( String, String ),
+}
+
+impl Clone for LeaveReason {
+  fn clone( &self ) -> LeaveReason {
+    match *self {
+      LeaveReason::Parted ( ref r )           => LeaveReason::Parted( r.clone( ) ),
+      LeaveReason::Kicked ( ref b, ref r )    => LeaveReason::Kicked( b.clone( ), r.clone( ) ),
+      LeaveReason::JoinFailed ( ref c, ref r ) => LeaveReason::JoinFailed( c.clone( ), r.clone( ) ),
+    }
+  }
+}
+
+/// `ChannelLeft` tells the user we're no longer in a channel
+///
+/// # Members
+///
+/// * `channel` - the channel we left
+/// * `reason` - why we left it
+/// * `retrying` - set if a rejoin has been scheduled
+pub struct ChannelLeft {
+  pub channel  : String,
+  pub reason   : LeaveReason,
+  pub retrying : bool,
+}
+
+impl Clone for ChannelLeft {
+  fn clone( &self ) -> ChannelLeft {
+    ChannelLeft {
+      channel  : self.channel.clone( ),
+      reason   : self.reason.clone( ),
+      retrying : self.retrying,
+    }
+  }
+}
+
+/// `JoinPolicy` says when to rejoin channels
+///
+/// # Members
+///
+/// * `rejoin_on_kick` - rejoin after being kicked
+/// * `kick_delay` - milliseconds to wait before rejoining after a kick
+/// * `retry_full` - retry joins refused because the channel is full (+l)
+/// * `retry_delay` - milliseconds between retries of a full channel
+/// * `max_retries` - retries of a full channel before giving up
+/// * `knock` - KNOCK on invite only (+i) channels we can't join
+/// * `join_on_invite` - join when invited to a channel we failed to join
+pub struct JoinPolicy {
+  pub rejoin_on_kick : bool,
+  pub kick_delay     : TDELAY,
+  pub retry_full     : bool,
+  pub retry_delay    : TDELAY,
+  pub max_retries    : u32,
+  pub knock          : bool,
+  pub join_on_invite : bool,
+}
+
+impl JoinPolicy {
+  /// `new` creates a policy that never rejoins, like the client always has
+  pub fn new( ) -> JoinPolicy {
+    JoinPolicy {
+      rejoin_on_kick : false,
+      kick_delay     : 5000,
+      retry_full     : false,
+      retry_delay    : 60000,
+      max_retries    : 5,
+      knock          : false,
+      join_on_invite : false,
+    }
+  }
+}
+
+/// `JoinManager` remembers channel keys and decides when to rejoin
+///
+/// # Members
+///
+/// * `policy` - when to rejoin
+/// * `keys` - channel keys by lowercased channel
+/// * `retries` - retries of full channels so far, by lowercased channel
+/// * `failed` - lowercased channels whose JOIN failed, for `join_on_invite`
+/// * `pending` - lowercased channels we sent a JOIN for and haven't entered
+/// * `events` - subscribers to ChannelLeft events
+///
+/// # Notes
+///
+/// * Keys are learned from `set_key`, from MODE +k and from RPL_CHANNELMODEIS,
+/// so rejoins work on +k channels.
+/// * `handle` returns the lines to send with the milliseconds to wait before
+/// sending each of them, leaving the waiting to the client.
+/// * Join errors only count for channels with a JOIN from `join_line` still
+/// outstanding. The same numerics answer other commands, e.g. 403 for a PART
+/// or 437 for a NICK, and those mustn't look like a refused JOIN.
+pub struct JoinManager {
+  pub policy  : JoinPolicy,
+
+  keys        : HashMap < String, String >,
+  retries     : HashMap < String, u32 >,
+  failed      : Vec < String >,
+  pending     : Vec < String >,
+  events      : Vec < mpsc::Sender < ChannelLeft > >,
+}
+
+impl JoinManager {
+  /// `new` creates a manager with the default policy
+  pub fn new( ) -> JoinManager {
+    JoinManager {
+      policy  : JoinPolicy::new( ),
+      keys    : HashMap::new( ),
+      retries : HashMap::new( ),
+      failed  : Vec::new( ),
+      pending : Vec::new( ),
+      events  : Vec::new( ),
+    }
+  }
+
+  /// `subscribe` returns a receiver for ChannelLeft events
+  pub fn subscribe( &mut self ) -> mpsc::Receiver < ChannelLeft > {
+    let ( tx, rx ) = mpsc::channel( );
+    self.events.push( tx );
+    rx
+  }
+
+  /// `set_key` remembers or forgets the key of a channel
+  ///
+  /// # Arguments
+  ///
+  /// * `chan` - channel the key belongs to
+  /// * `key` - the key, or None to forget it
+  pub fn set_key( &mut self, chan : &str, key : Option < &str > ) {
+    match key {
+      Some ( k ) => { self.keys.insert( chan.to_ascii_lowercase( ), k.to_string( ) ); },
+      None       => { self.keys.remove( &chan.to_ascii_lowercase( ) ); },
+    }
+  }
+
+  /// `key` returns the key we know for a channel
+  pub fn key( &self, chan : &str ) -> Option < &str > {
+    self.keys.get( &chan.to_ascii_lowercase( ) ).map( |k| k.as_slice( ) )
+  }
+
+  /// `join_line` builds the JOIN for a channel, with its key if we know it,
+  /// and remembers that the JOIN is outstanding
+  pub fn join_line( &mut self, chan : &str ) -> String {
+    self.mark_pending( chan );
+    self.build_join( chan )
+  }
+
+  /// `build_join` builds the JOIN for a channel, with its key if we know it
+  pub fn build_join( &self, chan : &str ) -> String {
+    match self.key( chan ) {
+      Some ( k ) => format! ( "JOIN {} {}", chan, k ),
+      None       => format! ( "JOIN {}", chan ),
+    }
+  }
+
+  /// `mark_pending` remembers that a JOIN for a channel is outstanding
+  pub fn mark_pending( &mut self, chan : &str ) {
+    let lower = chan.to_ascii_lowercase( );
+    if !self.pending.contains( &lower ) {
+      self.pending.push( lower );
+    }
+  }
+
+  /// `handle` reacts to a message from the server
+  ///
+  /// # Arguments
+  ///
+  /// * `msg` - a message received from the server
+  /// * `nick` - our current nick
+  ///
+  /// # Returns
+  ///
+  /// Lines to send, each with the milliseconds to wait before sending it
+  pub fn handle( &mut self, msg : &message::Message, nick : &str ) -> Vec < ( TDELAY, String ) > {
+    let mut out = Vec::new( );
+    let us = |n : &str| n.to_ascii_lowercase( ) == nick.to_ascii_lowercase( );
+    let from_us = msg.nick( ).map( |n| us( n.as_slice( ) ) ).unwrap_or( false );
+    let trailing = msg.trailing( ).unwrap_or( "" ).to_string( );
+    match msg.code.as_slice( ) {
+      "JOIN" if from_us => {
+        let chan = msg.param( 1 ).unwrap_or( "" ).to_ascii_lowercase( );
+        self.retries.remove( &chan );
+        self.failed.retain( |c| *c != chan );
+        self.pending.retain( |c| *c != chan );
+      },
+      "PART" if from_us => {
+        let chan = msg.param( 1 ).unwrap_or( "" ).to_string( );
+        let reason = msg.param( 2 ).unwrap_or( "" ).to_string( );
+        self.emit( chan.as_slice( ), LeaveReason::Parted( reason ), false );
+      },
+      "KICK"            => {
+        let chan = msg.param( 1 ).unwrap_or( "" ).to_string( );
+        if us( msg.param( 2 ).unwrap_or( "" ) ) {
+          let by = msg.nick( ).unwrap_or( String::new( ) );
+          let rejoin = self.policy.rejoin_on_kick;
+          if rejoin {
+            out.push( ( self.policy.kick_delay, self.join_line( chan.as_slice( ) ) ) );
+          }
+          self.emit( chan.as_slice( ), LeaveReason::Kicked( by, msg.param( 3 ).unwrap_or( "" ).to_string( ) ), rejoin );
+        }
+      },
+      "MODE"            => self.observe_modes( msg, 1 ),
+      "324"             => self.observe_modes( msg, 2 ),
+      "INVITE"          => {
+        let chan = msg.param( 2 ).unwrap_or( "" ).to_string( );
+        let lower = chan.to_ascii_lowercase( );
+        if self.policy.join_on_invite && self.failed.contains( &lower ) {
+          out.push( ( 0, self.join_line( chan.as_slice( ) ) ) );
+        }
+      },
+      // ERR_CHANNELISFULL
+      "471"             => {
+        let chan = match self.refused( msg ) {
+          Some ( c ) => c,
+          None       => return out,
+        };
+        let lower = chan.to_ascii_lowercase( );
+        let tries = *self.retries.get( &lower ).unwrap_or( &0 );
+        let retry = self.policy.retry_full && tries < self.policy.max_retries;
+        if retry {
+          self.retries.insert( lower, tries + 1 );
+          out.push( ( self.policy.retry_delay, self.join_line( chan.as_slice( ) ) ) );
+        } else {
+          self.retries.remove( &lower );
+        }
+        self.fail( chan.as_slice( ), msg, trailing, retry );
+      },
+      // ERR_INVITEONLYCHAN
+      "473"             => {
+        let chan = match self.refused( msg ) {
+          Some ( c ) => c,
+          None       => return out,
+        };
+        if self.policy.knock {
+          out.push( ( 0, format! ( "KNOCK {} :Requesting an invite", chan ) ) );
+        }
+        self.fail( chan.as_slice( ), msg, trailing, false );
+      },
+      // ERR_NOSUCHCHANNEL, ERR_TOOMANYCHANNELS, ERR_UNAVAILRESOURCE,
+      // ERR_BANNEDFROMCHAN, ERR_BADCHANNELKEY, ERR_BADCHANMASK
+      "403" | "405" | "437" | "474" | "475" | "476" => {
+        let chan = match self.refused( msg ) {
+          Some ( c ) => c,
+          None       => return out,
+        };
+        self.fail( chan.as_slice( ), msg, trailing, false );
+      },
+      _                 => (),
+    }
+    out
+  }
+
+  /// `refused` finds the channel a join error refers to, if we're waiting on
+  /// a JOIN to it, and stops waiting
+  ///
+  /// # Arguments
+  ///
+  /// * `msg` - a join error numeric, with the channel after our nick
+  ///
+  /// # Returns
+  ///
+  /// The channel as the server named it, or None if the numeric isn't about
+  /// a JOIN of ours
+  fn refused( &mut self, msg : &message::Message ) -> Option < String > {
+    let chan = match msg.param( 2 ) {
+      Some ( c ) if c.chars( ).next( ).map_or( false, |p| CHAN_PREFIXES.contains_char( p ) ) => c.to_string( ),
+      _                                                                                    => return None,
+    };
+    let lower = chan.to_ascii_lowercase( );
+    match self.pending.iter( ).position( |c| *c == lower ) {
+      Some ( i ) => { self.pending.remove( i ); },
+      None       => return None,
+    }
+    Some( chan )
+  }
+
+  /// `fail` records a refused JOIN and tells the subscribers
+  fn fail( &mut self, chan : &str, msg : &message::Message, reason : String, retrying : bool ) {
+    let lower = chan.to_ascii_lowercase( );
+    if !self.failed.contains( &lower ) {
+      self.failed.push( lower );
+    }
+    self.emit( chan, LeaveReason::JoinFailed( msg.code.clone( ), reason ), retrying );
+  }
+
+  /// `emit` sends a ChannelLeft event to every subscriber, dropping the ones
+  /// that have hung up
+  fn emit( &mut self, chan : &str, reason : LeaveReason, retrying : bool ) {
+    let event = ChannelLeft { channel : chan.to_string( ), reason : reason, retrying : retrying };
+    self.events.retain( |tx| tx.send( event.clone( ) ).is_ok( ) );
+  }
+
+  /// `observe_modes` learns channel keys from a mode change
+  ///
+  /// # Arguments
+  ///
+  /// * `msg` - a MODE or RPL_CHANNELMODEIS message
+  /// * `first` - index of the channel parameter
+  ///
+  /// # Notes
+  ///
+  /// * Which modes take arguments varies between servers, so this assumes the
+  /// common ones.
+  fn observe_modes( &mut self, msg : &message::Message, first : usize ) {
+    let chan = match msg.param( first ) {
+      Some ( c ) => c.to_string( ),
+      None       => return,
+    };
+    let modes = msg.param( first + 1 ).unwrap_or( "" ).to_string( );
+    if !modes.as_slice( ).contains_char( 'k' ) {
+      return;
+    }
+    let mut arg = first + 2;
+    let mut set = true;
+    for c in modes.as_slice( ).chars( ) {
+      match c {
+        '+' => set = true,
+        '-' => set = false,
+        'k' => {
+          if set {
+            let key = msg.param( arg ).map( |k| k.to_string( ) );
+            match key {
+              // some servers hide the key from non-ops
+              Some ( ref k ) if k.as_slice( ) != "*" => self.set_key( chan.as_slice( ), Some( k.as_slice( ) ) ),
+              _                                     => (),
+            }
+          } else {
+            self.set_key( chan.as_slice( ), None );
+          }
+          arg += 1;
+        },
+        _   => if ( set && ARG_MODES_SET.contains_char( c ) ) || ( !set && ARG_MODES_UNSET.contains_char( c ) ) {
+          arg += 1;
+        },
+      }
+    }
+  }
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[allow(unused_imports)]
+  use message::Message;
+  #[allow(unused_imports)]
+  use super::{JoinManager, LeaveReason};
+
+  #[allow(dead_code)]
+  fn feed( m : &mut JoinManager, line : &str ) -> Vec < ( u64, String ) > {
+    m.handle( &Message::parse( line ).unwrap( ), "me" )
+  }
+
+  #[test]
+  fn test_kick () {
+    let mut m = JoinManager::new( );
+    let events = m.subscribe( );
+    assert! ( feed( &mut m, ":op!o@h KICK #rust me :behave" ).is_empty( ) );
+    match events.try_recv( ).unwrap( ).reason {
+      LeaveReason::Kicked ( by, reason ) => assert! ( by == "op" && reason == "behave" ),
+      _                                  => panic! ( "expected Kicked" ),
+    }
+
+    m.policy.rejoin_on_kick = true;
+    m.set_key( "#rust", Some( "secret" ) );
+    assert! ( feed( &mut m, ":op!o@h KICK #rust someone :not us" ).is_empty( ) );
+    assert! ( feed( &mut m, ":op!o@h KICK #Rust ME :again" ) == vec![ ( 5000, "JOIN #Rust secret".to_string( ) ) ] );
+    assert! ( events.try_recv( ).unwrap( ).retrying );
+  }
+
+  #[test]
+  fn test_keys () {
+    let mut m = JoinManager::new( );
+    feed( &mut m, ":op!o@h MODE #rust +ok someone hunter2" );
+    assert! ( m.key( "#RUST" ) == Some( "hunter2" ) );
+    feed( &mut m, ":srv 324 me #irc +ntlk 50 letmein" );
+    assert! ( m.join_line( "#irc" ) == "JOIN #irc letmein" );
+    feed( &mut m, ":op!o@h MODE #rust -lk *" );
+    assert! ( m.key( "#rust" ).is_none( ) );
+  }
+
+  #[test]
+  fn test_join_errors () {
+    let mut m = JoinManager::new( );
+    let events = m.subscribe( );
+    m.policy.retry_full = true;
+    m.policy.max_retries = 1;
+    m.join_line( "#full" );
+    assert! ( feed( &mut m, ":srv 471 me #full :Cannot join channel (+l)" ) == vec![ ( 60000, "JOIN #full".to_string( ) ) ] );
+    assert! ( events.try_recv( ).unwrap( ).retrying );
+    assert! ( feed( &mut m, ":srv 471 me #full :Cannot join channel (+l)" ).is_empty( ) );
+    let left = events.try_recv( ).unwrap( );
+    assert! ( !left.retrying );
+    match left.reason {
+      LeaveReason::JoinFailed ( code, _ ) => assert! ( code == "471" ),
+      _                                   => panic! ( "expected JoinFailed" ),
+    }
+
+    m.policy.knock = true;
+    m.policy.join_on_invite = true;
+    m.join_line( "#secret" );
+    assert! ( feed( &mut m, ":srv 473 me #secret :Cannot join channel (+i)" ) ==
+      vec![ ( 0, "KNOCK #secret :Requesting an invite".to_string( ) ) ] );
+    assert! ( feed( &mut m, ":op!o@h INVITE me #other" ).is_empty( ) );
+    assert! ( feed( &mut m, ":op!o@h INVITE me :#secret" ) == vec![ ( 0, "JOIN #secret".to_string( ) ) ] );
+    feed( &mut m, ":me!m@h JOIN #secret" );
+    assert! ( feed( &mut m, ":op!o@h INVITE me #secret" ).is_empty( ) );
+  }
+
+  #[test]
+  fn test_unsolicited_errors () {
+    let mut m = JoinManager::new( );
+    let events = m.subscribe( );
+    assert! ( feed( &mut m, ":srv 403 me #gone :No such channel" ).is_empty( ) );
+    assert! ( feed( &mut m, ":srv 437 me Lancey :Nick is temporarily unavailable" ).is_empty( ) );
+    assert! ( events.try_recv( ).is_err( ) );
+
+    m.join_line( "#Rust" );
+    feed( &mut m, ":srv 437 me Lancey :Nick is temporarily unavailable" );
+    assert! ( events.try_recv( ).is_err( ) );
+    feed( &mut m, ":srv 474 me #rust :Cannot join channel (+b)" );
+    assert! ( events.try_recv( ).unwrap( ).channel == "#rust" );
+    feed( &mut m, ":srv 474 me #rust :Cannot join channel (+b)" );
+    assert! ( events.try_recv( ).is_err( ) );
+  }
+}
@@ -0,0 +1,322 @@
+// import built in modules
+use std::ascii::AsciiExt;
+use std::collections;
+use time;
+
+// import custom modules
+use ctcp;
+use info;
+use message;
+
+static DEFAULT_VERSION  : &'static str = concat! ( "rustirc ", env! ( "CARGO_PKG_VERSION" ) );
+static DEFAULT_SOURCE   : &'static str = "https://github.com/Lancey6/rust-irc";
+pub static DEFAULT_LIMIT  : u32 = 3;    // replies allowed per sender per window
+pub static DEFAULT_WINDOW : i64 = 10;   // length of the rate limit window (secs)
+pub static DEFAULT_GLOBAL : u32 = 10;   // replies allowed to everyone per window
+static HISTORY_PRUNE    : usize = 256; // prune sender history past this size
+
+/// `CtcpReply` defines how the responder answers a particular CTCP command
+///
+/// # Options
+///
+/// `Text( s )` - reply with the fixed string s
+/// `Echo` - reply with the parameters of the request, as with PING
+/// `Time` - reply with the current local time
+/// `ClientInfo` - reply with the list of commands the responder answers
+/// `RealName` - reply with the client's realname, as with USERINFO or FINGER
+pub enum CtcpReply {
+  Text ( String ),
+  Echo,
+  Time,
+  ClientInfo,
+  RealName,
+}
+
+impl Clone for CtcpReply {
+  fn clone( &self ) -> CtcpReply {
+    match *self {
+      CtcpReply::Text ( ref s ) => CtcpReply::Text( s.clone( ) ),
+      CtcpReply::Echo           => CtcpReply::Echo,
+      CtcpReply::Time           => CtcpReply::Time,
+      CtcpReply::ClientInfo     => CtcpReply::ClientInfo,
+      CtcpReply::RealName       => CtcpReply::RealName,
+    }
+  }
+}
+
+/// `CtcpResponder` answers CTCP requests on behalf of the client
+///
+/// # Members
+///
+/// * `enabled` - whether the responder answers anything at all
+/// * `limit` - number of replies a single sender may receive per window
+/// * `global_limit` - number of replies sent to all senders together per window
+/// * `window` - length of the rate limiting window in seconds
+/// * `replies` - map of CTCP commands to the way they are answered
+/// * `history` - map of senders to the start of their window and reply count
+/// * `global` - start of the shared window and replies sent within it
+pub struct CtcpResponder {
+  pub enabled      : bool,
+  pub limit        : u32,
+  pub global_limit : u32,
+  pub window       : i64,
+
+  replies          : collections::HashMap < String, CtcpReply >,
+  history          : collections::HashMap < String, ( i64, u32 ) >,
+  global           : ( i64, u32 ),
+}
+
+impl Clone for CtcpResponder {
+  fn clone( &self ) -> CtcpResponder {
+    CtcpResponder {
+      enabled      : self.enabled,
+      limit        : self.limit,
+      global_limit : self.global_limit,
+      window       : self.window,
+      replies      : self.replies.clone( ),
+      history      : collections::HashMap::new( ),
+      global       : ( 0, 0 ),
+    }
+  }
+}
+
+impl CtcpResponder {
+  /// `new` creates a disabled responder with the standard replies registered
+  ///
+  /// # Returns
+  ///
+  /// A CtcpResponder that answers VERSION, PING, TIME, CLIENTINFO, SOURCE,
+  /// USERINFO and FINGER once it is enabled
+  pub fn new( ) -> CtcpResponder {
+    let mut rsp = CtcpResponder {
+      enabled      : false,
+      limit        : DEFAULT_LIMIT,
+      global_limit : DEFAULT_GLOBAL,
+      window       : DEFAULT_WINDOW,
+      replies      : collections::HashMap::new( ),
+      history      : collections::HashMap::new( ),
+      global       : ( 0, 0 ),
+    };
+    rsp.set_reply( "VERSION", CtcpReply::Text( String::from_str( DEFAULT_VERSION ) ) );
+    rsp.set_reply( "PING", CtcpReply::Echo );
+    rsp.set_reply( "TIME", CtcpReply::Time );
+    rsp.set_reply( "CLIENTINFO", CtcpReply::ClientInfo );
+    rsp.set_reply( "SOURCE", CtcpReply::Text( String::from_str( DEFAULT_SOURCE ) ) );
+    rsp.set_reply( "USERINFO", CtcpReply::RealName );
+    rsp.set_reply( "FINGER", CtcpReply::RealName );
+    rsp
+  }
+
+  /// `set_reply` registers or replaces the reply to a CTCP command
+  ///
+  /// # Arguments
+  ///
+  /// * `cmd` - CTCP command to answer, e.g. "VERSION"
+  /// * `reply` - the way the command should be answered
+  pub fn set_reply( &mut self, cmd : &str, reply : CtcpReply ) {
+    self.replies.insert( cmd.to_ascii_uppercase( ), reply );
+  }
+
+  /// `remove_reply` stops the responder from answering a CTCP command
+  ///
+  /// # Arguments
+  ///
+  /// * `cmd` - CTCP command to stop answering
+  pub fn remove_reply( &mut self, cmd : &str ) {
+    self.replies.remove( &cmd.to_ascii_uppercase( ) );
+  }
+
+  /// `client_info` lists every command the responder answers
+  ///
+  /// # Returns
+  ///
+  /// A space delimited, sorted String of CTCP commands
+  pub fn client_info( &self ) -> String {
+    let mut cmds : Vec < &str > = self.replies.keys( )
+      .map( |k| k.as_slice( ) ).collect( );
+    cmds.sort( );
+    cmds.connect( " " )
+  }
+
+  /// `respond` builds the replies to any CTCP requests in a message
+  ///
+  /// # Arguments
+  ///
+  /// * `msg` - message received from the server
+  /// * `i` - the client info, used for realname replies
+  ///
+  /// # Returns
+  ///
+  /// A vector of NOTICE lines to send back to the server
+  pub fn respond( &mut self, msg : &message::Message, i : &info::IrcInfo ) -> Vec < String > {
+    let now = time::get_time( ).sec;
+    self.respond_at( msg, i, now )
+  }
+
+  /// `respond_at` builds CTCP replies as if the current time were `now`
+  ///
+  /// # Notes
+  ///
+  /// * Only PRIVMSG requests are answered. Answering a NOTICE could start a
+  /// reply loop with another automated client.
+  fn respond_at(
+    &mut self,
+    msg : &message::Message,
+    i : &info::IrcInfo,
+    now : i64
+  ) -> Vec < String > {
+    let mut lines = Vec::new( );
+    if !self.enabled || msg.code.as_slice( ) != "PRIVMSG" {
+      return lines;
+    }
+
+    // find out who to answer
+    let sender = match msg.nick( ) {
+      Some ( n ) => n,
+      None       => return lines,
+    };
+
+    // only one CTCP request is answered per message, so a single line can't
+    // make us send a burst of replies
+    let req = match ctcp::Ctcp::from_message( msg ) {
+      Some ( c ) => c.to_request( ),
+      None       => return lines,
+    };
+    let cmd = req.command.to_ascii_uppercase( );
+    let body = match self.replies.get( &cmd ) {
+      Some ( &CtcpReply::Text ( ref s ) ) => s.clone( ),
+      Some ( &CtcpReply::Echo )           => req.params.clone( ),
+      Some ( &CtcpReply::Time )           => format! ( "{}", time::now( ).rfc822( ) ),
+      Some ( &CtcpReply::ClientInfo )     => self.client_info( ),
+      Some ( &CtcpReply::RealName )       => i.real_name.clone( ),
+      None                                => return lines,
+    };
+
+    if !self.allow( sender.as_slice( ), now ) {
+      irc_warn! ( "ctcp rate limit: dropping CTCP {} from {}", cmd, sender );
+      return lines;
+    }
+
+    let reply = if body.is_empty( ) {
+      cmd.clone( )
+    } else {
+      format! ( "{} {}", cmd, ctcp::ctcp_quote( body ) )
+    };
+    lines.push( format! ( "NOTICE {} :{}", sender, ctcp::tag( reply.as_slice( ) ) ) );
+    lines
+  }
+
+  /// `allow` records a reply to a sender and checks it against the limits
+  ///
+  /// # Arguments
+  ///
+  /// * `sender` - nick of the client that made the request
+  /// * `now` - current time in seconds
+  ///
+  /// # Returns
+  ///
+  /// true if the sender may receive another reply, false otherwise
+  ///
+  /// # Notes
+  ///
+  /// * The global limit stops a flood spread across many nicks, which the
+  /// per-sender limit alone would let through.
+  fn allow( &mut self, sender : &str, now : i64 ) -> bool {
+    // forget senders whose windows have passed so the map can't grow forever
+    if self.history.len( ) > HISTORY_PRUNE {
+      let window = self.window;
+      let stale : Vec < String > = self.history.iter( )
+        .filter( |&( _, &( start, _ ) )| now - start >= window )
+        .map( |( k, _ )| k.clone( ) ).collect( );
+      for k in stale.iter( ) {
+        self.history.remove( k );
+      }
+    }
+
+    let key = sender.to_ascii_lowercase( );
+    let entry = match self.history.get( &key ) {
+      Some ( &( start, count ) ) if now - start < self.window => ( start, count ),
+      _                                                       => ( now, 0 ),
+    };
+    if entry.1 >= self.limit {
+      return false;
+    }
+
+    let global = if now - self.global.0 < self.window {
+      self.global
+    } else {
+      ( now, 0 )
+    };
+    if global.1 >= self.global_limit {
+      return false;
+    }
+    self.global = ( global.0, global.1 + 1 );
+    self.history.insert( key, ( entry.0, entry.1 + 1 ) );
+    true
+  }
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[allow(unused_imports)]
+  use info::IrcInfo;
+  #[allow(unused_imports)]
+  use message::Message;
+
+  #[test]
+  fn test_disabled () {
+    let mut rsp = super::CtcpResponder::new( );
+    let info = IrcInfo::gen( "Lancey", "Lancey", "Lance", vec![] );
+    let msg = Message::parse( ":Detective!d@host PRIVMSG Lancey :\x01VERSION\x01" ).unwrap( );
+    assert! ( rsp.respond_at( &msg, &info, 0 ).is_empty( ) );
+  }
+
+  #[test]
+  fn test_replies () {
+    let mut rsp = super::CtcpResponder::new( );
+    rsp.enabled = true;
+    let info = IrcInfo::gen( "Lancey", "Lancey", "Lance", vec![] );
+    let ping = Message::parse( ":Detective!d@host PRIVMSG Lancey :\x01PING 12345\x01" ).unwrap( );
+    let user = Message::parse( ":Detective!d@host PRIVMSG Lancey :\x01USERINFO\x01" ).unwrap( );
+    let notc = Message::parse( ":Detective!d@host NOTICE Lancey :\x01PING 12345\x01" ).unwrap( );
+    assert! ( rsp.respond_at( &ping, &info, 0 ) == vec![ "NOTICE Detective :\x01PING 12345\x01".to_string( ) ] );
+    assert! ( rsp.respond_at( &user, &info, 0 ) == vec![ "NOTICE Detective :\x01USERINFO Lance\x01".to_string( ) ] );
+    assert! ( rsp.respond_at( &notc, &info, 0 ).is_empty( ) );
+  }
+
+  #[test]
+  fn test_clientinfo () {
+    let mut rsp = super::CtcpResponder::new( );
+    rsp.remove_reply( "finger" );
+    rsp.set_reply( "HELLO", super::CtcpReply::Text( "hi".to_string( ) ) );
+    assert! ( rsp.client_info( ) == "CLIENTINFO HELLO PING SOURCE TIME USERINFO VERSION" );
+  }
+
+  #[test]
+  fn test_rate_limit () {
+    let mut rsp = super::CtcpResponder::new( );
+    rsp.enabled = true;
+    let info = IrcInfo::gen( "Lancey", "Lancey", "Lance", vec![] );
+    let ping = Message::parse( ":Detective!d@host PRIVMSG Lancey :\x01PING 1\x01" ).unwrap( );
+    for _ in range( 0, rsp.limit ) {
+      assert! ( rsp.respond_at( &ping, &info, 100 ).len( ) == 1 );
+    }
+    assert! ( rsp.respond_at( &ping, &info, 101 ).is_empty( ) );
+    assert! ( rsp.respond_at( &ping, &info, 100 + rsp.window ).len( ) == 1 );
+  }
+
+  #[test]
+  fn test_global_limit () {
+    let mut rsp = super::CtcpResponder::new( );
+    rsp.enabled = true;
+    let info = IrcInfo::gen( "Lancey", "Lancey", "Lance", vec![] );
+    for n in range( 0, rsp.global_limit ) {
+      let line = format! ( ":Flood{}!f@host PRIVMSG Lancey :\x01PING 1\x01", n );
+      let ping = Message::parse( line.as_slice( ) ).unwrap( );
+      assert! ( rsp.respond_at( &ping, &info, 100 ).len( ) == 1 );
+    }
+    let last = Message::parse( ":Detective!d@host PRIVMSG Lancey :\x01PING 1\x01" ).unwrap( );
+    assert! ( rsp.respond_at( &last, &info, 101 ).is_empty( ) );
+    assert! ( rsp.respond_at( &last, &info, 100 + rsp.window ).len( ) == 1 );
+  }
+}
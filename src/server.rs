@@ -0,0 +1,1697 @@
+// import built in modules
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+use std::old_io as io;
+use std::old_io::{Acceptor, Listener};
+use std::old_io::net::ip::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use time;
+
+// import custom modules
+use message;
+use reader;
+use utils::debug;
+use writer;
+
+pub type TID                      = u64;
+pub type TTIMEOUT                 = u64;
+
+static SERVER_TICK   : TTIMEOUT   = 1000;    // ms between ping timeout checks
+static ACCEPT_POLL   : TTIMEOUT   = 500;     // ms between checks for a stop request
+static WRITE_TIMEOUT : TTIMEOUT   = 30000;   // ms a write to a client may take
+static SEND_QUEUE    : usize      = 1 << 20; // bytes queued for a client before it's dropped
+static MAX_CHANNELS  : usize      = 20;      // channels a client may be in
+static NICK_LEN      : usize      = 30;      // longest nick we accept
+static CHANNEL_LEN   : usize      = 50;      // longest channel name we accept
+static TOPIC_LEN     : usize      = 390;     // longest topic we keep
+static KICK_LEN      : usize      = 255;     // longest kick reason we keep
+
+/// `ServerConfig` holds the settings of a `Server`
+///
+/// # Members
+///
+/// * `name` - server name, sent as the source of server replies
+/// * `network` - network name advertised in ISUPPORT
+/// * `bind` - address to listen on, as "host:port"
+/// * `password` - connection password clients must send with PASS, if any
+/// * `motd` - lines of the message of the day
+/// * `ping_interval` - seconds of silence before a client is pinged
+/// * `ping_timeout` - seconds to wait for any reply to that ping
+pub struct ServerConfig {
+  pub name          : String,
+  pub network       : String,
+  pub bind          : String,
+  pub password      : Option < String >,
+  pub motd          : Vec < String >,
+  pub ping_interval : i64,
+  pub ping_timeout  : i64,
+}
+
+impl Clone for ServerConfig {
+  fn clone( &self ) -> ServerConfig {
+    ServerConfig {
+      name          : self.name.clone( ),
+      network       : self.network.clone( ),
+      bind          : self.bind.clone( ),
+      password      : self.password.clone( ),
+      motd          : self.motd.clone( ),
+      ping_interval : self.ping_interval,
+      ping_timeout  : self.ping_timeout,
+    }
+  }
+}
+
+impl ServerConfig {
+  /// `new` creates a config with the defaults for a local test network
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - server name
+  ///
+  /// # Returns
+  ///
+  /// A config listening on 127.0.0.1:6667 with no password
+  pub fn new( name : &str ) -> ServerConfig {
+    ServerConfig {
+      name          : name.to_string( ),
+      network       : String::from_str( "RustNet" ),
+      bind          : String::from_str( "127.0.0.1:6667" ),
+      password      : None,
+      motd          : Vec::new( ),
+      ping_interval : 120,
+      ping_timeout  : 60,
+    }
+  }
+}
+
+/// `ServerEvent` is an event handled by the server's core thread
+enum ServerEvent {
+  Connected ( TID, io::TcpStream, String ),
+  Line ( TID, String ),
+  Closed ( TID ),
+  Tick,
+  Stop,
+}
+
+/// `Server` is a small single-node IRC server, for test networks and CI
+///
+/// # Members
+///
+/// * `local` - address the server is listening on
+/// * `chan` - channel to the core thread
+/// * `stop` - set once the server has been asked to stop
+/// * `done` - receives a message when the core thread exits
+///
+/// # Notes
+///
+/// * All state is owned by one core thread. Every connection gets a reader
+/// thread that passes its lines to the core, so no locks are needed.
+/// * Every connection also gets a writer thread, so a slow client can't hold
+/// up the core. One that falls too far behind is disconnected.
+pub struct Server {
+  pub local : SocketAddr,
+
+  chan      : mpsc::Sender < ServerEvent >,
+  stop      : Arc < AtomicBool >,
+  done      : mpsc::Receiver < ( ) >,
+}
+
+impl Server {
+  /// `start` binds the server and starts its threads
+  ///
+  /// # Arguments
+  ///
+  /// * `config` - settings of the server
+  ///
+  /// # Returns
+  ///
+  /// The running server, or the error from binding its address
+  pub fn start( config : ServerConfig ) -> io::IoResult < Server > {
+    let listener = try! ( io::TcpListener::bind( config.bind.as_slice( ) ) );
+    let mut acceptor = try! ( listener.listen( ) );
+    let local = try! ( acceptor.socket_name( ) );
+    let ( tx, rx ) = mpsc::channel( );
+    let ( donetx, donerx ) = mpsc::channel( );
+    let stop = Arc::new( AtomicBool::new( false ) );
+
+    // accept clients until we're stopped
+    let ( atx, astop, alabel ) = ( tx.clone( ), stop.clone( ), config.name.clone( ) );
+    thread::Thread::spawn( move || {
+      let _span = debug::Span::enter( alabel.as_slice( ) );
+      Server::accept_loop( acceptor, atx, astop );
+    } );
+
+    // wake the core up regularly so idle clients can be pinged
+    let ( ttx, tstop ) = ( tx.clone( ), stop.clone( ) );
+    thread::Thread::spawn( move || {
+      while !tstop.load( Ordering::SeqCst ) {
+        io::timer::sleep( Duration::milliseconds( SERVER_TICK as i64 ) );
+        if ttx.send( ServerEvent::Tick ).is_err( ) {
+          break;
+        }
+      }
+    } );
+
+    // run the core
+    let label = config.name.clone( );
+    thread::Thread::spawn( move || {
+      let _span = debug::Span::enter( label.as_slice( ) );
+      let mut core = Core::new( config );
+      core.run( rx );
+      let _ = donetx.send( ( ) );
+    } );
+
+    irc_info! ( "irc server listening on {}", local );
+    Ok( Server {
+      local : local,
+      chan  : tx,
+      stop  : stop,
+      done  : donerx,
+    } )
+  }
+
+  /// `stop` disconnects every client and shuts the server down
+  pub fn stop( &self ) {
+    self.stop.store( true, Ordering::SeqCst );
+    match self.chan.send( ServerEvent::Stop ) {
+      Ok ( _ )  => (),
+      Err ( _ ) => irc_warn! ( "stopping server: server already stopped" ),
+    }
+  }
+
+  /// `wait` blocks until the server has stopped
+  pub fn wait( self ) {
+    let _ = self.done.recv( );
+  }
+
+  /// `accept_loop` accepts clients and starts a reader for each of them
+  ///
+  /// # Arguments
+  ///
+  /// * `acceptor` - the listening socket
+  /// * `chan` - channel to the core thread
+  /// * `stop` - checked between accepts, the loop ends once it is set
+  fn accept_loop(
+    mut acceptor : io::TcpAcceptor,
+    chan : mpsc::Sender < ServerEvent >,
+    stop : Arc < AtomicBool >
+  ) {
+    let mut next : TID = 0;
+    while !stop.load( Ordering::SeqCst ) {
+      acceptor.set_timeout( Some( ACCEPT_POLL ) );
+      let mut tcp = match acceptor.accept( ) {
+        Ok ( tcp )  => tcp,
+        Err ( e )   => match e.kind {
+          io::IoErrorKind::TimedOut => continue,
+          _                         => {
+            irc_error! ( "accepting client: {}", e.desc );
+            break;
+          },
+        },
+      };
+      next += 1;
+      let host = match tcp.peer_name( ) {
+        Ok ( peer ) => peer.ip.to_string( ),
+        Err ( _ )   => String::from_str( "unknown" ),
+      };
+      irc_debug! ( "client {} connected from {}", next, host );
+
+      // the core must hear about the client before its first line
+      if chan.send( ServerEvent::Connected( next, tcp.clone( ), host ) ).is_err( ) {
+        break;
+      }
+      Server::start_reader( next, tcp, chan.clone( ) );
+    }
+  }
+
+  /// `start_reader` reads lines from a client on a new thread
+  ///
+  /// # Arguments
+  ///
+  /// * `id` - id of the client
+  /// * `tcp` - connection to the client
+  /// * `chan` - channel to the core thread
+  ///
+  /// # Notes
+  ///
+  /// * Lines that aren't UTF-8 are passed on with the bad bytes replaced.
+  /// Lines longer than reader::MAX_LINE are dropped.
+  fn start_reader( id : TID, tcp : io::TcpStream, chan : mpsc::Sender < ServerEvent > ) {
+    thread::Thread::spawn( move || {
+      let mut reader = reader::LineFramer::new( tcp, reader::MAX_LINE );
+      loop {
+        match reader.next_line( ) {
+          Ok ( reader::Frame::Line ( line ) )   => {
+            let line = String::from_utf8_lossy( line.as_slice( ) ).into_owned( );
+            if chan.send( ServerEvent::Line( id, line ) ).is_err( ) {
+              break;
+            }
+          },
+          Ok ( reader::Frame::TooLong ( len ) ) => irc_warn! ( "client {} sent a {} byte line", id, len ),
+          Err ( _ )                             => break,
+        }
+      }
+      let _ = chan.send( ServerEvent::Closed( id ) );
+    } );
+  }
+}
+
+/// `Conn` is a client connected to the server
+struct Conn {
+  tcp         : io::TcpStream,
+  writer      : writer::LineWriter,
+  host        : String,
+  nick        : Option < String >,
+  user        : Option < String >,
+  real        : String,
+  pass_ok     : bool,
+  registered  : bool,
+  invisible   : bool,
+  away        : Option < String >,
+  channels    : Vec < String >,     // keys of the channels we're in
+  last_active : i64,
+  ping_sent   : bool,
+}
+
+/// `Member` is a client's status on a channel
+struct Member {
+  op    : bool,
+  voice : bool,
+}
+
+impl Member {
+  /// `prefix` returns the NAMES prefix of the member
+  fn prefix( &self ) -> &'static str {
+    if self.op { "@" } else if self.voice { "+" } else { "" }
+  }
+}
+
+/// `Channel` is a channel on the server
+struct Channel {
+  name        : String,
+  topic       : Option < String >,
+  topic_by    : String,
+  topic_at    : i64,
+  created     : i64,
+  members     : Vec < ( TID, Member ) >,
+  no_external : bool,
+  topic_lock  : bool,
+  moderated   : bool,
+  key         : Option < String >,
+  limit       : Option < usize >,
+}
+
+impl Channel {
+  /// `new` creates an empty channel with the default +nt modes
+  fn new( name : &str, now : i64 ) -> Channel {
+    Channel {
+      name        : name.to_string( ),
+      topic       : None,
+      topic_by    : String::new( ),
+      topic_at    : 0,
+      created     : now,
+      members     : Vec::new( ),
+      no_external : true,
+      topic_lock  : true,
+      moderated   : false,
+      key         : None,
+      limit       : None,
+    }
+  }
+
+  /// `member` finds the status of a client on the channel
+  fn member( &self, id : TID ) -> Option < &Member > {
+    self.members.iter( ).find( |m| m.0 == id ).map( |m| &m.1 )
+  }
+
+  /// `member_mut` finds the status of a client on the channel for editing
+  fn member_mut( &mut self, id : TID ) -> Option < &mut Member > {
+    self.members.iter_mut( ).find( |m| m.0 == id ).map( |m| &mut m.1 )
+  }
+
+  /// `is_op` returns whether a client is an operator of the channel
+  fn is_op( &self, id : TID ) -> bool {
+    self.member( id ).map( |m| m.op ).unwrap_or( false )
+  }
+
+  /// `ids` lists the clients on the channel
+  fn ids( &self ) -> Vec < TID > {
+    self.members.iter( ).map( |m| m.0 ).collect( )
+  }
+
+  /// `mode_string` formats the channel modes for RPL_CHANNELMODEIS
+  fn mode_string( &self ) -> String {
+    let mut modes = String::from_str( "+" );
+    let mut args  = Vec::new( );
+    if self.moderated   { modes.push( 'm' ); }
+    if self.no_external { modes.push( 'n' ); }
+    if self.topic_lock  { modes.push( 't' ); }
+    match self.key {
+      Some ( ref key ) => { modes.push( 'k' ); args.push( key.clone( ) ); },
+      None             => (),
+    }
+    match self.limit {
+      Some ( limit )   => { modes.push( 'l' ); args.push( limit.to_string( ) ); },
+      None             => (),
+    }
+    for arg in args.iter( ) {
+      modes.push( ' ' );
+      modes.push_str( arg.as_slice( ) );
+    }
+    modes
+  }
+}
+
+/// `Core` owns the state of the server and handles every event
+///
+/// # Members
+///
+/// * `config` - settings of the server
+/// * `created` - when the server was started, for RPL_CREATED
+/// * `clients` - connected clients by id
+/// * `nicks` - client ids by lowercased nick
+/// * `channels` - channels by lowercased name
+/// * `dead` - clients to disconnect once the current event is handled
+struct Core {
+  config    : ServerConfig,
+  created   : String,
+  clients   : HashMap < TID, Conn >,
+  nicks     : HashMap < String, TID >,
+  channels  : HashMap < String, Channel >,
+  dead      : Vec < ( TID, String ) >,
+}
+
+impl Core {
+  /// `new` creates a server core without any clients
+  fn new( config : ServerConfig ) -> Core {
+    Core {
+      config    : config,
+      created   : format! ( "{}", time::now( ).rfc822( ) ),
+      clients   : HashMap::new( ),
+      nicks     : HashMap::new( ),
+      channels  : HashMap::new( ),
+      dead      : Vec::new( ),
+    }
+  }
+
+  /// `run` handles events until the server is stopped
+  ///
+  /// # Arguments
+  ///
+  /// * `port` - receives events from the accept, reader and tick threads
+  fn run( &mut self, port : mpsc::Receiver < ServerEvent > ) {
+    irc_info! ( "server core started" );
+    for event in port.iter( ) {
+      match event {
+        ServerEvent::Connected ( id, tcp, host ) => self.connected( id, tcp, host ),
+        ServerEvent::Line ( id, line )           => self.handle_line( id, line ),
+        ServerEvent::Closed ( id )               => self.quit( id, "Connection closed" ),
+        ServerEvent::Tick                        => self.tick( ),
+        ServerEvent::Stop                        => break,
+      }
+      self.reap( );
+    }
+    let ids : Vec < TID > = self.clients.keys( ).map( |id| *id ).collect( );
+    for id in ids.into_iter( ) {
+      self.quit( id, "Server shutting down" );
+    }
+    irc_info! ( "server core stopped" );
+  }
+
+  /// `reap` disconnects the clients that failed since the last event
+  fn reap( &mut self ) {
+    while let Some ( ( id, reason ) ) = self.dead.pop( ) {
+      self.quit( id, reason.as_slice( ) );
+    }
+  }
+
+  /// `connected` adds a newly connected client
+  fn connected( &mut self, id : TID, tcp : io::TcpStream, host : String ) {
+    self.clients.insert( id, Conn {
+      tcp         : tcp.clone( ),
+      writer      : writer::LineWriter::start( tcp, SEND_QUEUE, WRITE_TIMEOUT ),
+      host        : host,
+      nick        : None,
+      user        : None,
+      real        : String::new( ),
+      pass_ok     : self.config.password.is_none( ),
+      registered  : false,
+      invisible   : false,
+      away        : None,
+      channels    : Vec::new( ),
+      last_active : time::get_time( ).sec,
+      ping_sent   : false,
+    } );
+    let line = format! ( ":{} NOTICE * :*** Welcome to {}", self.config.name, self.config.network );
+    self.send_to( id, line.as_slice( ) );
+  }
+
+  /// `tick` pings idle clients and drops the ones that never answered
+  fn tick( &mut self ) {
+    let now = time::get_time( ).sec;
+    let mut pings = Vec::new( );
+    for ( id, conn ) in self.clients.iter_mut( ) {
+      let idle = now - conn.last_active;
+      if conn.ping_sent && idle > self.config.ping_interval + self.config.ping_timeout {
+        self.dead.push( ( *id, format! ( "Ping timeout: {} seconds", idle ) ) );
+      } else if !conn.ping_sent && idle > self.config.ping_interval {
+        conn.ping_sent = true;
+        pings.push( *id );
+      }
+    }
+    let ping = format! ( "PING :{}", self.config.name );
+    for id in pings.into_iter( ) {
+      self.send_to( id, ping.as_slice( ) );
+    }
+  }
+
+  /// `send_to` queues a line for a client. Clients that have fallen too far
+  /// behind, or whose connection failed, are disconnected after the current
+  /// event.
+  fn send_to( &mut self, id : TID, line : &str ) {
+    match self.clients.get( &id ) {
+      Some ( conn ) => {
+        irc_trace! ( "{} < {}", id, debug::redact( line ) );
+        match conn.writer.send( line ) {
+          Ok ( _ )       => (),
+          Err ( reason ) => self.dead.push( ( id, reason.to_string( ) ) ),
+        }
+      },
+      None          => (),
+    }
+  }
+
+  /// `reply` sends a numeric reply from the server to a client
+  ///
+  /// # Arguments
+  ///
+  /// * `id` - client to reply to
+  /// * `code` - numeric of the reply
+  /// * `params` - parameters following the client's nick
+  fn reply( &mut self, id : TID, code : &str, params : &str ) {
+    let params = format! ( "{} {}", self.nick_of( id ), params );
+    let msg = message::Message::new(
+      message::Source::Sender( self.config.name.clone( ) ), code, params.as_slice( ) );
+    self.send_to( id, msg.raw.as_slice( ) );
+  }
+
+  /// `send_from` sends a message with a client as its source
+  fn send_from( &mut self, to : TID, from : TID, code : &str, params : &str ) {
+    let msg = message::Message::new(
+      message::Source::Sender( self.prefix_of( from ) ), code, params );
+    self.send_to( to, msg.raw.as_slice( ) );
+  }
+
+  /// `broadcast` sends a line to everyone on a channel
+  ///
+  /// # Arguments
+  ///
+  /// * `chan` - key of the channel
+  /// * `line` - line to send
+  /// * `except` - client to skip, usually the one who sent the line
+  fn broadcast( &mut self, chan : &str, line : &str, except : Option < TID > ) {
+    let ids = match self.channels.get( chan ) {
+      Some ( ch ) => ch.ids( ),
+      None        => return,
+    };
+    for id in ids.into_iter( ) {
+      if Some( id ) != except {
+        self.send_to( id, line );
+      }
+    }
+  }
+
+  /// `peers` lists the clients sharing a channel with a client, plus the
+  /// client itself
+  fn peers( &self, id : TID ) -> Vec < TID > {
+    let mut peers = vec![ id ];
+    let chans = match self.clients.get( &id ) {
+      Some ( conn ) => conn.channels.clone( ),
+      None          => return peers,
+    };
+    for key in chans.iter( ) {
+      match self.channels.get( key ) {
+        Some ( ch ) => for other in ch.ids( ).into_iter( ) {
+          if !peers.contains( &other ) {
+            peers.push( other );
+          }
+        },
+        None        => (),
+      }
+    }
+    peers
+  }
+
+  /// `nick_of` returns the nick of a client, or "*" before it has one
+  fn nick_of( &self, id : TID ) -> String {
+    match self.clients.get( &id ) {
+      Some ( conn ) => conn.nick.clone( ).unwrap_or( String::from_str( "*" ) ),
+      None          => String::from_str( "*" ),
+    }
+  }
+
+  /// `prefix_of` returns the nick!user@host of a client
+  fn prefix_of( &self, id : TID ) -> String {
+    match self.clients.get( &id ) {
+      Some ( conn ) => format! ( "{}!{}@{}",
+        conn.nick.clone( ).unwrap_or( String::from_str( "*" ) ),
+        conn.user.clone( ).unwrap_or( String::from_str( "*" ) ),
+        conn.host ),
+      None          => String::from_str( "*" ),
+    }
+  }
+
+  /// `find_nick` looks up a client by nick
+  fn find_nick( &self, nick : &str ) -> Option < TID > {
+    self.nicks.get( &nick.to_ascii_lowercase( ) ).map( |id| *id )
+  }
+
+  /// `handle_line` parses and handles a line sent by a client
+  fn handle_line( &mut self, id : TID, line : String ) {
+    let text = line.as_slice( ).trim_right_matches( |c : char| c == '\r' || c == '\n' );
+    if text.trim( ).is_empty( ) {
+      return;
+    }
+    irc_trace! ( "{} > {}", id, debug::redact( text ) );
+
+    // the parser needs a space after the command, even without parameters
+    let padded = if text.contains_char( ' ' ) { text.to_string( ) } else { format! ( "{} ", text ) };
+    let msg = match message::Message::parse( padded.as_slice( ) ) {
+      Some ( m ) => m,
+      None       => {
+        irc_debug! ( "ignoring unparseable line from {}", id );
+        return;
+      },
+    };
+    let registered = match self.clients.get_mut( &id ) {
+      Some ( conn ) => {
+        conn.last_active = time::get_time( ).sec;
+        conn.ping_sent = false;
+        conn.registered
+      },
+      None          => return,
+    };
+    let code = msg.code.to_ascii_uppercase( );
+    let params = params_of( &msg );
+
+    match code.as_slice( ) {
+      "PASS"    => self.cmd_pass( id, &params ),
+      "NICK"    => self.cmd_nick( id, &params ),
+      "USER"    => self.cmd_user( id, &params ),
+      "PING"    => self.cmd_ping( id, &params ),
+      "PONG"    => (),
+      "QUIT"    => {
+        let reason = match params.get( 0 ) {
+          Some ( r ) => format! ( "Quit: {}", r ),
+          None       => String::from_str( "Client Quit" ),
+        };
+        self.quit( id, reason.as_slice( ) );
+      },
+      // clients may probe for capabilities before registering
+      "CAP"     => (),
+      _ if !registered => self.reply( id, "451", ":You have not registered" ),
+      "JOIN"    => self.cmd_join( id, &params ),
+      "PART"    => self.cmd_part( id, &params ),
+      "KICK"    => self.cmd_kick( id, &params ),
+      "TOPIC"   => self.cmd_topic( id, &params ),
+      "MODE"    => self.cmd_mode( id, &params ),
+      "PRIVMSG" | "NOTICE" => self.cmd_message( id, code.as_slice( ), &params ),
+      "NAMES"   => self.cmd_names( id, &params ),
+      "LIST"    => self.cmd_list( id ),
+      "WHO"     => self.cmd_who( id, &params ),
+      "WHOIS"   => self.cmd_whois( id, &params ),
+      "AWAY"    => self.cmd_away( id, &params ),
+      "MOTD"    => self.send_motd( id ),
+      _         => {
+        let params = format! ( "{} :Unknown command", code );
+        self.reply( id, "421", params.as_slice( ) );
+      },
+    }
+  }
+
+  /// `need_params` replies ERR_NEEDMOREPARAMS if too few parameters were sent
+  fn need_params( &mut self, id : TID, cmd : &str, params : &Vec < String >, n : usize ) -> bool {
+    if params.len( ) < n || params[0].is_empty( ) {
+      let params = format! ( "{} :Not enough parameters", cmd );
+      self.reply( id, "461", params.as_slice( ) );
+      return false;
+    }
+    true
+  }
+
+  /// `is_registered` returns whether a client has finished registering
+  fn is_registered( &self, id : TID ) -> bool {
+    self.clients.get( &id ).map( |c| c.registered ).unwrap_or( false )
+  }
+
+  /// `cmd_pass` handles PASS
+  fn cmd_pass( &mut self, id : TID, params : &Vec < String > ) {
+    if self.is_registered( id ) {
+      return self.reply( id, "462", ":You may not reregister" );
+    }
+    if !self.need_params( id, "PASS", params, 1 ) {
+      return;
+    }
+    let ok = match self.config.password {
+      Some ( ref pass ) => *pass == params[0],
+      None              => true,
+    };
+    match self.clients.get_mut( &id ) {
+      Some ( conn ) => conn.pass_ok = ok,
+      None          => (),
+    }
+  }
+
+  /// `cmd_nick` handles NICK, both while registering and afterwards
+  fn cmd_nick( &mut self, id : TID, params : &Vec < String > ) {
+    if params.len( ) < 1 || params[0].is_empty( ) {
+      return self.reply( id, "431", ":No nickname given" );
+    }
+    let nick = params[0].clone( );
+    if !valid_nick( nick.as_slice( ) ) {
+      let params = format! ( "{} :Erroneous nickname", nick );
+      return self.reply( id, "432", params.as_slice( ) );
+    }
+    match self.find_nick( nick.as_slice( ) ) {
+      Some ( other ) if other != id => {
+        let params = format! ( "{} :Nickname is already in use", nick );
+        return self.reply( id, "433", params.as_slice( ) );
+      },
+      _                             => (),
+    }
+
+    // tell everyone who can see us before the nick changes
+    if self.is_registered( id ) {
+      let line = format! ( ":{} NICK :{}", self.prefix_of( id ), nick );
+      for peer in self.peers( id ).into_iter( ) {
+        self.send_to( peer, line.as_slice( ) );
+      }
+    }
+    let old = match self.clients.get_mut( &id ) {
+      Some ( conn ) => {
+        let old = conn.nick.clone( );
+        conn.nick = Some( nick.clone( ) );
+        old
+      },
+      None          => return,
+    };
+    match old {
+      Some ( old ) => { self.nicks.remove( &old.to_ascii_lowercase( ) ); },
+      None         => (),
+    }
+    self.nicks.insert( nick.to_ascii_lowercase( ), id );
+    self.try_register( id );
+  }
+
+  /// `cmd_user` handles USER
+  fn cmd_user( &mut self, id : TID, params : &Vec < String > ) {
+    if self.is_registered( id ) {
+      return self.reply( id, "462", ":You may not reregister" );
+    }
+    if !self.need_params( id, "USER", params, 4 ) {
+      return;
+    }
+    match self.clients.get_mut( &id ) {
+      Some ( conn ) => {
+        conn.user = Some( params[0].chars( ).take( 10 ).collect( ) );
+        conn.real = params[3].clone( );
+      },
+      None          => return,
+    }
+    self.try_register( id );
+  }
+
+  /// `try_register` welcomes a client once it has sent both NICK and USER
+  fn try_register( &mut self, id : TID ) {
+    let ( ready, pass_ok ) = match self.clients.get( &id ) {
+      Some ( conn ) => ( !conn.registered && conn.nick.is_some( ) && conn.user.is_some( ), conn.pass_ok ),
+      None          => return,
+    };
+    if !ready {
+      return;
+    }
+    if !pass_ok {
+      self.reply( id, "464", ":Password incorrect" );
+      return self.quit( id, "Bad password" );
+    }
+    match self.clients.get_mut( &id ) {
+      Some ( conn ) => conn.registered = true,
+      None          => return,
+    }
+    irc_info! ( "client {} registered as {}", id, self.prefix_of( id ) );
+
+    let welcome = format! ( ":Welcome to the {} Internet Relay Chat Network {}",
+      self.config.network, self.prefix_of( id ) );
+    let host = format! ( ":Your host is {}, running rustircd-{}",
+      self.config.name, env! ( "CARGO_PKG_VERSION" ) );
+    let created = format! ( ":This server was created {}", self.created );
+    let myinfo = format! ( "{} rustircd-{} i mntklov",
+      self.config.name, env! ( "CARGO_PKG_VERSION" ) );
+    let isupport = isupport( &self.config );
+    self.reply( id, "001", welcome.as_slice( ) );
+    self.reply( id, "002", host.as_slice( ) );
+    self.reply( id, "003", created.as_slice( ) );
+    self.reply( id, "004", myinfo.as_slice( ) );
+    self.reply( id, "005", isupport.as_slice( ) );
+    self.send_motd( id );
+  }
+
+  /// `send_motd` sends the message of the day
+  fn send_motd( &mut self, id : TID ) {
+    if self.config.motd.is_empty( ) {
+      return self.reply( id, "422", ":MOTD File is missing" );
+    }
+    let start = format! ( ":- {} Message of the day - ", self.config.name );
+    self.reply( id, "375", start.as_slice( ) );
+    let motd = self.config.motd.clone( );
+    for line in motd.iter( ) {
+      let line = format! ( ":- {}", line );
+      self.reply( id, "372", line.as_slice( ) );
+    }
+    self.reply( id, "376", ":End of MOTD command" );
+  }
+
+  /// `cmd_ping` handles PING
+  fn cmd_ping( &mut self, id : TID, params : &Vec < String > ) {
+    if params.len( ) < 1 {
+      return self.reply( id, "409", ":No origin specified" );
+    }
+    let line = format! ( ":{} PONG {} :{}", self.config.name, self.config.name, params[0] );
+    self.send_to( id, line.as_slice( ) );
+  }
+
+  /// `quit` disconnects a client and tells everyone who could see it
+  ///
+  /// # Arguments
+  ///
+  /// * `id` - client to disconnect. Unknown clients are ignored.
+  /// * `reason` - quit message
+  fn quit( &mut self, id : TID, reason : &str ) {
+    if !self.clients.contains_key( &id ) {
+      return;
+    }
+    irc_info! ( "client {} quit: {}", id, reason );
+    if self.is_registered( id ) {
+      let line = format! ( ":{} QUIT :{}", self.prefix_of( id ), reason );
+      for peer in self.peers( id ).into_iter( ) {
+        if peer != id {
+          self.send_to( peer, line.as_slice( ) );
+        }
+      }
+    }
+    let chans = self.clients.get( &id ).map( |c| c.channels.clone( ) ).unwrap_or( Vec::new( ) );
+    for key in chans.iter( ) {
+      self.remove_member( key.as_slice( ), id );
+    }
+
+    let line = match self.clients.get( &id ) {
+      Some ( conn ) => format! ( "ERROR :Closing Link: {} ({})", conn.host, reason ),
+      None          => return,
+    };
+    self.send_to( id, line.as_slice( ) );
+    match self.clients.remove( &id ) {
+      Some ( mut conn ) => {
+        match conn.nick {
+          Some ( ref nick ) => { self.nicks.remove( &nick.to_ascii_lowercase( ) ); },
+          None              => (),
+        }
+
+        // the writer closes the connection once the error has been written
+        let _ = conn.tcp.close_read( );
+      },
+      None              => (),
+    }
+  }
+
+  /// `remove_member` takes a client off a channel, dropping the channel once
+  /// it is empty
+  fn remove_member( &mut self, key : &str, id : TID ) {
+    let empty = match self.channels.get_mut( key ) {
+      Some ( ch ) => {
+        ch.members.retain( |m| m.0 != id );
+        ch.members.is_empty( )
+      },
+      None        => false,
+    };
+    if empty {
+      self.channels.remove( key );
+    }
+    match self.clients.get_mut( &id ) {
+      Some ( conn ) => conn.channels.retain( |c| c.as_slice( ) != key ),
+      None          => (),
+    }
+  }
+
+  /// `cmd_join` handles JOIN
+  fn cmd_join( &mut self, id : TID, params : &Vec < String > ) {
+    if !self.need_params( id, "JOIN", params, 1 ) {
+      return;
+    }
+
+    // JOIN 0 leaves every channel
+    if params[0].as_slice( ) == "0" {
+      let chans = self.clients.get( &id ).map( |c| c.channels.clone( ) ).unwrap_or( Vec::new( ) );
+      for key in chans.iter( ) {
+        let name = self.channels.get( key ).map( |ch| ch.name.clone( ) ).unwrap_or( key.clone( ) );
+        let line = format! ( ":{} PART {}", self.prefix_of( id ), name );
+        self.broadcast( key.as_slice( ), line.as_slice( ), None );
+        self.remove_member( key.as_slice( ), id );
+      }
+      return;
+    }
+
+    let keys : Vec < &str > = match params.get( 1 ) {
+      Some ( k ) => k.as_slice( ).split( ',' ).collect( ),
+      None       => Vec::new( ),
+    };
+    for ( n, name ) in params[0].as_slice( ).split( ',' ).enumerate( ) {
+      let key = keys.get( n ).map( |k| *k );
+      self.join_one( id, name, key );
+    }
+  }
+
+  /// `join_one` joins a client to a single channel
+  fn join_one( &mut self, id : TID, name : &str, key : Option < &str > ) {
+    if !valid_channel( name ) {
+      let params = format! ( "{} :No such channel", name );
+      return self.reply( id, "403", params.as_slice( ) );
+    }
+    let chan = name.to_ascii_lowercase( );
+    let count = match self.clients.get( &id ) {
+      Some ( conn ) => {
+        if conn.channels.contains( &chan ) {
+          return;
+        }
+        conn.channels.len( )
+      },
+      None          => return,
+    };
+    if count >= MAX_CHANNELS {
+      let params = format! ( "{} :You have joined too many channels", name );
+      return self.reply( id, "405", params.as_slice( ) );
+    }
+
+    // check the channel's key and limit, or create it with us as op
+    let now = time::get_time( ).sec;
+    let error = match self.channels.get( &chan ) {
+      Some ( ch ) => {
+        if ch.key.is_some( ) && ch.key.as_ref( ).map( |k| k.as_slice( ) ) != key {
+          Some( ( "475", "Cannot join channel (+k)" ) )
+        } else if ch.limit.map( |l| ch.members.len( ) >= l ).unwrap_or( false ) {
+          Some( ( "471", "Cannot join channel (+l)" ) )
+        } else {
+          None
+        }
+      },
+      None        => None,
+    };
+    match error {
+      Some ( ( code, text ) ) => {
+        let params = format! ( "{} :{}", name, text );
+        return self.reply( id, code, params.as_slice( ) );
+      },
+      None                    => (),
+    }
+    if !self.channels.contains_key( &chan ) {
+      self.channels.insert( chan.clone( ), Channel::new( name, now ) );
+    }
+    match self.channels.get_mut( &chan ) {
+      Some ( ch ) => {
+        let op = ch.members.is_empty( );
+        ch.members.push( ( id, Member { op : op, voice : false } ) );
+      },
+      None        => return,
+    }
+    match self.clients.get_mut( &id ) {
+      Some ( conn ) => conn.channels.push( chan.clone( ) ),
+      None          => return,
+    }
+
+    let name = self.channels.get( &chan ).map( |ch| ch.name.clone( ) ).unwrap_or( name.to_string( ) );
+    let line = format! ( ":{} JOIN {}", self.prefix_of( id ), name );
+    self.broadcast( chan.as_slice( ), line.as_slice( ), None );
+    if self.channels.get( &chan ).map( |ch| ch.topic.is_some( ) ).unwrap_or( false ) {
+      self.send_topic( id, chan.as_slice( ) );
+    }
+    self.send_names( id, chan.as_slice( ) );
+  }
+
+  /// `cmd_part` handles PART
+  fn cmd_part( &mut self, id : TID, params : &Vec < String > ) {
+    if !self.need_params( id, "PART", params, 1 ) {
+      return;
+    }
+    let reason = params.get( 1 ).map( |r| r.clone( ) );
+    for name in params[0].as_slice( ).split( ',' ) {
+      let chan = name.to_ascii_lowercase( );
+      if !self.channels.contains_key( &chan ) {
+        let params = format! ( "{} :No such channel", name );
+        self.reply( id, "403", params.as_slice( ) );
+        continue;
+      }
+      if self.channels.get( &chan ).and_then( |ch| ch.member( id ) ).is_none( ) {
+        let params = format! ( "{} :You're not on that channel", name );
+        self.reply( id, "442", params.as_slice( ) );
+        continue;
+      }
+      let name = self.channels.get( &chan ).map( |ch| ch.name.clone( ) ).unwrap_or( name.to_string( ) );
+      let line = match reason {
+        Some ( ref r ) => format! ( ":{} PART {} :{}", self.prefix_of( id ), name, r ),
+        None           => format! ( ":{} PART {}", self.prefix_of( id ), name ),
+      };
+      self.broadcast( chan.as_slice( ), line.as_slice( ), None );
+      self.remove_member( chan.as_slice( ), id );
+    }
+  }
+
+  /// `channel_for_op` finds a channel a client wants to change, replying with
+  /// an error if it doesn't exist or the client isn't allowed to
+  ///
+  /// # Returns
+  ///
+  /// The channel's key and name, or None after an error was sent
+  fn channel_for_op( &mut self, id : TID, name : &str, need_op : bool ) -> Option < ( String, String ) > {
+    let chan = name.to_ascii_lowercase( );
+    let ( real, member, op ) = match self.channels.get( &chan ) {
+      Some ( ch ) => ( ch.name.clone( ), ch.member( id ).is_some( ), ch.is_op( id ) ),
+      None        => {
+        let params = format! ( "{} :No such channel", name );
+        self.reply( id, "403", params.as_slice( ) );
+        return None;
+      },
+    };
+    if !member {
+      let params = format! ( "{} :You're not on that channel", real );
+      self.reply( id, "442", params.as_slice( ) );
+      return None;
+    }
+    if need_op && !op {
+      let params = format! ( "{} :You're not channel operator", real );
+      self.reply( id, "482", params.as_slice( ) );
+      return None;
+    }
+    Some( ( chan, real ) )
+  }
+
+  /// `cmd_kick` handles KICK
+  fn cmd_kick( &mut self, id : TID, params : &Vec < String > ) {
+    if !self.need_params( id, "KICK", params, 2 ) {
+      return;
+    }
+    let ( chan, name ) = match self.channel_for_op( id, params[0].as_slice( ), true ) {
+      Some ( c ) => c,
+      None       => return,
+    };
+    let target = match self.find_nick( params[1].as_slice( ) ) {
+      Some ( t ) => t,
+      None       => {
+        let params = format! ( "{} :No such nick/channel", params[1] );
+        return self.reply( id, "401", params.as_slice( ) );
+      },
+    };
+    if self.channels.get( &chan ).and_then( |ch| ch.member( target ) ).is_none( ) {
+      let params = format! ( "{} {} :They aren't on that channel", params[1], name );
+      return self.reply( id, "441", params.as_slice( ) );
+    }
+    let reason : String = match params.get( 2 ) {
+      Some ( r ) => r.chars( ).take( KICK_LEN ).collect( ),
+      None       => self.nick_of( id ),
+    };
+    let line = format! ( ":{} KICK {} {} :{}", self.prefix_of( id ), name, self.nick_of( target ), reason );
+    self.broadcast( chan.as_slice( ), line.as_slice( ), None );
+    self.remove_member( chan.as_slice( ), target );
+  }
+
+  /// `send_topic` sends the topic of a channel to a client
+  fn send_topic( &mut self, id : TID, chan : &str ) {
+    let ( name, topic, by, at ) = match self.channels.get( chan ) {
+      Some ( ch ) => ( ch.name.clone( ), ch.topic.clone( ), ch.topic_by.clone( ), ch.topic_at ),
+      None        => return,
+    };
+    match topic {
+      Some ( topic ) => {
+        let params = format! ( "{} :{}", name, topic );
+        self.reply( id, "332", params.as_slice( ) );
+        let params = format! ( "{} {} {}", name, by, at );
+        self.reply( id, "333", params.as_slice( ) );
+      },
+      None           => {
+        let params = format! ( "{} :No topic is set", name );
+        self.reply( id, "331", params.as_slice( ) );
+      },
+    }
+  }
+
+  /// `cmd_topic` handles TOPIC, both queries and changes
+  fn cmd_topic( &mut self, id : TID, params : &Vec < String > ) {
+    if !self.need_params( id, "TOPIC", params, 1 ) {
+      return;
+    }
+    if params.len( ) < 2 {
+      let chan = params[0].to_ascii_lowercase( );
+      if !self.channels.contains_key( &chan ) {
+        let params = format! ( "{} :No such channel", params[0] );
+        return self.reply( id, "403", params.as_slice( ) );
+      }
+      return self.send_topic( id, chan.as_slice( ) );
+    }
+    let locked = self.channels.get( &params[0].to_ascii_lowercase( ) ).map( |ch| ch.topic_lock ).unwrap_or( false );
+    let ( chan, name ) = match self.channel_for_op( id, params[0].as_slice( ), locked ) {
+      Some ( c ) => c,
+      None       => return,
+    };
+    let topic : String = params[1].chars( ).take( TOPIC_LEN ).collect( );
+    let by = self.prefix_of( id );
+    match self.channels.get_mut( &chan ) {
+      Some ( ch ) => {
+        ch.topic = if topic.is_empty( ) { None } else { Some( topic.clone( ) ) };
+        ch.topic_by = by.clone( );
+        ch.topic_at = time::get_time( ).sec;
+      },
+      None        => return,
+    }
+    let line = format! ( ":{} TOPIC {} :{}", by, name, topic );
+    self.broadcast( chan.as_slice( ), line.as_slice( ), None );
+  }
+
+  /// `cmd_mode` handles MODE for channels and for the client itself
+  fn cmd_mode( &mut self, id : TID, params : &Vec < String > ) {
+    if !self.need_params( id, "MODE", params, 1 ) {
+      return;
+    }
+    if params[0].as_slice( ).starts_with( "#" ) {
+      self.channel_mode( id, params );
+    } else {
+      self.user_mode( id, params );
+    }
+  }
+
+  /// `user_mode` handles MODE on a nick. Only +i can be changed.
+  fn user_mode( &mut self, id : TID, params : &Vec < String > ) {
+    let nick = self.nick_of( id );
+    if params[0].to_ascii_lowercase( ) != nick.to_ascii_lowercase( ) {
+      return self.reply( id, "502", ":Cannot change mode for other users" );
+    }
+    if params.len( ) < 2 {
+      let invisible = self.clients.get( &id ).map( |c| c.invisible ).unwrap_or( false );
+      return self.reply( id, "221", if invisible { "+i" } else { "+" } );
+    }
+    let mut adding = true;
+    let mut changed = String::new( );
+    let mut unknown = false;
+    for c in params[1].chars( ) {
+      match c {
+        '+' => adding = true,
+        '-' => adding = false,
+        'i' => {
+          match self.clients.get_mut( &id ) {
+            Some ( conn ) if conn.invisible != adding => {
+              conn.invisible = adding;
+              changed.push( if adding { '+' } else { '-' } );
+              changed.push( 'i' );
+            },
+            _                                         => (),
+          }
+        },
+        _   => unknown = true,
+      }
+    }
+    if unknown {
+      self.reply( id, "501", ":Unknown MODE flag" );
+    }
+    if !changed.is_empty( ) {
+      let line = format! ( ":{} MODE {} :{}", nick, nick, changed );
+      self.send_to( id, line.as_slice( ) );
+    }
+  }
+
+  /// `channel_mode` handles MODE on a channel
+  fn channel_mode( &mut self, id : TID, params : &Vec < String > ) {
+    let chan = params[0].to_ascii_lowercase( );
+    let ( name, modes, created ) = match self.channels.get( &chan ) {
+      Some ( ch ) => ( ch.name.clone( ), ch.mode_string( ), ch.created ),
+      None        => {
+        let params = format! ( "{} :No such channel", params[0] );
+        return self.reply( id, "403", params.as_slice( ) );
+      },
+    };
+    if params.len( ) < 2 {
+      let reply = format! ( "{} {}", name, modes );
+      self.reply( id, "324", reply.as_slice( ) );
+      let reply = format! ( "{} {}", name, created );
+      return self.reply( id, "329", reply.as_slice( ) );
+    }
+
+    // clients ask for the ban list on join, we don't keep one
+    if params[1].as_slice( ).trim_left_matches( '+' ) == "b" {
+      let reply = format! ( "{} :End of channel ban list", name );
+      return self.reply( id, "368", reply.as_slice( ) );
+    }
+    if self.channel_for_op( id, name.as_slice( ), true ).is_none( ) {
+      return;
+    }
+
+    let mut args = params.iter( ).skip( 2 );
+    let mut adding = true;
+    let mut applied = String::new( );
+    let mut applied_args : Vec < String > = Vec::new( );
+    let mut last_sign = ' ';
+    for c in params[1].chars( ) {
+      let mut arg = None;
+      match c {
+        '+' => { adding = true; continue; },
+        '-' => { adding = false; continue; },
+        'o' | 'v' => {
+          let nick = match args.next( ) {
+            Some ( n ) => n.clone( ),
+            None       => continue,
+          };
+          let target = match self.find_nick( nick.as_slice( ) ) {
+            Some ( t ) => t,
+            None       => {
+              let params = format! ( "{} :No such nick/channel", nick );
+              self.reply( id, "401", params.as_slice( ) );
+              continue;
+            },
+          };
+          let found = match self.channels.get_mut( &chan ).and_then( |ch| ch.member_mut( target ) ) {
+            Some ( m ) => {
+              if c == 'o' { m.op = adding; } else { m.voice = adding; }
+              true
+            },
+            None       => false,
+          };
+          if !found {
+            let params = format! ( "{} {} :They aren't on that channel", nick, name );
+            self.reply( id, "441", params.as_slice( ) );
+            continue;
+          }
+          arg = Some( self.nick_of( target ) );
+        },
+        'k' => {
+          let key = if adding {
+            match args.next( ) {
+              Some ( k ) => Some( k.clone( ) ),
+              None       => continue,
+            }
+          } else {
+            let _ = args.next( );
+            None
+          };
+          match self.channels.get_mut( &chan ) {
+            Some ( ch ) => ch.key = key.clone( ),
+            None        => return,
+          }
+          arg = Some( key.unwrap_or( String::from_str( "*" ) ) );
+        },
+        'l' => {
+          let limit = if adding {
+            match args.next( ).and_then( |l| l.parse::< usize >( ).ok( ) ) {
+              Some ( l ) => Some( l ),
+              None       => continue,
+            }
+          } else {
+            None
+          };
+          match self.channels.get_mut( &chan ) {
+            Some ( ch ) => ch.limit = limit,
+            None        => return,
+          }
+          arg = limit.map( |l| l.to_string( ) );
+        },
+        'n' | 't' | 'm' => {
+          match self.channels.get_mut( &chan ) {
+            Some ( ch ) => match c {
+              'n' => ch.no_external = adding,
+              't' => ch.topic_lock = adding,
+              _   => ch.moderated = adding,
+            },
+            None        => return,
+          }
+        },
+        _   => {
+          let params = format! ( "{} :is unknown mode char to me for {}", c, name );
+          self.reply( id, "472", params.as_slice( ) );
+          continue;
+        },
+      }
+      let sign = if adding { '+' } else { '-' };
+      if sign != last_sign {
+        applied.push( sign );
+        last_sign = sign;
+      }
+      applied.push( c );
+      match arg {
+        Some ( a ) => applied_args.push( a ),
+        None       => (),
+      }
+    }
+
+    if !applied.is_empty( ) {
+      let mut line = format! ( ":{} MODE {} {}", self.prefix_of( id ), name, applied );
+      for a in applied_args.iter( ) {
+        line.push( ' ' );
+        line.push_str( a.as_slice( ) );
+      }
+      self.broadcast( chan.as_slice( ), line.as_slice( ), None );
+    }
+  }
+
+  /// `cmd_message` handles PRIVMSG and NOTICE. NOTICE never gets error replies.
+  fn cmd_message( &mut self, id : TID, code : &str, params : &Vec < String > ) {
+    let notice = code == "NOTICE";
+    if params.len( ) < 1 || params[0].is_empty( ) {
+      if !notice {
+        let params = format! ( ":No recipient given ({})", code );
+        self.reply( id, "411", params.as_slice( ) );
+      }
+      return;
+    }
+    if params.len( ) < 2 || params[1].is_empty( ) {
+      if !notice {
+        self.reply( id, "412", ":No text to send" );
+      }
+      return;
+    }
+    for target in params[0].as_slice( ).split( ',' ) {
+      if target.starts_with( "#" ) {
+        let chan = target.to_ascii_lowercase( );
+        let ( name, allowed ) = match self.channels.get( &chan ) {
+          Some ( ch ) => {
+            let allowed = match ch.member( id ) {
+              Some ( m ) => !ch.moderated || m.op || m.voice,
+              None       => !ch.no_external && !ch.moderated,
+            };
+            ( ch.name.clone( ), allowed )
+          },
+          None        => {
+            if !notice {
+              let params = format! ( "{} :No such nick/channel", target );
+              self.reply( id, "401", params.as_slice( ) );
+            }
+            continue;
+          },
+        };
+        if !allowed {
+          if !notice {
+            let params = format! ( "{} :Cannot send to channel", name );
+            self.reply( id, "404", params.as_slice( ) );
+          }
+          continue;
+        }
+        let line = format! ( ":{} {} {} :{}", self.prefix_of( id ), code, name, params[1] );
+        self.broadcast( chan.as_slice( ), line.as_slice( ), Some( id ) );
+      } else {
+        let to = match self.find_nick( target ) {
+          Some ( to ) => to,
+          None        => {
+            if !notice {
+              let params = format! ( "{} :No such nick/channel", target );
+              self.reply( id, "401", params.as_slice( ) );
+            }
+            continue;
+          },
+        };
+        let text = format! ( "{} :{}", self.nick_of( to ), params[1] );
+        self.send_from( to, id, code, text.as_slice( ) );
+        let away = self.clients.get( &to ).and_then( |c| c.away.clone( ) );
+        match away {
+          Some ( away ) if !notice => {
+            let params = format! ( "{} :{}", self.nick_of( to ), away );
+            self.reply( id, "301", params.as_slice( ) );
+          },
+          _                        => (),
+        }
+      }
+    }
+  }
+
+  /// `send_names` sends the names on a channel
+  fn send_names( &mut self, id : TID, chan : &str ) {
+    let ( name, members ) = match self.channels.get( chan ) {
+      Some ( ch ) => ( ch.name.clone( ), ch.members.iter( )
+        .map( |m| ( m.0, m.1.prefix( ) ) ).collect::< Vec < ( TID, &str ) > >( ) ),
+      None        => ( chan.to_string( ), Vec::new( ) ),
+    };
+    let mut names = Vec::new( );
+    for &( member, prefix ) in members.iter( ) {
+      names.push( format! ( "{}{}", prefix, self.nick_of( member ) ) );
+    }
+
+    // keep each reply well under the 512 byte line limit
+    for chunk in names.chunks( 40 ) {
+      let params = format! ( "= {} :{}", name, chunk.connect( " " ) );
+      self.reply( id, "353", params.as_slice( ) );
+    }
+    let params = format! ( "{} :End of NAMES list", name );
+    self.reply( id, "366", params.as_slice( ) );
+  }
+
+  /// `cmd_names` handles NAMES
+  fn cmd_names( &mut self, id : TID, params : &Vec < String > ) {
+    if params.len( ) < 1 || params[0].is_empty( ) {
+      return self.reply( id, "366", "* :End of NAMES list" );
+    }
+    for name in params[0].as_slice( ).split( ',' ) {
+      let chan = name.to_ascii_lowercase( );
+      if self.channels.contains_key( &chan ) {
+        self.send_names( id, chan.as_slice( ) );
+      } else {
+        let params = format! ( "{} :End of NAMES list", name );
+        self.reply( id, "366", params.as_slice( ) );
+      }
+    }
+  }
+
+  /// `cmd_list` handles LIST
+  fn cmd_list( &mut self, id : TID ) {
+    let mut rows : Vec < String > = self.channels.values( ).map( |ch| format! ( "{} {} :{}",
+      ch.name, ch.members.len( ), ch.topic.clone( ).unwrap_or( String::new( ) ) ) ).collect( );
+    rows.sort( );
+    self.reply( id, "321", "Channel :Users  Name" );
+    for row in rows.iter( ) {
+      self.reply( id, "322", row.as_slice( ) );
+    }
+    self.reply( id, "323", ":End of LIST" );
+  }
+
+  /// `who_line` formats a RPL_WHOREPLY for a client
+  fn who_line( &self, target : TID, chan : &str, prefix : &str ) -> Option < String > {
+    let conn = match self.clients.get( &target ) {
+      Some ( conn ) => conn,
+      None          => return None,
+    };
+    Some( format! ( "{} {} {} {} {} {}{} :0 {}",
+      chan,
+      conn.user.clone( ).unwrap_or( String::from_str( "*" ) ),
+      conn.host,
+      self.config.name,
+      self.nick_of( target ),
+      if conn.away.is_some( ) { "G" } else { "H" },
+      prefix,
+      conn.real ) )
+  }
+
+  /// `cmd_who` handles WHO for channels and nicks
+  fn cmd_who( &mut self, id : TID, params : &Vec < String > ) {
+    let mask = params.get( 0 ).map( |m| m.clone( ) ).unwrap_or( String::from_str( "*" ) );
+    let mut lines = Vec::new( );
+    if mask.as_slice( ).starts_with( "#" ) {
+      match self.channels.get( &mask.to_ascii_lowercase( ) ) {
+        Some ( ch ) => for &( member, ref status ) in ch.members.iter( ) {
+          match self.who_line( member, ch.name.as_slice( ), status.prefix( ) ) {
+            Some ( line ) => lines.push( line ),
+            None          => (),
+          }
+        },
+        None        => (),
+      }
+    } else {
+      match self.find_nick( mask.as_slice( ) ) {
+        Some ( target ) => match self.who_line( target, "*", "" ) {
+          Some ( line ) => lines.push( line ),
+          None          => (),
+        },
+        None            => (),
+      }
+    }
+    for line in lines.iter( ) {
+      self.reply( id, "352", line.as_slice( ) );
+    }
+    let end = format! ( "{} :End of WHO list", mask );
+    self.reply( id, "315", end.as_slice( ) );
+  }
+
+  /// `cmd_whois` handles WHOIS
+  fn cmd_whois( &mut self, id : TID, params : &Vec < String > ) {
+    if params.len( ) < 1 || params[0].is_empty( ) {
+      return self.reply( id, "431", ":No nickname given" );
+    }
+    // WHOIS server nick asks a specific server, there's only us
+    let nick = if params.len( ) > 1 { params[1].clone( ) } else { params[0].clone( ) };
+    let target = match self.find_nick( nick.as_slice( ) ) {
+      Some ( t ) => t,
+      None       => {
+        let params = format! ( "{} :No such nick/channel", nick );
+        self.reply( id, "401", params.as_slice( ) );
+        let params = format! ( "{} :End of WHOIS list", nick );
+        return self.reply( id, "318", params.as_slice( ) );
+      },
+    };
+    let ( user, host, real, away, chans ) = match self.clients.get( &target ) {
+      Some ( c ) => ( c.user.clone( ).unwrap_or( String::from_str( "*" ) ), c.host.clone( ),
+        c.real.clone( ), c.away.clone( ), c.channels.clone( ) ),
+      None       => return,
+    };
+    let nick = self.nick_of( target );
+    let mut names = Vec::new( );
+    for key in chans.iter( ) {
+      match self.channels.get( key ) {
+        Some ( ch ) => {
+          let prefix = ch.member( target ).map( |m| m.prefix( ) ).unwrap_or( "" );
+          names.push( format! ( "{}{}", prefix, ch.name ) );
+        },
+        None        => (),
+      }
+    }
+
+    let line = format! ( "{} {} {} * :{}", nick, user, host, real );
+    self.reply( id, "311", line.as_slice( ) );
+    if !names.is_empty( ) {
+      let line = format! ( "{} :{}", nick, names.connect( " " ) );
+      self.reply( id, "319", line.as_slice( ) );
+    }
+    let line = format! ( "{} {} :{}", nick, self.config.name, self.config.network );
+    self.reply( id, "312", line.as_slice( ) );
+    match away {
+      Some ( away ) => {
+        let line = format! ( "{} :{}", nick, away );
+        self.reply( id, "301", line.as_slice( ) );
+      },
+      None          => (),
+    }
+    let line = format! ( "{} :End of WHOIS list", nick );
+    self.reply( id, "318", line.as_slice( ) );
+  }
+
+  /// `cmd_away` handles AWAY
+  fn cmd_away( &mut self, id : TID, params : &Vec < String > ) {
+    let away = params.get( 0 ).and_then( |a| if a.is_empty( ) { None } else { Some( a.clone( ) ) } );
+    let back = away.is_none( );
+    match self.clients.get_mut( &id ) {
+      Some ( conn ) => conn.away = away,
+      None          => return,
+    }
+    if back {
+      self.reply( id, "305", ":You are no longer marked as being away" );
+    } else {
+      self.reply( id, "306", ":You have been marked as being away" );
+    }
+  }
+}
+
+/// `params_of` collects every parameter of a message
+fn params_of( msg : &message::Message ) -> Vec < String > {
+  let mut params = Vec::new( );
+  let mut n = 1;
+  loop {
+    match msg.param( n ) {
+      Some ( p ) => params.push( p.to_string( ) ),
+      None       => break,
+    }
+    n += 1;
+  }
+  params
+}
+
+/// `isupport` formats the RPL_ISUPPORT tokens of the server
+fn isupport( config : &ServerConfig ) -> String {
+  format! ( "CASEMAPPING=ascii CHANTYPES=# CHANMODES=,k,l,mnt PREFIX=(ov)@+ MODES=4 \
+    CHANLIMIT=#:{} NICKLEN={} CHANNELLEN={} TOPICLEN={} KICKLEN={} NETWORK={} \
+    :are supported by this server",
+    MAX_CHANNELS, NICK_LEN, CHANNEL_LEN, TOPIC_LEN, KICK_LEN, config.network )
+}
+
+/// `valid_nick` returns whether a nick is allowed
+pub fn valid_nick( nick : &str ) -> bool {
+  let special = |c : char| "[]\\`_^{|}".contains_char( c );
+  match nick.chars( ).next( ) {
+    Some ( c ) if c.is_alphabetic( ) || special( c ) => (),
+    _                                                => return false,
+  }
+  nick.len( ) <= NICK_LEN &&
+    nick.chars( ).all( |c| c.is_ascii( ) && ( c.is_alphanumeric( ) || special( c ) || c == '-' ) )
+}
+
+/// `valid_channel` returns whether a channel name is allowed
+pub fn valid_channel( name : &str ) -> bool {
+  name.starts_with( "#" ) && name.len( ) > 1 && name.len( ) <= CHANNEL_LEN &&
+    !name.chars( ).any( |c| c == ' ' || c == ',' || c == '\x07' || c == ':' || c.is_control( ) )
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[allow(unused_imports)]
+  use std::old_io as io;
+  #[allow(unused_imports)]
+  use super::{Server, ServerConfig};
+
+  /// `client` connects to a test server and registers as nick
+  #[allow(dead_code)]
+  fn client( server : &Server, nick : &str ) -> ( io::BufferedReader < io::TcpStream >, io::TcpStream ) {
+    let tcp = io::TcpStream::connect( server.local ).unwrap( );
+    let mut writer = tcp.clone( );
+    let mut reader = io::BufferedReader::new( tcp );
+    writer.write_str( format! ( "NICK {}\r\nUSER {} 0 * :Test User\r\n", nick, nick ).as_slice( ) ).unwrap( );
+    expect( &mut reader, " 422 " );
+    ( reader, writer )
+  }
+
+  /// `expect` reads lines until one contains the pattern
+  #[allow(dead_code)]
+  fn expect( reader : &mut io::BufferedReader < io::TcpStream >, pattern : &str ) -> String {
+    loop {
+      let line = reader.read_line( ).unwrap( );
+      if line.as_slice( ).contains( pattern ) {
+        return line.as_slice( ).trim_right( ).to_string( );
+      }
+    }
+  }
+
+  #[allow(dead_code)]
+  fn start( ) -> Server {
+    let mut config = ServerConfig::new( "irc.test" );
+    config.bind = String::from_str( "127.0.0.1:0" );
+    Server::start( config ).unwrap( )
+  }
+
+  #[test]
+  fn test_valid_names () {
+    assert! ( super::valid_nick( "Lancey" ) );
+    assert! ( super::valid_nick( "[func_door]" ) );
+    assert! ( !super::valid_nick( "1up" ) );
+    assert! ( !super::valid_nick( "two words" ) );
+    assert! ( super::valid_channel( "#rust" ) );
+    assert! ( !super::valid_channel( "rust" ) );
+    assert! ( !super::valid_channel( "#a,b" ) );
+  }
+
+  #[test]
+  fn test_registration () {
+    let server = start( );
+    let tcp = io::TcpStream::connect( server.local ).unwrap( );
+    let mut writer = tcp.clone( );
+    let mut reader = io::BufferedReader::new( tcp );
+    expect( &mut reader, "NOTICE * :" );
+    writer.write_str( "PING :early\r\n" ).unwrap( );
+    expect( &mut reader, "PONG irc.test :early" );
+    writer.write_str( "JOIN #rust\r\n" ).unwrap( );
+    expect( &mut reader, " 451 * :" );
+    writer.write_str( "NICK Lancey\r\nUSER lancey 0 * :Lancey\r\n" ).unwrap( );
+    assert! ( expect( &mut reader, " 001 " ).starts_with( ":irc.test 001 Lancey :Welcome" ) );
+    assert! ( expect( &mut reader, " 005 " ).contains( "PREFIX=(ov)@+" ) );
+    server.stop( );
+    expect( &mut reader, "ERROR :" );
+    server.wait( );
+  }
+
+  #[test]
+  fn test_nick_in_use () {
+    let server = start( );
+    let ( _r, _w ) = client( &server, "Lancey" );
+    let tcp = io::TcpStream::connect( server.local ).unwrap( );
+    let mut writer = tcp.clone( );
+    let mut reader = io::BufferedReader::new( tcp );
+    writer.write_str( "NICK lancey\r\n" ).unwrap( );
+    expect( &mut reader, " 433 * lancey :" );
+    server.stop( );
+  }
+
+  #[test]
+  fn test_bad_input () {
+    let server = start( );
+    let ( mut ra, mut wa ) = client( &server, "Lancey" );
+    let ( mut rb, mut wb ) = client( &server, "Detective" );
+    wa.write_str( "JOIN #rust\r\n" ).unwrap( );
+    expect( &mut ra, " 366 " );
+    wb.write_str( "JOIN #rust\r\n" ).unwrap( );
+    expect( &mut rb, " 366 " );
+
+    // bytes that aren't UTF-8 are replaced, and the client stays connected
+    wa.write_all( b"PRIVMSG #rust :caf\xe9\r\n" ).unwrap( );
+    assert! ( expect( &mut rb, "PRIVMSG" ) == ":Lancey!Lancey@127.0.0.1 PRIVMSG #rust :caf\u{fffd}" );
+    let long : String = range( 0, 10000 ).map( |_| 'a' ).collect( );
+    wa.write_str( format! ( "PRIVMSG #rust :{}\r\nPING :alive\r\n", long ).as_slice( ) ).unwrap( );
+    expect( &mut ra, "PONG irc.test :alive" );
+    server.stop( );
+  }
+
+  #[test]
+  fn test_channel_routing () {
+    let server = start( );
+    let ( mut ra, mut wa ) = client( &server, "Lancey" );
+    let ( mut rb, mut wb ) = client( &server, "Detective" );
+
+    wa.write_str( "JOIN #rust\r\n" ).unwrap( );
+    expect( &mut ra, ":Lancey!Lancey@127.0.0.1 JOIN #rust" );
+    expect( &mut ra, " 353 Lancey = #rust :@Lancey" );
+    wb.write_str( "JOIN #Rust\r\n" ).unwrap( );
+    expect( &mut ra, ":Detective!Detective@127.0.0.1 JOIN #rust" );
+    expect( &mut rb, " 353 Detective = #rust :@Lancey Detective" );
+
+    wb.write_str( "PRIVMSG #rust :hello there\r\n" ).unwrap( );
+    expect( &mut ra, ":Detective!Detective@127.0.0.1 PRIVMSG #rust :hello there" );
+    wa.write_str( "PRIVMSG Detective :psst\r\n" ).unwrap( );
+    expect( &mut rb, ":Lancey!Lancey@127.0.0.1 PRIVMSG Detective :psst" );
+
+    wb.write_str( "TOPIC #rust :not allowed\r\n" ).unwrap( );
+    expect( &mut rb, " 482 Detective #rust :" );
+    wa.write_str( "MODE #rust +v-t Detective\r\n" ).unwrap( );
+    expect( &mut rb, ":Lancey!Lancey@127.0.0.1 MODE #rust +v-t Detective" );
+    wb.write_str( "TOPIC #rust :now allowed\r\n" ).unwrap( );
+    expect( &mut ra, "TOPIC #rust :now allowed" );
+
+    wa.write_str( "KICK #rust Detective :out\r\n" ).unwrap( );
+    expect( &mut rb, ":Lancey!Lancey@127.0.0.1 KICK #rust Detective :out" );
+    wa.write_str( "NAMES #rust\r\n" ).unwrap( );
+    expect( &mut ra, " 353 Lancey = #rust :@Lancey\r" );
+    server.stop( );
+  }
+
+  #[test]
+  fn test_who_whois () {
+    let server = start( );
+    let ( mut ra, mut wa ) = client( &server, "Lancey" );
+    let ( _rb, mut wb ) = client( &server, "Detective" );
+    wb.write_str( "JOIN #rust\r\nAWAY :gone\r\n" ).unwrap( );
+
+    wa.write_str( "WHOIS Detective\r\n" ).unwrap( );
+    expect( &mut ra, " 311 Lancey Detective Detective 127.0.0.1 * :Test User" );
+    expect( &mut ra, " 319 Lancey Detective :@#rust" );
+    expect( &mut ra, " 301 Lancey Detective :gone" );
+    expect( &mut ra, " 318 Lancey Detective :" );
+
+    wa.write_str( "WHO #rust\r\n" ).unwrap( );
+    expect( &mut ra, " 352 Lancey #rust Detective 127.0.0.1 irc.test Detective G@ :0 Test User" );
+    expect( &mut ra, " 315 Lancey #rust :" );
+    server.stop( );
+  }
+
+  #[test]
+  fn test_quit_broadcast () {
+    let server = start( );
+    let ( mut ra, mut wa ) = client( &server, "Lancey" );
+    let ( mut rb, mut wb ) = client( &server, "Detective" );
+    wa.write_str( "JOIN #rust\r\n" ).unwrap( );
+    expect( &mut ra, " 366 " );
+    wb.write_str( "JOIN #rust\r\n" ).unwrap( );
+    expect( &mut ra, "JOIN #rust" );
+    wb.write_str( "QUIT :bye\r\n" ).unwrap( );
+    expect( &mut rb, "ERROR :Closing Link: 127.0.0.1 (Quit: bye)" );
+    expect( &mut ra, ":Detective!Detective@127.0.0.1 QUIT :Quit: bye" );
+    server.stop( );
+  }
+}
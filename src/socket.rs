@@ -0,0 +1,135 @@
+// import built in modules
+use std::mem;
+use std::old_io as io;
+use std::old_io::{Acceptor, Listener};
+use std::old_io::net::ip;
+use std::old_io::net::ip::IpAddr;
+use std::os;
+use std::os::unix::AsRawFd;
+use libc;
+
+/// `connect_from` connects to an address from a chosen local address
+///
+/// # Arguments
+///
+/// * `local` - the local address to connect from. The system picks the port.
+/// * `addr` - the address to connect to
+/// * `timeout` - milliseconds to wait for the connection, or None to wait as
+/// long as the system does
+///
+/// # Returns
+///
+/// The stream, or why it couldn't connect
+///
+/// # Notes
+///
+/// * `local` and `addr` must be the same family.
+/// * Our sockets can't be bound before they connect, so the socket is made
+/// here and then moved into a TcpStream in place of the one it was made with.
+pub fn connect_from( local : IpAddr, addr : ip::SocketAddr, timeout : Option < i64 > )
+  -> io::IoResult < io::TcpStream > {
+  let family = match local {
+    IpAddr::Ipv4Addr( .. ) => libc::AF_INET,
+    IpAddr::Ipv6Addr( .. ) => libc::AF_INET6,
+  };
+  let fd = unsafe { libc::socket( family, libc::SOCK_STREAM, 0 ) };
+  if fd < 0 {
+    return Err( io::IoError::last_error( ) );
+  }
+  let result = bind_connect( fd, local, addr, timeout );
+
+  // on success the stream holds its own copy of the socket
+  unsafe { libc::close( fd ) };
+  result
+}
+
+/// `bind_connect` binds a socket, connects it and moves it into a TcpStream
+fn bind_connect( fd : libc::c_int, local : IpAddr, addr : ip::SocketAddr, timeout : Option < i64 > )
+  -> io::IoResult < io::TcpStream > {
+  let from = ip::SocketAddr { ip : local, port : 0 };
+  try! ( check( with_sockaddr( from, |sa, len| unsafe { libc::bind( fd, sa, len ) } ) ) );
+
+  // the send timeout bounds connect as well
+  try! ( set_send_timeout( fd, timeout ) );
+  if with_sockaddr( addr, |sa, len| unsafe { libc::connect( fd, sa, len ) } ) < 0 {
+    if os::errno( ) as libc::c_int == libc::EINPROGRESS {
+      return Err( io::standard_error( io::TimedOut ) );
+    }
+    return Err( io::IoError::last_error( ) );
+  }
+  try! ( set_send_timeout( fd, None ) );
+
+  let stream = try! ( placeholder( ) );
+  try! ( check( unsafe { libc::dup2( fd, stream.as_raw_fd( ) ) } ) );
+  Ok( stream )
+}
+
+/// `placeholder` opens a loopback TcpStream for a socket to be moved into
+fn placeholder( ) -> io::IoResult < io::TcpStream > {
+  let mut acceptor = try! ( try! ( io::TcpListener::bind( "127.0.0.1:0" ) ).listen( ) );
+  let addr = try! ( acceptor.socket_name( ) );
+  let stream = try! ( io::TcpStream::connect( addr ) );
+  try! ( acceptor.accept( ) );
+  Ok( stream )
+}
+
+/// `with_sockaddr` calls a function with an address laid out for the system
+///
+/// # Arguments
+///
+/// * `addr` - the address
+/// * `f` - the function, given a pointer to the address and its length
+///
+/// # Returns
+///
+/// What the function returned
+fn with_sockaddr < F > ( addr : ip::SocketAddr, f : F ) -> libc::c_int
+  where F : FnOnce( *const libc::sockaddr, libc::socklen_t ) -> libc::c_int {
+  match addr.ip {
+    IpAddr::Ipv4Addr( a, b, c, d ) => {
+      let mut sa : libc::sockaddr_in = unsafe { mem::zeroed( ) };
+      sa.sin_family = libc::AF_INET as libc::sa_family_t;
+      sa.sin_port = addr.port.to_be( );
+      sa.sin_addr = libc::in_addr {
+        s_addr : ( ( a as u32 ) << 24 | ( b as u32 ) << 16 | ( c as u32 ) << 8 | d as u32 ).to_be( ),
+      };
+      f( &sa as *const libc::sockaddr_in as *const libc::sockaddr, mem::size_of::< libc::sockaddr_in >( ) as libc::socklen_t )
+    },
+    IpAddr::Ipv6Addr( a, b, c, d, e, g, h, i ) => {
+      let mut sa : libc::sockaddr_in6 = unsafe { mem::zeroed( ) };
+      sa.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+      sa.sin6_port = addr.port.to_be( );
+      sa.sin6_addr = libc::in6_addr {
+        s6_addr : [ a.to_be( ), b.to_be( ), c.to_be( ), d.to_be( ), e.to_be( ), g.to_be( ), h.to_be( ), i.to_be( ) ],
+      };
+      f( &sa as *const libc::sockaddr_in6 as *const libc::sockaddr, mem::size_of::< libc::sockaddr_in6 >( ) as libc::socklen_t )
+    },
+  }
+}
+
+/// `set_send_timeout` sets how long a send, or a connect, may block
+///
+/// # Arguments
+///
+/// * `fd` - the socket
+/// * `timeout` - milliseconds, or None for no limit
+fn set_send_timeout( fd : libc::c_int, timeout : Option < i64 > ) -> io::IoResult < ( ) > {
+  let ms = timeout.unwrap_or( 0 );
+  let tv = libc::timeval {
+    tv_sec  : ( ms / 1000 ) as libc::time_t,
+    tv_usec : ( ( ms % 1000 ) * 1000 ) as libc::suseconds_t,
+  };
+  check( unsafe {
+    libc::setsockopt( fd, libc::SOL_SOCKET, libc::SO_SNDTIMEO, &tv as *const libc::timeval as *const libc::c_void,
+      mem::size_of::< libc::timeval >( ) as libc::socklen_t )
+  } )
+}
+
+/// `check` turns a failed system call into the error it set
+fn check( ret : libc::c_int ) -> io::IoResult < ( ) > {
+  if ret < 0 {
+    Err( io::IoError::last_error( ) )
+  } else {
+    Ok( ( ) )
+  }
+}
@@ -0,0 +1,145 @@
+// import built in modules
+use std::collections::VecDeque;
+
+/// `RateLimit` limits how fast lines are sent to the server
+///
+/// # Members
+///
+/// * `messages` - lines that may be sent in a burst
+/// * `per` - milliseconds before the burst allowance refills
+pub struct RateLimit {
+  pub messages : u32,
+  pub per      : i64,
+}
+
+impl Copy for RateLimit {}
+
+/// `Throttle` holds lines back so no more than `messages` of them are sent in
+/// any `per` milliseconds
+///
+/// # Members
+///
+/// * `limit` - the rate limit, or None to send everything at once
+/// * `sent` - when the lines in the current window were sent, oldest first
+/// * `queue` - lines waiting to be sent, oldest first
+///
+/// # Notes
+///
+/// * Lines always leave in the order they were queued.
+pub struct Throttle {
+  limit : Option < RateLimit >,
+  sent  : VecDeque < i64 >,
+  queue : VecDeque < Vec < u8 > >,
+}
+
+impl Throttle {
+  /// `new` creates an empty Throttle
+  ///
+  /// # Arguments
+  ///
+  /// * `limit` - the rate limit, or None for no limit
+  pub fn new( limit : Option < RateLimit > ) -> Throttle {
+    Throttle {
+      limit : limit,
+      sent  : VecDeque::new( ),
+      queue : VecDeque::new( ),
+    }
+  }
+
+  /// `push` queues a line to send
+  pub fn push( &mut self, line : Vec < u8 > ) {
+    self.queue.push_back( line );
+  }
+
+  /// `ready` takes the queued lines that may be sent now
+  ///
+  /// # Arguments
+  ///
+  /// * `now` - the time in milliseconds
+  ///
+  /// # Returns
+  ///
+  /// The lines to send, oldest first. They're counted as sent.
+  pub fn ready( &mut self, now : i64 ) -> Vec < Vec < u8 > > {
+    let mut out = Vec::new( );
+    while !self.queue.is_empty( ) && self.wait_one( now ) == 0 {
+      if self.limit.is_some( ) {
+        self.sent.push_back( now );
+      }
+      out.push( self.queue.pop_front( ).unwrap( ) );
+    }
+    out
+  }
+
+  /// `wait` returns how long until the next queued line may be sent
+  ///
+  /// # Arguments
+  ///
+  /// * `now` - the time in milliseconds
+  ///
+  /// # Returns
+  ///
+  /// Milliseconds to wait, or None if nothing is queued
+  pub fn wait( &mut self, now : i64 ) -> Option < i64 > {
+    if self.queue.is_empty( ) {
+      None
+    } else {
+      Some( self.wait_one( now ) )
+    }
+  }
+
+  /// `drain` takes every queued line, ignoring the limit
+  pub fn drain( &mut self ) -> Vec < Vec < u8 > > {
+    self.queue.drain( ).collect( )
+  }
+
+  /// `wait_one` forgets sends that have left the window and returns how long
+  /// until one more line may be sent
+  fn wait_one( &mut self, now : i64 ) -> i64 {
+    let limit = match self.limit {
+      Some ( l ) => l,
+      None       => return 0,
+    };
+    while self.sent.front( ).map_or( false, |t| now - *t >= limit.per ) {
+      self.sent.pop_front( );
+    }
+    if self.sent.len( ) < limit.messages as usize {
+      0
+    } else {
+      *self.sent.front( ).unwrap( ) + limit.per - now
+    }
+  }
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[allow(unused_imports)]
+  use super::{RateLimit, Throttle};
+
+  #[test]
+  fn test_unlimited () {
+    let mut t = Throttle::new( None );
+    for n in 0u8..10 {
+      t.push( vec![ n ] );
+    }
+    assert! ( t.ready( 0 ).len( ) == 10 );
+    assert! ( t.wait( 0 ).is_none( ) );
+  }
+
+  #[test]
+  fn test_limited () {
+    let mut t = Throttle::new( Some( RateLimit { messages : 2, per : 1000 } ) );
+    for n in 0u8..5 {
+      t.push( vec![ n ] );
+    }
+
+    // a burst of two, then one more each time the window moves on
+    assert! ( t.ready( 0 ) == vec![ vec![ 0 ], vec![ 1 ] ] );
+    assert! ( t.wait( 400 ) == Some( 600 ) );
+    assert! ( t.ready( 400 ).is_empty( ) );
+    assert! ( t.ready( 1000 ) == vec![ vec![ 2 ], vec![ 3 ] ] );
+    assert! ( t.wait( 1500 ) == Some( 500 ) );
+    assert! ( t.drain( ) == vec![ vec![ 4 ] ] );
+    assert! ( t.wait( 1500 ).is_none( ) );
+  }
+}
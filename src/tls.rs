@@ -0,0 +1,215 @@
+// import built in modules
+use std::ascii::AsciiExt;
+use std::old_io as io;
+use std::old_io::net::ip;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use openssl::nid::Nid;
+use openssl::ssl::{Ssl, SslContext, SslMethod, SslStream};
+use openssl::ssl::SslVerifyMode::{SslVerifyNone, SslVerifyPeer};
+use openssl::ssl::error::SslError;
+
+static POLL_MS : usize = 100; // ms a read holds the stream before letting a write in
+
+/// `Polled` is a socket whose reads give up after a while, so a reader
+/// waiting on the server doesn't hold the TLS stream forever
+///
+/// # Members
+///
+/// * `tcp` - the socket
+/// * `ms` - milliseconds each read waits, or 0 to wait as long as it takes
+struct Polled {
+  tcp : io::TcpStream,
+  ms  : Arc < AtomicUsize >,
+}
+
+impl Reader for Polled {
+  fn read( &mut self, buf : &mut [u8] ) -> io::IoResult < usize > {
+    // timeouts are deadlines, so they're set again before every read
+    match self.ms.load( Ordering::SeqCst ) {
+      0  => self.tcp.set_read_timeout( None ),
+      ms => self.tcp.set_read_timeout( Some( ms as u64 ) ),
+    }
+    self.tcp.read( buf )
+  }
+}
+
+impl Writer for Polled {
+  fn write_all( &mut self, buf : &[u8] ) -> io::IoResult < ( ) > {
+    self.tcp.write_all( buf )
+  }
+
+  fn flush( &mut self ) -> io::IoResult < ( ) > {
+    self.tcp.flush( )
+  }
+}
+
+/// `TlsStream` is a TLS session with a server
+///
+/// # Members
+///
+/// * `tcp` - the socket under the session, for shutting it down
+/// * `ssl` - the session, shared by every clone
+///
+/// # Notes
+///
+/// * Clones share the session, so one thread can read while another
+/// writes. A read gives the session up every POLL_MS while it waits, which
+/// is the longest a write waits for it.
+pub struct TlsStream {
+  tcp : io::TcpStream,
+  ssl : Arc < Mutex < SslStream < Polled > > >,
+}
+
+impl Clone for TlsStream {
+  fn clone( &self ) -> TlsStream {
+    TlsStream {
+      tcp : self.tcp.clone( ),
+      ssl : self.ssl.clone( ),
+    }
+  }
+}
+
+impl Reader for TlsStream {
+  fn read( &mut self, buf : &mut [u8] ) -> io::IoResult < usize > {
+    loop {
+      match self.ssl.lock( ).unwrap( ).read( buf ) {
+        Err ( ref e ) if e.kind == io::TimedOut => (),
+        result                                  => return result,
+      }
+    }
+  }
+}
+
+impl Writer for TlsStream {
+  fn write_all( &mut self, buf : &[u8] ) -> io::IoResult < ( ) > {
+    self.ssl.lock( ).unwrap( ).write_all( buf )
+  }
+
+  fn flush( &mut self ) -> io::IoResult < ( ) > {
+    self.ssl.lock( ).unwrap( ).flush( )
+  }
+}
+
+impl TlsStream {
+  /// `peer_name` returns the address of the other end
+  pub fn peer_name( &mut self ) -> io::IoResult < ip::SocketAddr > {
+    self.tcp.peer_name( )
+  }
+
+  /// `close_read` shuts the reading half, ending any read in progress
+  pub fn close_read( &mut self ) -> io::IoResult < ( ) > {
+    self.tcp.close_read( )
+  }
+
+  /// `close_write` shuts the writing half
+  pub fn close_write( &mut self ) -> io::IoResult < ( ) > {
+    self.tcp.close_write( )
+  }
+}
+
+/// `connect` starts TLS on a connected socket
+///
+/// # Arguments
+///
+/// * `tcp` - the socket, straight to the server or through a proxy
+/// * `host` - name of the server, sent for SNI and checked against its
+/// certificate
+/// * `verify` - check the server's certificate
+/// * `timeout` - milliseconds to wait for each step of the handshake, or
+/// None to wait as long as it takes
+///
+/// # Returns
+///
+/// The TLS stream, or why the handshake failed
+///
+/// # Notes
+///
+/// * The certificate is checked against the system's trusted roots, and its
+/// common name must match `host`.
+pub fn connect( tcp : io::TcpStream, host : &str, verify : bool, timeout : Option < i64 > )
+  -> io::IoResult < TlsStream > {
+  irc_info! ( "starting TLS with {}...", host );
+  let mut ctx = try! ( SslContext::new( SslMethod::Sslv23 ).map_err( lift_error ) );
+  if verify {
+    ctx.set_verify( SslVerifyPeer, None );
+    try! ( ctx.set_default_verify_paths( ).map_err( lift_error ) );
+  } else {
+    ctx.set_verify( SslVerifyNone, None );
+  }
+  let ssl = try! ( Ssl::new( &ctx ).map_err( lift_error ) );
+  try! ( ssl.set_hostname( host ).map_err( lift_error ) );
+
+  let ms = Arc::new( AtomicUsize::new( timeout.map_or( 0, |t| t as usize ) ) );
+  let polled = Polled { tcp : tcp.clone( ), ms : ms.clone( ) };
+  let stream = try! ( SslStream::new_from( ssl, polled ).map_err( lift_error ) );
+  if verify {
+    let name = stream.get_peer_certificate( ).and_then( |c| c.subject_name( ).text_by_nid( Nid::CN ) ).map( |n| n.to_string( ) );
+    match name {
+      Some ( ref n ) if name_matches( n.as_slice( ), host ) => (),
+      _                                                     => {
+        irc_error! ( "TLS certificate doesn't match {}", host );
+        return Err( io::IoError {
+          kind   : io::OtherIoError,
+          desc   : "TLS certificate is for another host",
+          detail : Some( format! ( "expected {}, got {}", host, name.as_ref( ).map_or( "no name", |n| n.as_slice( ) ) ) ),
+        } );
+      },
+    }
+  }
+  ms.store( POLL_MS, Ordering::SeqCst );
+  irc_info! ( "TLS started with {}", host );
+  Ok( TlsStream { tcp : tcp, ssl : Arc::new( Mutex::new( stream ) ) } )
+}
+
+/// `name_matches` checks a certificate name against a host name
+///
+/// # Arguments
+///
+/// * `pattern` - the name in the certificate, which may start with "*."
+/// * `host` - the host we connected to
+///
+/// # Returns
+///
+/// true if the name covers the host. A wildcard stands for exactly one
+/// label, so "*.example.org" covers "irc.example.org" but not "example.org".
+pub fn name_matches( pattern : &str, host : &str ) -> bool {
+  let pattern = pattern.to_ascii_lowercase( );
+  let host = host.to_ascii_lowercase( );
+  if pattern.as_slice( ).starts_with( "*." ) {
+    match host.as_slice( ).find( '.' ) {
+      Some ( n ) => n > 0 && host.as_slice( ).slice_from( n ) == pattern.as_slice( ).slice_from( 1 ),
+      None       => false,
+    }
+  } else {
+    pattern == host
+  }
+}
+
+/// `lift_error` turns an OpenSSL error into an IoError
+fn lift_error( e : SslError ) -> io::IoError {
+  match e {
+    SslError::StreamError( e )      => e,
+    SslError::SslSessionClosed      => io::standard_error( io::EndOfFile ),
+    SslError::OpenSslErrors( errs ) => io::IoError {
+      kind   : io::OtherIoError,
+      desc   : "TLS error",
+      detail : Some( format! ( "{:?}", errs ) ),
+    },
+  }
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[allow(unused_imports)]
+  use super::name_matches;
+
+  #[test]
+  fn test_name_matches () {
+    assert! ( name_matches( "irc.example.org", "IRC.example.org" ) );
+    assert! ( name_matches( "*.example.org", "irc.example.org" ) );
+    assert! ( !name_matches( "*.example.org", "example.org" ) );
+    assert! ( !name_matches( "*.example.org", "a.b.example.org" ) );
+    assert! ( !name_matches( "irc.example.org", "irc.example.net" ) );
+  }
+}
@@ -0,0 +1,947 @@
+// import built in modules
+use std::cmp;
+use std::old_io as io;
+use std::old_io::fs::PathExtensions;
+use std::old_io::net::ip::{IpAddr, SocketAddr};
+use std::sync::{Arc, mpsc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// import custom modules
+use dcc;
+
+pub type TTRANSFER                 = u32;
+
+static DCC_CHUNK_SIZE   : usize    = 4096;  // bytes sent per write
+static DCC_MAX_SIZE     : u64      = 1 << 30; // default largest accepted file
+static DCC_TIMEOUT      : dcc::TTIMEOUT = 120000; // ms to wait for a peer
+
+/// `TransferEvent` defines the events reported by a TransferManager
+///
+/// # Options
+///
+/// `Offered( id, nick, name, size )` - nick offered us the file name
+/// `Progress( id, done, total )` - done of total bytes have been moved
+/// `Finished( id, path )` - the transfer completed and the file is at path
+/// `Failed( id, reason )` - the transfer was abandoned for the given reason
+pub enum TransferEvent {
+  Offered ( TTRANSFER, String, String, u64 ),
+  Progress ( TTRANSFER, u64, u64 ),
+  Finished ( TTRANSFER, Path ),
+  Failed ( TTRANSFER, String ),
+}
+
+/// `Outgoing` is a file we offered that hasn't been fetched yet, or is being
+/// fetched from our listener
+///
+/// # Members
+///
+/// * `id` - id of the transfer
+/// * `nick` - nick we offered the file to
+/// * `req` - the offer we sent
+/// * `path` - file being offered
+/// * `size` - size of the file as offered
+/// * `position` - resume position agreed with the peer
+struct Outgoing {
+  id        : TTRANSFER,
+  nick      : String,
+  req       : dcc::DccRequest,
+  path      : Path,
+  size      : u64,
+  position  : Arc < Mutex < u64 > >,
+}
+
+/// `Incoming` is a file offered to us that we haven't started receiving
+///
+/// # Members
+///
+/// * `id` - id of the transfer
+/// * `nick` - nick that offered us the file
+/// * `req` - the offer we received
+/// * `path` - where the file will be written
+/// * `size` - size of the file as offered
+/// * `position` - position we asked to resume from, if any
+struct Incoming {
+  id        : TTRANSFER,
+  nick      : String,
+  req       : dcc::DccRequest,
+  path      : Path,
+  size      : u64,
+  position  : u64,
+}
+
+/// `TransferManager` negotiates and runs DCC SEND file transfers
+///
+/// # Members
+///
+/// * `dir` - directory received files are written to
+/// * `max_size` - largest file that will be accepted
+/// * `bind` - local address to listen on
+/// * `public` - address to advertise to peers
+/// * `timeout` - milliseconds to wait for a peer to connect, and for each
+/// read or write once it has
+/// * `events` - transmission half of the event channel
+/// * `outgoing` - our offers, shared with the transfers fetching them so they
+/// can forget themselves when they end
+pub struct TransferManager {
+  pub dir       : Path,
+  pub max_size  : u64,
+  pub bind      : IpAddr,
+  pub public    : IpAddr,
+  pub timeout   : dcc::TTIMEOUT,
+
+  events        : mpsc::Sender < TransferEvent >,
+  next_id       : TTRANSFER,
+  outgoing      : Arc < Mutex < Vec < Outgoing > > >,
+  incoming      : Vec < Incoming >,
+}
+
+impl TransferManager {
+  /// `new` creates a transfer manager
+  ///
+  /// # Arguments
+  ///
+  /// * `dir` - directory received files are written to
+  /// * `bind` - local address to listen on
+  /// * `public` - address to advertise to peers
+  ///
+  /// # Returns
+  ///
+  /// A tuple containing:
+  /// * Receiver the manager will send transfer events to
+  /// * The transfer manager
+  pub fn new( dir : Path, bind : IpAddr, public : IpAddr )
+    -> ( mpsc::Receiver < TransferEvent >, TransferManager ) {
+    let ( tx, rx ) = mpsc::channel( );
+    ( rx, TransferManager {
+      dir       : dir,
+      max_size  : DCC_MAX_SIZE,
+      bind      : bind,
+      public    : public,
+      timeout   : DCC_TIMEOUT,
+      events    : tx,
+      next_id   : 0,
+      outgoing  : Arc::new( Mutex::new( Vec::new( ) ) ),
+      incoming  : Vec::new( ),
+    } )
+  }
+
+  /// `offer` offers a file to a nick and waits for them to fetch it
+  ///
+  /// # Arguments
+  ///
+  /// * `nick` - nick to offer the file to
+  /// * `path` - file to offer
+  /// * `passive` - true to ask the peer to listen instead of us
+  ///
+  /// # Returns
+  ///
+  /// A tuple containing the id of the transfer and the DCC SEND request to
+  /// send to the nick
+  pub fn offer( &mut self, nick : &str, path : &Path, passive : bool )
+    -> io::IoResult < ( TTRANSFER, dcc::DccRequest ) > {
+    let size = try! ( path.stat( ) ).size;
+    let name = match path.filename_str( ) {
+      Some ( n ) => n.to_string( ),
+      None       => return Err( invalid( "path has no filename" ) ),
+    };
+    let id = self.gen_id( );
+    let position = Arc::new( Mutex::new( 0u64 ) );
+    let public = dcc::encode_address( self.public );
+    let ( req, listener ) = if passive {
+      ( dcc::DccRequest::new( "SEND", name.as_slice( ), public.as_slice( ), 0,
+        vec![ size.to_string( ), id.to_string( ) ] ), None )
+    } else {
+      let listener = try! ( dcc::DccListener::bind( self.bind ) );
+      ( dcc::DccRequest::new( "SEND", name.as_slice( ), public.as_slice( ),
+        listener.local.port, vec![ size.to_string( ) ] ), Some( listener ) )
+    };
+    self.outgoing.lock( ).unwrap( ).push( Outgoing {
+      id        : id,
+      nick      : nick.to_string( ),
+      req       : req.clone( ),
+      path      : path.clone( ),
+      size      : size,
+      position  : position.clone( ),
+    } );
+
+    // the offer is forgotten once the peer has fetched it or given up
+    match listener {
+      Some ( listener ) => {
+        let ( events, timeout, file, offers ) = ( self.events.clone( ), self.timeout,
+          path.clone( ), self.outgoing.clone( ) );
+        thread::Thread::spawn( move || {
+          let result = listener.accept_stream( Some( timeout ) ).and_then( |tcp| {
+            let start = *position.lock( ).unwrap( );
+            run_send( tcp, &file, start, size, timeout, id, &events )
+          } );
+          offers.lock( ).unwrap( ).retain( |o| o.id != id );
+          report( id, result, file, &events );
+        } );
+      },
+      None              => (),
+    }
+    Ok( ( id, req ) )
+  }
+
+  /// `handle` processes a DCC request received from a nick
+  ///
+  /// # Arguments
+  ///
+  /// * `nick` - nick the request came from
+  /// * `req` - the DCC request
+  ///
+  /// # Returns
+  ///
+  /// A DCC request to send back to the nick, if one is needed
+  pub fn handle( &mut self, nick : &str, req : &dcc::DccRequest ) -> Option < dcc::DccRequest > {
+    match req.kind.as_slice( ) {
+      "SEND"   => self.handle_send( nick, req ),
+      "RESUME" => self.handle_resume( nick, req ),
+      "ACCEPT" => self.handle_accept( nick, req ),
+      _        => None,
+    }
+  }
+
+  /// `accept` accepts a file that was offered to us
+  ///
+  /// # Arguments
+  ///
+  /// * `id` - id of the offer, as reported by `Offered`
+  /// * `resume` - true to continue a partial file of the same name
+  ///
+  /// # Returns
+  ///
+  /// A DCC request to send back to the offering nick, if one is needed
+  pub fn accept( &mut self, id : TTRANSFER, resume : bool )
+    -> io::IoResult < Option < dcc::DccRequest > > {
+    let idx = match self.incoming.iter( ).position( |i| i.id == id ) {
+      Some ( i ) => i,
+      None       => return Err( invalid( "no such offer" ) ),
+    };
+    let partial = if resume && self.incoming[idx].path.exists( ) {
+      try! ( self.incoming[idx].path.stat( ) ).size
+    } else {
+      0
+    };
+
+    // ask to resume, and wait for the matching ACCEPT before connecting
+    if partial > 0 && partial < self.incoming[idx].size {
+      let inc = &mut self.incoming[idx];
+      inc.position = partial;
+      let mut extra = vec![ partial.to_string( ) ];
+      match inc.req.token( ) {
+        Some ( t ) if inc.req.port == 0 => extra.push( t.to_string( ) ),
+        _                               => (),
+      }
+      return Ok( Some( dcc::DccRequest::new( "RESUME", inc.req.argument.as_slice( ), "",
+        inc.req.port, extra ) ) );
+    }
+
+    let inc = self.incoming.remove( idx );
+    self.start_receive( inc )
+  }
+
+  /// `reject` forgets a file that was offered to us
+  ///
+  /// # Arguments
+  ///
+  /// * `id` - id of the offer to reject
+  pub fn reject( &mut self, id : TTRANSFER ) {
+    self.incoming.retain( |i| i.id != id );
+  }
+
+  /// `handle_send` handles a DCC SEND request, which is either a new offer
+  /// or the answer to a passive offer of ours
+  fn handle_send( &mut self, nick : &str, req : &dcc::DccRequest ) -> Option < dcc::DccRequest > {
+    // the peer is answering one of our passive offers
+    if req.port != 0 {
+      let mut outgoing = self.outgoing.lock( ).unwrap( );
+      match find_outgoing( outgoing.as_slice( ), nick, req ) {
+        Some ( idx ) if outgoing[idx].req.port == 0 => {
+          let out = outgoing.remove( idx );
+          let addr = match req.socket( ) {
+            Some ( a ) => a,
+            None       => {
+              send_event( &self.events, TransferEvent::Failed( out.id,
+                String::from_str( "peer sent a bad address" ) ) );
+              return None;
+            },
+          };
+          let size = out.size;
+          let ( events, timeout ) = ( self.events.clone( ), self.timeout );
+          thread::Thread::spawn( move || {
+            let start = *out.position.lock( ).unwrap( );
+            let result = dial( addr, timeout ).and_then( |tcp| {
+              run_send( tcp, &out.path, start, size, timeout, out.id, &events )
+            } );
+            report( out.id, result, out.path.clone( ), &events );
+          } );
+          return None;
+        },
+        _                                           => (),
+      }
+    }
+
+    // anything else is a new offer
+    let name = match sanitize_filename( req.argument.as_slice( ) ) {
+      Some ( n ) => n,
+      None       => {
+        irc_warn! ( "dcc send: ignoring offer of '{}' from {}", req.argument, nick );
+        return None;
+      },
+    };
+    // without a size we can't tell a finished transfer from a dropped one
+    let size = match offered_size( req ) {
+      Some ( s ) => s,
+      None       => {
+        irc_warn! ( "dcc send: ignoring offer of '{}' from {} with no size", req.argument, nick );
+        return None;
+      },
+    };
+    if size > self.max_size {
+      irc_warn! ( "dcc send: ignoring {} byte offer from {}", size, nick );
+      return None;
+    }
+    let id = self.gen_id( );
+    self.incoming.push( Incoming {
+      id        : id,
+      nick      : nick.to_string( ),
+      req       : req.clone( ),
+      path      : self.dir.join( name.as_slice( ) ),
+      size      : size,
+      position  : 0,
+    } );
+    send_event( &self.events, TransferEvent::Offered( id, nick.to_string( ), name, size ) );
+    None
+  }
+
+  /// `handle_resume` handles a peer asking to resume one of our offers
+  fn handle_resume( &mut self, nick : &str, req : &dcc::DccRequest ) -> Option < dcc::DccRequest > {
+    let outgoing = self.outgoing.lock( ).unwrap( );
+    let idx = match find_outgoing( outgoing.as_slice( ), nick, req ) {
+      Some ( i ) => i,
+      None       => return None,
+    };
+    let out = &outgoing[idx];
+    let position = match req.extra.get( 0 ).and_then( |p| p.parse::< u64 >( ).ok( ) ) {
+      Some ( p ) if p <= out.size => p,
+      _                          => return None,
+    };
+    *out.position.lock( ).unwrap( ) = position;
+    let mut extra = vec![ position.to_string( ) ];
+    match req.token( ) {
+      Some ( t ) => extra.push( t.to_string( ) ),
+      None       => (),
+    }
+    Some( dcc::DccRequest::new( "ACCEPT", req.argument.as_slice( ), "", req.port, extra ) )
+  }
+
+  /// `handle_accept` handles a peer agreeing to resume one of their offers
+  fn handle_accept( &mut self, nick : &str, req : &dcc::DccRequest ) -> Option < dcc::DccRequest > {
+    let idx = match self.incoming.iter( ).position( |i| {
+      i.nick == nick && i.position > 0 && same_offer( &i.req, req )
+    } ) {
+      Some ( i ) => i,
+      None       => return None,
+    };
+    let inc = self.incoming.remove( idx );
+    let id = inc.id;
+    match self.start_receive( inc ) {
+      Ok ( reply ) => reply,
+      Err ( e )    => {
+        send_event( &self.events, TransferEvent::Failed( id, e.desc.to_string( ) ) );
+        None
+      },
+    }
+  }
+
+  /// `start_receive` starts receiving an accepted offer
+  fn start_receive( &mut self, mut inc : Incoming ) -> io::IoResult < Option < dcc::DccRequest > > {
+    // a new transfer never overwrites an existing file
+    if inc.position == 0 {
+      inc.path = unique_path( &inc.path );
+    }
+    let size = inc.size;
+    let ( events, timeout, max ) = ( self.events.clone( ), self.timeout, self.max_size );
+
+    // passive offers need us to listen and tell the peer where
+    if inc.req.is_passive( ) {
+      let listener = try! ( dcc::DccListener::bind( self.bind ) );
+      let mut extra = vec![ size.to_string( ) ];
+      match inc.req.token( ) {
+        Some ( t ) => extra.push( t.to_string( ) ),
+        None       => (),
+      }
+      let reply = dcc::DccRequest::new( "SEND", inc.req.argument.as_slice( ),
+        dcc::encode_address( self.public ).as_slice( ), listener.local.port, extra );
+      thread::Thread::spawn( move || {
+        let result = listener.accept_stream( Some( timeout ) ).and_then( |tcp| {
+          run_receive( tcp, &inc.path, inc.position, size, max, timeout, inc.id, &events )
+        } );
+        report( inc.id, result, inc.path.clone( ), &events );
+      } );
+      return Ok( Some( reply ) );
+    }
+
+    let addr = match inc.req.socket( ) {
+      Some ( a ) => a,
+      None       => return Err( invalid( "offer has a bad address" ) ),
+    };
+    thread::Thread::spawn( move || {
+      let result = dial( addr, timeout ).and_then( |tcp| {
+        run_receive( tcp, &inc.path, inc.position, size, max, timeout, inc.id, &events )
+      } );
+      report( inc.id, result, inc.path.clone( ), &events );
+    } );
+    Ok( None )
+  }
+
+  /// `gen_id` gets the next transfer id
+  fn gen_id( &mut self ) -> TTRANSFER {
+    self.next_id += 1;
+    self.next_id
+  }
+}
+
+/// `send_file` sends a file over a DCC connection
+///
+/// # Arguments
+///
+/// * `tcp` - the connection to the receiver
+/// * `path` - file to send
+/// * `start` - position to start sending from
+/// * `timeout` - milliseconds each write, and the wait for the final
+/// acknowledgement, may take, or None to wait as long as it takes
+/// * `progress` - called with the number of bytes sent so far
+///
+/// # Returns
+///
+/// The total number of bytes the receiver acknowledged
+///
+/// # Notes
+///
+/// * The receiver acknowledges with the low 32 bits of the number of bytes it
+/// has received. Some clients never acknowledge, so a close after the whole
+/// file was written also counts as success.
+/// * Acknowledgements are read on their own thread while the file is
+/// written, so a receiver blocked sending them can't stall the transfer.
+pub fn send_file < F > (
+  tcp : &mut io::TcpStream,
+  path : &Path,
+  start : u64,
+  timeout : Option < dcc::TTIMEOUT >,
+  progress : &mut F
+) -> io::IoResult < u64 > where F : FnMut( u64 ) {
+  let mut file = try! ( io::File::open( path ) );
+  let size = try! ( file.stat( ) ).size;
+  try! ( file.seek( start as i64, io::SeekStyle::SeekSet ) );
+
+  let writing = Arc::new( AtomicBool::new( true ) );
+  let acks = read_acks( tcp.clone( ), timeout, writing.clone( ) );
+  let written = write_chunks( tcp, &mut file, start, size, timeout, progress );
+  writing.store( false, Ordering::SeqCst );
+
+  // wait for the final acknowledgement
+  let result = written.and_then( |sent| {
+    let want = ( size & 0xffffffff ) as u32;
+    for ack in acks.iter( ) {
+      match ack {
+        Ok ( a ) if a == want => return Ok( size ),
+        Ok ( _ )              => (),
+        Err ( e )             => match e.kind {
+          io::IoErrorKind::EndOfFile => return Ok( sent ),
+          _                          => return Err( e ),
+        },
+      }
+    }
+    Ok( sent )
+  } );
+
+  // ends the ack reader if it's still waiting
+  let _ = tcp.close_read( );
+  result
+}
+
+/// `receive_file` receives a file over a DCC connection
+///
+/// # Arguments
+///
+/// * `tcp` - the connection to the sender
+/// * `path` - file to write to
+/// * `start` - position to resume from, 0 for a new file
+/// * `size` - size of the file as offered
+/// * `max` - largest number of bytes to accept
+/// * `timeout` - milliseconds each read or write may take, or None to wait as
+/// long as it takes
+/// * `progress` - called with the number of bytes received so far
+///
+/// # Returns
+///
+/// The number of bytes in the file once the transfer is complete, or an
+/// error if `start` is 0 and the file already exists
+///
+/// # Notes
+///
+/// * Nothing past `size` is written. A sender that goes past it fails the
+/// transfer.
+pub fn receive_file < F > (
+  tcp : &mut io::TcpStream,
+  path : &Path,
+  start : u64,
+  size : u64,
+  max : u64,
+  timeout : Option < dcc::TTIMEOUT >,
+  progress : &mut F
+) -> io::IoResult < u64 > where F : FnMut( u64 ) {
+  if size > max {
+    return Err( invalid( "transfer is larger than the size limit" ) );
+  }
+  let mut file = if start > 0 {
+    try! ( io::File::open_mode( path, io::FileMode::Append, io::FileAccess::Write ) )
+  } else if path.exists( ) {
+    return Err( invalid( "file already exists" ) );
+  } else {
+    try! ( io::File::create( path ) )
+  };
+
+  let mut buf = [0u8; DCC_CHUNK_SIZE];
+  let mut received = start;
+  while received < size {
+    // timeouts are deadlines, so they're set again before every read
+    tcp.set_read_timeout( timeout );
+    let n = match tcp.read( &mut buf ) {
+      Ok ( n )  => n,
+      Err ( e ) => match e.kind {
+        io::IoErrorKind::EndOfFile => break,
+        _                          => return Err( e ),
+      },
+    };
+    let keep = cmp::min( n as u64, size - received ) as usize;
+    try! ( file.write_all( buf.slice_to( keep ) ) );
+    received += keep as u64;
+    if keep < n {
+      try! ( file.flush( ) );
+      return Err( invalid( "sender sent more than the offered size" ) );
+    }
+    tcp.set_write_timeout( timeout );
+    try! ( tcp.write_be_u32( ( received & 0xffffffff ) as u32 ) );
+    (*progress)( received );
+  }
+  try! ( file.flush( ) );
+
+  if received < size {
+    return Err( io::IoError {
+      kind    : io::IoErrorKind::EndOfFile,
+      desc    : "sender closed the transfer early",
+      detail  : Some( format! ( "got {} of {} bytes", received, size ) ),
+    } );
+  }
+  Ok( received )
+}
+
+/// `sanitize_filename` makes an offered filename safe to write
+///
+/// # Arguments
+///
+/// * `name` - filename as offered by the peer
+///
+/// # Returns
+///
+/// The last path component with control characters removed and leading dots
+/// stripped, or None if nothing usable is left
+pub fn sanitize_filename( name : &str ) -> Option < String > {
+  let base = match name.split( |c : char| c == '/' || c == '\\' ).last( ) {
+    Some ( b ) => b,
+    None       => return None,
+  };
+  let clean : String = base.chars( )
+    .filter( |c| !c.is_control( ) && *c != ':' )
+    .collect( );
+  let clean = clean.as_slice( ).trim( ).trim_left_matches( '.' ).to_string( );
+  if clean.is_empty( ) {
+    None
+  } else {
+    Some( clean )
+  }
+}
+
+/// `unique_path` finds a name for a received file that isn't taken yet
+///
+/// # Arguments
+///
+/// * `path` - the name the file was offered as
+///
+/// # Returns
+///
+/// `path` if it doesn't exist, otherwise the first free name of the form
+/// "name (1).ext"
+pub fn unique_path( path : &Path ) -> Path {
+  if !path.exists( ) {
+    return path.clone( );
+  }
+  let stem = path.filestem_str( ).unwrap_or( "" ).to_string( );
+  let ext = match path.extension_str( ) {
+    Some ( e ) => format! ( ".{}", e ),
+    None       => String::new( ),
+  };
+  let mut n = 1u32;
+  loop {
+    let next = path.with_filename( format! ( "{} ({}){}", stem, n, ext ) );
+    if !next.exists( ) {
+      return next;
+    }
+    n += 1;
+  }
+}
+
+/// `run_send` sends a file and reports its progress as events
+fn run_send(
+  mut tcp : io::TcpStream,
+  path : &Path,
+  start : u64,
+  size : u64,
+  timeout : dcc::TTIMEOUT,
+  id : TTRANSFER,
+  events : &mpsc::Sender < TransferEvent >
+) -> io::IoResult < u64 > {
+  send_file( &mut tcp, path, start, Some( timeout ), &mut |done| {
+    send_event( events, TransferEvent::Progress( id, done, size ) );
+  } )
+}
+
+/// `run_receive` receives a file and reports its progress as events
+fn run_receive(
+  mut tcp : io::TcpStream,
+  path : &Path,
+  start : u64,
+  size : u64,
+  max : u64,
+  timeout : dcc::TTIMEOUT,
+  id : TTRANSFER,
+  events : &mpsc::Sender < TransferEvent >
+) -> io::IoResult < u64 > {
+  receive_file( &mut tcp, path, start, size, max, Some( timeout ), &mut |done| {
+    send_event( events, TransferEvent::Progress( id, done, size ) );
+  } )
+}
+
+/// `write_chunks` writes a file to the receiver from its current position
+///
+/// # Returns
+///
+/// The position the file was written up to
+fn write_chunks < F > (
+  tcp : &mut io::TcpStream,
+  file : &mut io::File,
+  start : u64,
+  size : u64,
+  timeout : Option < dcc::TTIMEOUT >,
+  progress : &mut F
+) -> io::IoResult < u64 > where F : FnMut( u64 ) {
+  let mut buf = [0u8; DCC_CHUNK_SIZE];
+  let mut sent = start;
+  while sent < size {
+    let n = match file.read( &mut buf ) {
+      Ok ( n )  => n,
+      Err ( e ) => match e.kind {
+        io::IoErrorKind::EndOfFile => break,
+        _                          => return Err( e ),
+      },
+    };
+    tcp.set_write_timeout( timeout );
+    try! ( tcp.write_all( buf.slice_to( n ) ) );
+    sent += n as u64;
+    (*progress)( sent );
+  }
+  Ok( sent )
+}
+
+/// `read_acks` reads the receiver's acknowledgements on a thread of their own
+///
+/// # Arguments
+///
+/// * `tcp` - a clone of the connection to the receiver
+/// * `timeout` - milliseconds to wait for an acknowledgement once the file is
+/// written, or None to wait as long as it takes
+/// * `writing` - true while the file is still being written
+///
+/// # Returns
+///
+/// Receiver for each acknowledgement, ending with the error that stopped
+/// the reads
+fn read_acks( mut tcp : io::TcpStream, timeout : Option < dcc::TTIMEOUT >, writing : Arc < AtomicBool > )
+  -> mpsc::Receiver < io::IoResult < u32 > > {
+  let ( tx, rx ) = mpsc::channel( );
+  thread::Thread::spawn( move || {
+    let mut buf = [0u8; 4];
+    let mut have = 0;
+    loop {
+      // a receiver that doesn't acknowledge is fine until the file is written
+      tcp.set_read_timeout( timeout );
+      let ack = match tcp.read( buf.slice_from_mut( have ) ) {
+        Ok ( n )                                                                    => {
+          have += n;
+          if have < 4 {
+            continue;
+          }
+          have = 0;
+          Ok( ( buf[0] as u32 ) << 24 | ( buf[1] as u32 ) << 16 | ( buf[2] as u32 ) << 8 | buf[3] as u32 )
+        },
+        Err ( ref e ) if e.kind == io::TimedOut && writing.load( Ordering::SeqCst ) => continue,
+        Err ( e )                                                                   => Err( e ),
+      };
+      let done = ack.is_err( );
+      if tx.send( ack ).is_err( ) || done {
+        break;
+      }
+    }
+  } );
+  rx
+}
+
+/// `report` reports the end of a transfer as an event
+fn report(
+  id : TTRANSFER,
+  result : io::IoResult < u64 >,
+  path : Path,
+  events : &mpsc::Sender < TransferEvent >
+) {
+  match result {
+    Ok ( _ )  => send_event( events, TransferEvent::Finished( id, path ) ),
+    Err ( e ) => {
+      irc_error! ( "dcc transfer: {}", e.desc );
+      send_event( events, TransferEvent::Failed( id, e.desc.to_string( ) ) );
+    },
+  }
+}
+
+/// `send_event` sends an event, ignoring a hung up receiver
+fn send_event( events : &mpsc::Sender < TransferEvent >, ev : TransferEvent ) {
+  match events.send( ev ) {
+    Ok ( _ )  => (),
+    Err ( _ ) => irc_warn! ( "dcc transfer: event receiver hung up" ),
+  }
+}
+
+/// `dial` connects to a peer with a timeout
+fn dial( addr : SocketAddr, timeout : dcc::TTIMEOUT ) -> io::IoResult < io::TcpStream > {
+  io::TcpStream::connect_timeout( addr, Duration::milliseconds( timeout as i64 ) )
+}
+
+/// `find_outgoing` finds the offer of ours a request from a nick refers to
+fn find_outgoing( outgoing : &[Outgoing], nick : &str, req : &dcc::DccRequest ) -> Option < usize > {
+  outgoing.iter( ).position( |o| o.nick == nick && same_offer( &o.req, req ) )
+}
+
+/// `offered_size` gets the file size of a DCC SEND request, or None if it's
+/// missing or unparsable
+fn offered_size( req : &dcc::DccRequest ) -> Option < u64 > {
+  req.extra.get( 0 ).and_then( |s| s.parse::< u64 >( ).ok( ) )
+}
+
+/// `same_offer` checks whether a request refers to an offer, by token for
+/// passive offers and by port otherwise
+fn same_offer( offer : &dcc::DccRequest, req : &dcc::DccRequest ) -> bool {
+  if offer.port == 0 || req.port == 0 {
+    offer.token( ).is_some( ) && offer.token( ) == req.token( )
+  } else {
+    offer.port == req.port
+  }
+}
+
+/// `invalid` creates an IoError for a bad transfer argument
+fn invalid( desc : &'static str ) -> io::IoError {
+  io::IoError {
+    kind    : io::IoErrorKind::InvalidInput,
+    desc    : desc,
+    detail  : None,
+  }
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[allow(unused_imports)]
+  use std::old_io as io;
+  #[allow(unused_imports)]
+  use std::old_io::net::ip::IpAddr;
+  #[allow(unused_imports)]
+  use std::old_io::{Acceptor, Listener};
+
+  #[test]
+  fn test_sanitize () {
+    assert! ( super::sanitize_filename( "log.txt" ) == Some( "log.txt".to_string( ) ) );
+    assert! ( super::sanitize_filename( "../../etc/passwd" ) == Some( "passwd".to_string( ) ) );
+    assert! ( super::sanitize_filename( "C:\\windows\\evil.exe" ) == Some( "evil.exe".to_string( ) ) );
+    assert! ( super::sanitize_filename( ".bashrc" ) == Some( "bashrc".to_string( ) ) );
+    assert! ( super::sanitize_filename( "bad\x07name" ) == Some( "badname".to_string( ) ) );
+    assert! ( super::sanitize_filename( ".." ).is_none( ) );
+    assert! ( super::sanitize_filename( "dir/" ).is_none( ) );
+  }
+
+  #[test]
+  fn test_send_receive_loopback () {
+    let local = IpAddr::Ipv4Addr( 127, 0, 0, 1 );
+    let senddir = io::TempDir::new( "rustirc-send" ).unwrap( );
+    let recvdir = io::TempDir::new( "rustirc-recv" ).unwrap( );
+    let source = senddir.path( ).join( "log.txt" );
+    let data : Vec < u8 > = range( 0, 10000 ).map( |i| ( i % 251 ) as u8 ).collect( );
+    io::File::create( &source ).write_all( data.as_slice( ) ).unwrap( );
+
+    let ( sevents, mut sender ) = super::TransferManager::new( senddir.path( ).clone( ), local, local );
+    let ( revents, mut receiver ) = super::TransferManager::new( recvdir.path( ).clone( ), local, local );
+    let ( _, offer ) = sender.offer( "Detective", &source, false ).unwrap( );
+    assert! ( receiver.handle( "Lancey", &offer ).is_none( ) );
+    let id = match revents.recv( ).unwrap( ) {
+      super::TransferEvent::Offered ( id, _, name, size ) => {
+        assert! ( name.as_slice( ) == "log.txt" );
+        assert! ( size == 10000 );
+        id
+      },
+      _                                                   => panic! ( "expected an offer" ),
+    };
+    assert! ( receiver.accept( id, false ).unwrap( ).is_none( ) );
+    let mut finished = false;
+    for ev in revents.iter( ) {
+      match ev {
+        super::TransferEvent::Finished ( _, path ) => {
+          assert! ( io::File::open( &path ).read_to_end( ).unwrap( ) == data );
+          finished = true;
+          break;
+        },
+        super::TransferEvent::Failed ( _, e )      => panic! ( "transfer failed: {}", e ),
+        _                                          => (),
+      }
+    }
+    assert! ( finished );
+    for ev in sevents.iter( ) {
+      match ev {
+        super::TransferEvent::Finished ( .. ) => break,
+        super::TransferEvent::Failed ( _, e ) => panic! ( "send failed: {}", e ),
+        _                                     => (),
+      }
+    }
+    assert! ( sender.outgoing.lock( ).unwrap( ).is_empty( ) );
+  }
+
+  #[test]
+  fn test_extra_data () {
+    let dir = io::TempDir::new( "rustirc-recv" ).unwrap( );
+    let path = dir.path( ).join( "log.txt" );
+    let mut acceptor = io::TcpListener::bind( "127.0.0.1:0" ).unwrap( ).listen( ).unwrap( );
+    let addr = acceptor.socket_name( ).unwrap( );
+    let _sender = ::std::thread::Thread::scoped( move || {
+      let mut tcp = io::TcpStream::connect( addr ).unwrap( );
+      tcp.write_str( "ten bytes!and more" ).unwrap( );
+    } );
+    let mut tcp = acceptor.accept( ).unwrap( );
+    assert! ( super::receive_file( &mut tcp, &path, 0, 10, 100, Some( 5000 ), &mut |_| () ).is_err( ) );
+    assert! ( io::File::open( &path ).read_to_string( ).unwrap( ) == "ten bytes!" );
+  }
+
+  #[test]
+  fn test_resume_loopback () {
+    let local = IpAddr::Ipv4Addr( 127, 0, 0, 1 );
+    let senddir = io::TempDir::new( "rustirc-send" ).unwrap( );
+    let recvdir = io::TempDir::new( "rustirc-recv" ).unwrap( );
+    let source = senddir.path( ).join( "log.txt" );
+    let data : Vec < u8 > = range( 0, 10000 ).map( |i| ( i % 251 ) as u8 ).collect( );
+    io::File::create( &source ).write_all( data.as_slice( ) ).unwrap( );
+    io::File::create( &recvdir.path( ).join( "log.txt" ) ).write_all( data.slice_to( 4000 ) ).unwrap( );
+
+    let ( _, mut sender ) = super::TransferManager::new( senddir.path( ).clone( ), local, local );
+    let ( revents, mut receiver ) = super::TransferManager::new( recvdir.path( ).clone( ), local, local );
+    let ( _, offer ) = sender.offer( "Detective", &source, false ).unwrap( );
+    receiver.handle( "Lancey", &offer );
+    let id = match revents.recv( ).unwrap( ) {
+      super::TransferEvent::Offered ( id, _, _, _ ) => id,
+      _                                             => panic! ( "expected an offer" ),
+    };
+    let resume = receiver.accept( id, true ).unwrap( ).unwrap( );
+    assert! ( resume.kind.as_slice( ) == "RESUME" );
+    assert! ( resume.extra[0].as_slice( ) == "4000" );
+    let accept = sender.handle( "Detective", &resume ).unwrap( );
+    assert! ( accept.kind.as_slice( ) == "ACCEPT" );
+    assert! ( receiver.handle( "Lancey", &accept ).is_none( ) );
+    for ev in revents.iter( ) {
+      match ev {
+        super::TransferEvent::Progress ( _, done, _ ) => assert! ( done > 4000 ),
+        super::TransferEvent::Finished ( _, path )    => {
+          assert! ( io::File::open( &path ).read_to_end( ).unwrap( ) == data );
+          break;
+        },
+        super::TransferEvent::Failed ( _, e )         => panic! ( "transfer failed: {}", e ),
+        _                                             => (),
+      }
+    }
+  }
+
+  #[test]
+  fn test_passive_loopback () {
+    let local = IpAddr::Ipv4Addr( 127, 0, 0, 1 );
+    let senddir = io::TempDir::new( "rustirc-send" ).unwrap( );
+    let recvdir = io::TempDir::new( "rustirc-recv" ).unwrap( );
+    let source = senddir.path( ).join( "log.txt" );
+    io::File::create( &source ).write_str( "passive transfer" ).unwrap( );
+
+    let ( _, mut sender ) = super::TransferManager::new( senddir.path( ).clone( ), local, local );
+    let ( revents, mut receiver ) = super::TransferManager::new( recvdir.path( ).clone( ), local, local );
+    let ( _, offer ) = sender.offer( "Detective", &source, true ).unwrap( );
+    assert! ( offer.is_passive( ) );
+    receiver.handle( "Lancey", &offer );
+    let id = match revents.recv( ).unwrap( ) {
+      super::TransferEvent::Offered ( id, _, _, _ ) => id,
+      _                                             => panic! ( "expected an offer" ),
+    };
+    let answer = receiver.accept( id, false ).unwrap( ).unwrap( );
+    assert! ( answer.port != 0 );
+    assert! ( sender.handle( "Detective", &answer ).is_none( ) );
+    for ev in revents.iter( ) {
+      match ev {
+        super::TransferEvent::Finished ( _, path ) => {
+          assert! ( io::File::open( &path ).read_to_string( ).unwrap( ) == "passive transfer" );
+          break;
+        },
+        super::TransferEvent::Failed ( _, e )      => panic! ( "transfer failed: {}", e ),
+        _                                          => (),
+      }
+    }
+  }
+
+  #[test]
+  fn test_size_limit () {
+    let local = IpAddr::Ipv4Addr( 127, 0, 0, 1 );
+    let recvdir = io::TempDir::new( "rustirc-recv" ).unwrap( );
+    let ( revents, mut receiver ) = super::TransferManager::new( recvdir.path( ).clone( ), local, local );
+    receiver.max_size = 100;
+    let offer = ::dcc::DccRequest::parse( "SEND big.iso 2130706433 5000 1000000" ).unwrap( );
+    assert! ( receiver.handle( "Lancey", &offer ).is_none( ) );
+    assert! ( revents.try_recv( ).is_err( ) );
+  }
+
+  #[test]
+  fn test_missing_size () {
+    let local = IpAddr::Ipv4Addr( 127, 0, 0, 1 );
+    let recvdir = io::TempDir::new( "rustirc-recv" ).unwrap( );
+    let ( revents, mut receiver ) = super::TransferManager::new( recvdir.path( ).clone( ), local, local );
+    let nosize = ::dcc::DccRequest::parse( "SEND log.txt 2130706433 5000" ).unwrap( );
+    let badsize = ::dcc::DccRequest::parse( "SEND log.txt 2130706433 5000 lots" ).unwrap( );
+    assert! ( receiver.handle( "Lancey", &nosize ).is_none( ) );
+    assert! ( receiver.handle( "Lancey", &badsize ).is_none( ) );
+    assert! ( revents.try_recv( ).is_err( ) );
+  }
+
+  #[test]
+  fn test_unique_path () {
+    let dir = io::TempDir::new( "rustirc-recv" ).unwrap( );
+    let path = dir.path( ).join( "log.txt" );
+    assert! ( super::unique_path( &path ) == path );
+    io::File::create( &path ).write_str( "first" ).unwrap( );
+    io::File::create( &dir.path( ).join( "log (1).txt" ) ).write_str( "second" ).unwrap( );
+    assert! ( super::unique_path( &path ) == dir.path( ).join( "log (2).txt" ) );
+    let bare = dir.path( ).join( "README" );
+    io::File::create( &bare ).write_str( "third" ).unwrap( );
+    assert! ( super::unique_path( &bare ) == dir.path( ).join( "README (1)" ) );
+  }
+}
@@ -1,51 +1,218 @@
-use std::fmt;
+use std::ascii::AsciiExt;
+use std::cell::RefCell;
+use std::old_io as io;
+use log;
 
-pub enum Level {
-  Err,
-  Warn,
-  Oper,
-  Info,
-  DispIn,
-  DispOut,
+// Everything the crate reports goes through the `log` facade, so nothing is
+// printed unless the application installs a logger. The macros below log
+// under the calling module's path as the target and prefix the message with
+// the current span, e.g. "[irc.mozilla.org:6667] ".
+
+macro_rules! irc_error {
+  ( $($arg:tt)+ ) => ( error! ( "{}{}", ::utils::debug::span( ), format_args! ( $($arg)+ ) ) )
+}
+
+macro_rules! irc_warn {
+  ( $($arg:tt)+ ) => ( warn! ( "{}{}", ::utils::debug::span( ), format_args! ( $($arg)+ ) ) )
 }
 
-#[allow(dead_code)]
-pub fn err <T,S> ( s : T, e : S ) where T : fmt::Display, S : fmt::Display {
-  let data = format! ( "err in {} : {}", s, e );
-  log( Level::Err, data );
+macro_rules! irc_info {
+  ( $($arg:tt)+ ) => ( info! ( "{}{}", ::utils::debug::span( ), format_args! ( $($arg)+ ) ) )
 }
 
-#[allow(dead_code)]
-pub fn warn <T,S> ( s : T, w : S ) where T : fmt::Display, S : fmt::Display {
-  let data = format! ( "warning in {} : {}", s, w );
-  log( Level::Warn, data );
+macro_rules! irc_debug {
+  ( $($arg:tt)+ ) => ( debug! ( "{}{}", ::utils::debug::span( ), format_args! ( $($arg)+ ) ) )
 }
 
-#[allow(dead_code)]
-pub fn oper <T> ( s : T ) where T : fmt::Display {
-  log( Level::Oper, s );
+macro_rules! irc_trace {
+  ( $($arg:tt)+ ) => ( trace! ( "{}{}", ::utils::debug::span( ), format_args! ( $($arg)+ ) ) )
 }
 
-#[allow(dead_code)]
-pub fn info <T> ( s : T ) where T : fmt::Display {
-  log( Level::Info, s );
+thread_local! ( static SPAN : RefCell < Vec < String > > = RefCell::new( Vec::new( ) ) );
+
+/// `Span` names the connection or network the current thread is working for.
+/// Log lines written while a span is entered are prefixed with its name.
+///
+/// # Notes
+///
+/// * Spans nest. Dropping a span restores the one that was entered before it.
+pub struct Span;
+
+impl Span {
+  /// `enter` enters a new span on the current thread
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - name of the span, such as "host:port"
+  ///
+  /// # Returns
+  ///
+  /// A guard that leaves the span when dropped
+  pub fn enter( name : &str ) -> Span {
+    SPAN.with( |s| s.borrow_mut( ).push( name.to_string( ) ) );
+    Span
+  }
 }
 
-#[allow(dead_code)]
-pub fn disp <T> ( s : T, inc : bool ) where T : fmt::Display {
-  match inc {
-    true  => log( Level::DispIn, s ),
-    false => log( Level::DispOut, s ),
+impl Drop for Span {
+  fn drop ( &mut self ) {
+    SPAN.with( |s| { s.borrow_mut( ).pop( ); } );
   }
 }
 
-fn log <T> ( l : Level, s : T ) where T : fmt::Display {
-  match l {
-    Level::Err     => println!( "!! {}", s ),
-    Level::Warn    => println!( "** {}", s ),
-    Level::Oper    => println!( "$$ {}", s ),
-    Level::Info    => println!( " ~ {}", s ),
-    Level::DispIn  => println!( " > {}", s ),
-    Level::DispOut => println!( " < {}", s ),
+/// `span` formats the current span as a log line prefix
+///
+/// # Returns
+///
+/// "[name] " for the innermost span, or an empty String outside of any span
+pub fn span( ) -> String {
+  SPAN.with( |s| match s.borrow( ).last( ) {
+    Some ( name ) => format! ( "[{}] ", name ),
+    None          => String::new( ),
+  } )
+}
+
+/// `StderrLogger` writes log lines to stderr
+///
+/// # Members
+///
+/// * `level` - the most detailed level written
+struct StderrLogger {
+  level : log::LogLevelFilter,
+}
+
+impl log::Log for StderrLogger {
+  fn enabled( &self, level : log::LogLevel, _module : &str ) -> bool {
+    level <= self.level
+  }
+
+  fn log( &self, record : &log::LogRecord ) {
+    if self.enabled( record.level( ), record.location( ).module_path ) {
+      let _ = writeln! ( &mut io::stderr( ), "{} {}: {}", record.level( ), record.location( ).module_path, record.args( ) );
+    }
   }
-}
\ No newline at end of file
+}
+
+/// `start_logger` installs a logger that writes to stderr
+///
+/// # Arguments
+///
+/// * `level` - the most detailed level to write
+///
+/// # Returns
+///
+/// false if a logger was already installed, which is then left alone
+pub fn start_logger( level : log::LogLevelFilter ) -> bool {
+  log::set_logger( |max| {
+    max.set( level );
+    Box::new( StderrLogger { level : level } ) as Box < log::Log >
+  } ).is_ok( )
+}
+
+/// `redact` hides secrets in an outgoing IRC line before it is logged
+///
+/// # Arguments
+///
+/// * `line` - the raw line being sent
+///
+/// # Returns
+///
+/// The line with the arguments of PASS, OPER and AUTHENTICATE, the keys of a
+/// JOIN, and NickServ IDENTIFY replaced by asterisks
+///
+/// # Notes
+///
+/// * Tags and a prefix before the command are skipped, so they can't hide
+/// the command from the rules.
+pub fn redact( line : &str ) -> String {
+  let words : Vec < &str > = line.split( ' ' ).collect( );
+  let mut at = 0;
+  if words[at].starts_with( "@" ) && words.len( ) > at + 1 {
+    at += 1;
+  }
+  if words[at].starts_with( ":" ) && words.len( ) > at + 1 {
+    at += 1;
+  }
+  let code = words[at].to_ascii_uppercase( );
+  match code.as_slice( ) {
+    "PASS" | "OPER" | "AUTHENTICATE" if words.len( ) > at + 1 => hide_after( &words, at + 1 ),
+    // the channels are fine, the keys after them aren't
+    "JOIN" if words.len( ) > at + 2 => hide_after( &words, at + 2 ),
+    "NS" | "NICKSERV" => redact_identify( line, at + 1 ),
+    "PRIVMSG" | "NOTICE" if words.len( ) > at + 2 => {
+      let target = words[at + 1].to_ascii_uppercase( );
+      if target.as_slice( ) == "NICKSERV" || target.as_slice( ).starts_with( "NICKSERV@" ) {
+        redact_identify( line, at + 2 )
+      } else {
+        line.to_string( )
+      }
+    },
+    _ => line.to_string( ),
+  }
+}
+
+/// `hide_after` keeps the first words of a line and hides the rest
+///
+/// # Arguments
+///
+/// * `words` - the words of the line
+/// * `keep` - how many words to keep
+fn hide_after( words : &Vec < &str >, keep : usize ) -> String {
+  let mut out = words.slice_to( keep ).connect( " " );
+  out.push_str( " ****" );
+  out
+}
+
+/// `redact_identify` hides everything after an IDENTIFY command
+///
+/// # Arguments
+///
+/// * `line` - the line to redact
+/// * `at` - index of the word that should be IDENTIFY
+fn redact_identify( line : &str, at : usize ) -> String {
+  let words : Vec < &str > = line.split( ' ' ).collect( );
+  if words.len( ) <= at + 1 {
+    return line.to_string( );
+  }
+  let cmd = words[at].trim_left_matches( ':' ).to_ascii_uppercase( );
+  match cmd.as_slice( ) {
+    "IDENTIFY" | "ID" | "REGISTER" | "GHOST" | "RECOVER" => hide_after( &words, at + 1 ),
+    _ => line.to_string( ),
+  }
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[test]
+  fn test_redact () {
+    assert! ( super::redact( "PASS hunter2" ) == "PASS ****" );
+    assert! ( super::redact( "OPER admin hunter2" ) == "OPER ****" );
+    assert! ( super::redact( "AUTHENTICATE bG9sAGxvbABodW50ZXIy" ) == "AUTHENTICATE ****" );
+    assert! ( super::redact( "AUTHENTICATE AAAAAAAAAAAAAAAA" ) == "AUTHENTICATE ****" );
+    assert! ( super::redact( "JOIN #rust,#secret hunter2" ) == "JOIN #rust,#secret ****" );
+    assert! ( super::redact( "JOIN #rust" ) == "JOIN #rust" );
+    assert! ( super::redact( "@label=3 PASS hunter2" ) == "@label=3 PASS ****" );
+    assert! ( super::redact( ":me!u@h OPER admin hunter2" ) == ":me!u@h OPER ****" );
+    assert! ( super::redact( "@label=4 :me PRIVMSG NickServ :IDENTIFY hunter2" ) == "@label=4 :me PRIVMSG NickServ :IDENTIFY ****" );
+    assert! ( super::redact( "PRIVMSG NickServ :IDENTIFY hunter2" ) == "PRIVMSG NickServ :IDENTIFY ****" );
+    assert! ( super::redact( "PRIVMSG NickServ :IDENTIFY Lancey hunter2" ) == "PRIVMSG NickServ :IDENTIFY ****" );
+    assert! ( super::redact( "NICKSERV IDENTIFY hunter2" ) == "NICKSERV IDENTIFY ****" );
+    assert! ( super::redact( "PRIVMSG NickServ :INFO Lancey" ) == "PRIVMSG NickServ :INFO Lancey" );
+    assert! ( super::redact( "PRIVMSG #rust :IDENTIFY hunter2" ) == "PRIVMSG #rust :IDENTIFY hunter2" );
+  }
+
+  #[test]
+  fn test_span () {
+    assert! ( super::span( ) == "" );
+    {
+      let _outer = super::Span::enter( "irc.mozilla.org:6667" );
+      assert! ( super::span( ) == "[irc.mozilla.org:6667] " );
+      {
+        let _inner = super::Span::enter( "dcc" );
+        assert! ( super::span( ) == "[dcc] " );
+      }
+      assert! ( super::span( ) == "[irc.mozilla.org:6667] " );
+    }
+    assert! ( super::span( ) == "" );
+  }
+}
@@ -1 +1,2 @@
-pub mod debug;
\ No newline at end of file
+#[macro_use]
+pub mod debug;
@@ -0,0 +1,253 @@
+// import built in modules
+use std::cmp;
+use std::fmt;
+
+// import custom modules
+use info;
+
+pub static MAX_LINE : usize = 510; // bytes in a line, not counting the CRLF
+
+static DEFAULT_CHANTYPES  : &'static str = "#&"; // channel prefixes if ISUPPORT doesn't say
+static DEFAULT_CHANNELLEN : usize        = 50;   // channel length if ISUPPORT doesn't say
+static DEFAULT_NICKLEN    : usize        = 9;    // nick length before we know ours
+static DEFAULT_USERLEN    : usize        = 10;   // user name length before we know ours
+static HOSTLEN            : usize        = 63;   // longest host a server shows for us
+
+/// `SendError` is why a line wasn't sent
+///
+/// # Options
+///
+/// `BadText( what )` - what contains a CR, LF or NUL, which would end the line
+/// early or cut it short
+/// `TooLong( n, max )` - the line would be n bytes, more than the max bytes
+/// left once the server adds our prefix
+/// `BadNick( n )` - n isn't a nick the server would accept
+/// `BadChannel( c )` - c isn't a channel name the server would accept
+/// `BadTarget( t )` - t is neither a nick nor a channel
+/// `BadParam( p )` - p has a space or comma where one word was needed
+#[derive(PartialEq)]
+pub enum SendError {
+  BadText ( String ),
+  TooLong ( usize, usize ),
+  BadNick ( String ),
+  BadChannel ( String ),
+  BadTarget ( String ),
+  BadParam ( String ),
+}
+
+impl fmt::Display for SendError {
+  fn fmt( &self, f : &mut fmt::Formatter ) -> fmt::Result {
+    match *self {
+      SendError::BadText ( ref s )    => write! ( f, "{} contains a line break or NUL", s ),
+      SendError::TooLong ( n, max )   => write! ( f, "line is {} bytes, the limit is {}", n, max ),
+      SendError::BadNick ( ref n )    => write! ( f, "'{}' is not a valid nick", n ),
+      SendError::BadChannel ( ref c ) => write! ( f, "'{}' is not a valid channel", c ),
+      SendError::BadTarget ( ref t )  => write! ( f, "'{}' is not a nick or channel", t ),
+      SendError::BadParam ( ref p )   => write! ( f, "'{}' must be a single word", p ),
+    }
+  }
+}
+
+impl fmt::Debug for SendError {
+  fn fmt( &self, f : &mut fmt::Formatter ) -> fmt::Result {
+    fmt::Display::fmt( self, f )
+  }
+}
+
+/// `Limits` are the server's rules for names, from its ISUPPORT tokens
+///
+/// # Members
+///
+/// * `chantypes` - characters a channel name may start with
+/// * `statusmsg` - prefixes that address only the ops (or voices) of a
+/// channel, e.g. "@#rust"
+/// * `nicklen`, `channellen` - longest nick and channel name
+/// * `prefix` - bytes the server adds in front of our lines when it passes
+/// them on, e.g. ":nick!user@host "
+pub struct Limits {
+  pub chantypes  : String,
+  pub statusmsg  : String,
+  pub nicklen    : Option < usize >,
+  pub channellen : usize,
+  pub prefix     : usize,
+}
+
+impl Limits {
+  /// `new` returns the limits of a server that hasn't sent ISUPPORT
+  pub fn new( ) -> Limits {
+    Limits {
+      chantypes  : String::from_str( DEFAULT_CHANTYPES ),
+      statusmsg  : String::new( ),
+      nicklen    : None,
+      channellen : DEFAULT_CHANNELLEN,
+      prefix     : prefix_len( DEFAULT_NICKLEN, DEFAULT_USERLEN ),
+    }
+  }
+
+  /// `from_info` reads the limits from the ISUPPORT tokens in the client info
+  pub fn from_info( info : &info::IrcInfo ) -> Limits {
+    let mut limits = Limits::new( );
+    match info.get_isupport( "CHANTYPES" ) {
+      Some ( c ) => limits.chantypes = c.to_string( ),
+      None       => (),
+    }
+    match info.get_isupport( "STATUSMSG" ) {
+      Some ( s ) => limits.statusmsg = s.to_string( ),
+      None       => (),
+    }
+    limits.nicklen = info.get_isupport( "NICKLEN" ).and_then( |n| n.parse::< usize >( ).ok( ) );
+    match info.get_isupport( "CHANNELLEN" ).and_then( |n| n.parse::< usize >( ).ok( ) ) {
+      Some ( n ) => limits.channellen = n,
+      None       => (),
+    }
+    if !info.nick_name.is_empty( ) {
+      limits.prefix = prefix_len( info.nick_name.len( ), info.user_name.len( ) );
+    }
+    limits
+  }
+
+  /// `is_channel` returns whether a name starts with a channel prefix
+  pub fn is_channel( &self, name : &str ) -> bool {
+    match name.chars( ).next( ) {
+      Some ( c ) => self.chantypes.as_slice( ).contains_char( c ),
+      None       => false,
+    }
+  }
+}
+
+/// `check_text` makes sure text can't break out of its line
+///
+/// # Arguments
+///
+/// * `what` - name of the text for the error, e.g. "message"
+/// * `text` - the text to check
+pub fn check_text( what : &str, text : &str ) -> Result < ( ), SendError > {
+  if text.chars( ).any( |c| c == '\r' || c == '\n' || c == '\0' ) {
+    return Err( SendError::BadText( what.to_string( ) ) );
+  }
+  Ok( ( ) )
+}
+
+/// `check_word` makes sure a parameter is a single word that can't break out
+/// of its line
+pub fn check_word( word : &str ) -> Result < ( ), SendError > {
+  try! ( check_text( "parameter", word ) );
+  if word.is_empty( ) || word.starts_with( ":" ) || word.chars( ).any( |c| c == ' ' || c == ',' ) {
+    return Err( SendError::BadParam( word.to_string( ) ) );
+  }
+  Ok( ( ) )
+}
+
+/// `check_line` makes sure a whole line is safe to send
+///
+/// # Arguments
+///
+/// * `line` - the line, without its line ending
+/// * `bytes` - length of the line as it goes out, quoted and encoded
+/// * `limits` - the server's rules, for the room its prefix takes
+///
+/// # Notes
+///
+/// * The server relays our lines with our prefix added, and cuts whatever no
+/// longer fits, so that room is kept free.
+pub fn check_line( line : &str, bytes : usize, limits : &Limits ) -> Result < ( ), SendError > {
+  try! ( check_text( "line", line ) );
+  let max = MAX_LINE - cmp::min( limits.prefix, MAX_LINE );
+  if bytes > max {
+    return Err( SendError::TooLong( bytes, max ) );
+  }
+  Ok( ( ) )
+}
+
+/// `check_nick` makes sure a nick is one the server would accept
+pub fn check_nick( nick : &str, limits : &Limits ) -> Result < ( ), SendError > {
+  let special = |c : char| "[]\\`_^{|}".contains_char( c );
+  let first = match nick.chars( ).next( ) {
+    Some ( c ) => c,
+    None       => return Err( SendError::BadNick( nick.to_string( ) ) ),
+  };
+  let too_long = limits.nicklen.map_or( false, |n| nick.len( ) > n );
+  if !( first.is_alphabetic( ) || special( first ) ) || too_long ||
+    !nick.chars( ).all( |c| c.is_ascii( ) && ( c.is_alphanumeric( ) || special( c ) || c == '-' ) ) {
+    return Err( SendError::BadNick( nick.to_string( ) ) );
+  }
+  Ok( ( ) )
+}
+
+/// `check_channel` makes sure a channel name is one the server would accept
+pub fn check_channel( name : &str, limits : &Limits ) -> Result < ( ), SendError > {
+  if !limits.is_channel( name ) || name.len( ) < 2 || name.len( ) > limits.channellen ||
+    name.chars( ).any( |c| c == ' ' || c == ',' || c == '\x07' || c.is_control( ) ) {
+    return Err( SendError::BadChannel( name.to_string( ) ) );
+  }
+  Ok( ( ) )
+}
+
+/// `prefix_len` returns the length of the prefix ":nick!~user@host "
+fn prefix_len( nick : usize, user : usize ) -> usize {
+  nick + user + HOSTLEN + 5
+}
+
+/// `check_target` makes sure a message target is a nick or channel
+///
+/// # Notes
+///
+/// * Channels may carry a STATUSMSG prefix, e.g. "@#rust".
+pub fn check_target( target : &str, limits : &Limits ) -> Result < ( ), SendError > {
+  let chan = target.trim_left_matches( |c : char| limits.statusmsg.as_slice( ).contains_char( c ) );
+  if limits.is_channel( chan ) {
+    return check_channel( chan, limits ).map_err( |_| SendError::BadTarget( target.to_string( ) ) );
+  }
+  check_nick( target, limits ).map_err( |_| SendError::BadTarget( target.to_string( ) ) )
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[allow(unused_imports)]
+  use super::{check_channel, check_line, check_nick, check_target, check_text, check_word, Limits, SendError, MAX_LINE};
+
+  #[test]
+  fn test_text () {
+    assert! ( check_text( "message", "hello there" ).is_ok( ) );
+    assert! ( check_text( "message", "hi\r\nQUIT :pwned" ).is_err( ) );
+    assert! ( check_text( "message", "hi\nQUIT" ).is_err( ) );
+    assert! ( check_text( "message", "cut\0short" ).is_err( ) );
+    let limits = Limits::new( );
+    assert! ( check_line( "PRIVMSG #rust :hi", 17, &limits ).is_ok( ) );
+    let long = format! ( "PRIVMSG #rust :{}", ::std::iter::repeat( 'a' ).take( MAX_LINE ).collect::< String >( ) );
+    assert! ( check_line( long.as_slice( ), long.len( ), &limits ).is_err( ) );
+
+    // room is kept for the prefix, and the line is measured as it's sent
+    let max = MAX_LINE - limits.prefix;
+    assert! ( check_line( "PRIVMSG #rust :hi", max, &limits ).is_ok( ) );
+    assert! ( check_line( "PRIVMSG #rust :hi", max + 1, &limits ) == Err( SendError::TooLong( max + 1, max ) ) );
+    assert! ( check_word( "hunter2" ).is_ok( ) );
+    assert! ( check_word( "two words" ).is_err( ) );
+    assert! ( check_word( "a,b" ).is_err( ) );
+    assert! ( check_word( ":trailing" ).is_err( ) );
+  }
+
+  #[test]
+  fn test_names () {
+    let mut limits = Limits::new( );
+    assert! ( check_nick( "Lancey", &limits ).is_ok( ) );
+    assert! ( check_nick( "[away]", &limits ).is_ok( ) );
+    assert! ( check_nick( "9lives", &limits ).is_err( ) );
+    assert! ( check_nick( "two words", &limits ).is_err( ) );
+    assert! ( check_channel( "#rust", &limits ).is_ok( ) );
+    assert! ( check_channel( "&local", &limits ).is_ok( ) );
+    assert! ( check_channel( "rust", &limits ).is_err( ) );
+    assert! ( check_channel( "#a,#b", &limits ).is_err( ) );
+    assert! ( check_target( "NickServ", &limits ).is_ok( ) );
+    assert! ( check_target( "#rust", &limits ).is_ok( ) );
+    assert! ( check_target( "@#rust", &limits ).is_err( ) );
+
+    // the server's ISUPPORT tokens change the rules
+    limits.chantypes = String::from_str( "#" );
+    limits.statusmsg = String::from_str( "@+" );
+    limits.nicklen = Some( 5 );
+    assert! ( check_channel( "&local", &limits ).is_err( ) );
+    assert! ( check_target( "@#rust", &limits ).is_ok( ) );
+    assert! ( check_nick( "Lancey", &limits ).is_err( ) );
+  }
+}
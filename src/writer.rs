@@ -0,0 +1,116 @@
+// import built in modules
+use std::old_io as io;
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+pub type TTIMEOUT                 = u64;
+
+/// `LineWriter` writes lines to a peer on a thread of its own, so a slow peer
+/// never holds up the thread sending to it
+///
+/// # Members
+///
+/// * `chan` - lines waiting to be written, with their line endings
+/// * `queued` - bytes waiting to be written
+/// * `max` - most bytes that may wait at once
+///
+/// # Notes
+///
+/// * A peer that falls more than `max` bytes behind, or takes longer than the
+/// timeout over one write, is given up on. Its connection is shut down, which
+/// ends its reader too.
+/// * Dropping the LineWriter writes whatever is still queued, then shuts the
+/// writing half of the connection.
+pub struct LineWriter {
+  chan   : mpsc::Sender < String >,
+  queued : Arc < AtomicUsize >,
+  max    : usize,
+}
+
+impl LineWriter {
+  /// `start` starts writing to a connection
+  ///
+  /// # Arguments
+  ///
+  /// * `tcp` - connection to the peer
+  /// * `max` - most bytes that may wait to be written
+  /// * `timeout` - milliseconds one write may take
+  ///
+  /// # Returns
+  ///
+  /// A LineWriter queueing lines for the connection
+  pub fn start( tcp : io::TcpStream, max : usize, timeout : TTIMEOUT ) -> LineWriter {
+    let ( tx, rx ) = mpsc::channel::< String >( );
+    let queued = Arc::new( AtomicUsize::new( 0 ) );
+    let left = queued.clone( );
+    thread::Thread::spawn( move || {
+      let mut tcp = tcp;
+      for line in rx.iter( ) {
+        // timeouts are deadlines, so they're set again before every write
+        tcp.set_write_timeout( Some( timeout ) );
+        match tcp.write_str( line.as_slice( ) ) {
+          Ok ( _ )  => { left.fetch_sub( line.len( ), Ordering::SeqCst ); },
+          Err ( e ) => {
+            irc_debug! ( "giving up on a slow peer: {}", e.desc );
+            let _ = tcp.close_read( );
+            break;
+          },
+        }
+      }
+      let _ = tcp.close_write( );
+    } );
+    LineWriter {
+      chan   : tx,
+      queued : queued,
+      max    : max,
+    }
+  }
+
+  /// `send` queues a line to write
+  ///
+  /// # Arguments
+  ///
+  /// * `line` - the line, without its line ending
+  ///
+  /// # Returns
+  ///
+  /// Nothing, or why the line can't be written
+  pub fn send( &self, line : &str ) -> Result < ( ), &'static str > {
+    let out = format! ( "{}\r\n", line );
+    let len = out.len( );
+    if self.queued.fetch_add( len, Ordering::SeqCst ) + len > self.max {
+      self.queued.fetch_sub( len, Ordering::SeqCst );
+      return Err( "Send queue exceeded" );
+    }
+    match self.chan.send( out ) {
+      Ok ( _ )  => Ok( ( ) ),
+      Err ( _ ) => Err( "Write error" ),
+    }
+  }
+}
+
+// ** TEST MODULE ************************************************************
+mod test {
+  #[allow(unused_imports)]
+  use std::old_io as io;
+  #[allow(unused_imports)]
+  use std::old_io::{Acceptor, Listener};
+  #[allow(unused_imports)]
+  use super::LineWriter;
+
+  #[test]
+  fn test_line_writer () {
+    let mut acceptor = io::TcpListener::bind( "127.0.0.1:0" ).unwrap( ).listen( ).unwrap( );
+    let addr = acceptor.socket_name( ).unwrap( );
+    let near = io::TcpStream::connect( addr ).unwrap( );
+    let far = acceptor.accept( ).unwrap( );
+
+    let writer = LineWriter::start( far, 16, 5000 );
+    assert! ( writer.send( "PING :a" ).is_ok( ) );
+    assert! ( writer.send( "a line that is far too long to queue" ) == Err( "Send queue exceeded" ) );
+    drop( writer );
+    let mut reader = io::BufferedReader::new( near );
+    assert! ( reader.read_to_string( ).unwrap( ) == "PING :a\r\n" );
+  }
+}
@@ -0,0 +1,86 @@
+#![feature(slicing_syntax)]
+#![allow(unstable)]
+extern crate rustirc;
+
+use std::old_io as io;
+
+use rustirc::bouncer::{Bouncer, BouncerConfig};
+use rustirc::client::Client;
+use rustirc::info::IrcInfo;
+use rustirc::mock::{self, MockServer};
+
+/// `expect` reads lines until one contains the pattern
+fn expect( reader : &mut io::BufferedReader < io::TcpStream >, pattern : &str ) -> String {
+  loop {
+    let line = reader.read_line( ).unwrap( );
+    if line.as_slice( ).contains( pattern ) {
+      return line.as_slice( ).trim_right( ).to_string( );
+    }
+  }
+}
+
+/// `attach` connects a downstream client to the bouncer
+fn attach( bnc : &Bouncer, pass : &str ) -> ( io::BufferedReader < io::TcpStream >, io::TcpStream ) {
+  let tcp = io::TcpStream::connect( bnc.local ).unwrap( );
+  let mut writer = tcp.clone( );
+  writer.write_str( format! ( "PASS {}\r\nNICK desktop\r\nUSER desktop 0 * :Desktop\r\n", pass ).as_slice( ) ).unwrap( );
+  ( io::BufferedReader::new( tcp ), writer )
+}
+
+#[test]
+fn test_bouncer_playback () {
+  let server = MockServer::start( vec![
+    mock::send( ":mock.server NOTICE * :*** Looking up your hostname" ),
+    mock::expect( "^USER " ),
+    mock::send( ":mock.server 001 MyIrcTest :Welcome to the mock network" ),
+    mock::expect( "^JOIN #rust$" ),
+    mock::send( ":MyIrcTest!me@mock.host JOIN #rust" ),
+    mock::send( ":mock.server 332 MyIrcTest #rust :Rust talk" ),
+    mock::send( ":mock.server 353 MyIrcTest = #rust :MyIrcTest @Lancey" ),
+    mock::send( ":mock.server 366 MyIrcTest #rust :End of /NAMES list." ),
+    mock::send( ":Lancey!l@h PRIVMSG #rust :you missed this" ),
+    mock::expect( "^PRIVMSG #rust :from the desktop$" ),
+  ] ).unwrap( );
+  let info = IrcInfo::gen( "MyIrcTest", "MyIrcTest", "Testing rust-irc", vec![ "#rust" ] );
+  let preclient = Client::connect( server.host.as_slice( ), server.port, "", Box::new( info ) );
+  let ( rx, client ) = preclient.start_thread( );
+  let ( out, bnc ) = Bouncer::start( BouncerConfig::new( "127.0.0.1:0", "secret" ), rx, client ).unwrap( );
+
+  // wait for the message to be buffered before anyone attaches
+  for msg in out.iter( ) {
+    if msg.code.as_slice( ) == "PRIVMSG" {
+      break;
+    }
+  }
+
+  let ( mut reader, mut writer ) = attach( &bnc, "secret" );
+  assert! ( expect( &mut reader, " 001 " ) == ":mock.server 001 MyIrcTest :Welcome to the mock network" );
+  assert! ( expect( &mut reader, " JOIN " ) == ":MyIrcTest!me@mock.host JOIN #rust" );
+  expect( &mut reader, " 332 MyIrcTest #rust :Rust talk" );
+  expect( &mut reader, " 353 MyIrcTest = #rust :MyIrcTest @Lancey" );
+  let played = expect( &mut reader, "PRIVMSG #rust" );
+  assert! ( played.as_slice( ).starts_with( ":Lancey!l@h PRIVMSG #rust :[" ) );
+  assert! ( played.as_slice( ).ends_with( "] you missed this" ) );
+
+  writer.write_str( "PRIVMSG #rust :from the desktop\r\n" ).unwrap( );
+  server.finish( ).unwrap( );
+  bnc.stop( );
+}
+
+#[test]
+fn test_bouncer_bad_password () {
+  let server = MockServer::start( vec![
+    mock::send( ":mock.server NOTICE * :*** Looking up your hostname" ),
+    mock::expect( "^USER " ),
+  ] ).unwrap( );
+  let info = IrcInfo::gen( "MyIrcTest", "MyIrcTest", "Testing rust-irc", vec![] );
+  let preclient = Client::connect( server.host.as_slice( ), server.port, "", Box::new( info ) );
+  let ( rx, client ) = preclient.start_thread( );
+  let ( _out, bnc ) = Bouncer::start( BouncerConfig::new( "127.0.0.1:0", "secret" ), rx, client ).unwrap( );
+
+  let ( mut reader, _writer ) = attach( &bnc, "wrong" );
+  expect( &mut reader, " 464 * :Password incorrect" );
+  expect( &mut reader, "ERROR :Closing Link: Bad password" );
+  server.finish( ).unwrap( );
+  bnc.stop( );
+}
@@ -0,0 +1,655 @@
+#![feature(slicing_syntax)]
+#![allow(unstable)]
+extern crate rustirc;
+
+use std::sync::mpsc::Receiver;
+use std::thread::Thread;
+
+use rustirc::client::{Client, ClientBuilder, ClientHandle};
+use rustirc::connection::{AddrPreference, DisconnectReason};
+use rustirc::encoding::Encoding;
+use rustirc::info::{InfoChange, IrcInfo};
+use rustirc::message::Message;
+use rustirc::mock::{self, MockServer, Step};
+use rustirc::query::ListFilter;
+use rustirc::rejoin::{JoinPolicy, LeaveReason};
+
+/// `connect` connects a fresh client to a mock server and starts it
+fn connect( server : &MockServer, chans : Vec < &str > ) -> ( Receiver < Message >, Client ) {
+  let info = IrcInfo::gen( "MyIrcTest", "MyIrcTest", "Testing rust-irc", chans );
+  let preclient = Client::connect( server.host.as_slice( ), server.port, "", Box::new( info ) );
+  preclient.start_thread( )
+}
+
+/// `wait_for` reads messages until one with the given code arrives
+fn wait_for( rx : &Receiver < Message >, code : &str ) -> Message {
+  for msg in rx.iter( ) {
+    if msg.code.as_slice( ) == code {
+      return msg;
+    }
+  }
+  panic! ( "client closed before {} arrived", code );
+}
+
+/// `registration` is the script of a server registering a client
+fn registration( ) -> Vec < Step > {
+  vec![
+    mock::send( ":mock.server NOTICE * :*** Looking up your hostname" ),
+    mock::expect( "^NICK MyIrcTest$" ),
+    mock::expect( "^USER MyIrcTest \\* \\* :Testing rust-irc$" ),
+    mock::send( ":mock.server 001 MyIrcTest :Welcome to the mock network" ),
+  ]
+}
+
+#[test]
+fn test_registration () {
+  let mut script = registration( );
+  script.push( mock::expect( "^JOIN #rust$" ) );
+  script.push( mock::expect( "^JOIN #mock$" ) );
+  let server = MockServer::start( script ).unwrap( );
+  let ( rx, _client ) = connect( &server, vec![ "#rust", "#mock" ] );
+  wait_for( &rx, "001" );
+  let transcript = server.finish( ).unwrap( );
+  assert! ( transcript == vec![ "NICK MyIrcTest", "USER MyIrcTest * * :Testing rust-irc",
+    "JOIN #rust", "JOIN #mock" ] );
+}
+
+#[test]
+fn test_server_password () {
+  let server = MockServer::start( vec![
+    mock::send( ":mock.server NOTICE * :*** Looking up your hostname" ),
+    mock::expect( "^PASS hunter2$" ),
+    mock::expect( "^NICK MyIrcTest$" ),
+  ] ).unwrap( );
+  let info = IrcInfo::gen( "MyIrcTest", "MyIrcTest", "Testing rust-irc", vec![] );
+  let preclient = Client::connect( server.host.as_slice( ), server.port, "hunter2", Box::new( info ) );
+  let ( _rx, _client ) = preclient.start_thread( );
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_ping () {
+  let mut script = registration( );
+  script.push( mock::send( "PING :mock.server" ) );
+  script.push( mock::expect( "^PONG :mock.server$" ) );
+  let server = MockServer::start( script ).unwrap( );
+  let ( rx, _client ) = connect( &server, vec![] );
+  wait_for( &rx, "PING" );
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_malformed_input () {
+  let mut script = registration( );
+  script.push( mock::send( "garbage" ) );
+  script.push( mock::send( "" ) );
+  script.push( Step::SendRaw( b":mock.server\r\n".to_vec( ) ) );
+  script.push( Step::Sleep( 100 ) );
+  script.push( mock::send( "PING :still.alive" ) );
+  script.push( mock::expect( "^PONG :still.alive$" ) );
+  let server = MockServer::start( script ).unwrap( );
+  let ( rx, _client ) = connect( &server, vec![] );
+  wait_for( &rx, "PING" );
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_join_names_tracking () {
+  let mut script = registration( );
+  script.push( mock::expect( "^JOIN #rust$" ) );
+  script.push( mock::send( ":MyIrcTest!MyIrcTest@localhost JOIN #rust" ) );
+  script.push( mock::send( ":mock.server 353 MyIrcTest = #rust :MyIrcTest @Lancey Detective" ) );
+  script.push( mock::send( ":mock.server 366 MyIrcTest #rust :End of /NAMES list." ) );
+  script.push( mock::send( ":Newbie!n@host JOIN #rust" ) );
+  script.push( mock::send( ":Detective!d@host PART #rust :bye" ) );
+  script.push( mock::send( "PING :sync" ) );
+  script.push( mock::expect( "^PONG :sync$" ) );
+  let server = MockServer::start( script ).unwrap( );
+  let ( rx, client ) = connect( &server, vec![ "#rust" ] );
+
+  wait_for( &rx, "366" );
+  {
+    let info = client.get_info( );
+    assert! ( info.channels == vec![ "#rust".to_string( ) ] );
+    let names = info.get_channel_names( "#rust".to_string( ) ).unwrap( );
+    assert! ( *names == vec![ "MyIrcTest".to_string( ), "@Lancey".to_string( ), "Detective".to_string( ) ] );
+  }
+
+  wait_for( &rx, "PING" );
+  let info = client.get_info( );
+  let names = info.get_channel_names( "#rust".to_string( ) ).unwrap( );
+  assert! ( names.contains( &"Newbie".to_string( ) ) );
+  assert! ( !names.contains( &"Detective".to_string( ) ) );
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_server_disconnect () {
+  let mut script = registration( );
+  script.push( mock::send( "ERROR :Closing Link: mock.server (Goodbye)" ) );
+  script.push( Step::Disconnect );
+  let server = MockServer::start( script ).unwrap( );
+  let ( rx, _client ) = connect( &server, vec![] );
+  wait_for( &rx, "ERROR" );
+
+  // the receiver closes once the handler notices the connection is gone
+  for _ in rx.iter( ) { }
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_client_stop () {
+  let mut script = registration( );
+  script.push( mock::send( "PING :ready" ) );
+  script.push( mock::expect( "^PONG :ready$" ) );
+  script.push( mock::expect( "^QUIT" ) );
+  let server = MockServer::start( script ).unwrap( );
+  let ( rx, mut client ) = connect( &server, vec![] );
+  wait_for( &rx, "PING" );
+  client.stop( );
+  for _ in rx.iter( ) { }
+  let _ = server.finish( );
+}
+
+#[test]
+fn test_client_quit () {
+  let mut script = registration( );
+  script.push( mock::send( "PING :ready" ) );
+  script.push( mock::expect( "^PONG :ready$" ) );
+  script.push( mock::expect( "^PRIVMSG #rust :last words$" ) );
+  script.push( mock::expect( "^QUIT :gone fishing$" ) );
+  script.push( mock::send( "ERROR :Closing Link: MyIrcTest (Quit: gone fishing)" ) );
+  let server = MockServer::start( script ).unwrap( );
+  let ( rx, mut client ) = connect( &server, vec![] );
+  wait_for( &rx, "PING" );
+  client.send_str( "PRIVMSG #rust :last words" ).unwrap( );
+  let reason = client.quit( "gone fishing", 5000 );
+  assert! ( reason == DisconnectReason::Quit( "Closing Link: MyIrcTest (Quit: gone fishing)".to_string( ) ) );
+  for _ in rx.iter( ) { }
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_client_quit_timeout () {
+  let mut script = registration( );
+  script.push( mock::send( "PING :ready" ) );
+  script.push( mock::expect( "^PONG :ready$" ) );
+  script.push( mock::expect( "^QUIT$" ) );
+  script.push( Step::Sleep( 1000 ) );
+  let server = MockServer::start( script ).unwrap( );
+  let ( rx, mut client ) = connect( &server, vec![] );
+  wait_for( &rx, "PING" );
+  assert! ( client.quit( "", 100 ) == DisconnectReason::TimedOut );
+  for _ in rx.iter( ) { }
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_whois_query () {
+  let mut script = registration( );
+  script.push( mock::expect( "^WHOIS Lancey$" ) );
+  script.push( mock::send( ":mock.server 311 MyIrcTest Lancey lancey example.org * :Lancey Real" ) );
+  script.push( mock::send( ":Detective!d@host PRIVMSG #rust :unrelated" ) );
+  script.push( mock::send( ":mock.server 319 MyIrcTest Lancey :@#rust" ) );
+  script.push( mock::send( ":mock.server 318 MyIrcTest Lancey :End of /WHOIS list." ) );
+  script.push( mock::expect( "^WHOIS Ghost$" ) );
+  script.push( mock::send( ":mock.server 401 MyIrcTest Ghost :No such nick/channel" ) );
+  script.push( mock::send( ":mock.server 318 MyIrcTest Ghost :End of /WHOIS list." ) );
+  let server = MockServer::start( script ).unwrap( );
+  let ( rx, mut client ) = connect( &server, vec![] );
+  wait_for( &rx, "001" );
+
+  let whois = client.whois( "Lancey" ).wait_timeout( 5000 ).ok( ).unwrap( );
+  assert! ( whois.host == "example.org" && whois.channels == vec![ "@#rust" ] );
+  assert! ( client.whois( "Ghost" ).wait_timeout( 5000 ).is_err( ) );
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_list_stream () {
+  let mut script = registration( );
+  script.push( mock::send( ":mock.server 005 MyIrcTest ELIST=MU CHANTYPES=# :are supported by this server" ) );
+  script.push( mock::expect( "^LIST #rust\\*,>9$" ) );
+  script.push( mock::send( ":mock.server 321 MyIrcTest Channel :Users  Name" ) );
+  script.push( mock::send( ":mock.server 322 MyIrcTest #rust 12 :Rust talk" ) );
+  script.push( mock::send( ":mock.server 322 MyIrcTest #rust-gamedev 40 :Games" ) );
+  script.push( mock::send( ":mock.server 323 MyIrcTest :End of /LIST" ) );
+  script.push( mock::send( "PING :done" ) );
+  script.push( mock::expect( "^PONG :done$" ) );
+  let server = MockServer::start( script ).unwrap( );
+  let ( rx, mut client ) = connect( &server, vec![] );
+  wait_for( &rx, "005" );
+
+  let mut filter = ListFilter::new( );
+  filter.mask = Some( "#rust*".to_string( ) );
+  filter.min_users = Some( 10 );
+  let mut stream = client.list( filter );
+  let names : Vec < String > = stream.by_ref( ).map( |e| e.name ).collect( );
+  assert! ( names == vec![ "#rust", "#rust-gamedev" ] );
+  assert! ( stream.error( ).is_none( ) );
+
+  // the LIST replies never reach the receiver
+  for msg in rx.iter( ) {
+    assert! ( msg.code.as_slice( ) != "322" );
+    if msg.code.as_slice( ) == "PING" {
+      break;
+    }
+  }
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_whox_members () {
+  let mut script = registration( );
+  script.push( mock::send( ":mock.server 005 MyIrcTest WHOX :are supported by this server" ) );
+  script.push( mock::expect( "^JOIN #rust$" ) );
+  script.push( mock::send( ":MyIrcTest!MyIrcTest@localhost JOIN #rust" ) );
+  script.push( mock::expect( "^WHO #rust %tcuhnfar,1$" ) );
+  script.push( mock::send( ":mock.server 354 MyIrcTest 1 #rust lancey example.org Lancey H@ lancey_acct :Lancey Real" ) );
+  script.push( mock::send( ":mock.server 354 MyIrcTest 1 #rust det host.net Detective G 0 :Det" ) );
+  script.push( mock::send( ":mock.server 354 MyIrcTest 1 #rust bot bots.org Bot[m] H * :Bot" ) );
+  script.push( mock::send( ":mock.server 354 MyIrcTest 999 #rust other else.org Other H * :Not ours" ) );
+  script.push( mock::send( ":mock.server 315 MyIrcTest #rust :End of /WHO list." ) );
+  script.push( mock::send( "PING :sync" ) );
+  script.push( mock::expect( "^PONG :sync$" ) );
+  let server = MockServer::start( script ).unwrap( );
+  let ( rx, client ) = connect( &server, vec![ "#rust" ] );
+  wait_for( &rx, "PING" );
+
+  let info = client.get_info( );
+  let lancey = info.get_member( "lancey" ).unwrap( );
+  assert! ( lancey.hostmask( ) == "Lancey!lancey@example.org" );
+  assert! ( lancey.account == Some( "lancey_acct".to_string( ) ) && !lancey.away );
+  let det = info.get_member( "Detective" ).unwrap( );
+  assert! ( det.account.is_none( ) && det.away && det.real == "Det" );
+  assert! ( info.get_member( "Other" ).is_none( ) );
+
+  // nicks are folded with the server's casemapping, rfc1459 by default
+  assert! ( info.get_member( "BOT{M}" ).unwrap( ).nick == "Bot[m]" );
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_member_pruning () {
+  let mut script = registration( );
+  script.push( mock::expect( "^JOIN #rust$" ) );
+  script.push( mock::send( ":MyIrcTest!MyIrcTest@localhost JOIN #rust" ) );
+  script.push( mock::send( ":mock.server 353 MyIrcTest = #rust :MyIrcTest @Detective" ) );
+  script.push( mock::send( ":mock.server 366 MyIrcTest #rust :End of /NAMES list." ) );
+  script.push( mock::send( ":Lancey!lancey@example.org JOIN #rust" ) );
+  script.push( mock::send( ":Other!other@else.org JOIN #rust" ) );
+  script.push( mock::send( ":Lancey!lancey@example.org PART #rust" ) );
+  script.push( mock::send( "PING :parted" ) );
+  script.push( mock::expect( "^PONG :parted$" ) );
+  script.push( mock::send( ":MyIrcTest!MyIrcTest@localhost PART #rust" ) );
+  script.push( mock::send( "PING :left" ) );
+  script.push( mock::expect( "^PONG :left$" ) );
+  let server = MockServer::start( script ).unwrap( );
+  let ( rx, client ) = connect( &server, vec![ "#rust" ] );
+
+  // users who leave our only channel are forgotten
+  wait_for( &rx, "PING" );
+  let info = client.get_info( );
+  assert! ( info.get_member( "Lancey" ).is_none( ) );
+  assert! ( info.get_member( "Other" ).is_some( ) );
+
+  // and so is everyone once we leave it
+  wait_for( &rx, "PING" );
+  assert! ( client.get_info( ).get_member( "Other" ).is_none( ) );
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_ignore_list () {
+  let mut script = registration( );
+  script.push( mock::expect( "^AWAY :ready$" ) );
+  script.push( mock::send( ":Spammer!spam@spam.example PRIVMSG MyIrcTest :buy now" ) );
+  script.push( mock::send( ":Spammer!spam@spam.example PRIVMSG MyIrcTest :\x01VERSION\x01" ) );
+  script.push( mock::send( ":Friend!f@friend.example PRIVMSG MyIrcTest :hello" ) );
+  script.push( mock::send( "PING :sync" ) );
+  script.push( mock::expect( "^PONG :sync$" ) );
+  let server = MockServer::start( script ).unwrap( );
+  let ( rx, mut client ) = connect( &server, vec![] );
+  wait_for( &rx, "001" );
+  client.enable_ctcp( true );
+  assert! ( client.ignore( "*!*@spam.example" ).is_ok( ) );
+  assert! ( client.ignore( "$nonsense" ).is_err( ) );
+  client.send_str( "AWAY :ready" ).unwrap( );
+
+  let msg = wait_for( &rx, "PRIVMSG" );
+  assert! ( msg.nick( ) == Some( "Friend".to_string( ) ) );
+  let transcript = server.finish( ).unwrap( );
+  assert! ( !transcript.iter( ).any( |l| l.as_slice( ).starts_with( "NOTICE Spammer" ) ) );
+}
+
+#[test]
+fn test_rejoin_on_kick () {
+  let mut script = registration( );
+  script.push( mock::expect( "^JOIN #rust$" ) );
+  script.push( mock::send( ":MyIrcTest!MyIrcTest@localhost JOIN #rust" ) );
+  script.push( mock::send( ":Lancey!l@host KICK #rust MyIrcTest :out" ) );
+  script.push( mock::expect( "^JOIN #rust$" ) );
+  let server = MockServer::start( script ).unwrap( );
+  let info = IrcInfo::gen( "MyIrcTest", "MyIrcTest", "Testing rust-irc", vec![ "#rust" ] );
+  let mut preclient = Client::connect( server.host.as_slice( ), server.port, "", Box::new( info ) );
+  let mut policy = JoinPolicy::new( );
+  policy.rejoin_on_kick = true;
+  policy.kick_delay = 50;
+  preclient.set_join_policy( policy );
+  let events = preclient.channel_events( );
+  let ( _rx, _client ) = preclient.start_thread( );
+
+  let left = events.recv( ).unwrap( );
+  assert! ( left.channel == "#rust" && left.retrying );
+  match left.reason {
+    LeaveReason::Kicked ( by, reason ) => assert! ( by == "Lancey" && reason == "out" ),
+    _                                  => panic! ( "expected Kicked" ),
+  }
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_builder () {
+  let mut script = registration( );
+  script.push( mock::expect( "^JOIN #rust$" ) );
+  script.push( mock::expect( "^JOIN #secret hunter2$" ) );
+  let server = MockServer::start( script ).unwrap( );
+  let preclient = ClientBuilder::new( )
+    .server( server.host.as_slice( ) ).port( server.port ).prefer( AddrPreference::Ipv4 ).connect_timeout( 2000 )
+    .nick( "MyIrcTest" ).alt_nicks( vec![ "MyIrcTest_" ] ).realname( "Testing rust-irc" )
+    .sasl_plain( "acct", "pw" )
+    .channel( "#rust" ).channel_with_key( "#secret", "hunter2" )
+    .connect( ).unwrap( );
+  assert_eq! ( preclient.conn.addr.map( |a| a.port ), Some( server.port ) );
+  assert_eq! ( preclient.register.alt_nicks, vec![ String::from_str( "MyIrcTest_" ) ] );
+  assert! ( preclient.register.sasl == Some( ( String::from_str( "acct" ), String::from_str( "pw" ) ) ) );
+  let ( _rx, _client ) = preclient.start_thread( );
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_builder_errors () {
+  let key_of = |b : ClientBuilder| match b.build( ) {
+    Ok ( _ )  => panic! ( "expected an error" ),
+    Err ( e ) => e.key,
+  };
+  assert_eq! ( key_of( ClientBuilder::new( ).nick( "MyIrcTest" ) ), "servers" );
+  assert_eq! ( key_of( ClientBuilder::new( ).port( 6667 ).server( "irc.example.net" ).nick( "MyIrcTest" ) ), "servers" );
+  assert_eq! ( key_of( ClientBuilder::new( ).server( "irc.example.net" ) ), "nick" );
+  assert_eq! ( key_of( ClientBuilder::new( ).server( "irc.example.net" ).nick( "MyIrcTest" ).channel( "rust" ) ),
+    "channels[0]" );
+  assert_eq! ( key_of( ClientBuilder::new( ).server( "irc.example.net" ).nick( "MyIrcTest" ).sasl_plain( "", "pw" ) ),
+    "sasl.account" );
+  assert_eq! ( key_of( ClientBuilder::new( ).server( "irc.example.net" ).connect_timeout( 0 ).nick( "MyIrcTest" ) ),
+    "servers[0].connect_timeout" );
+
+  let c = ClientBuilder::new( )
+    .server( "irc.example.net" )
+    .server( "irc.example.org" ).port( 7000 )
+    .server( "irc.example.com" ).tls( true )
+    .server( "irc.example.edu" ).port( 7000 ).tls( true ).tls_verify( false )
+    .nick( "MyIrcTest" ).alt_nicks( vec![ "MyIrcTest_" ] ).sasl_plain( "acct", "pw" )
+    .build( ).unwrap( );
+  assert_eq! ( c.servers[0].port, 6667 );
+  assert_eq! ( c.servers[1].port, 7000 );
+  assert_eq! ( c.servers[2].port, 6697 );
+  assert! ( c.servers[2].connect.tls && c.servers[2].connect.tls_verify );
+  assert_eq! ( c.servers[3].port, 7000 );
+  assert! ( c.servers[3].connect.tls && !c.servers[3].connect.tls_verify );
+  assert_eq! ( c.user, "MyIrcTest" );
+  assert_eq! ( c.real, "MyIrcTest" );
+  assert_eq! ( c.alt_nicks, vec![ String::from_str( "MyIrcTest_" ) ] );
+}
+
+#[test]
+fn test_client_handle () {
+  fn shareable < T : Send + Sync + Clone > ( ) { }
+  shareable::< ClientHandle >( );
+
+  let mut script = registration( );
+  script.push( mock::expect( "^PRIVMSG #rust :worker [01]$" ) );
+  script.push( mock::expect( "^PRIVMSG #rust :worker [01]$" ) );
+  script.push( mock::send( ":mock.server 001 MyIrcTest :Welcome again" ) );
+  let server = MockServer::start( script ).unwrap( );
+  let ( rx, client ) = connect( &server, vec![] );
+  wait_for( &rx, "001" );
+
+  let workers : Vec < _ > = ( 0 .. 2 ).map( |n| {
+    let handle = client.handle( );
+    Thread::scoped( move || {
+      handle.message( "#rust", format! ( "worker {}", n ).as_slice( ) ).unwrap( );
+      handle.get_info( ).nick_name.clone( )
+    } )
+  } ).collect( );
+  for w in workers.into_iter( ) {
+    assert! ( w.join( ).ok( ).unwrap( ) == "MyIrcTest" );
+  }
+  wait_for( &rx, "001" );
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_cap_negotiation () {
+  let script = vec![
+    mock::send( ":mock.server NOTICE * :*** Looking up your hostname" ),
+    mock::expect( "^CAP LS 302$" ),
+    mock::expect( "^USER MyIrcTest \\* \\* :Testing rust-irc$" ),
+    mock::send( ":mock.server CAP * LS :multi-prefix batch labeled-response" ),
+    mock::expect( "^CAP REQ :labeled-response batch$" ),
+    mock::send( ":mock.server CAP * ACK :labeled-response batch" ),
+    mock::expect( "^CAP END$" ),
+    mock::send( ":mock.server 001 MyIrcTest :Welcome to the mock network" ),
+    mock::expect( "^@label=\\S+ WHOIS Lancey$" ),
+  ];
+  let server = MockServer::start( script ).unwrap( );
+  let ( rx, client ) = connect( &server, vec![] );
+  wait_for( &rx, "001" );
+  client.whois( "Lancey" );
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_alt_nicks () {
+  let script = vec![
+    mock::send( ":mock.server NOTICE * :*** Looking up your hostname" ),
+    mock::expect( "^NICK MyIrcTest$" ),
+    mock::send( ":mock.server 433 * MyIrcTest :Nickname is already in use" ),
+    mock::expect( "^NICK MyIrcTest_$" ),
+    mock::send( ":mock.server 001 MyIrcTest_ :Welcome to the mock network" ),
+    mock::send( "PING :welcomed" ),
+    mock::expect( "^PONG :welcomed$" ),
+  ];
+  let server = MockServer::start( script ).unwrap( );
+  let info = IrcInfo::gen( "MyIrcTest", "MyIrcTest", "Testing rust-irc", vec![] );
+  let mut preclient = Client::connect( server.host.as_slice( ), server.port, "", Box::new( info ) );
+  preclient.register.alt_nicks = vec![ "MyIrcTest_".to_string( ) ];
+  let ( rx, client ) = preclient.start_thread( );
+  wait_for( &rx, "PING" );
+  assert_eq! ( client.get_info( ).nick_name, "MyIrcTest_" );
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_sasl_plain () {
+  let script = vec![
+    mock::send( ":mock.server NOTICE * :*** Looking up your hostname" ),
+    mock::expect( "^CAP LS 302$" ),
+    mock::send( ":mock.server CAP * LS :sasl" ),
+    mock::expect( "^CAP REQ :sasl$" ),
+    mock::send( ":mock.server CAP * ACK :sasl" ),
+    mock::expect( "^AUTHENTICATE PLAIN$" ),
+    mock::send( "AUTHENTICATE +" ),
+    mock::expect( "^AUTHENTICATE YWNjdABhY2N0AHB3$" ),
+    mock::send( ":mock.server 903 MyIrcTest :SASL authentication successful" ),
+    mock::expect( "^CAP END$" ),
+    mock::send( ":mock.server 001 MyIrcTest :Welcome to the mock network" ),
+  ];
+  let server = MockServer::start( script ).unwrap( );
+  let info = IrcInfo::gen( "MyIrcTest", "MyIrcTest", "Testing rust-irc", vec![] );
+  let mut preclient = Client::connect( server.host.as_slice( ), server.port, "", Box::new( info ) );
+  preclient.register.sasl = Some( ( "acct".to_string( ), "pw".to_string( ) ) );
+  let ( rx, _client ) = preclient.start_thread( );
+  wait_for( &rx, "001" );
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_info_snapshots () {
+  let mut script = registration( );
+  script.push( mock::expect( "^JOIN #rust$" ) );
+  script.push( mock::send( ":MyIrcTest!MyIrcTest@localhost JOIN #rust" ) );
+  script.push( mock::send( ":mock.server 353 MyIrcTest = #rust :MyIrcTest @Lancey" ) );
+  script.push( mock::send( ":mock.server 366 MyIrcTest #rust :End of /NAMES list." ) );
+  script.push( mock::send( "PING :names" ) );
+  script.push( mock::expect( "^PONG :names$" ) );
+  script.push( mock::expect( "^PRIVMSG #rust :go$" ) );
+  script.push( mock::send( ":Newbie!n@host JOIN #rust" ) );
+  script.push( mock::send( ":mock.server PRIVMSG #rust :no change" ) );
+  script.push( mock::send( ":MyIrcTest!MyIrcTest@localhost NICK RustyTest" ) );
+  let server = MockServer::start( script ).unwrap( );
+  let info = IrcInfo::gen( "MyIrcTest", "MyIrcTest", "Testing rust-irc", vec![ "#rust" ] );
+  let preclient = Client::connect( server.host.as_slice( ), server.port, "", Box::new( info ) );
+  let events = preclient.info_events( );
+  let ( rx, client ) = preclient.start_thread( );
+  wait_for( &rx, "PING" );
+  let before = client.get_info( );
+  client.message( "#rust", "go" ).unwrap( );
+
+  let mut changes = Vec::new( );
+  for ev in events.iter( ) {
+    changes.push( ev.change.clone( ) );
+    if ev.change == InfoChange::Nick {
+      assert! ( ev.info.nick_name == "RustyTest" );
+      break;
+    }
+  }
+  assert! ( changes == vec![ InfoChange::Channels, InfoChange::Names( "#rust".to_string( ) ),
+    InfoChange::Names( "#rust".to_string( ) ), InfoChange::Nick ] );
+
+  // older snapshots keep what they saw
+  assert! ( before.nick_name == "MyIrcTest" );
+  assert! ( *before.get_channel_names( "#rust".to_string( ) ).unwrap( ) ==
+    vec![ "MyIrcTest".to_string( ), "@Lancey".to_string( ) ] );
+  let after = client.get_info( );
+  assert! ( after.get_channel_names( "#rust".to_string( ) ).unwrap( ).contains( &"Newbie".to_string( ) ) );
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_info_who_burst () {
+  let mut script = registration( );
+  script.push( mock::expect( "^PRIVMSG #rust :go$" ) );
+  script.push( mock::send( ":mock.server 352 MyIrcTest #rust lance host.one mock.server Lancey H :0 Lance" ) );
+  script.push( mock::send( ":mock.server 352 MyIrcTest #rust det host.two mock.server Detective G :0 Det" ) );
+  script.push( mock::send( ":mock.server 315 MyIrcTest #rust :End of /WHO list." ) );
+  script.push( mock::send( ":MyIrcTest!MyIrcTest@localhost NICK RustyTest" ) );
+  let server = MockServer::start( script ).unwrap( );
+  let ( rx, client ) = connect( &server, vec![] );
+  let events = client.info_events( );
+  wait_for( &rx, "001" );
+  client.message( "#rust", "go" ).unwrap( );
+
+  // the whole burst is published once, at the end
+  let mut changes = Vec::new( );
+  for ev in events.iter( ) {
+    changes.push( ev.change.clone( ) );
+    if ev.change == InfoChange::Members {
+      assert! ( ev.info.get_member( "Lancey" ).unwrap( ).host == "host.one" );
+      assert! ( ev.info.get_member( "Detective" ).unwrap( ).away );
+    }
+    if ev.change == InfoChange::Nick {
+      break;
+    }
+  }
+  assert! ( changes == vec![ InfoChange::Members, InfoChange::Nick ] );
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_send_validation () {
+  let mut script = registration( );
+  script.push( mock::send( ":mock.server 005 MyIrcTest CHANTYPES=# NICKLEN=9 :are supported by this server" ) );
+  script.push( mock::expect( "^PRIVMSG NickServ :IDENTIFY acct hunter2$" ) );
+  script.push( mock::expect( "^PRIVMSG #rust :safe$" ) );
+  script.push( mock::expect( "^RAW line$" ) );
+  let server = MockServer::start( script ).unwrap( );
+  let ( rx, client ) = connect( &server, vec![] );
+  wait_for( &rx, "005" );
+
+  // nothing unsafe reaches the server, so the next line it sees is "safe"
+  assert! ( client.message( "#rust", "hi\r\nQUIT :pwned" ).is_err( ) );
+  assert! ( client.message( "#rust\r\nQUIT", "hi" ).is_err( ) );
+  assert! ( client.notice( "two words", "hi" ).is_err( ) );
+  assert! ( client.join( "&local" ).is_err( ) );
+  assert! ( client.join_key( "#rust", "key\nQUIT" ).is_err( ) );
+  assert! ( client.nick( "MuchTooLongNick" ).is_err( ) );
+  assert! ( client.send_str( "PRIVMSG #rust :a\nQUIT" ).is_err( ) );
+  let long = ::std::iter::repeat( 'a' ).take( 600 ).collect::< String >( );
+  assert! ( client.message( "#rust", long.as_slice( ) ).is_err( ) );
+  assert! ( client.whois( "Lancey\r\nQUIT" ).wait_timeout( 1000 ).is_err( ) );
+
+  // lines are measured with room for the prefix the server relays them with
+  let near = ::std::iter::repeat( 'a' ).take( 450 ).collect::< String >( );
+  assert! ( client.message( "#rust", near.as_slice( ) ).is_err( ) );
+
+  // a password may hold spaces, e.g. "account password"
+  client.identify( "acct hunter2" ).unwrap( );
+  client.message( "#rust", "safe" ).unwrap( );
+  client.send_raw( "RAW line" );
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_encodings () {
+  let mut script = registration( );
+  script.push( mock::expect( "^PRIVMSG #rust :go$" ) );
+  script.push( Step::SendRaw( b":a!b@c PRIVMSG #rust :caf\xC3\xA9\r\n".to_vec( ) ) );
+  script.push( Step::SendRaw( b":a!b@c PRIVMSG #rust :caf\xE9\r\n".to_vec( ) ) );
+  script.push( Step::SendRaw( b":a!b@c PRIVMSG #vieux :caf\xC3\xA9\n".to_vec( ) ) );
+  script.push( mock::expect( "^PRIVMSG #rust :still here$" ) );
+  let server = MockServer::start( script ).unwrap( );
+  let ( rx, client ) = connect( &server, vec![] );
+  wait_for( &rx, "001" );
+  client.set_channel_encoding( "#Vieux", Some( Encoding::Cp1252 ) );
+  client.message( "#rust", "go" ).unwrap( );
+
+  // UTF-8 is read as UTF-8
+  let msg = wait_for( &rx, "PRIVMSG" );
+  assert! ( msg.trailing( ) == Some( "café" ) );
+
+  // anything else falls back to CP1252, with the bytes kept as they came
+  let msg = wait_for( &rx, "PRIVMSG" );
+  assert! ( msg.trailing( ) == Some( "café" ) );
+  assert! ( msg.bytes == Some( b":a!b@c PRIVMSG #rust :caf\xE9".to_vec( ) ) );
+
+  // a channel's own encoding wins, even over valid UTF-8
+  let msg = wait_for( &rx, "PRIVMSG" );
+  assert! ( msg.trailing( ) == Some( "caf\u{C3}\u{A9}" ) );
+  client.message( "#rust", "still here" ).unwrap( );
+  server.finish( ).unwrap( );
+}
+
+#[test]
+fn test_line_framing () {
+  let long = format! ( ":a!b@c PRIVMSG #rust :{}", ::std::iter::repeat( 'a' ).take( 600 ).collect::< String >( ) );
+  let mut script = registration( );
+  script.push( mock::send( long.as_slice( ) ) );
+  script.push( Step::SendRaw( b":a!b@c PRIVMSG #rust :bare LF  \n".to_vec( ) ) );
+  script.push( mock::send( ":a!b@c PRIVMSG #rust :spaced  " ) );
+  script.push( mock::expect( "^PRIVMSG #rust :still here$" ) );
+  let server = MockServer::start( script ).unwrap( );
+  let info = IrcInfo::gen( "MyIrcTest", "MyIrcTest", "Testing rust-irc", vec![] );
+  let mut preclient = Client::connect( server.host.as_slice( ), server.port, "", Box::new( info ) );
+  preclient.conn.max_line = 512;
+  let oversized = preclient.oversized_lines( );
+  let ( rx, client ) = preclient.start_thread( );
+
+  // the oversized line is dropped and reported, and trailing spaces are kept
+  assert_eq! ( oversized.recv( ).unwrap( ), long.len( ) + 2 );
+  let msg = wait_for( &rx, "PRIVMSG" );
+  assert! ( msg.trailing( ) == Some( "bare LF  " ) );
+  let msg = wait_for( &rx, "PRIVMSG" );
+  assert! ( msg.trailing( ) == Some( "spaced  " ) );
+  client.message( "#rust", "still here" ).unwrap( );
+  server.finish( ).unwrap( );
+}
@@ -0,0 +1,33 @@
+#![feature(slicing_syntax)]
+#![allow(unstable)]
+extern crate rustirc;
+
+use rustirc::client::Client;
+use rustirc::info::IrcInfo;
+use rustirc::server::{Server, ServerConfig};
+
+#[test]
+fn test_client_against_server () {
+  let mut config = ServerConfig::new( "irc.test" );
+  config.bind = String::from_str( "127.0.0.1:0" );
+  let server = Server::start( config ).unwrap( );
+
+  let info = IrcInfo::gen( "MyIrcTest", "MyIrcTest", "Testing rust-irc", vec![ "#rust" ] );
+  let host = server.local.ip.to_string( );
+  let preclient = Client::connect( host.as_slice( ), server.local.port, "", Box::new( info ) );
+  let ( rx, client ) = preclient.start_thread( );
+
+  for msg in rx.iter( ) {
+    if msg.code.as_slice( ) == "366" {
+      break;
+    }
+  }
+  let info = client.get_info( );
+  assert! ( info.channels == vec![ "#rust".to_string( ) ] );
+  assert! ( *info.get_channel_names( "#rust".to_string( ) ).unwrap( ) == vec![ "@MyIrcTest".to_string( ) ] );
+
+  // the client's receiver ends once the server closes the link
+  server.stop( );
+  for _ in rx.iter( ) { }
+  server.wait( );
+}